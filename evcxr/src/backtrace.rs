@@ -0,0 +1,112 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::code_block::CellLocation;
+use crate::code_block::CodeBlock;
+use regex::Regex;
+use std::path::Path;
+
+/// Maps lines of the crate that we most recently compiled back to the cells that they came from, so
+/// that panic messages and backtraces can refer to the user's code.
+#[derive(Default)]
+pub(crate) struct CellLineMap {
+    /// Indexed by line number in the generated source, starting from 0.
+    locations: Vec<Option<CellLocation>>,
+    /// Matches references to lines of our generated source.
+    location_re: Option<Regex>,
+}
+
+impl CellLineMap {
+    pub(crate) fn new(code: &CodeBlock, current_cell: usize, crate_dir: &Path) -> CellLineMap {
+        // Panic messages use a path relative to the crate, whereas backtraces use an absolute path.
+        // We don't want to match paths in other crates, even if they happen to end in src/lib.rs.
+        let location_re = Regex::new(&format!(
+            r"(^|[ \t',(])(?:{}/)?src/lib\.rs:([0-9]+):[0-9]+",
+            regex::escape(&crate_dir.to_string_lossy())
+        ))
+        .ok();
+        CellLineMap {
+            locations: code.cell_locations(current_cell),
+            location_re,
+        }
+    }
+
+    /// Returns `line` with each reference to a line of our generated source file followed by the
+    /// cell and line that it came from.
+    pub(crate) fn annotate(&self, line: &str) -> String {
+        let location_re = if let Some(location_re) = &self.location_re {
+            location_re
+        } else {
+            return line.to_owned();
+        };
+        let mut annotated = String::new();
+        let mut copied_up_to = 0;
+        for captures in location_re.captures_iter(line) {
+            let location = captures[2]
+                .parse::<usize>()
+                .ok()
+                .and_then(|line_number| self.locations.get(line_number.checked_sub(1)?))
+                .copied()
+                .flatten();
+            if let Some(location) = location {
+                let end = captures.get(0).unwrap().end();
+                annotated.push_str(&line[copied_up_to..end]);
+                annotated.push_str(&format!(
+                    " (cell {}, line {})",
+                    location.cell, location.line
+                ));
+                copied_up_to = end;
+            }
+        }
+        annotated.push_str(&line[copied_up_to..]);
+        annotated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CellLineMap;
+    use crate::code_block::CodeBlock;
+    use std::path::Path;
+
+    #[test]
+    fn test_annotate() {
+        let (mut earlier, _) = CodeBlock::from_original_user_code("fn foo() {\n    panic!();\n}");
+        earlier.commit_old_user_code(3);
+        let (current, _) = CodeBlock::from_original_user_code("let a = 1;\nfoo();");
+        let code = CodeBlock::new()
+            .generated("fn wrapper() {")
+            .add_all(earlier)
+            .add_all(current)
+            .generated("}");
+        let map = CellLineMap::new(&code, 5, Path::new("/tmp/ctx"));
+        assert_eq!(
+            map.annotate("thread '<unnamed>' panicked at 'explicit panic', src/lib.rs:3:5"),
+            "thread '<unnamed>' panicked at 'explicit panic', src/lib.rs:3:5 (cell 3, line 2)"
+        );
+        assert_eq!(
+            map.annotate("             at /tmp/ctx/src/lib.rs:6:1"),
+            "             at /tmp/ctx/src/lib.rs:6:1 (cell 5, line 2)"
+        );
+        // Generated code and paths in other crates are left alone.
+        assert_eq!(
+            map.annotate("             at src/lib.rs:1:1"),
+            "             at src/lib.rs:1:1"
+        );
+        assert_eq!(
+            map.annotate("             at /home/foo/bar/src/lib.rs:3:5"),
+            "             at /home/foo/bar/src/lib.rs:3:5"
+        );
+    }
+}
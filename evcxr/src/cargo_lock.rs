@@ -0,0 +1,219 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Returns the versions of registry packages recorded in the supplied Cargo.lock contents, keyed by
+/// package name. Packages that appear with more than one version are omitted, since we can't tell
+/// which of those versions a dependency is referring to.
+pub(crate) fn registry_versions(lock_contents: &str) -> HashMap<String, String> {
+    unique_versions(lock_contents, true)
+}
+
+/// Like `registry_versions`, but also includes path and git packages.
+pub(crate) fn package_versions(lock_contents: &str) -> HashMap<String, String> {
+    unique_versions(lock_contents, false)
+}
+
+fn unique_versions(lock_contents: &str, registry_only: bool) -> HashMap<String, String> {
+    // As with make_paths_absolute, we avoid pulling in a toml parser. The lock file format is
+    // generated by cargo and is sufficiently regular that matching on lines works fine.
+    static KEY_VALUE_RE: OnceCell<Regex> = OnceCell::new();
+    let key_value_re =
+        KEY_VALUE_RE.get_or_init(|| Regex::new("^(name|version|source) *= *\"([^\"]*)\"").unwrap());
+    let mut versions_by_name: HashMap<String, Vec<String>> = HashMap::new();
+    let mut add_package = |name: Option<String>, version: Option<String>, is_registry: bool| {
+        if let (Some(name), Some(version), true) = (name, version, is_registry || !registry_only) {
+            let versions = versions_by_name.entry(name).or_default();
+            if !versions.contains(&version) {
+                versions.push(version);
+            }
+        }
+    };
+    let mut name = None;
+    let mut version = None;
+    let mut is_registry = false;
+    for line in lock_contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            add_package(name.take(), version.take(), is_registry);
+            is_registry = false;
+        } else if let Some(captures) = key_value_re.captures(line) {
+            let value = captures[2].to_owned();
+            match &captures[1] {
+                "name" => name = Some(value),
+                "version" => version = Some(value),
+                _ => is_registry = value.starts_with("registry+"),
+            }
+        }
+    }
+    add_package(name, version, is_registry);
+    versions_by_name
+        .into_iter()
+        .filter_map(|(name, mut versions)| {
+            if versions.len() == 1 {
+                versions.pop().map(|version| (name, version))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns the number of packages in the supplied Cargo.lock contents.
+pub(crate) fn package_count(lock_contents: &str) -> usize {
+    lock_contents
+        .lines()
+        .filter(|line| line.trim() == "[[package]]")
+        .count()
+}
+
+/// Returns the name of the package that a dependency refers to. This is the dependency name, unless
+/// it has been renamed with `package = "..."`.
+pub(crate) fn package_name(dep_name: &str, dep_config: &str) -> String {
+    static PACKAGE_RE: OnceCell<Regex> = OnceCell::new();
+    let package_re = PACKAGE_RE.get_or_init(|| Regex::new("package *= *\"([^\"]+)\"").unwrap());
+    package_re
+        .captures(dep_config)
+        .map(|captures| captures[1].to_owned())
+        .unwrap_or_else(|| dep_name.to_owned())
+}
+
+/// Returns `dep_config` with its version requirement replaced by an exact requirement on the version
+/// from `locked_versions`. Dependencies that aren't in `locked_versions` or that don't come from a
+/// registry (e.g. path or git dependencies) are returned unchanged.
+pub(crate) fn pin_version(
+    dep_name: &str,
+    dep_config: &str,
+    locked_versions: &HashMap<String, String>,
+) -> String {
+    static VERSION_RE: OnceCell<Regex> = OnceCell::new();
+    let version_re = VERSION_RE.get_or_init(|| Regex::new("version *= *\"[^\"]*\"").unwrap());
+    let locked_version = if let Some(v) = locked_versions.get(&package_name(dep_name, dep_config)) {
+        v
+    } else {
+        return dep_config.to_owned();
+    };
+    let trimmed = dep_config.trim();
+    if trimmed.starts_with('"') {
+        format!("\"={}\"", locked_version)
+    } else {
+        version_re
+            .replace(
+                dep_config,
+                format!("version = \"={}\"", locked_version).as_str(),
+            )
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::package_count;
+    use super::package_versions;
+    use super::pin_version;
+    use super::registry_versions;
+
+    const SAMPLE_LOCK: &str = r#"
+# This file is automatically @generated by Cargo.
+# It is not intended for manual editing.
+version = 3
+
+[[package]]
+name = "regex"
+version = "1.5.4"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "d07a8629359eb56f1e2fb1652bb04212c072a87ba68546a04065d525673ac461"
+dependencies = [
+ "aho-corasick",
+]
+
+[[package]]
+name = "my_local_crate"
+version = "0.1.0"
+
+[[package]]
+name = "syn"
+version = "0.15.44"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "syn"
+version = "1.0.98"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "unicode-xid"
+version = "0.2.3"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+
+    #[test]
+    fn test_registry_versions() {
+        let versions = registry_versions(SAMPLE_LOCK);
+        assert_eq!(versions.get("regex").map(String::as_str), Some("1.5.4"));
+        assert_eq!(
+            versions.get("unicode-xid").map(String::as_str),
+            Some("0.2.3")
+        );
+        // Not from a registry.
+        assert_eq!(versions.get("my_local_crate"), None);
+        // Ambiguous.
+        assert_eq!(versions.get("syn"), None);
+    }
+
+    #[test]
+    fn test_package_versions() {
+        let versions = package_versions(SAMPLE_LOCK);
+        assert_eq!(versions.get("regex").map(String::as_str), Some("1.5.4"));
+        assert_eq!(
+            versions.get("my_local_crate").map(String::as_str),
+            Some("0.1.0")
+        );
+        assert_eq!(versions.get("syn"), None);
+    }
+
+    #[test]
+    fn test_package_count() {
+        assert_eq!(package_count(SAMPLE_LOCK), 5);
+        assert_eq!(package_count(""), 0);
+    }
+
+    #[test]
+    fn test_pin_version() {
+        let versions = registry_versions(SAMPLE_LOCK);
+        assert_eq!(pin_version("regex", "\"1.0\"", &versions), "\"=1.5.4\"");
+        assert_eq!(
+            pin_version(
+                "regex",
+                "{ version = \"1\", features = [\"std\"] }",
+                &versions
+            ),
+            "{ version = \"=1.5.4\", features = [\"std\"] }"
+        );
+        assert_eq!(
+            pin_version(
+                "unicode_xid",
+                "{ package = \"unicode-xid\", version = \"*\" }",
+                &versions
+            ),
+            "{ package = \"unicode-xid\", version = \"=0.2.3\" }"
+        );
+        // Deps that aren't in the lock file are left as-is.
+        assert_eq!(pin_version("rand", "\"0.8\"", &versions), "\"0.8\"");
+        assert_eq!(pin_version("syn", "\"1.0\"", &versions), "\"1.0\"");
+    }
+}
@@ -19,13 +19,25 @@ use json::JsonValue;
 use json::{self};
 use once_cell::sync::OnceCell;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 
 use crate::eval_context::Config;
 
 /// Returns the library names for the direct dependencies of the crate rooted at
 /// the specified path.
 pub(crate) fn get_library_names(config: &Config) -> Result<Vec<String>> {
+    library_names_from_metadata(&run_cargo_metadata(config)?)
+}
+
+/// Returns the dependencies of the crate rooted at the specified path, including transitive
+/// dependencies, formatted as an indented tree.
+pub(crate) fn get_dependency_tree(config: &Config) -> Result<String> {
+    dependency_tree_from_metadata(&run_cargo_metadata(config)?)
+}
+
+fn run_cargo_metadata(config: &Config) -> Result<String> {
     let output = config
         .cargo_command("metadata")
         .arg("--format-version")
@@ -33,7 +45,7 @@ pub(crate) fn get_library_names(config: &Config) -> Result<Vec<String>> {
         .output()
         .with_context(|| "Error running cargo metadata")?;
     if output.status.success() {
-        library_names_from_metadata(std::str::from_utf8(&output.stdout)?)
+        Ok(std::str::from_utf8(&output.stdout)?.to_owned())
     } else {
         bail!(
             "cargo metadata failed with output:\n{}{}",
@@ -81,6 +93,13 @@ pub(crate) fn validate_dep(dep: &str, dep_config: &str, config: &Config) -> Resu
                 message.push(line.to_owned());
             }
         }
+        if config.offline_mode {
+            message.push(
+                "Offline mode is on, so only crates that are already in the local cargo cache can \
+                 be used. Use `:offline off` to allow downloading crates."
+                    .to_owned(),
+            );
+        }
         bail!(message.join("\n"));
     }
 }
@@ -130,15 +149,129 @@ fn library_names_from_metadata(metadata: &str) -> Result<Vec<String>> {
     Ok(library_names)
 }
 
+fn dependency_tree_from_metadata(metadata: &str) -> Result<String> {
+    let metadata = json::parse(metadata)?;
+    let mut descriptions = HashMap::new();
+    let mut versions_by_name: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for package in metadata["packages"].members() {
+        if let (Some(id), Some(name), Some(version)) = (
+            package["id"].as_str(),
+            package["name"].as_str(),
+            package["version"].as_str(),
+        ) {
+            descriptions.insert(id, format!("{} v{}", name, version));
+            versions_by_name.entry(name).or_default().push(version);
+        }
+    }
+    // For each package, the packages that it depends on, together with whether they're only a
+    // build dependency. Dev-dependencies aren't included.
+    let mut dependencies: HashMap<&str, Vec<(&str, bool)>> = HashMap::new();
+    for node in metadata["resolve"]["nodes"].members() {
+        let id = match node["id"].as_str() {
+            Some(id) => id,
+            None => continue,
+        };
+        let mut node_dependencies = Vec::new();
+        for dep in node["deps"].members() {
+            let dep_kinds = &dep["dep_kinds"];
+            let is_normal = dep_kinds.members().any(|kind| kind["kind"].is_null());
+            let is_build = dep_kinds.members().any(|kind| kind["kind"] == "build");
+            if let Some(dep_id) = dep["pkg"].as_str() {
+                if is_normal || is_build {
+                    node_dependencies.push((dep_id, !is_normal));
+                }
+            }
+        }
+        node_dependencies.sort_by_key(|(dep_id, _)| descriptions.get(dep_id));
+        dependencies.insert(id, node_dependencies);
+    }
+    let root = metadata["resolve"]["root"]
+        .as_str()
+        .or_else(|| metadata["workspace_members"][0].as_str());
+    let mut lines = Vec::new();
+    if let Some(root) = root {
+        add_dependency_tree_lines(
+            root,
+            0,
+            &descriptions,
+            &dependencies,
+            &mut HashSet::new(),
+            &mut lines,
+        );
+    }
+    if lines.is_empty() {
+        return Ok("No dependencies".to_owned());
+    }
+    if lines.iter().any(|line| line.ends_with("(*)")) {
+        lines.push(String::new());
+        lines.push("(*) Dependencies already shown above".to_owned());
+    }
+    // Different versions of the same crate are distinct as far as the compiler is concerned, so
+    // types from one can't be used where types from another are expected.
+    let multiple_versions: Vec<String> = versions_by_name
+        .iter()
+        .filter(|(_, versions)| versions.len() > 1)
+        .map(|(name, versions)| format!("{} (v{})", name, versions.join(", v")))
+        .collect();
+    if !multiple_versions.is_empty() {
+        lines.push(String::new());
+        lines.push(format!(
+            "Crates with more than one version: {}",
+            multiple_versions.join(", ")
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Adds a line for each dependency of the package with ID `id`, each followed by the lines for
+/// its own dependencies, indented by a further level. The dependencies of each package are only
+/// shown the first time that package is shown.
+fn add_dependency_tree_lines<'a>(
+    id: &str,
+    depth: usize,
+    descriptions: &HashMap<&str, String>,
+    dependencies: &HashMap<&str, Vec<(&'a str, bool)>>,
+    expanded: &mut HashSet<&'a str>,
+    lines: &mut Vec<String>,
+) {
+    for (dep_id, is_build) in dependencies.get(id).into_iter().flatten() {
+        let mut line = format!(
+            "{}{}",
+            "  ".repeat(depth),
+            descriptions.get(dep_id).map_or(*dep_id, String::as_str)
+        );
+        if *is_build {
+            line.push_str(" (build)");
+        }
+        let has_dependencies = dependencies
+            .get(dep_id)
+            .map_or(false, |deps| !deps.is_empty());
+        if has_dependencies && !expanded.insert(dep_id) {
+            line.push_str(" (*)");
+            lines.push(line);
+            continue;
+        }
+        lines.push(line);
+        add_dependency_tree_lines(
+            dep_id,
+            depth + 1,
+            descriptions,
+            dependencies,
+            expanded,
+            lines,
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::eval_context::Config;
 
+    use super::dependency_tree_from_metadata;
     use super::get_library_names;
     use super::library_names_from_metadata;
     use anyhow::Result;
     use std::path::Path;
-    use tempfile;
 
     #[test]
     fn test_library_names_from_metadata() {
@@ -148,6 +281,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dependency_tree_from_metadata() {
+        assert_eq!(
+            dependency_tree_from_metadata(include_str!("testdata/sample_metadata.json")).unwrap(),
+            "crate1 v0.0.1"
+        );
+    }
+
     fn create_crate(path: &Path, name: &str, deps: &str) -> Result<()> {
         let src_dir = path.join("src");
         std::fs::create_dir_all(&src_dir)?;
@@ -171,7 +312,7 @@ mod tests {
     }
 
     fn path_to_string(path: &Path) -> String {
-        path.to_string_lossy().replace("\\", "\\\\")
+        path.to_string_lossy().replace('\\', "\\\\")
     }
 
     #[test]
@@ -186,7 +327,7 @@ mod tests {
             &format!(r#"crate1 = {{ path = "{}" }}"#, path_to_string(&crate1)),
         )?;
         assert_eq!(
-            get_library_names(&Config::new(crate2.to_owned())).unwrap(),
+            get_library_names(&Config::new(crate2)).unwrap(),
             vec!["crate1".to_owned()]
         );
         Ok(())
@@ -208,7 +349,7 @@ mod tests {
         )?;
         // Make sure that the problematic feature "no_such_feature" is mentioned
         // somewhere in the error message.
-        assert!(get_library_names(&Config::new(crate2.to_owned()))
+        assert!(get_library_names(&Config::new(crate2))
             .unwrap_err()
             .to_string()
             .contains("no_such_feature"));
@@ -12,27 +12,151 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::backtrace::CellLineMap;
 use crate::errors::bail;
 use crate::errors::Error;
+use crate::eval_context::PARTIAL_LINE_MARKER;
 use crate::runtime;
+use std::borrow::Cow;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 
 pub(crate) struct ChildProcess {
-    process: std::process::Child,
-    stdout: std::io::Lines<BufReader<std::process::ChildStdout>>,
+    process: Arc<Mutex<std::process::Child>>,
+    /// Lines from the process's stdout, other than those that were forwarded directly to
+    /// `stdout_sender` because they were written by background threads between cells.
+    stdout: crossbeam_channel::Receiver<String>,
+    stdout_state: Arc<Mutex<StdoutState>>,
     // Only none while in drop.
     stdin: Option<std::process::ChildStdin>,
+    shared: SharedState,
+    /// The message of the most recent panic in user code during the current execution, if any.
+    panic_message: Option<String>,
+}
+
+/// State that's shared by a child process, the threads that read its output and the processes
+/// that replace it when it's restarted.
+#[derive(Clone)]
+struct SharedState {
     command: Arc<Mutex<process::Command>>,
     stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+    cell_line_map: Arc<Mutex<CellLineMap>>,
+    /// The maximum number of characters in a line of stderr before it gets truncated, or 0 for no
+    /// limit.
+    max_line_length: Arc<AtomicUsize>,
+    /// Whether we wait for a whole line of stdout before passing it on. If false, partial lines
+    /// are passed on as soon as they're received.
+    line_buffered: Arc<AtomicBool>,
+    /// Whether output after a carriage return replaces the line it was written on, rather than
+    /// being appended to it. See `ProgressLine`.
+    progress_bars: Arc<AtomicBool>,
+    /// Used when lines of stderr are merged into stdout.
+    stdout_sender: crossbeam_channel::Sender<String>,
+    stderr_mode: Arc<Mutex<StderrMode>>,
+    interrupt_handle: InterruptHandle,
+}
+
+/// Allows code that's running in the subprocess to be stopped from another thread, e.g. when the
+/// user asks a Jupyter kernel to interrupt execution. The evaluation that was running then fails
+/// with `Error::Interrupted`. A running Rust thread can't be stopped without its cooperation. If
+/// the cell is running on the subprocess's worker thread (`:run_on worker`), the subprocess
+/// abandons that thread and uses a new one for later cells. Variables that the cell doesn't
+/// reference are kept, while those that it does are lost. The abandoned thread carries on until
+/// the cell finishes, but is stopped if it tries to store variables. Otherwise, or on platforms
+/// where this isn't supported, the subprocess is killed and all variables are lost, as they would
+/// be if it had crashed. Either way, definitions and dependencies are kept. Handles remain valid
+/// when the subprocess is restarted.
+#[derive(Clone, Default)]
+pub struct InterruptHandle {
+    /// The cell that's running, if any.
+    running: Arc<Mutex<Option<RunningCell>>>,
+    /// Whether the process was killed by `interrupt`.
+    interrupted: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+struct RunningCell {
+    process: Arc<Mutex<std::process::Child>>,
+    /// Whether the cell is running on the worker thread, which the process can abandon.
+    on_worker: bool,
+}
+
+impl InterruptHandle {
+    /// Stops the cell that's currently running, if any. Returns whether there was one.
+    pub fn interrupt(&self) -> bool {
+        match &*self.running.lock().unwrap() {
+            Some(cell) => {
+                let mut process = cell.process.lock().unwrap();
+                if cell.on_worker && runtime::worker_interrupt_supported() {
+                    runtime::request_worker_interrupt(process.id());
+                } else {
+                    self.interrupted.store(true, Ordering::SeqCst);
+                    let _ = process.kill();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_running(&self, cell: Option<RunningCell>) {
+        *self.running.lock().unwrap() = cell;
+    }
+}
+
+/// State shared with the thread that reads stdout from the child process.
+#[derive(Default)]
+struct StdoutState {
+    /// Whether a cell is running. Set when we ask the child process to run a cell and cleared when
+    /// it reports that it's done.
+    executing: bool,
+    /// The number of threads spawned by `evcxr_runtime::spawn` during the last cell that are still
+    /// running. While there are any and no cell is running, their output is forwarded directly.
+    background_threads: usize,
+}
+
+/// Where lines written to stderr by the child process get sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StderrMode {
+    /// To the stderr channel, so that frontends can display them differently to stdout.
+    Separate,
+    /// To the stdout channel, interleaved with regular output.
+    Merge,
+    /// Nowhere.
+    Discard,
+}
+
+impl StderrMode {
+    pub(crate) fn parse(mode: &str) -> Result<StderrMode, Error> {
+        Ok(match mode {
+            "separate" => StderrMode::Separate,
+            "merge" => StderrMode::Merge,
+            "discard" => StderrMode::Discard,
+            other => bail!("Expected `separate`, `merge` or `discard`, got `{}`", other),
+        })
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            StderrMode::Separate => "separate",
+            StderrMode::Merge => "merge",
+            StderrMode::Discard => "discard",
+        }
+    }
 }
 
 impl ChildProcess {
     pub(crate) fn new(
         mut command: std::process::Command,
+        stdout_sender: crossbeam_channel::Sender<String>,
         stderr_sender: crossbeam_channel::Sender<String>,
+        cell_line_map: Arc<Mutex<CellLineMap>>,
     ) -> Result<ChildProcess, Error> {
         // Avoid a fork bomb. We could call runtime_hook here but then all the work that we did up
         // to this point would be wasted. Also, it's possible that we could already have started
@@ -46,56 +170,235 @@ impl ChildProcess {
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
             .stderr(std::process::Stdio::piped());
-        ChildProcess::new_internal(
-            Arc::new(Mutex::new(command)),
-            Arc::new(Mutex::new(stderr_sender)),
-        )
+        ChildProcess::new_internal(SharedState {
+            command: Arc::new(Mutex::new(command)),
+            stderr_sender: Arc::new(Mutex::new(stderr_sender)),
+            cell_line_map,
+            max_line_length: Arc::new(AtomicUsize::new(0)),
+            line_buffered: Arc::new(AtomicBool::new(true)),
+            progress_bars: Arc::new(AtomicBool::new(false)),
+            stdout_sender,
+            stderr_mode: Arc::new(Mutex::new(StderrMode::Separate)),
+            interrupt_handle: InterruptHandle::default(),
+        })
     }
 
-    fn new_internal(
-        command: Arc<Mutex<std::process::Command>>,
-        stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
-    ) -> Result<ChildProcess, Error> {
-        let process = command.lock().unwrap().spawn();
+    fn new_internal(shared: SharedState) -> Result<ChildProcess, Error> {
+        let process = shared.command.lock().unwrap().spawn();
         let mut process = match process {
             Ok(c) => c,
-            Err(error) => bail!("Failed to run '{:?}': {:?}", command, error),
+            Err(error) => bail!("Failed to run '{:?}': {:?}", shared.command, error),
         };
 
-        let stdout = std::io::BufRead::lines(BufReader::new(process.stdout.take().unwrap()));
+        let mut child_stdout =
+            OutputLineReader::new(BufReader::new(process.stdout.take().unwrap()));
+        let stdout_state = Arc::new(Mutex::new(StdoutState::default()));
+        let (stdout_line_sender, stdout) = crossbeam_channel::unbounded();
+        std::thread::spawn({
+            let stdout_state = Arc::clone(&stdout_state);
+            let max_line_length = Arc::clone(&shared.max_line_length);
+            let line_buffered = Arc::clone(&shared.line_buffered);
+            let progress_bars = Arc::clone(&shared.progress_bars);
+            let stdout_sender = shared.stdout_sender.clone();
+            move || {
+                // Whether we're within content of a specific mime type, which we mustn't split.
+                let mut in_content = false;
+                let mut progress_line = ProgressLine::default();
+                loop {
+                    let whole_lines = || {
+                        (line_buffered.load(Ordering::Relaxed)
+                            && !progress_bars.load(Ordering::Relaxed))
+                            || in_content
+                    };
+                    let line = match child_stdout.next_line(whole_lines) {
+                        Some(line) => line,
+                        None => break,
+                    };
+                    let progress_bars = progress_bars.load(Ordering::Relaxed);
+                    if line.starts_with("EVCXR_BEGIN_CONTENT") {
+                        in_content = true;
+                    } else if line == "EVCXR_END_CONTENT" {
+                        in_content = false;
+                    }
+                    let line = if progress_bars && !in_content && !line.starts_with("EVCXR_") {
+                        match progress_line.update(&line, !child_stdout.partial) {
+                            Some(line) => line,
+                            None => continue,
+                        }
+                    } else {
+                        progress_line = ProgressLine::default();
+                        line
+                    };
+                    let line = mark_partial_line(line, child_stdout.partial);
+                    let mut state = stdout_state.lock().unwrap();
+                    if line == runtime::EVCXR_BACKGROUND_THREAD_STARTED {
+                        state.background_threads += 1;
+                        continue;
+                    }
+                    if line == runtime::EVCXR_BACKGROUND_THREAD_FINISHED {
+                        state.background_threads = state.background_threads.saturating_sub(1);
+                        continue;
+                    }
+                    if !state.executing
+                        && state.background_threads > 0
+                        && !line.starts_with("EVCXR_")
+                    {
+                        let line = match max_line_length.load(Ordering::Relaxed) {
+                            0 => line,
+                            max => truncate_long_line(&line, max).into_owned(),
+                        };
+                        // Ignore errors, since it just means that the user of the library has
+                        // dropped the receive end.
+                        let _ = stdout_sender.send(line);
+                        continue;
+                    }
+                    if line == runtime::EVCXR_EXECUTION_COMPLETE
+                        || line == runtime::EVCXR_EXECUTION_INTERRUPTED
+                    {
+                        state.executing = false;
+                    }
+                    if stdout_line_sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
 
         // Handle stderr by patching it through to a channel in our output struct.
         let mut child_stderr =
-            std::io::BufRead::lines(BufReader::new(process.stderr.take().unwrap()));
+            OutputLineReader::new(BufReader::new(process.stderr.take().unwrap()));
         std::thread::spawn({
-            let stderr_sender = Arc::clone(&stderr_sender);
+            let stderr_sender = Arc::clone(&shared.stderr_sender);
+            let cell_line_map = Arc::clone(&shared.cell_line_map);
+            let max_line_length = Arc::clone(&shared.max_line_length);
+            let progress_bars = Arc::clone(&shared.progress_bars);
+            let stdout_sender = shared.stdout_sender.clone();
+            let stderr_mode = Arc::clone(&shared.stderr_mode);
             move || {
                 let stderr_sender = stderr_sender.lock().unwrap();
-                while let Some(Ok(line)) = child_stderr.next() {
+                let mut progress_line = ProgressLine::default();
+                while let Some(line) =
+                    child_stderr.next_line(|| !progress_bars.load(Ordering::Relaxed))
+                {
+                    let progress_bars = progress_bars.load(Ordering::Relaxed);
+                    let line = if progress_bars {
+                        match progress_line.update(&line, !child_stderr.partial) {
+                            Some(line) => line,
+                            None => continue,
+                        }
+                    } else {
+                        line
+                    };
+                    let line = mark_partial_line(line, child_stderr.partial);
+                    let line = match max_line_length.load(Ordering::Relaxed) {
+                        0 => line,
+                        max => truncate_long_line(&line, max).into_owned(),
+                    };
+                    // Point panic locations and backtrace frames at the user's code.
+                    let line = cell_line_map.lock().unwrap().annotate(&line);
                     // Ignore errors, since it just means that the user of the library has dropped the receive end.
-                    let _ = stderr_sender.send(line);
+                    match *stderr_mode.lock().unwrap() {
+                        StderrMode::Separate => {
+                            let _ = stderr_sender.send(line);
+                        }
+                        StderrMode::Merge => {
+                            let _ = stdout_sender.send(line);
+                        }
+                        StderrMode::Discard => {}
+                    }
                 }
             }
         });
 
         let stdin = process.stdin.take();
         Ok(ChildProcess {
-            process,
+            process: Arc::new(Mutex::new(process)),
             stdout,
+            stdout_state,
             stdin,
-            command,
-            stderr_sender,
+            shared,
+            panic_message: None,
         })
     }
 
     /// Terminates this process if it hasn't already, then restarts
     pub(crate) fn restart(&mut self) -> Result<ChildProcess, Error> {
         // If the process hasn't already terminated for some reason, kill it.
-        if let Ok(None) = self.process.try_wait() {
-            let _ = self.process.kill();
-            let _ = self.process.wait();
+        self.shared.interrupt_handle.set_running(None);
+        {
+            let mut process = self.process.lock().unwrap();
+            if let Ok(None) = process.try_wait() {
+                let _ = process.kill();
+                let _ = process.wait();
+            }
         }
-        ChildProcess::new_internal(Arc::clone(&self.command), Arc::clone(&self.stderr_sender))
+        ChildProcess::new_internal(self.shared.clone())
+    }
+
+    /// Returns whether the process has exited, e.g. because it was killed by an interrupt.
+    pub(crate) fn has_terminated(&self) -> bool {
+        !matches!(self.process.lock().unwrap().try_wait(), Ok(None))
+    }
+
+    pub(crate) fn interrupt_handle(&self) -> InterruptHandle {
+        self.shared.interrupt_handle.clone()
+    }
+
+    pub(crate) fn set_stderr_mode(&self, stderr_mode: StderrMode) {
+        *self.shared.stderr_mode.lock().unwrap() = stderr_mode;
+    }
+
+    pub(crate) fn set_max_line_length(&self, max_line_length: Option<usize>) {
+        self.shared
+            .max_line_length
+            .store(max_line_length.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_line_buffered(&self, line_buffered: bool) {
+        self.shared
+            .line_buffered
+            .store(line_buffered, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_progress_bars(&self, progress_bars: bool) {
+        self.shared
+            .progress_bars
+            .store(progress_bars, Ordering::Relaxed);
+    }
+
+    /// Returns a copy of the command that we use to start our process, suitable for starting
+    /// another, independent, child process.
+    pub(crate) fn command_copy(&self) -> process::Command {
+        let command = self.shared.command.lock().unwrap();
+        let mut copy = process::Command::new(command.get_program());
+        copy.args(command.get_args());
+        for (key, value) in command.get_envs() {
+            match value {
+                Some(value) => copy.env(key, value),
+                None => copy.env_remove(key),
+            };
+        }
+        if let Some(dir) = command.get_current_dir() {
+            copy.current_dir(dir);
+        }
+        copy
+    }
+
+    /// Records that we're about to ask the process to run a cell, on its worker thread if
+    /// `on_worker` is true. Output from background threads spawned by earlier cells is no longer
+    /// forwarded directly, but shown as part of the output of this cell.
+    pub(crate) fn begin_execution(&self, on_worker: bool) {
+        let mut state = self.stdout_state.lock().unwrap();
+        state.executing = true;
+        state.background_threads = 0;
+        self.shared
+            .interrupt_handle
+            .interrupted
+            .store(false, Ordering::SeqCst);
+        self.shared.interrupt_handle.set_running(Some(RunningCell {
+            process: Arc::clone(&self.process),
+            on_worker,
+        }));
     }
 
     pub(crate) fn send(&mut self, command: &str) -> Result<(), Error> {
@@ -107,23 +410,61 @@ impl ChildProcess {
     }
 
     pub(crate) fn recv_line(&mut self) -> Result<String, Error> {
-        Ok(self
-            .stdout
-            .next()
-            .ok_or_else(|| self.get_termination_error())??)
+        loop {
+            let line = self
+                .stdout
+                .recv()
+                .map_err(|_| self.get_termination_error())?;
+            if let Some(escaped) = line.strip_prefix(runtime::EVCXR_PANIC_MESSAGE) {
+                // Keep the message in case the panic terminates the subprocess. If a panic unwinds
+                // out of our extern "C" function, a second panic, about not being able to unwind,
+                // follows. The first one is the one that the user wants to know about.
+                if self.panic_message.is_none() {
+                    self.panic_message = Some(runtime::unescape_panic_message(escaped));
+                }
+                continue;
+            }
+            if line == runtime::EVCXR_EXECUTION_COMPLETE
+                || line == runtime::EVCXR_EXECUTION_INTERRUPTED
+            {
+                self.panic_message = None;
+                self.shared.interrupt_handle.set_running(None);
+            }
+            if line == runtime::EVCXR_EXECUTION_INTERRUPTED {
+                return Err(Error::Interrupted);
+            }
+            return Ok(line);
+        }
     }
 
     fn get_termination_error(&mut self) -> Error {
         // Wait until the stderr handling thread has released its lock on stderr_sender, which it
         // will do when there's nothing more to read from stderr. We don't need to keep the lock,
         // just wait until we can aquire it, then drop it straight away.
-        std::mem::drop(self.stderr_sender.lock().unwrap());
+        std::mem::drop(self.shared.stderr_sender.lock().unwrap());
         let mut content = String::new();
-        while let Some(Ok(line)) = self.stdout.next() {
+        let mut panic_message = self.panic_message.take();
+        while let Ok(line) = self.stdout.recv() {
+            if let Some(escaped) = line.strip_prefix(runtime::EVCXR_PANIC_MESSAGE) {
+                if panic_message.is_none() {
+                    panic_message = Some(runtime::unescape_panic_message(escaped));
+                }
+                continue;
+            }
             content.push_str(&line);
             content.push('\n');
         }
-        Error::SubprocessTerminated(match self.process.wait() {
+        self.shared.interrupt_handle.set_running(None);
+        let exit_status = self.process.lock().unwrap().wait();
+        if self
+            .shared
+            .interrupt_handle
+            .interrupted
+            .swap(false, Ordering::SeqCst)
+        {
+            return Error::Interrupted;
+        }
+        let details = match exit_status {
             Ok(exit_status) => {
                 #[cfg(target_os = "macos")]
                 {
@@ -144,7 +485,11 @@ impl ChildProcess {
                 )
             }
             Err(wait_error) => format!("Subprocess didn't start: {}", wait_error),
-        })
+        };
+        match panic_message {
+            Some(message) => Error::Panic { message, details },
+            None => Error::SubprocessTerminated(details),
+        }
     }
 }
 
@@ -155,6 +500,167 @@ impl Drop for ChildProcess {
         self.stdin.take();
         // Wait for our subprocess to terminate. Otherwise we'll be left with
         // zombie processes.
-        let _ = self.process.wait();
+        let _ = self.process.lock().unwrap().wait();
+    }
+}
+
+/// Splits output into lines. Unlike `BufRead::lines`, can also return partial lines as soon as
+/// they're received.
+struct OutputLineReader<R> {
+    reader: R,
+    /// Bytes of the current line that we've read, but not yet returned.
+    pending: Vec<u8>,
+    /// Whether the line most recently returned was only part of a line.
+    partial: bool,
+    /// A line that we've already read, to be returned next.
+    queued: Option<String>,
+}
+
+impl<R: BufRead> OutputLineReader<R> {
+    fn new(reader: R) -> OutputLineReader<R> {
+        OutputLineReader {
+            reader,
+            pending: Vec::new(),
+            partial: false,
+            queued: None,
+        }
+    }
+
+    /// Returns the next line, without its line ending, or None once there's nothing more to read.
+    /// If `whole_lines` returns false, then if what's been received so far doesn't end with a
+    /// newline, it's returned without waiting for the rest of the line. `whole_lines` is checked
+    /// each time output is received, since the setting may change while we're waiting. Lines
+    /// that might be part of our protocol are never split.
+    fn next_line(&mut self, whole_lines: impl Fn() -> bool) -> Option<String> {
+        self.partial = false;
+        if let Some(line) = self.queued.take() {
+            return Some(line);
+        }
+        loop {
+            let available = match self.reader.fill_buf() {
+                Ok(available) => available,
+                Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => &[],
+            };
+            if available.is_empty() {
+                if self.pending.is_empty() {
+                    return None;
+                }
+                let line = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending.clear();
+                return Some(self.split_off_panic_message(line));
+            }
+            if let Some(newline) = available.iter().position(|byte| *byte == b'\n') {
+                self.pending.extend_from_slice(&available[..newline]);
+                self.reader.consume(newline + 1);
+                if self.pending.last() == Some(&b'\r') {
+                    self.pending.pop();
+                }
+                let line = String::from_utf8_lossy(&self.pending).into_owned();
+                self.pending.clear();
+                return Some(self.split_off_panic_message(line));
+            }
+            self.pending.extend_from_slice(available);
+            let len = available.len();
+            self.reader.consume(len);
+            if !whole_lines() && !self.might_be_protocol() {
+                // Don't split a multi-byte character.
+                let valid_len = match std::str::from_utf8(&self.pending) {
+                    Ok(_) => self.pending.len(),
+                    Err(error) => error.valid_up_to(),
+                };
+                if valid_len > 0 {
+                    let rest = self.pending.split_off(valid_len);
+                    let line = String::from_utf8_lossy(&self.pending).into_owned();
+                    self.pending = rest;
+                    self.partial = true;
+                    return Some(line);
+                }
+            }
+        }
+    }
+
+    /// The panic hook writes its message on a line of its own, but if the cell had written part of
+    /// a line when it panicked, the message follows that. In that case, we return the part before
+    /// the message as a line by itself and queue the message to be returned next.
+    fn split_off_panic_message(&mut self, mut line: String) -> String {
+        if let Some(start) = line.find(runtime::EVCXR_PANIC_MESSAGE) {
+            if start > 0 {
+                self.queued = Some(line.split_off(start));
+            }
+        }
+        line
+    }
+
+    fn might_be_protocol(&self) -> bool {
+        const PREFIX: &[u8] = b"EVCXR_";
+        self.pending.starts_with(PREFIX) || PREFIX.starts_with(&self.pending)
+    }
+}
+
+/// Prefixes `line` with `PARTIAL_LINE_MARKER` if it's only part of a line. Progress output already
+/// starts with `\r`, which tells frontends not to follow it with a newline.
+fn mark_partial_line(line: String, partial: bool) -> String {
+    if partial && !line.starts_with('\r') {
+        format!("{}{}", PARTIAL_LINE_MARKER, line)
+    } else {
+        line
+    }
+}
+
+/// Tracks a line of output that's being repeatedly rewritten by writing a carriage return, then
+/// its new contents, as progress bars do. Rather than passing on each version of the line to be
+/// appended to the output, we pass on the line's new contents prefixed by `\r`, which tells
+/// frontends to replace the previous such output.
+#[derive(Default)]
+struct ProgressLine {
+    /// The current contents of the line, if it has contained a carriage return since the last
+    /// newline.
+    text: Option<String>,
+    /// Whether a carriage return has been written since the last text, so that the next text
+    /// replaces the line.
+    at_start: bool,
+}
+
+impl ProgressLine {
+    /// Processes `chunk`, which is output up to either a newline, in which case `ends_line` is
+    /// true, or the end of what's been received so far. Returns what should be passed on, if
+    /// anything.
+    fn update(&mut self, chunk: &str, ends_line: bool) -> Option<String> {
+        if self.text.is_none() && !chunk.contains('\r') {
+            return Some(chunk.to_owned());
+        }
+        let text = self.text.get_or_insert_with(String::new);
+        let previous = text.clone();
+        for (index, part) in chunk.split('\r').enumerate() {
+            if index > 0 {
+                self.at_start = true;
+            }
+            if !part.is_empty() {
+                if self.at_start {
+                    text.clear();
+                    self.at_start = false;
+                }
+                text.push_str(part);
+            }
+        }
+        let output = (*text != previous).then(|| format!("\r{}", text));
+        if ends_line {
+            *self = ProgressLine::default();
+        }
+        output
+    }
+}
+
+/// Returns `line` cut down to `max_chars` characters followed by an ellipsis if it's longer than
+/// that. Some frontends struggle to display very long lines.
+pub(crate) fn truncate_long_line(line: &str, max_chars: usize) -> Cow<str> {
+    match line.char_indices().nth(max_chars) {
+        Some((end, _)) => Cow::Owned(format!(
+            "{}… [{} more characters]",
+            &line[..end],
+            line[end..].chars().count()
+        )),
+        None => Cow::Borrowed(line),
     }
 }
@@ -12,11 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::errors::CompilationError;
+use crate::errors::Span;
+use crate::errors::SpannedMessage;
 use crate::statement_splitter;
 use anyhow::anyhow;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
 use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextRange;
+use ra_ap_syntax::TextSize;
 use regex::Regex;
 use statement_splitter::OriginalUserCode;
 
@@ -28,6 +33,17 @@ pub(crate) struct Segment {
     /// Only present for original user code. Provides ordering and identity to the segments that
     /// came from the user.
     pub(crate) sequence: Option<usize>,
+    /// Where this code came from, if it was original user code from an earlier cell.
+    earlier_cell: Option<CellLocation>,
+}
+
+/// A location within some cell that was previously evaluated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct CellLocation {
+    /// The cell number, starting from 1.
+    pub(crate) cell: usize,
+    /// The line number within the cell, starting from 1.
+    pub(crate) line: usize,
 }
 
 impl Segment {
@@ -40,21 +56,33 @@ impl Segment {
             num_lines: num_lines(&code),
             code,
             sequence: None,
+            earlier_cell: None,
         }
     }
+
+    pub(crate) fn generated<T: Into<String>>(code: T) -> Segment {
+        Segment::new(CodeKind::OtherGeneratedCode, code.into())
+    }
+
+    /// Returns where this segment came from, if it was user code from an earlier cell.
+    pub(crate) fn earlier_cell(&self) -> Option<CellLocation> {
+        self.earlier_cell
+    }
 }
 
 /// Information about the code the user supplied.
 pub(crate) struct UserCodeInfo<'a> {
     pub(crate) nodes: Vec<SyntaxNode>,
     pub(crate) original_lines: Vec<&'a str>,
+    /// Errors encountered while parsing the user's code. Positions are relative to the user's code.
+    pub(crate) syntax_errors: Vec<CompilationError>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub(crate) struct CommandCall {
     pub(crate) command: String,
     pub(crate) args: Option<String>,
-    start_byte: usize,
+    pub(crate) start_byte: usize,
     pub(crate) line_number: usize,
 }
 
@@ -73,6 +101,22 @@ pub(crate) enum CodeKind {
     AssertCopyType {
         variable_name: String,
     },
+    /// Clones a variable that the user's code moves out of, so that the clone can be stored in its
+    /// place.
+    CloneVariable {
+        variable_name: String,
+    },
+    /// Puts back the value that a variable had before the current cell redefined it, for when the
+    /// cell returns an error. Fails if the cell moved out of the previous value.
+    RetainVariable {
+        variable_name: String,
+    },
+    /// The point at which a statement wrapped for `:try` leaves the cell if a `?` in it returns an
+    /// error. Replaced with code that stores the variables in scope at that point, then returns.
+    /// `defined_variables` holds the variables that earlier statements in the cell defined.
+    EarlyExit {
+        defined_variables: Vec<String>,
+    },
     /// A line of code that has a fallback to be used in case the supplied line fails to compile.
     WithFallback(CodeBlock),
     /// Code that we generated, but which we don't expect errors from. If we get errors there's not
@@ -127,6 +171,58 @@ pub(crate) struct UserCodeMetadata {
     /// The number of graphemes (not characters or bytes) on the line from which
     /// this code came that are prior to and not included in this code.
     pub(crate) column_offset: usize,
+    /// Paths in `include_str!` and similar macros that have been replaced in this code, in the
+    /// order that they appear.
+    pub(crate) path_rewrites: Vec<PathRewrite>,
+}
+
+impl UserCodeMetadata {
+    /// Returns the byte offset within the code as we send it to the compiler that corresponds to
+    /// `position` within the code as the user wrote it.
+    pub(crate) fn rewritten_position(&self, position: usize) -> usize {
+        let mut rewritten = position as isize;
+        for rewrite in &self.path_rewrites {
+            if rewrite.original_end_byte <= position {
+                rewritten += rewrite.extra_bytes;
+            }
+        }
+        rewritten as usize
+    }
+
+    /// Converts the 1-based `column` on line `line_offset` (starting from 0) of the code as we send
+    /// it to the compiler into a column on the line as the user wrote it.
+    pub(crate) fn user_column(&self, line_offset: usize, column: usize) -> usize {
+        let mut extra_columns = 0;
+        for rewrite in &self.path_rewrites {
+            if rewrite.line_offset == line_offset
+                && column as isize > rewrite.original_end_column as isize + extra_columns
+            {
+                extra_columns += rewrite.extra_columns;
+            }
+        }
+        let column = (column as isize - extra_columns) as usize;
+        if line_offset == 0 {
+            column + self.column_offset
+        } else {
+            column
+        }
+    }
+}
+
+/// Records that a relative path in an include macro was replaced with an absolute path, which
+/// shifts any code that follows it on the same line.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct PathRewrite {
+    /// The line within the code (starting from 0) on which the path was found.
+    pub(crate) line_offset: usize,
+    /// The number of characters on that line up to the end of the original path.
+    pub(crate) original_end_column: usize,
+    /// The byte offset of the end of the original path.
+    pub(crate) original_end_byte: usize,
+    /// How many more characters the replacement has than the original.
+    pub(crate) extra_columns: isize,
+    /// How many more bytes the replacement has than the original.
+    pub(crate) extra_bytes: isize,
 }
 
 /// Represents a unit of code. This may be code that the user supplied, in which case it might
@@ -152,9 +248,14 @@ impl CodeBlock {
         *self = block;
     }
 
-    pub(crate) fn commit_old_user_code(&mut self) {
+    /// Converts any original user code to OtherUserCode, recording that it came from `cell`.
+    pub(crate) fn commit_old_user_code(&mut self, cell: usize) {
         for segment in self.segments.iter_mut() {
-            if matches!(segment.kind, CodeKind::OriginalUserCode(_)) {
+            if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+                segment.earlier_cell = Some(CellLocation {
+                    cell,
+                    line: meta.start_line,
+                });
                 segment.kind = CodeKind::OtherUserCode;
             }
         }
@@ -197,6 +298,7 @@ impl CodeBlock {
         let command_re = COMMAND_RE.get_or_init(|| Regex::new("^ *(:[^ ]*)( +(.*))?$").unwrap());
         let mut code_block = CodeBlock::new();
         let mut nodes = Vec::new();
+        let mut syntax_errors = Vec::new();
 
         let mut lines = user_code.lines();
         let mut line_number = 1;
@@ -214,12 +316,18 @@ impl CodeBlock {
                     }),
                     line,
                 );
-            } else if line.starts_with(r"//") || line.trim().is_empty() {
+            } else if line.trim_start().starts_with(r"//") || line.trim().is_empty() {
                 // Ignore blank lines, otherwise we can't have blank lines before :dep commands.
-                // We also ignore lines that start with //, because those are line comments.
+                // We also ignore lines that start with //, because those are line comments
+                // (including doc comments, which wouldn't be documenting anything here).
             } else {
                 // Anything else, we treat as Rust code to be executed. Since we don't accept commands after Rust code, we're done looking for commands.
                 let non_command_start_byte = line.as_ptr() as usize - user_code.as_ptr() as usize;
+                syntax_errors =
+                    statement_splitter::syntax_errors(&user_code[non_command_start_byte..]);
+                for syntax_error in &mut syntax_errors {
+                    syntax_error.offset += non_command_start_byte;
+                }
                 for OriginalUserCode {
                     code,
                     start_byte,
@@ -243,6 +351,7 @@ impl CodeBlock {
                             node_index,
                             start_line: line_number,
                             column_offset,
+                            path_rewrites: Vec::new(),
                         }),
                         code,
                     );
@@ -254,15 +363,70 @@ impl CodeBlock {
         for (index, segment) in code_block.segments.iter_mut().enumerate() {
             segment.sequence = Some(index);
         }
+        let original_lines: Vec<&str> = user_code.lines().collect();
+        let syntax_errors = syntax_errors
+            .into_iter()
+            .filter_map(|error| {
+                code_block.syntax_error_to_compilation_error(error, &original_lines)
+            })
+            .collect();
         (
             code_block,
             UserCodeInfo {
                 nodes,
-                original_lines: user_code.lines().collect(),
+                original_lines,
+                syntax_errors,
             },
         )
     }
 
+    fn syntax_error_to_compilation_error(
+        &self,
+        error: statement_splitter::SyntaxError,
+        original_lines: &[&str],
+    ) -> Option<CompilationError> {
+        // Find the last segment of user code that starts at or before the error.
+        let (segment, start_byte) = self
+            .segments
+            .iter()
+            .filter_map(|segment| {
+                if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+                    Some((segment, meta.start_byte))
+                } else {
+                    None
+                }
+            })
+            .take_while(|(_, start_byte)| *start_byte <= error.offset)
+            .last()?;
+        // Make the span cover a single character, so that there's something to point at. If the
+        // error is at the end of a line, we point at the last character on the line.
+        let offset = (error.offset - start_byte).min(segment.code.len());
+        let (start, end) = if let Some(ch) = segment.code[offset..]
+            .chars()
+            .next()
+            .filter(|ch| *ch != '\n' && *ch != '\r')
+        {
+            (offset, offset + ch.len_utf8())
+        } else if let Some(ch) = segment.code[..offset].chars().next_back() {
+            (offset - ch.len_utf8(), offset)
+        } else {
+            (offset, offset)
+        };
+        let range = TextRange::new(TextSize::from(start as u32), TextSize::from(end as u32));
+        let span = Span::from_segment(segment, range)?;
+        let mut spanned_message = SpannedMessage::from_segment_span(segment, span);
+        spanned_message.lines = original_lines
+            .get(span.start_line - 1..span.end_line)?
+            .iter()
+            .map(|line| (*line).to_owned())
+            .collect();
+        Some(CompilationError::from_segment_span(
+            segment,
+            spanned_message,
+            error.message,
+        ))
+    }
+
     pub(crate) fn command_containing_user_offset(
         &self,
         user_code_offset: usize,
@@ -335,6 +499,51 @@ impl CodeBlock {
         ));
     }
 
+    pub(crate) fn clone_variable(&mut self, variable_name: String, code: String) {
+        self.segments.push(Segment::new(
+            CodeKind::CloneVariable { variable_name },
+            code,
+        ));
+    }
+
+    pub(crate) fn retain_variable(&mut self, variable_name: String, code: String) {
+        self.segments.push(Segment::new(
+            CodeKind::RetainVariable { variable_name },
+            code,
+        ));
+    }
+
+    pub(crate) fn early_exit(mut self, defined_variables: Vec<String>) -> Self {
+        self.segments.push(Segment::new(
+            CodeKind::EarlyExit { defined_variables },
+            String::new(),
+        ));
+        self
+    }
+
+    pub(crate) fn has_early_exit(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| matches!(segment.kind, CodeKind::EarlyExit { .. }))
+    }
+
+    /// Replaces each early exit with the code returned by `exit_code`.
+    pub(crate) fn expand_early_exits(
+        self,
+        exit_code: impl Fn(&[String]) -> CodeBlock,
+    ) -> CodeBlock {
+        let mut code = CodeBlock::new();
+        for segment in self.segments {
+            code = match &segment.kind {
+                CodeKind::EarlyExit { defined_variables } => {
+                    code.add_all(exit_code(defined_variables))
+                }
+                _ => code.with_segment(segment),
+            };
+        }
+        code
+    }
+
     pub(crate) fn add_all(mut self, other: CodeBlock) -> Self {
         self.segments.extend(other.segments);
         self
@@ -365,6 +574,28 @@ impl CodeBlock {
         (&CodeKind::Unknown, 0)
     }
 
+    /// Returns the cell location of each line of code in this block. `current_cell` is used for
+    /// original user code.
+    pub(crate) fn cell_locations(&self, current_cell: usize) -> Vec<Option<CellLocation>> {
+        let mut locations = Vec::new();
+        for segment in &self.segments {
+            let start = match (&segment.kind, segment.earlier_cell) {
+                (CodeKind::OriginalUserCode(meta), _) => Some(CellLocation {
+                    cell: current_cell,
+                    line: meta.start_line,
+                }),
+                (_, earlier_cell) => earlier_cell,
+            };
+            locations.extend((0..segment.num_lines).map(|offset| {
+                start.map(|start| CellLocation {
+                    cell: start.cell,
+                    line: start.line + offset,
+                })
+            }));
+        }
+        locations
+    }
+
     pub(crate) fn apply_fallback(&mut self, fallback: &CodeBlock) {
         let mut replacement_segments = Vec::new();
         for segment in std::mem::take(&mut self.segments) {
@@ -383,6 +614,20 @@ mod test {
     use super::CodeBlock;
     use super::CodeKind;
 
+    #[test]
+    fn comments_and_commands_only() {
+        let (code, info) = CodeBlock::from_original_user_code(
+            "  // Indented comment\n/// Doc comment\n:dep foo = \"1.0\"\n\n//! Inner doc comment",
+        );
+        assert_eq!(code.segments.len(), 1);
+        assert!(matches!(code.segments[0].kind, CodeKind::Command(_)));
+        assert!(info.nodes.is_empty());
+
+        let (code, info) = CodeBlock::from_original_user_code("/* Block comment */\n;\n");
+        assert!(code.is_empty());
+        assert!(info.syntax_errors.is_empty());
+    }
+
     #[test]
     fn basic_usage() {
         let user_code = "l3";
@@ -13,34 +13,124 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::collections::VecDeque;
 
+use crate::child_process::InterruptHandle;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::{self};
 use crate::crash_guard::CrashGuard;
+use crate::doctest;
+use crate::doctest::DoctestMode;
+use crate::doctest::TEST_RESULTS_MIME_TYPE;
 use crate::errors::bail;
+use crate::errors::err;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
+use crate::eval_context::CodegenTrace;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::eval_context::ForcedCodeKind;
+use crate::eval_context::VariableValue;
+use crate::eval_context::DETERMINISTIC_ENV_VARS;
 use crate::rust_analyzer::Completion;
 use crate::rust_analyzer::Completions;
+use crate::statement_splitter;
+use crate::statement_splitter::InputStatus;
 use crate::EvalContext;
 use crate::EvalContextOutputs;
 use crate::EvalOutputs;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
+use ra_ap_syntax::AstNode;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 /// A higher level interface to EvalContext. A bit closer to a Repl. Provides commands (start with
 /// ':') that alter context state or print information.
 pub struct CommandContext {
     print_timings: bool,
+    allow_shell: bool,
     eval_context: EvalContext,
     last_errors: Vec<CompilationError>,
+    custom_commands: HashMap<String, CustomCommand>,
+    /// Cells that have been successfully executed, together with their text/plain output, if any.
+    /// Used by `:replay`.
+    history: Vec<(String, Option<String>)>,
+    /// The most recent cells that contained code, including those that failed. Used by `:history`.
+    recent_cells: VecDeque<RecentCell>,
+    /// States from before each of the most recent successful cells, most recent last. Each is
+    /// paired with whether that cell was recorded in `history`. Used by `:undo`.
+    undo_stack: Vec<(ContextState, bool)>,
+    /// The outputs of each cell that produced any, keyed by MIME type. Used by `:export_html`.
+    display_history: Vec<HashMap<String, String>>,
+    /// Whether we're part way through executing a cell. Custom commands have access to the
+    /// context, so could otherwise start executing another cell, which would then have its effects
+    /// overwritten when the outer cell completes.
+    executing: bool,
+    /// A background compilation started by `:prewarm`, together with a flag that gets set once
+    /// it has finished.
+    prewarm: Option<(std::thread::JoinHandle<Result<(), Error>>, Arc<AtomicBool>)>,
+}
+
+/// How many cells `:undo` can revert.
+const MAX_UNDO_DEPTH: usize = 10;
+
+/// How many cells `:history` remembers. Older cells are dropped.
+const MAX_HISTORY_LEN: usize = 100;
+
+/// A cell remembered by `:history`.
+struct RecentCell {
+    /// The cell number, as also shown by `:items` and in error locations.
+    cell: usize,
+    /// The cell's code, excluding commands.
+    source: String,
+    succeeded: bool,
+}
+
+/// Returns the code of `cell`, excluding any commands, or None if it only has commands.
+fn cell_source(cell: &str) -> Option<&str> {
+    let (user_code, _) = CodeBlock::from_original_user_code(cell);
+    let start_byte = user_code
+        .segments
+        .iter()
+        .find_map(|segment| match &segment.kind {
+            CodeKind::OriginalUserCode(meta) => Some(meta.start_byte),
+            _ => None,
+        })?;
+    Some(cell[start_byte..].trim_end())
+}
+
+/// Returns a message saying that the variables `removed` were removed because their types came
+/// from the dependency `dep`.
+fn removed_variables_message(removed: &[String], dep: &str) -> String {
+    format!(
+        "Removed variable{} {}, since {} type{} came from `{}`",
+        if removed.len() == 1 { "" } else { "s" },
+        removed
+            .iter()
+            .map(|name| format!("`{}`", name))
+            .collect::<Vec<_>>()
+            .join(", "),
+        if removed.len() == 1 { "its" } else { "their" },
+        if removed.len() == 1 { "" } else { "s" },
+        dep
+    )
+}
+
+/// A handler for a command registered via `CommandContext::register_command`. It receives the
+/// context and the command's arguments, if any.
+pub type CustomCommandHandler =
+    dyn Fn(&mut CommandContext, Option<&str>) -> Result<EvalOutputs, Error> + Send + Sync;
+
+struct CustomCommand {
+    short_description: String,
+    handler: Arc<CustomCommandHandler>,
 }
 
 impl CommandContext {
@@ -53,8 +143,16 @@ impl CommandContext {
     pub fn with_eval_context(eval_context: EvalContext) -> CommandContext {
         CommandContext {
             print_timings: false,
+            allow_shell: false,
             eval_context,
             last_errors: Vec::new(),
+            custom_commands: HashMap::new(),
+            history: Vec::new(),
+            recent_cells: VecDeque::new(),
+            undo_stack: Vec::new(),
+            display_history: Vec::new(),
+            executing: false,
+            prewarm: None,
         }
     }
 
@@ -68,6 +166,13 @@ impl CommandContext {
         self.execute_with_callbacks(to_run, &mut EvalCallbacks::default())
     }
 
+    /// Returns a handle that can be used to interrupt execution from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.eval_context.interrupt_handle()
+    }
+
+    /// Runs `cargo check` on `code` in the current context and returns any errors and warnings.
+    /// Nothing is executed and the session's variables, items and settings are left untouched.
     pub fn check(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
         let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
@@ -80,12 +185,83 @@ impl CommandContext {
         self.eval_context.check(non_command_code, state, &code_info)
     }
 
+    /// Returns whether `code` is ready to be executed, or whether it looks like the user hasn't
+    /// finished typing it yet, e.g. because there are unclosed braces. Leading commands are
+    /// ignored.
+    pub fn input_status(&self, code: &str) -> InputStatus {
+        let (user_code, _code_info) = CodeBlock::from_original_user_code(code);
+        let rust_start = user_code
+            .segments
+            .iter()
+            .filter_map(|segment| {
+                if let CodeKind::Command(command) = &segment.kind {
+                    Some(command.start_byte + segment.code.len())
+                } else {
+                    None
+                }
+            })
+            .last()
+            .unwrap_or(0)
+            .min(code.len());
+        statement_splitter::input_status(&code[rust_start..])
+    }
+
     pub fn variables_and_types(&self) -> impl Iterator<Item = (&str, &str)> {
         self.eval_context.variables_and_types()
     }
 
+    pub fn variable_values(&mut self) -> Result<Vec<VariableValue>, Error> {
+        self.eval_context.variable_values()
+    }
+
     pub fn reset_config(&mut self) {
         self.eval_context.reset_config();
+        self.reset_command_settings();
+    }
+
+    /// Restores settings that are held by this context rather than by the eval context.
+    fn reset_command_settings(&mut self) {
+        self.print_timings = false;
+        self.allow_shell = false;
+    }
+
+    /// Registers a command, which can then be invoked in the same way as the built-in commands.
+    /// `name` should include the leading ':'. Fails if a command with the same name already
+    /// exists. Custom commands aren't run when preparing for analysis (e.g. for completions).
+    pub fn register_command(
+        &mut self,
+        name: &str,
+        short_description: &str,
+        handler: impl Fn(&mut CommandContext, Option<&str>) -> Result<EvalOutputs, Error>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Result<(), Error> {
+        if !name.starts_with(':') || name.len() < 2 || name.contains(char::is_whitespace) {
+            bail!("Invalid command name `{}`", name);
+        }
+        if Self::commands_by_name().contains_key(name) || self.custom_commands.contains_key(name) {
+            bail!("A command named `{}` already exists", name);
+        }
+        self.custom_commands.insert(
+            name.to_owned(),
+            CustomCommand {
+                short_description: short_description.to_owned(),
+                handler: Arc::new(handler),
+            },
+        );
+        Ok(())
+    }
+
+    /// Registers a renderer for values of the type `type_name`. See
+    /// `EvalContext::register_type_renderer`.
+    pub fn register_type_renderer(
+        &mut self,
+        type_name: &str,
+        renderer: impl Fn(&str) -> EvalOutputs + Send + Sync + 'static,
+    ) {
+        self.eval_context
+            .register_type_renderer(type_name, renderer);
     }
 
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
@@ -97,6 +273,9 @@ impl CommandContext {
         to_run: &str,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
+        if self.executing {
+            bail!("Busy: another cell is still being evaluated. Cells can't be evaluated concurrently");
+        }
         let mut state = self.eval_context.state();
         state.clear_non_debug_relevant_fields();
         let mut guard = CrashGuard::new(|| {
@@ -113,7 +292,9 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 to_run, state
             );
         });
+        self.executing = true;
         let result = self.execute_with_callbacks_internal(to_run, callbacks);
+        self.executing = false;
         guard.disarm();
         result
     }
@@ -129,6 +310,26 @@ Panic detected. Here's some useful information if you're filing a bug report.
         let mut state = self.eval_context.state();
         let mut non_command_code = CodeBlock::new();
         let (user_code, code_info) = CodeBlock::from_original_user_code(to_run);
+        let has_command = |name: &str| {
+            user_code.segments.iter().any(|segment| {
+                matches!(&segment.kind, CodeKind::Command(command) if command.command == name)
+            })
+        };
+        // A scratch cell's effects get discarded afterwards, so we snapshot the state first.
+        let scratch_snapshot = if has_command(":scratch") {
+            Some(self.eval_context.state())
+        } else {
+            None
+        };
+        // Replaying a replay would never terminate. Scratch cells don't affect the session.
+        let record_in_history = !has_command(":replay") && scratch_snapshot.is_none();
+        let record_display = !has_command(":export_html");
+        let undo_snapshot = if has_command(":undo") || scratch_snapshot.is_some() {
+            None
+        } else {
+            Some(self.eval_context.state())
+        };
+        let run_doctests = has_command(":doctest");
         for segment in user_code.segments {
             if let CodeKind::Command(command) = &segment.kind {
                 eval_outputs.merge(self.execute_command(
@@ -141,13 +342,50 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 non_command_code = non_command_code.with_segment(segment);
             }
         }
-        let result =
-            self.eval_context
-                .eval_with_callbacks(non_command_code, state, &code_info, callbacks);
+        let result = self
+            .eval_context
+            .eval_with_callbacks(non_command_code, state, &code_info, callbacks)
+            .and_then(|mut outputs| {
+                if run_doctests {
+                    // We look for doc comments in the original code, since they're not kept in
+                    // the code that we evaluate.
+                    outputs.merge(self.run_doctests(to_run)?);
+                }
+                Ok(outputs)
+            });
+        if let Some(snapshot) = scratch_snapshot {
+            self.eval_context.roll_back_to(snapshot);
+        }
         let duration = start.elapsed();
+        if let Some(source) = cell_source(to_run) {
+            if self.recent_cells.len() == MAX_HISTORY_LEN {
+                self.recent_cells.pop_front();
+            }
+            self.recent_cells.push_back(RecentCell {
+                cell: self.eval_context.cell_count(),
+                source: source.to_owned(),
+                succeeded: result.is_ok(),
+            });
+        }
         match result {
             Ok(m) => {
                 eval_outputs.merge(m);
+                if record_in_history {
+                    self.history.push((
+                        to_run.to_owned(),
+                        eval_outputs.get("text/plain").map(str::to_owned),
+                    ));
+                }
+                if record_display && !eval_outputs.is_empty() {
+                    self.display_history
+                        .push(eval_outputs.content_by_mime_type.clone());
+                }
+                if let Some(snapshot) = undo_snapshot {
+                    if self.undo_stack.len() == MAX_UNDO_DEPTH {
+                        self.undo_stack.remove(0);
+                    }
+                    self.undo_stack.push((snapshot, record_in_history));
+                }
                 if self.print_timings {
                     eval_outputs.timing = Some(duration);
                 }
@@ -161,6 +399,190 @@ Panic detected. Here's some useful information if you're filing a bug report.
         }
     }
 
+    fn history(&self, index: Option<&str>) -> Result<String, Error> {
+        if let Some(index) = index.map(str::trim).filter(|index| !index.is_empty()) {
+            let index: usize = index
+                .parse()
+                .map_err(|_| err!("Expected a cell number, got `{}`", index))?;
+            return match self.recent_cells.iter().find(|recent| recent.cell == index) {
+                Some(recent) => Ok(recent.source.clone()),
+                None => bail!("No cell {} in history", index),
+            };
+        }
+        if self.recent_cells.is_empty() {
+            return Ok("No cells in history".to_owned());
+        }
+        let mut out = String::new();
+        for recent in &self.recent_cells {
+            let prefix = if recent.succeeded {
+                format!("[{}] ", recent.cell)
+            } else {
+                format!("[{}] (failed) ", recent.cell)
+            };
+            for (line_index, line) in recent.source.lines().enumerate() {
+                if line_index == 0 {
+                    out.push_str(&prefix);
+                } else {
+                    out.push_str(&" ".repeat(prefix.len()));
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        out.pop();
+        Ok(out)
+    }
+
+    /// Runs the examples found in doc comments within `source` and returns a report of the
+    /// outcomes. Examples are run in a copy of the session that has its own subprocess, so
+    /// don't affect the session, even if they fail or panic. Examples marked `should_panic` are
+    /// only compiled. As well as a text summary, the outputs include a JSON array with an entry
+    /// for each example under `TEST_RESULTS_MIME_TYPE`, so that frontends can display the results
+    /// as a table.
+    fn run_doctests(&mut self, source: &str) -> Result<EvalOutputs, Error> {
+        let mut lines = Vec::new();
+        let mut results = json::JsonValue::new_array();
+        let (mut passed, mut failed, mut ignored) = (0, 0, 0);
+        let mut example_context = None;
+        for (index, doctest) in doctest::extract_doctests(source).iter().enumerate() {
+            let start = std::time::Instant::now();
+            let mut output = Vec::new();
+            let outcome = match doctest.mode {
+                DoctestMode::Ignore => None,
+                DoctestMode::Run => {
+                    if example_context.is_none() {
+                        example_context = Some(self.eval_context.new_detached_copy()?);
+                    }
+                    let (context, _outputs) = example_context.as_mut().unwrap();
+                    let snapshot = context.state();
+                    context.begin_stdout_capture();
+                    let result = context.eval(&doctest.code);
+                    output = context.end_stdout_capture();
+                    context.roll_back_to(snapshot);
+                    Some(result.map(|_| ()).map_err(|error| error.to_string()))
+                }
+                DoctestMode::NoRun | DoctestMode::ShouldPanic => {
+                    Some(self.check_compiles(&doctest.code))
+                }
+                DoctestMode::CompileFail => Some(match self.check_compiles(&doctest.code) {
+                    Ok(()) => Err("Expected compilation to fail, but it succeeded".to_owned()),
+                    Err(_) => Ok(()),
+                }),
+            };
+            let name = format!("Example {}", index + 1);
+            let duration = start.elapsed();
+            let (status, summary) = match &outcome {
+                None => {
+                    ignored += 1;
+                    ("ignored", "ignored".to_owned())
+                }
+                Some(Ok(())) => {
+                    passed += 1;
+                    ("ok", "ok".to_owned())
+                }
+                Some(Err(error)) => {
+                    failed += 1;
+                    ("failed", format!("FAILED\n{}", error))
+                }
+            };
+            lines.push(format!("{} ... {}", name, summary));
+            let mut result = json::object! {
+                "name" => name,
+                "status" => status,
+                "duration_secs" => duration.as_secs_f64(),
+                "output" => output,
+            };
+            if let Some(Err(error)) = outcome {
+                result["failure"] = error.into();
+            }
+            results.push(result)?;
+        }
+        lines.push(format!(
+            "Doctests: {} passed, {} failed, {} ignored",
+            passed, failed, ignored
+        ));
+        let mut outputs = text_output(lines.join("\n"))?;
+        outputs
+            .content_by_mime_type
+            .insert(TEST_RESULTS_MIME_TYPE.to_owned(), results.dump());
+        Ok(outputs)
+    }
+
+    /// Returns an error describing why `code` doesn't compile, if it doesn't.
+    fn check_compiles(&mut self, code: &str) -> Result<(), String> {
+        let errors = self.check(code).map_err(|error| error.to_string())?;
+        let messages: Vec<String> = errors
+            .iter()
+            .filter(|error| error.level() == "error")
+            .map(|error| error.message())
+            .collect();
+        if messages.is_empty() {
+            Ok(())
+        } else {
+            Err(messages.join("\n"))
+        }
+    }
+
+    /// Starts compiling a trivial cell in a separate, throwaway context on a background thread. This
+    /// gets the compiler and standard library into the OS's caches, so that the first real cell
+    /// doesn't have to wait for them to be read from disk. Returns false if a previous prewarm is
+    /// still running.
+    fn start_prewarm(&mut self) -> Result<bool, Error> {
+        if matches!(&self.prewarm, Some((_, finished)) if !finished.load(Ordering::Relaxed)) {
+            return Ok(false);
+        }
+        let (mut context, _outputs) = self.eval_context.new_sibling()?;
+        let finished = Arc::new(AtomicBool::new(false));
+        let handle = std::thread::spawn({
+            let finished = Arc::clone(&finished);
+            move || {
+                let result = context.eval("let _ = 1;").map(|_| ());
+                finished.store(true, Ordering::Relaxed);
+                result
+            }
+        });
+        self.prewarm = Some((handle, finished));
+        Ok(true)
+    }
+
+    /// Waits for any background compilation started by `:prewarm` to complete.
+    pub fn wait_for_prewarm(&mut self) -> Result<(), Error> {
+        match self.prewarm.take() {
+            Some((handle, _)) => handle
+                .join()
+                .map_err(|_| err!("Prewarming the compiler panicked"))?,
+            None => Ok(()),
+        }
+    }
+
+    /// Re-executes all previously successful cells, in order, in a fresh context. Fails if any
+    /// cell fails or produces different output to what it produced originally.
+    fn replay(&self) -> Result<usize, Error> {
+        let (eval_context, _outputs) = self.eval_context.new_sibling()?;
+        let mut replay_context = CommandContext::with_eval_context(eval_context);
+        for (index, (cell, expected_output)) in self.history.iter().enumerate() {
+            let output = replay_context.execute(cell).map_err(|error| {
+                err!(
+                    "Replay failed at cell {}:\n{}\nError: {}",
+                    index + 1,
+                    cell,
+                    error
+                )
+            })?;
+            let output = output.get("text/plain");
+            if output != expected_output.as_deref() {
+                bail!(
+                    "Replay diverged at cell {}:\n{}\nOriginal output: {}\nReplayed output: {}",
+                    index + 1,
+                    cell,
+                    expected_output.as_deref().unwrap_or("(none)"),
+                    output.unwrap_or("(none)")
+                );
+            }
+        }
+        Ok(self.history.len())
+    }
+
     pub fn set_opt_level(&mut self, level: &str) -> Result<(), Error> {
         self.eval_context.set_opt_level(level)
     }
@@ -217,7 +639,13 @@ Panic detected. Here's some useful information if you're filing a bug report.
             end_offset: full_position,
             ..Completions::default()
         };
-        for cmd in Self::commands_by_name().keys() {
+        let mut command_names: Vec<&str> = Self::commands_by_name()
+            .keys()
+            .copied()
+            .chain(self.custom_commands.keys().map(String::as_str))
+            .collect();
+        command_names.sort_unstable();
+        for cmd in command_names {
             if cmd.starts_with(existing) {
                 completions.completions.push(Completion {
                     code: (*cmd).to_owned(),
@@ -236,8 +664,12 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     println!("Loading startup commands from {:?}", config_file);
                 }
                 let contents = std::fs::read_to_string(config_file)?;
+                // We're called while the `:load_config` cell is being evaluated, so we bypass the
+                // check that stops cells being evaluated concurrently.
                 for line in contents.lines() {
-                    outputs.merge(self.execute(line)?);
+                    outputs.merge(
+                        self.execute_with_callbacks_internal(line, &mut EvalCallbacks::default())?,
+                    );
                 }
             }
             // Note: Loaded *after* init.evcxr so that it can access `:dep`s (or
@@ -248,7 +680,18 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     println!("Executing prelude from {:?}", prelude_file);
                 }
                 let prelude = std::fs::read_to_string(prelude_file)?;
-                outputs.merge(self.execute(&prelude)?);
+                // Imports become part of the `:prelude`, so that they're applied again after
+                // `:clear`. The rest of the code is only evaluated now.
+                let (imports, prelude) = split_prelude_imports(&prelude);
+                for import in imports {
+                    self.execute_with_callbacks_internal(
+                        &format!(":prelude add {}", import),
+                        &mut EvalCallbacks::default(),
+                    )?;
+                }
+                outputs.merge(
+                    self.execute_with_callbacks_internal(&prelude, &mut EvalCallbacks::default())?,
+                );
             }
         }
         Ok(outputs)
@@ -273,42 +716,22 @@ Panic detected. Here's some useful information if you're filing a bug report.
         args: &Option<String>,
         analysis_mode: bool,
     ) -> Result<EvalOutputs, CompilationError> {
-        if let Some(command) = Self::commands_by_name().get(command_call.command.as_str()) {
-            let result = match &command.analysis_callback {
+        let result = if let Some(command) =
+            Self::commands_by_name().get(command_call.command.as_str())
+        {
+            match &command.analysis_callback {
                 Some(analysis_callback) if analysis_mode => (analysis_callback)(self, state, args),
                 _ => (command.callback)(self, state, args),
-            };
-            result.map_err(|error| {
-                // Span from the start of the arguments to the end of the arguments, or if no
-                // arguments are found, span the command. We look for the first non-space character
-                // after a space is found.
-                let mut found_space = false;
-                let start_byte = segment
-                    .code
-                    .bytes()
-                    .enumerate()
-                    .find(|(_index, byte)| {
-                        if *byte == b' ' {
-                            found_space = true;
-                            return false;
-                        }
-                        found_space
-                    })
-                    .map(|(index, _char)| index)
-                    .unwrap_or(0);
-                let start_column = code_block::count_columns(&segment.code[..start_byte]) + 1;
-                let end_column = code_block::count_columns(&segment.code);
-                CompilationError::from_segment_span(
-                    segment,
-                    SpannedMessage::from_segment_span(
-                        segment,
-                        Span::from_command(command_call, start_column, end_column),
-                    ),
-                    error.to_string(),
-                )
-            })
+            }
+        } else if let Some(command) = self.custom_commands.get(&command_call.command) {
+            if analysis_mode {
+                Ok(EvalOutputs::default())
+            } else {
+                let handler = Arc::clone(&command.handler);
+                (handler)(self, args.as_deref())
+            }
         } else {
-            Err(CompilationError::from_segment_span(
+            return Err(CompilationError::from_segment_span(
                 segment,
                 SpannedMessage::from_segment_span(
                     segment,
@@ -319,8 +742,37 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ),
                 ),
                 format!("Unrecognised command {}", command_call.command),
-            ))
-        }
+            ));
+        };
+        result.map_err(|error| {
+            // Span from the start of the arguments to the end of the arguments, or if no
+            // arguments are found, span the command. We look for the first non-space character
+            // after a space is found.
+            let mut found_space = false;
+            let start_byte = segment
+                .code
+                .bytes()
+                .enumerate()
+                .find(|(_index, byte)| {
+                    if *byte == b' ' {
+                        found_space = true;
+                        return false;
+                    }
+                    found_space
+                })
+                .map(|(index, _char)| index)
+                .unwrap_or(0);
+            let start_column = code_block::count_columns(&segment.code[..start_byte]) + 1;
+            let end_column = code_block::count_columns(&segment.code);
+            CompilationError::from_segment_span(
+                segment,
+                SpannedMessage::from_segment_span(
+                    segment,
+                    Span::from_command(command_call, start_column, end_column),
+                ),
+                error.to_string(),
+            )
+        })
     }
 
     fn commands_by_name() -> &'static HashMap<&'static str, AvailableCommand> {
@@ -345,6 +797,29 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Internals debugging: {}", debug_mode))
                 },
             ),
+            AvailableCommand::new(
+                ":trace_codegen",
+                "Log the code passed to the compiler on each build, to stderr or appended to a \
+                 file. e.g. :trace_codegen on, :trace_codegen codegen.rs or :trace_codegen off",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("on") => state.set_trace_codegen(Some(CodegenTrace::Stderr)),
+                        Some("off") => state.set_trace_codegen(None),
+                        Some(path) => {
+                            let path = state.resolve_path(path)?;
+                            state.set_trace_codegen(Some(CodegenTrace::File(path)));
+                        }
+                    }
+                    match state.trace_codegen() {
+                        None => text_output("Codegen tracing: off"),
+                        Some(CodegenTrace::Stderr) => text_output("Codegen tracing: stderr"),
+                        Some(CodegenTrace::File(path)) => {
+                            text_output(format!("Codegen tracing: {}", path.display()))
+                        }
+                    }
+                },
+            ),
             AvailableCommand::new(
                 ":load_config",
                 "Reloads startup configuration files. Accepts optional flag `--quiet` to suppress logging.",
@@ -356,9 +831,16 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 },
             )
             .disable_in_analysis(),
-            AvailableCommand::new(":version", "Print Evcxr version", |_ctx, _state, _args| {
-                text_output(env!("CARGO_PKG_VERSION"))
-            }),
+            AvailableCommand::new(
+                ":version",
+                "Print Evcxr version and the version of rustc that's in use",
+                |_ctx, state, _args| {
+                    let rustc_version = state
+                        .rustc_version()
+                        .unwrap_or_else(|error| format!("rustc version unknown: {}", error));
+                    text_output(format!("{}\n{}", env!("CARGO_PKG_VERSION"), rustc_version))
+                },
+            ),
             AvailableCommand::new(
                 ":vars",
                 "List bound variables and their types",
@@ -369,12 +851,98 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":vars_unused",
+                "List variables that haven't been referenced by any cell since the one that \
+                 defined them",
+                |_ctx, state, _args| {
+                    let unused = state.unused_variables();
+                    if unused.is_empty() {
+                        return text_output("No unused variables");
+                    }
+                    text_output(
+                        unused
+                            .into_iter()
+                            .map(|(name, type_name)| format!("{}: {}", name, type_name))
+                            .collect::<Vec<_>>()
+                            .join("\n"),
+                    )
+                },
+            ),
+            AvailableCommand::new(
+                ":items",
+                "List defined items with their kinds and the cells that defined them",
+                |_ctx, state, _args| {
+                    let lines: Vec<String> = state
+                        .defined_items()
+                        .into_iter()
+                        .map(|item| {
+                            let mut line = item.kind.to_owned();
+                            if !item.name.is_empty() {
+                                line.push(' ');
+                                line.push_str(&item.name);
+                            }
+                            if let Some(location) = item.location {
+                                line.push_str(&format!(" (cell {})", location.cell));
+                            }
+                            line
+                        })
+                        .collect();
+                    if lines.is_empty() {
+                        return text_output("No items defined");
+                    }
+                    text_output(lines.join("\n"))
+                },
+            ),
+            AvailableCommand::new(
+                ":types_eq",
+                "Print the types of two expressions and whether they're the same. \
+                 e.g. :types_eq 1u8 b'a'",
+                |ctx, state, args| {
+                    let args = args.as_deref().unwrap_or_default();
+                    let (first, second) = split_two_expressions(args).ok_or_else(|| {
+                        err!("Usage: :types_eq <expression> <expression>. Separate expressions \
+                             that contain spaces with a comma")
+                    })?;
+                    let types = ctx.eval_context.expression_types(state, &[first, second])?;
+                    text_output(format!(
+                        "{}: {}\n{}: {}\n{}",
+                        first,
+                        types[0],
+                        second,
+                        types[1],
+                        if types[0] == types[1] {
+                            "The types are the same"
+                        } else {
+                            "The types differ"
+                        }
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":vars_clear_type",
+                "Remove all variables whose type contains the specified type name. e.g. \
+                 :vars_clear_type DataFrame",
+                |_ctx, state, args| {
+                    let type_name = args.as_ref().map(|a| a.trim()).unwrap_or("");
+                    if type_name.is_empty() {
+                        bail!(":vars_clear_type requires a type name");
+                    }
+                    let removed = state.remove_variables_of_type(type_name);
+                    text_output(format!(
+                        "Removed {} variable{} of type `{}`",
+                        removed.len(),
+                        if removed.len() == 1 { "" } else { "s" },
+                        type_name
+                    ))
+                },
+            ),
             AvailableCommand::new(
                 ":preserve_vars_on_panic",
-                "Try to keep vars on panic (0/1)",
+                "Try to keep vars on panic (on/off)",
                 |_ctx, state, args| {
-                    state
-                        .set_preserve_vars_on_panic(args.as_ref().map(String::as_str) == Some("1"));
+                    state.set_preserve_vars_on_panic(parse_toggle(args)?.unwrap_or(false));
                     text_output(format!(
                         "Preserve vars on panic: {}",
                         state.preserve_vars_on_panic()
@@ -395,11 +963,232 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 *state = ctx.eval_context.cleared_state();
                 Ok(EvalOutputs::default())
             }),
+            AvailableCommand::new(
+                ":rebuild",
+                "Discard build artifacts and rebuild from scratch, keeping variables",
+                |_ctx, state, _args| {
+                    state.request_rebuild();
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":dep",
                 "Add dependency. e.g. :dep regex = \"1.0\"",
                 |_ctx, state, args| process_dep_command(state, args),
             ),
+            AvailableCommand::new(
+                ":reload_dep",
+                "Rebuild a dependency from its current source, keeping variables. Git \
+                 dependencies are first updated to their latest commit",
+                |_ctx, state, args| {
+                    let name = args.as_deref().map(str::trim).unwrap_or("");
+                    if name.is_empty() {
+                        bail!(":reload_dep requires the name of a dependency");
+                    }
+                    let removed = state.request_dep_reload(name)?;
+                    if removed.is_empty() {
+                        return Ok(EvalOutputs::new());
+                    }
+                    text_output(removed_variables_message(&removed, name))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":dep_remove",
+                "Remove a dependency, along with any variables whose types come from it",
+                |_ctx, state, args| {
+                    let name = args.as_deref().map(str::trim).unwrap_or("");
+                    if name.is_empty() {
+                        bail!(":dep_remove requires the name of a dependency");
+                    }
+                    let removed = state.remove_dep(name)?;
+                    if removed.is_empty() {
+                        return Ok(EvalOutputs::new());
+                    }
+                    text_output(removed_variables_message(&removed, name))
+                },
+            ),
+            AvailableCommand::new(
+                ":deps",
+                "List dependencies with their resolved versions. :deps begin and :deps end \
+                 group the :dep commands between them into a single build",
+                |ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        Some("begin") => {
+                            state.begin_deps_transaction()?;
+                            return text_output("Deferring builds until :deps end");
+                        }
+                        Some("end") => {
+                            state.end_deps_transaction()?;
+                            return Ok(EvalOutputs::new());
+                        }
+                        None | Some("") => {}
+                        Some(other) => bail!("Expected `begin` or `end`, got `{}`", other),
+                    }
+                    let lock_contents =
+                        std::fs::read_to_string(ctx.eval_context.last_compile_dir().join("Cargo.lock"))
+                            .ok();
+                    let deps = state.describe_deps(lock_contents.as_deref());
+                    if deps.is_empty() {
+                        text_output("No dependencies")
+                    } else {
+                        text_output(deps.join("\n"))
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":dep_tree",
+                "List all dependencies, including transitive ones, as a tree",
+                |ctx, state, _args| text_output(ctx.eval_context.dependency_tree(state)?),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":dylib",
+                "Link against a prebuilt dynamic library. e.g. :dylib ./libfoo.so",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {
+                            if state.dylibs().is_empty() {
+                                text_output("No dynamic libraries")
+                            } else {
+                                text_output(
+                                    state
+                                        .dylibs()
+                                        .iter()
+                                        .map(|path| path.display().to_string())
+                                        .collect::<Vec<_>>()
+                                        .join("\n"),
+                                )
+                            }
+                        }
+                        Some(path) => {
+                            let name = state.add_dylib(path)?;
+                            text_output(format!(
+                                "Linking against `{}`. Declare its functions with :extern, or in \
+                                 an `extern \"C\"` block, to call them",
+                                name
+                            ))
+                        }
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":extern",
+                "Declare functions from a library added with :dylib. e.g. \
+                 :extern fn add(a: i32, b: i32) -> i32",
+                |_ctx, state, args| {
+                    let signatures = args.as_deref().unwrap_or("");
+                    if signatures.trim().is_empty() {
+                        bail!(":extern requires one or more function signatures");
+                    }
+                    let names = state.declare_extern_functions(signatures)?;
+                    text_output(format!("Declared {}", names.join(", ")))
+                },
+            ),
+            AvailableCommand::new(
+                ":lock_from",
+                "Pin dependency versions to those in a Cargo.lock. e.g. :lock_from ../Cargo.lock",
+                |_ctx, state, args| {
+                    let path = if let Some(path) = args {
+                        state.resolve_path(path)?
+                    } else {
+                        bail!(":lock_from requires the path to a Cargo.lock");
+                    };
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|e| err!("Failed to read {}: {}", path.display(), e))?;
+                    let num_versions = state.set_locked_versions_from_lock_file(&contents);
+                    text_output(format!(
+                        "Pinning dependencies to {} versions from {}",
+                        num_versions,
+                        path.display()
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":freeze",
+                "Keep dependencies at the versions resolved by the last build, including \
+                 dependencies of dependencies. :freeze off unfreezes them",
+                |ctx, state, args| match args.as_deref().map(str::trim) {
+                    Some("off") => {
+                        state.set_frozen_lock(None);
+                        text_output("Dependency versions unfrozen")
+                    }
+                    None | Some("") => {
+                        let lock_file = ctx.eval_context.last_compile_dir().join("Cargo.lock");
+                        let contents = std::fs::read_to_string(lock_file).map_err(|_| {
+                            err!("Nothing has been built yet, so there are no versions to freeze")
+                        })?;
+                        let num_packages = state.set_frozen_lock(Some(contents));
+                        text_output(format!(
+                            "Froze the versions of {} package{}",
+                            num_packages,
+                            if num_packages == 1 { "" } else { "s" }
+                        ))
+                    }
+                    Some(other) => bail!("Expected no arguments or `off`, got `{}`", other),
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":merge_dep_features",
+                "Set whether re-adding a dep keeps its previous features (on/off)",
+                |_ctx, state, args| {
+                    state.set_merge_dep_features(parse_toggle(args)?.unwrap_or(true));
+                    text_output(format!(
+                        "Merge dep features: {}",
+                        state.merge_dep_features()
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":pin",
+                "Keep the specified variable when running :clear",
+                |_ctx, state, args| {
+                    let name = args.as_ref().map(|a| a.trim()).unwrap_or("");
+                    if name.is_empty() {
+                        bail!(":pin requires a variable name");
+                    }
+                    state.pin_variable(name)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":unpin",
+                "Stop keeping the specified variable when running :clear",
+                |_ctx, state, args| {
+                    let name = args.as_ref().map(|a| a.trim()).unwrap_or("");
+                    if name.is_empty() {
+                        bail!(":unpin requires a variable name");
+                    }
+                    state.unpin_variable(name)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":max_vars",
+                "Limit the number of variables kept. Usage: :max_vars <n|off> [evict|reject]",
+                |_ctx, state, args| {
+                    if let Some(args) = args {
+                        let mut parts = args.split_whitespace();
+                        let max_vars = match parts.next() {
+                            Some("off") | None => None,
+                            Some(n) => Some(n.parse::<usize>().map_err(|_| {
+                                err!("Expected a number of variables or `off`, got `{}`", n)
+                            })?),
+                        };
+                        let reject = match parts.next() {
+                            Some("reject") => true,
+                            Some("evict") | None => false,
+                            Some(other) => {
+                                bail!("Expected `evict` or `reject`, got `{}`", other)
+                            }
+                        };
+                        state.set_max_vars(max_vars, reject);
+                    }
+                    text_output(format!("Max vars: {}", state.max_vars()))
+                },
+            ),
             AvailableCommand::new(
                 ":last_compile_dir",
                 "Print the directory in which we last compiled",
@@ -446,50 +1235,654 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 },
             ),
             AvailableCommand::new(
-                ":toolchain",
-                "Set which toolchain to use (e.g. nightly)",
+                ":logging",
+                "Set whether to print records logged via the `log` crate (on/off)",
                 |_ctx, state, args| {
-                    if let Some(arg) = args {
-                        state.set_toolchain(arg);
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_logging(value);
                     }
-                    text_output(format!("Toolchain: {}", state.toolchain()))
+                    text_output(format!(
+                        "Logging: {}",
+                        if state.logging() { "on" } else { "off" }
+                    ))
                 },
             ),
             AvailableCommand::new(
-                ":offline",
-                "Set offline mode when invoking cargo",
+                ":log_level",
+                "Set/print the maximum level of log records printed when :logging is on",
                 |_ctx, state, args| {
-                    state.set_offline_mode(args.as_ref().map(String::as_str) == Some("1"));
-                    text_output(format!("Offline mode: {}", state.offline_mode()))
+                    if let Some(level) = args.as_ref().map(|arg| arg.trim()) {
+                        if !level.is_empty() {
+                            state.set_log_level(level)?;
+                        }
+                    }
+                    text_output(format!("Log level: {}", state.log_level()))
                 },
             ),
             AvailableCommand::new(
-                ":quit",
-                "Quit evaluation and exit",
-                |_ctx, _state, _args| std::process::exit(0),
-            )
-            .disable_in_analysis(),
-            AvailableCommand::new(
-                ":timing",
-                "Toggle printing of how long evaluations take",
-                |ctx, _state, _args| {
-                    ctx.print_timings = !ctx.print_timings;
-                    text_output(format!("Timing: {}", ctx.print_timings))
+                ":no_std",
+                "Set whether to compile code as #![no_std] (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_no_std(value);
+                    }
+                    text_output(format!(
+                        "no_std: {}",
+                        if state.no_std() { "on" } else { "off" }
+                    ))
                 },
             ),
             AvailableCommand::new(
-                ":time_passes",
-                "Toggle printing of rustc pass times (requires nightly)",
-                |_ctx, state, _args| {
+                ":lint",
+                "Set the level of a lint for subsequent cells. e.g. :lint deny unused_variables",
+                |_ctx, state, args| {
+                    let args = args.as_deref().unwrap_or("").trim();
+                    if !args.is_empty() {
+                        if let Some((level, lint)) = args.split_once(' ') {
+                            state.set_lint_level(level, lint.trim())?;
+                        } else {
+                            bail!("Expected `:lint <level> <lint name>`");
+                        }
+                    }
+                    if state.lint_levels().is_empty() {
+                        text_output("No lint levels set")
+                    } else {
+                        text_output(
+                            state
+                                .lint_levels()
+                                .iter()
+                                .map(|(level, lint)| format!("{}: {}", lint, level))
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                        )
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":crate_name",
+                "Set/print the package name of the generated crate",
+                |_ctx, state, args| {
+                    if let Some(name) = args.as_deref().map(str::trim).filter(|n| !n.is_empty()) {
+                        state.set_crate_name(name)?;
+                    }
+                    text_output(format!("Crate name: {}", state.crate_name()))
+                },
+            ),
+            AvailableCommand::new(
+                ":toolchain",
+                "Set which toolchain to use (e.g. nightly, or default). Variables are cleared \
+                 when the toolchain changes",
+                |_ctx, state, args| {
+                    match args.as_deref().map(str::trim) {
+                        None | Some("") => {}
+                        Some("default") => state.set_toolchain("")?,
+                        Some(toolchain) => state.set_toolchain(toolchain)?,
+                    }
+                    let toolchain = match state.toolchain() {
+                        "" => "default",
+                        toolchain => toolchain,
+                    };
+                    text_output(format!("Toolchain: {}", toolchain))
+                },
+            ),
+            AvailableCommand::new(
+                ":min_rust",
+                "Require a minimum version of rustc, e.g. :min_rust 1.70, or :min_rust off",
+                |_ctx, state, args| {
+                    match args.as_deref().map(str::trim) {
+                        None | Some("") => {}
+                        Some("off") => state.set_min_rust_version(None)?,
+                        Some(version) => state.set_min_rust_version(Some(version))?,
+                    }
+                    text_output(format!(
+                        "Minimum Rust version: {}",
+                        state.min_rust_version().unwrap_or("none")
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":offline",
+                "Set offline mode when invoking cargo (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_offline_mode(value);
+                    }
+                    text_output(format!("Offline mode: {}", state.offline_mode()))
+                },
+            ),
+            AvailableCommand::new(
+                ":deterministic",
+                "Set whether code is run with fixed seeds in environment variables (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_deterministic(value);
+                    }
+                    if !state.deterministic() {
+                        return text_output("Deterministic mode: off");
+                    }
+                    let vars: Vec<String> = DETERMINISTIC_ENV_VARS
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect();
+                    text_output(format!(
+                        "Deterministic mode: on\nSet {}. Time, thread scheduling and other \
+                         sources of nondeterminism aren't affected.",
+                        vars.join(", ")
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":quit",
+                "Quit evaluation and exit",
+                |_ctx, _state, _args| std::process::exit(0),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":jobs",
+                "Set/print the number of parallel jobs cargo uses. e.g. :jobs 2 or :jobs default",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("default") => state.set_jobs(None)?,
+                        Some(n) => state.set_jobs(Some(n.parse().map_err(|_| {
+                            err!("Expected a positive number of jobs or `default`, got `{}`", n)
+                        })?))?,
+                    }
+                    match state.jobs() {
+                        Some(jobs) => text_output(format!("Jobs: {}", jobs)),
+                        None => text_output("Jobs: default"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":keep_generations",
+                "Set/print how many of the most recent builds to keep on disk. e.g. \
+                 :keep_generations 3",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some(n) => state.set_keep_generations(n.parse().map_err(|_| {
+                            err!("Expected a positive number of generations, got `{}`", n)
+                        })?)?,
+                    }
+                    text_output(format!("Keep generations: {}", state.keep_generations()))
+                },
+            ),
+            AvailableCommand::new(
+                ":parallel_frontend",
+                "Set the number of threads for rustc's parallel frontend (requires nightly). \
+                 e.g. :parallel_frontend 8 or :parallel_frontend off",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("off") => state.set_parallel_frontend(None)?,
+                        Some(n) => state.set_parallel_frontend(Some(n.parse().map_err(|_| {
+                            err!("Expected a positive number of threads or `off`, got `{}`", n)
+                        })?))?,
+                    }
+                    match state.parallel_frontend() {
+                        Some(threads) => text_output(format!("Parallel frontend: {}", threads)),
+                        None => text_output("Parallel frontend: off"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":auto_await",
+                "Set whether futures returned by the last expression of a cell in async mode are \
+                 awaited and their output displayed (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_auto_await(value);
+                    }
+                    text_output(format!(
+                        "Auto await: {}",
+                        if state.auto_await() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":allow_unsafe",
+                "Set whether cells may contain `unsafe` code (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_allow_unsafe(value);
+                    }
+                    text_output(format!(
+                        "Allow unsafe: {}",
+                        if state.allow_unsafe() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":autobind",
+                "Set whether the value of the final expression of each cell is kept in a \
+                 variable named after the cell, e.g. _3 (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_autobind(value);
+                    }
+                    text_output(format!(
+                        "Autobind: {}",
+                        if state.autobind() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":auto_clone",
+                "Set whether variables that a cell moves out of are cloned beforehand, so that \
+                 they remain available (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_auto_clone(value);
+                    }
+                    text_output(format!(
+                        "Auto clone: {}",
+                        if state.auto_clone() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":allow_unused_imports",
+                "Set whether to suppress warnings about unused imports (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_allow_unused_imports(value);
+                    }
+                    text_output(format!(
+                        "Allow unused imports: {}",
+                        if state.allow_unused_imports() {
+                            "on"
+                        } else {
+                            "off"
+                        }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":replaced_items",
+                "Set whether to report when a cell replaces an existing item (off/on/verbose). \
+                 verbose also shows how the item's signature changed",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some(value) => state.set_replaced_item_notices(value)?,
+                    }
+                    text_output(format!(
+                        "Replaced item notices: {}",
+                        state.replaced_item_notices()
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":prelude_shadowing",
+                "Set whether to warn when an import shadows a name from the standard prelude, \
+                 such as Result (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_prelude_shadowing_warnings(value);
+                    }
+                    text_output(format!(
+                        "Prelude shadowing warnings: {}",
+                        if state.prelude_shadowing_warnings() {
+                            "on"
+                        } else {
+                            "off"
+                        }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":stderr",
+                "Set where stderr from executing cells goes (separate/merge/discard)",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some(mode) => state.set_stderr_mode(mode)?,
+                    }
+                    text_output(format!("Stderr: {}", state.stderr_mode()))
+                },
+            ),
+            AvailableCommand::new(
+                ":features",
+                "Set features to enable when building cells. e.g. :features a,b or \
+                 :features serde/derive. Use :features none to clear",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("none") => state.set_features("")?,
+                        Some(features) => state.set_features(features)?,
+                    }
+                    if state.features().is_empty() {
+                        text_output("Features: none")
+                    } else {
+                        text_output(format!("Features: {}", state.features().join(",")))
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":line_buffered",
+                "Set whether output is shown a line at a time (on), or whether output from print! \
+                 is shown as soon as it's written, even if it's only part of a line (off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_line_buffered(value);
+                    }
+                    text_output(format!(
+                        "Line buffered: {}",
+                        if state.line_buffered() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":progress_bars",
+                "Set whether output after a carriage return replaces the line it was written on, \
+                 so that progress bars update in place (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_progress_bars(value);
+                    }
+                    text_output(format!(
+                        "Progress bars: {}",
+                        if state.progress_bars() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":try",
+                "Set whether each statement that uses ? is run such that an error skips the rest \
+                 of the cell, but keeps variables defined before it (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_try_mode(value);
+                    }
+                    text_output(format!(
+                        "Try mode: {}",
+                        if state.try_mode() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":retain_on_error",
+                "Set whether variables that a cell redefines keep their previous values if the \
+                 cell returns an error via ? (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_retain_on_error(value);
+                    }
+                    text_output(format!(
+                        "Retain on error: {}",
+                        if state.retain_on_error() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":max_line_length",
+                "Truncate lines of output longer than this many characters. \
+                 e.g. :max_line_length 1000 or :max_line_length off",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("off") => state.set_max_line_length(None)?,
+                        Some(n) => state.set_max_line_length(Some(n.parse().map_err(|_| {
+                            err!("Expected a positive number of characters or `off`, got `{}`", n)
+                        })?))?,
+                    }
+                    match state.max_line_length() {
+                        Some(max) => text_output(format!("Max line length: {}", max)),
+                        None => text_output("Max line length: off"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":float_precision",
+                "Set the number of decimal places with which floats in final expressions are \
+                 displayed. e.g. :float_precision 3 or :float_precision off",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("off") => state.set_float_precision(None),
+                        Some(n) => state.set_float_precision(Some(n.parse().map_err(|_| {
+                            err!("Expected a number of decimal places or `off`, got `{}`", n)
+                        })?)),
+                    }
+                    match state.float_precision() {
+                        Some(precision) => text_output(format!("Float precision: {}", precision)),
+                        None => text_output("Float precision: off"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":width",
+                "Fit pretty-printed output to a number of columns. Off unless set. \
+                 e.g. :width 120 or :width default",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("default") => state.set_display_width(None)?,
+                        Some(n) => state.set_display_width(Some(n.parse().map_err(|_| {
+                            err!("Expected a number of columns or `default`, got `{}`", n)
+                        })?))?,
+                    }
+                    match state.display_width() {
+                        Some(width) => text_output(format!("Width: {}", width)),
+                        None => text_output("Width: default"),
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":replay",
+                "Re-run all successful cells in a fresh process, reporting the first failure or \
+                 difference in output",
+                |ctx, _state, _args| {
+                    let num_cells = ctx.replay()?;
+                    text_output(format!("Replayed {} cells successfully", num_cells))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":history",
+                "List the code of recently evaluated cells, or with a cell number, print that cell",
+                |ctx, _state, args| text_output(ctx.history(args.as_deref())?),
+            ),
+            AvailableCommand::new(
+                ":prelude",
+                "List imports applied to fresh sessions, or change them with \
+                 :prelude add/remove <path>",
+                |_ctx, state, args| {
+                    let args = args.as_deref().unwrap_or("").trim();
+                    if let Some(import) = args.strip_prefix("add ") {
+                        state.add_prelude_import(import)?;
+                    } else if let Some(import) = args.strip_prefix("remove ") {
+                        state.remove_prelude_import(import)?;
+                    } else if !args.is_empty() {
+                        bail!("Expected `:prelude`, `:prelude add <path>` or `:prelude remove <path>`");
+                    }
+                    if state.prelude().is_empty() {
+                        text_output("The prelude is empty")
+                    } else {
+                        text_output(state.prelude().join("\n"))
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":scratch",
+                "Evaluate the rest of the cell without keeping any variables, items, imports or \
+                 settings that it defines",
+                // The work is done in execute_with_callbacks, since we need to act after the rest
+                // of the cell has been evaluated.
+                |_ctx, _state, _args| Ok(EvalOutputs::default()),
+            ),
+            AvailableCommand::new(
+                ":stmt",
+                "Evaluate the items in the rest of the cell as statements, so that they don't \
+                 persist",
+                |_ctx, state, args| {
+                    force_code_kind(state, ":stmt", args, ForcedCodeKind::Statement)
+                },
+            ),
+            AvailableCommand::new(
+                ":item",
+                "Treat macro invocations in the rest of the cell as items, so that any items \
+                 they define persist",
+                |_ctx, state, args| force_code_kind(state, ":item", args, ForcedCodeKind::Item),
+            ),
+            AvailableCommand::new(
+                ":module",
+                "Place items defined from now on in the named module, until :module end. e.g. \
+                 :module math",
+                |_ctx, state, args| match args.as_ref().map(|arg| arg.trim()) {
+                    None | Some("") => match state.current_module() {
+                        Some(module) => text_output(format!("Current module: {}", module)),
+                        None => text_output("No module is open"),
+                    },
+                    Some("end") => {
+                        state.close_module()?;
+                        Ok(EvalOutputs::new())
+                    }
+                    Some(name) => {
+                        state.open_module(name)?;
+                        Ok(EvalOutputs::new())
+                    }
+                },
+            ),
+            AvailableCommand::new(
+                ":prewarm",
+                "Compile a throwaway cell in the background so that subsequent cells build faster",
+                |ctx, _state, _args| {
+                    if ctx.start_prewarm()? {
+                        text_output("Prewarming the compiler in the background")
+                    } else {
+                        text_output("Already prewarming the compiler")
+                    }
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":doctest",
+                "Evaluate the rest of the cell, then run the examples in its doc comments",
+                // The work is done in execute_with_callbacks, since the examples need to run after
+                // the rest of the cell has been evaluated.
+                |_ctx, _state, _args| Ok(EvalOutputs::default()),
+            ),
+            AvailableCommand::new(
+                ":export_html",
+                "Write the outputs of this session to an HTML file. e.g. :export_html out.html",
+                |ctx, _state, args| {
+                    let path = if let Some(path) = args {
+                        ctx.eval_context.state().resolve_path(path)?
+                    } else {
+                        bail!(":export_html requires the path of the file to write");
+                    };
+                    std::fs::write(&path, ctx.display_history_as_html())
+                        .map_err(|e| err!("Failed to write {}: {}", path.display(), e))?;
+                    text_output(format!(
+                        "Exported {} outputs to {}",
+                        ctx.display_history.len(),
+                        path.display()
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":export_deps",
+                "Write the dependencies added with :dep as a Cargo.toml [dependencies] table. \
+                 e.g. :export_deps deps.toml",
+                |_ctx, state, args| {
+                    let path = if let Some(path) = args {
+                        state.resolve_path(path)?
+                    } else {
+                        bail!(":export_deps requires the path of the file to write");
+                    };
+                    std::fs::write(&path, state.dependencies_table())
+                        .map_err(|e| err!("Failed to write {}: {}", path.display(), e))?;
+                    text_output(format!(
+                        "Exported {} dependencies to {}",
+                        state.external_deps.len(),
+                        path.display()
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":undo",
+                "Revert the imports, items, settings and new variables of the last cell",
+                |ctx, state, _args| {
+                    let (snapshot, was_recorded) = ctx
+                        .undo_stack
+                        .pop()
+                        .ok_or_else(|| err!("Nothing to undo"))?;
+                    if was_recorded {
+                        ctx.history.pop();
+                    }
+                    ctx.eval_context.roll_back_to(snapshot);
+                    *state = ctx.eval_context.state();
+                    text_output("Reverted the last cell")
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":reset_config",
+                "Restore all settings to their defaults, keeping variables and dependencies",
+                |ctx, state, _args| {
+                    ctx.eval_context.reset_config_of(state);
+                    ctx.reset_command_settings();
+                    text_output("Settings restored to defaults")
+                },
+            ),
+            AvailableCommand::new(
+                ":timing",
+                "Toggle printing of how long evaluations take",
+                |ctx, _state, _args| {
+                    ctx.print_timings = !ctx.print_timings;
+                    text_output(format!("Timing: {}", ctx.print_timings))
+                },
+            ),
+            AvailableCommand::new(
+                ":profile_memory",
+                "Set whether to report memory allocated by each evaluation (on/off)",
+                |_ctx, state, args| {
+                    if let Some(value) = parse_toggle(args)? {
+                        state.set_profile_memory(value)?;
+                    }
+                    text_output(format!(
+                        "Memory profiling: {}",
+                        if state.profile_memory() { "on" } else { "off" }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":run_on",
+                "Set whether code runs on the main thread (main), or on a worker thread (worker)",
+                |_ctx, state, args| {
+                    match args.as_ref().map(|arg| arg.trim()) {
+                        None | Some("") => {}
+                        Some("main") => state.set_run_on_worker(false),
+                        Some("worker") => state.set_run_on_worker(true),
+                        Some(other) => bail!("Expected `main` or `worker`, got `{}`", other),
+                    }
+                    text_output(format!(
+                        "Run on: {}",
+                        if state.run_on_worker() {
+                            "worker"
+                        } else {
+                            "main"
+                        }
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":time_passes",
+                "Toggle printing of rustc pass times (requires nightly)",
+                |_ctx, state, _args| {
                     state.set_time_passes(!state.time_passes());
                     text_output(format!("Time passes: {}", state.time_passes()))
                 },
             ),
             AvailableCommand::new(
                 ":sccache",
-                "Set whether to use sccache (0/1).",
+                "Set whether to use sccache (on/off).",
                 |_ctx, state, args| {
-                    state.set_sccache(args.as_ref().map(String::as_str) != Some("0"))?;
+                    state.set_sccache(parse_toggle(args)?.unwrap_or(true))?;
                     text_output(format!("sccache: {}", state.sccache()))
                 },
             ),
@@ -503,6 +1896,61 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("linker: {}", state.linker()))
                 },
             ),
+            AvailableCommand::new(
+                ":allow_shell",
+                "Set whether :sh is permitted to run shell commands (on/off)",
+                |ctx, _state, args| {
+                    ctx.allow_shell = parse_toggle(args)?.unwrap_or(false);
+                    text_output(format!("Allow shell: {}", ctx.allow_shell))
+                },
+            ),
+            AvailableCommand::new(
+                ":pwd",
+                "Print the working directory",
+                |_ctx, state, _args| text_output(state.working_dir()?.to_string_lossy()),
+            ),
+            AvailableCommand::new(
+                ":cd",
+                "Change the working directory, which relative paths are resolved against. \
+                 e.g. :cd data",
+                |_ctx, state, args| {
+                    let dir = if let Some(dir) = args {
+                        dir.trim()
+                    } else {
+                        bail!(":cd requires a directory");
+                    };
+                    state.set_working_dir(dir)?;
+                    text_output(state.working_dir()?.to_string_lossy())
+                },
+            ),
+            AvailableCommand::new(
+                ":sh",
+                "Run a shell command. e.g. :sh mkdir data",
+                |ctx, state, args| {
+                    if !ctx.allow_shell {
+                        bail!("Shell commands are disabled. Enable them with `:allow_shell 1`");
+                    }
+                    let command = if let Some(command) = args {
+                        command
+                    } else {
+                        bail!(":sh requires a command to run");
+                    };
+                    ctx.eval_context
+                        .run_shell_command(command, &state.working_dir()?)?;
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":cargo",
+                "Run a cargo subcommand in the directory of the generated crate. e.g. :cargo tree",
+                |ctx, state, args| {
+                    ctx.eval_context
+                        .run_cargo_command(state, args.as_deref().unwrap_or_default())?;
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":explain",
                 "Print explanation of last error",
@@ -535,19 +1983,26 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     bail!(errors_out);
                 },
             ),
-            AvailableCommand::new(":help", "Print command help", |_ctx, _state, _args| {
+            AvailableCommand::new(":help", "Print command help", |ctx, _state, _args| {
                 use std::fmt::Write;
                 let mut text = String::new();
                 let mut html = String::new();
                 writeln!(html, "<table>")?;
-                let mut commands = CommandContext::create_commands();
-                commands.sort_by(|a, b| a.name.cmp(b.name));
-                for cmd in commands {
-                    writeln!(text, "{:<17} {}", cmd.name, cmd.short_description).unwrap();
+                let commands = CommandContext::create_commands();
+                let mut descriptions: Vec<(&str, &str)> = commands
+                    .iter()
+                    .map(|cmd| (cmd.name, cmd.short_description))
+                    .chain(ctx.custom_commands.iter().map(|(name, cmd)| {
+                        (name.as_str(), cmd.short_description.as_str())
+                    }))
+                    .collect();
+                descriptions.sort_unstable();
+                for (name, short_description) in descriptions {
+                    writeln!(text, "{:<17} {}", name, short_description).unwrap();
                     writeln!(
                         html,
                         "<tr><td>{}</td><td>{}</td></tr>",
-                        cmd.name, cmd.short_description
+                        name, short_description
                     )?;
                 }
                 writeln!(html, "</table>")?;
@@ -580,6 +2035,29 @@ Panic detected. Here's some useful information if you're filing a bug report.
         out.push_str("</table>");
         out
     }
+
+    /// Returns a standalone HTML document containing the output of each cell that produced any.
+    /// HTML outputs are included as-is, otherwise we fall back to plain text.
+    fn display_history_as_html(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Evcxr session</title>\n</head>\n<body>\n",
+        );
+        for outputs in &self.display_history {
+            if let Some(html) = outputs.get("text/html") {
+                out.push_str("<div class=\"output\">");
+                out.push_str(html);
+                out.push_str("</div>\n");
+            } else if let Some(text) = outputs.get("text/plain") {
+                out.push_str("<div class=\"output\"><pre>");
+                html_escape(text, &mut out);
+                out.push_str("</pre></div>\n");
+            }
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
 }
 
 fn process_dep_command(
@@ -595,11 +2073,15 @@ fn process_dep_command(
     static DEP_RE: OnceCell<Regex> = OnceCell::new();
     let dep_re = DEP_RE.get_or_init(|| Regex::new("^([^= ]+) *(= *(.+))?$").unwrap());
     if let Some(captures) = dep_re.captures(args) {
-        state.add_dep(
-            &captures[1],
-            captures.get(3).map_or("\"*\"", |m| m.as_str()),
-        )?;
-        Ok(EvalOutputs::new())
+        let name = &captures[1];
+        let old_config = state.dep_config(name).map(str::to_owned);
+        state.add_dep(name, captures.get(3).map_or("\"*\"", |m| m.as_str()))?;
+        match (old_config, state.dep_config(name)) {
+            (Some(old_config), Some(new_config)) if old_config != new_config => {
+                text_output(format!("Updated {} = {}", name, new_config))
+            }
+            _ => Ok(EvalOutputs::new()),
+        }
     } else {
         bail!("Invalid :dep command. Expected: name = ... or just name");
     }
@@ -670,6 +2152,111 @@ fn html_escape(input: &str, out: &mut String) {
     }
 }
 
+fn force_code_kind(
+    state: &mut ContextState,
+    command: &str,
+    args: &Option<String>,
+    kind: ForcedCodeKind,
+) -> Result<EvalOutputs, Error> {
+    if args
+        .as_deref()
+        .map_or(false, |args| !args.trim().is_empty())
+    {
+        bail!(
+            "`{}` should be on its own line, followed by the code that it applies to",
+            command
+        );
+    }
+    state.force_code_kind(kind);
+    Ok(EvalOutputs::default())
+}
+
+/// Splits `args` into two expressions. If there's a comma that's not nested within brackets, we
+/// split there, otherwise the expressions must be separated by whitespace and not contain any.
+fn split_two_expressions(args: &str) -> Option<(&str, &str)> {
+    let args = args.trim();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut separators = Vec::new();
+    for (index, c) in args.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                let (first, second) = (args[..index].trim(), args[index + 1..].trim());
+                if first.is_empty() || second.is_empty() {
+                    return None;
+                }
+                return Some((first, second));
+            }
+            c if c.is_whitespace() && depth == 0 => separators.push(index),
+            _ => {}
+        }
+    }
+    let first_end = *separators.first()?;
+    let last = *separators.last()?;
+    // Any whitespace outside of brackets must all be between the two expressions.
+    if !args[first_end..last].chars().all(char::is_whitespace) {
+        return None;
+    }
+    Some((&args[..first_end], args[last..].trim_start()))
+}
+
+/// Splits the contents of prelude.rs into its top-level use statements, each on a single line, and
+/// the rest of its code. Use statements with attributes, a visibility or comments are left in the
+/// code.
+fn split_prelude_imports(code: &str) -> (Vec<String>, String) {
+    use ra_ap_syntax::ast::HasAttrs;
+    use ra_ap_syntax::ast::HasVisibility;
+    let mut imports = Vec::new();
+    let mut rest = String::new();
+    for statement in statement_splitter::split_into_statements(code) {
+        let import = ra_ap_syntax::ast::Use::cast(statement.node.clone()).filter(|use_item| {
+            use_item.attrs().next().is_none()
+                && use_item.visibility().is_none()
+                && !use_item
+                    .syntax()
+                    .descendants_with_tokens()
+                    .any(|element| element.kind() == ra_ap_syntax::SyntaxKind::COMMENT)
+        });
+        match import {
+            Some(use_item) => imports.push(
+                use_item
+                    .syntax()
+                    .text()
+                    .to_string()
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            None => rest.push_str(statement.code),
+        }
+    }
+    (imports, rest)
+}
+
+/// Parses the argument to a command that turns a setting on or off. Accepts `on` or `off`, as well
+/// as `1` or `0`, which is what commands used to accept. Returns None if there's no argument.
+fn parse_toggle(args: &Option<String>) -> Result<Option<bool>, Error> {
+    match args.as_ref().map(|arg| arg.trim()) {
+        None | Some("") => Ok(None),
+        Some("on") | Some("1") => Ok(Some(true)),
+        Some("off") | Some("0") => Ok(Some(false)),
+        Some(other) => bail!("Expected `on` or `off`, got `{}`", other),
+    }
+}
+
 fn text_output<T: Into<String>>(text: T) -> Result<EvalOutputs, Error> {
     let mut outputs = EvalOutputs::new();
     let mut content = text.into();
@@ -14,6 +14,7 @@
 
 use crate::errors::bail;
 use crate::errors::Error;
+use crate::paths;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::path::Path;
@@ -28,60 +29,343 @@ pub(crate) struct ExternalCrate {
     pub(crate) config: String,
 }
 
-fn make_paths_absolute(config: String) -> Result<String, Error> {
+fn make_paths_absolute(config: String, base_dir: Option<&Path>) -> Result<String, Error> {
     // Perhaps not the nicest way to do this. Using a toml parser would possibly
     // be nicer. At the time this was written that wasn't an option due to a
     // compiler bug that prevented us from using any crate that used custom
     // derive. That bug is long fixed though, so switching this to use a toml
     // parser would be an option.
     static PATH_RE: OnceCell<Regex> = OnceCell::new();
-    let path_re = PATH_RE.get_or_init(|| Regex::new("^(.*)path *= *\"([^\"]+)\"(.*)$").unwrap());
+    let path_re = PATH_RE
+        .get_or_init(|| Regex::new(r#"^(.*?)path *= *("(?:[^"\\]|\\.)+"|'[^']+')(.*)$"#).unwrap());
     if let Some(captures) = path_re.captures(&config) {
-        let path = Path::new(&captures[2]);
-        if !path.is_absolute() {
-            match path.canonicalize() {
-                Ok(path) => {
-                    return Ok(captures[1].to_owned()
-                        + "path = \""
-                        + &path.to_string_lossy()
-                        + "\""
-                        + &captures[3]);
-                }
+        let value = match paths::toml_string_value(&captures[2]) {
+            Some(value) => value,
+            None => return Ok(config),
+        };
+        let path = Path::new(&value);
+        let path = &match base_dir {
+            Some(base_dir) => base_dir.join(path),
+            None => path.to_owned(),
+        };
+        let path = if path.is_absolute() {
+            path.to_owned()
+        } else {
+            match paths::canonicalize(path) {
+                Ok(path) => path,
                 Err(err) => {
                     bail!("{}: {:?}", err, path);
                 }
             }
-        }
+        };
+        // We always write the path back out, since it might have been written in a way that
+        // isn't valid TOML, such as a Windows path with unescaped backslashes.
+        return Ok(captures[1].to_owned() + "path = " + &paths::toml_string(&path) + &captures[3]);
     }
     Ok(config)
 }
 
 impl ExternalCrate {
     pub(crate) fn new(name: String, config: String) -> Result<ExternalCrate, Error> {
-        let config = make_paths_absolute(config)?;
+        let config = make_paths_absolute(config, None)?;
+        Ok(ExternalCrate { name, config })
+    }
+
+    /// Like `new`, but resolves relative paths against `base_dir` rather than the current
+    /// directory.
+    pub(crate) fn new_relative_to(
+        name: String,
+        config: String,
+        base_dir: &Path,
+    ) -> Result<ExternalCrate, Error> {
+        let config = make_paths_absolute(config, Some(base_dir))?;
         Ok(ExternalCrate { name, config })
     }
+
+    /// Returns a short description of where this crate comes from.
+    pub(crate) fn source(&self) -> &'static str {
+        static SOURCE_RE: OnceCell<Regex> = OnceCell::new();
+        let source_re = SOURCE_RE.get_or_init(|| Regex::new("(path|git) *=").unwrap());
+        match source_re.captures(&self.config) {
+            Some(captures) if &captures[1] == "path" => "path",
+            Some(_) => "git",
+            None => "crates.io",
+        }
+    }
+}
+
+fn features_re() -> &'static Regex {
+    static FEATURES_RE: OnceCell<Regex> = OnceCell::new();
+    FEATURES_RE.get_or_init(|| Regex::new(r"features *= *\[([^\]]*)\]").unwrap())
+}
+
+/// Returns the features listed in `config`.
+fn features(config: &str) -> Vec<String> {
+    static FEATURE_RE: OnceCell<Regex> = OnceCell::new();
+    let feature_re = FEATURE_RE.get_or_init(|| Regex::new("\"([^\"]*)\"").unwrap());
+    features_re()
+        .captures(config)
+        .map(|captures| {
+            feature_re
+                .captures_iter(&captures[1])
+                .map(|c| c[1].to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Keys that say where a dependency comes from. If a new configuration for a dependency gives any
+/// of these, then they replace all of them from the old configuration.
+const SOURCE_KEYS: &[&str] = &["version", "path", "git", "branch", "tag", "rev", "registry"];
+
+/// Splits a dependency configuration into its keys and values. A plain string is treated as a
+/// version. Returns `None` if `config` isn't something that we understand.
+fn config_entries(config: &str) -> Option<Vec<(String, String)>> {
+    let config = config.trim();
+    if config.starts_with('"') || config.starts_with('\'') {
+        return Some(vec![("version".to_owned(), config.to_owned())]);
+    }
+    let inner = config.strip_prefix('{')?.strip_suffix('}')?;
+    let mut entries = Vec::new();
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    // A trailing comma makes sure that we see the last entry.
+    for (index, c) in inner
+        .char_indices()
+        .chain(std::iter::once((inner.len(), ',')))
+    {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' && q == '"' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth = depth.checked_sub(1)?,
+            ',' if depth == 0 => {
+                let entry = inner[start..index].trim();
+                start = index + 1;
+                if !entry.is_empty() {
+                    let (key, value) = entry.split_once('=')?;
+                    entries.push((key.trim().to_owned(), value.trim().to_owned()));
+                }
+            }
+            _ => {}
+        }
+    }
+    if quote.is_some() || depth != 0 {
+        return None;
+    }
+    Some(entries)
+}
+
+/// Returns the configuration that results from re-specifying a dependency that was configured as
+/// `old_config` with `new_config`. Keys given in `new_config` replace those in `old_config`, while
+/// other keys, e.g. `default-features`, are kept. Features aren't kept, see `merge_features`.
+pub(crate) fn update_config(old_config: &str, new_config: &str) -> String {
+    let (old_entries, new_entries) = match (config_entries(old_config), config_entries(new_config))
+    {
+        (Some(old_entries), Some(new_entries)) => (old_entries, new_entries),
+        // Not something we understand, leave it for cargo to report any problems.
+        _ => return new_config.to_owned(),
+    };
+    let new_has_source = new_entries
+        .iter()
+        .any(|(key, _)| SOURCE_KEYS.contains(&key.as_str()));
+    let mut entries: Vec<(String, String)> = old_entries
+        .into_iter()
+        .filter(|(key, _)| {
+            key != "features"
+                && !(new_has_source && SOURCE_KEYS.contains(&key.as_str()))
+                && !new_entries.iter().any(|(new_key, _)| new_key == key)
+        })
+        .collect();
+    entries.extend(new_entries);
+    // Put where the dependency comes from first, for readability.
+    entries.sort_by_key(|(key, _)| !SOURCE_KEYS.contains(&key.as_str()));
+    match entries.as_slice() {
+        [(key, version)] if key == "version" => version.clone(),
+        _ => format!(
+            "{{ {} }}",
+            entries
+                .iter()
+                .map(|(key, value)| format!("{} = {}", key, value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Returns `new_config` updated so that its features also include any features from
+/// `old_config`.
+pub(crate) fn merge_features(old_config: &str, new_config: &str) -> String {
+    let mut all_features = features(old_config);
+    if all_features.is_empty() {
+        return new_config.to_owned();
+    }
+    for feature in features(new_config) {
+        if !all_features.contains(&feature) {
+            all_features.push(feature);
+        }
+    }
+    let features_toml = format!(
+        "features = [{}]",
+        all_features
+            .iter()
+            .map(|f| format!("\"{}\"", f))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let new_config = new_config.trim();
+    if features_re().is_match(new_config) {
+        features_re()
+            .replace(new_config, features_toml.as_str())
+            .to_string()
+    } else if new_config.starts_with('"') {
+        format!("{{ version = {}, {} }}", new_config, features_toml)
+    } else if let Some(inner) = new_config
+        .strip_prefix('{')
+        .and_then(|c| c.strip_suffix('}'))
+    {
+        let inner = inner.trim().trim_end_matches(',');
+        if inner.is_empty() {
+            format!("{{ {} }}", features_toml)
+        } else {
+            format!("{{ {}, {} }}", inner, features_toml)
+        }
+    } else {
+        // Not something we understand, leave it for cargo to report any problems.
+        new_config.to_owned()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::merge_features;
+    use super::update_config;
     use super::ExternalCrate;
+    use crate::paths;
     use std::path::Path;
 
     #[test]
     fn make_paths_absolute() {
+        let expected = format!(
+            "{{ path = {} }}",
+            paths::toml_string(&paths::canonicalize(Path::new("src/testdata")).unwrap())
+        );
         let krate =
             ExternalCrate::new("foo".to_owned(), "{ path = \"src/testdata\" }".to_owned()).unwrap();
         assert_eq!(krate.name, "foo");
+        assert_eq!(krate.config, expected);
+        let krate =
+            ExternalCrate::new("foo".to_owned(), "{ path = 'src/testdata' }".to_owned()).unwrap();
+        assert_eq!(krate.config, expected);
+        let krate = ExternalCrate::new_relative_to(
+            "foo".to_owned(),
+            "{ path = \"testdata\" }".to_owned(),
+            Path::new("src"),
+        )
+        .unwrap();
+        assert_eq!(krate.config, expected);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_paths() {
+        // Backslashes that aren't escaped aren't valid TOML, but are what users tend to write.
+        for config in [
+            r#"{ path = "C:\work\foo" }"#,
+            r#"{ path = "C:\\work\\foo" }"#,
+            r#"{ path = 'C:\work\foo' }"#,
+        ] {
+            let krate = ExternalCrate::new("foo".to_owned(), config.to_owned()).unwrap();
+            assert_eq!(krate.config, r#"{ path = "C:\\work\\foo" }"#);
+        }
+    }
+
+    #[test]
+    fn test_source() {
+        let source = |config: &str| {
+            ExternalCrate {
+                name: "foo".to_owned(),
+                config: config.to_owned(),
+            }
+            .source()
+        };
+        assert_eq!(source(r#""1.0""#), "crates.io");
+        assert_eq!(
+            source(r#"{ version = "1.0", features = ["a"] }"#),
+            "crates.io"
+        );
+        assert_eq!(source(r#"{ path = "/tmp/foo" }"#), "path");
+        assert_eq!(
+            source(r#"{ git = "https://github.com/foo/foo", branch = "main" }"#),
+            "git"
+        );
+    }
+
+    #[test]
+    fn test_merge_features() {
+        assert_eq!(
+            merge_features(
+                r#"{ version = "1", features = ["rt"] }"#,
+                r#"{ version = "1", features = ["macros"] }"#
+            ),
+            r#"{ version = "1", features = ["rt", "macros"] }"#
+        );
+        assert_eq!(
+            merge_features(r#"{ version = "1", features = ["rt"] }"#, r#""1.2""#),
+            r#"{ version = "1.2", features = ["rt"] }"#
+        );
+        assert_eq!(
+            merge_features(
+                r#"{ version = "1", features = ["rt"] }"#,
+                r#"{ version = "1", default-features = false }"#
+            ),
+            r#"{ version = "1", default-features = false, features = ["rt"] }"#
+        );
+        assert_eq!(
+            merge_features(r#"{ version = "1", features = ["rt"] }"#, "{ }"),
+            r#"{ features = ["rt"] }"#
+        );
+        // Nothing to merge.
+        assert_eq!(merge_features(r#""1""#, r#""2""#), r#""2""#);
+    }
+
+    #[test]
+    fn test_update_config() {
+        assert_eq!(
+            update_config(r#""1""#, "{ default-features = false }"),
+            r#"{ version = "1", default-features = false }"#
+        );
+        assert_eq!(
+            update_config(
+                r#"{ version = "1", default-features = false, optional = true }"#,
+                r#"{ path = "/tmp/foo", default-features = true }"#
+            ),
+            r#"{ path = "/tmp/foo", optional = true, default-features = true }"#
+        );
+        assert_eq!(
+            update_config(
+                r#"{ git = "https://github.com/foo/foo", branch = "main" }"#,
+                r#"{ features = ["a", "b"] }"#
+            ),
+            r#"{ git = "https://github.com/foo/foo", branch = "main", features = ["a", "b"] }"#
+        );
+        // Features are left to `merge_features`.
         assert_eq!(
-            krate.config,
-            format!(
-                "{{ path = \"{}\" }}",
-                Path::new("src/testdata")
-                    .canonicalize()
-                    .unwrap()
-                    .to_string_lossy()
-            )
+            update_config(r#"{ version = "1", features = ["rt"] }"#, r#""2""#),
+            r#""2""#
         );
+        // Not something we understand.
+        assert_eq!(update_config(r#""1""#, "1"), "1");
     }
 }
@@ -0,0 +1,237 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reflows output produced by pretty Debug formatting (`{:#?}`) so that it makes use of the
+//! configured display width. Pretty Debug output puts every field and element on its own line,
+//! which for small values wastes a lot of vertical space. Here we join the lines of any struct,
+//! list, tuple or map that fits within the width back onto a single line, leaving larger values
+//! spread over multiple lines.
+
+const INDENT: &str = "    ";
+
+#[derive(Debug)]
+enum Node<'a> {
+    Line(&'a str),
+    Group {
+        /// The text of the opening line up to and including the opening bracket.
+        open: &'a str,
+        children: Vec<Node<'a>>,
+        /// The text of the closing line, e.g. `},`.
+        close: &'a str,
+    },
+}
+
+/// Returns `text`, which should be the output of pretty Debug formatting, with every group that
+/// fits within `width` columns (including its indentation) placed on a single line. If `text`
+/// doesn't have the structure of pretty Debug output, it's returned unchanged.
+pub(crate) fn fit_pretty_debug(text: &str, width: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut pos = 0;
+    let mut nodes = Vec::new();
+    while pos < lines.len() {
+        match parse_node(&lines, &mut pos, 0) {
+            Some(node) => nodes.push(node),
+            None => return text.to_owned(),
+        }
+    }
+    let mut out = Vec::new();
+    for node in &nodes {
+        render(node, 0, width, &mut out);
+    }
+    let mut result = out.join("\n");
+    if text.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+fn parse_node<'a>(lines: &[&'a str], pos: &mut usize, depth: usize) -> Option<Node<'a>> {
+    let line = strip_indent(lines[*pos], depth)?;
+    *pos += 1;
+    let closer = match line.chars().last() {
+        Some('{') => '}',
+        Some('[') => ']',
+        Some('(') => ')',
+        _ => return Some(Node::Line(line)),
+    };
+    let mut children = Vec::new();
+    while *pos < lines.len() {
+        if let Some(close) = strip_indent(lines[*pos], depth) {
+            if close.starts_with(closer) {
+                *pos += 1;
+                if children.is_empty() {
+                    return None;
+                }
+                return Some(Node::Group {
+                    open: line,
+                    children,
+                    close,
+                });
+            }
+        }
+        children.push(parse_node(lines, pos, depth + 1)?);
+    }
+    None
+}
+
+/// Returns `line` without its indentation, provided it's indented to exactly `depth` levels.
+fn strip_indent(line: &str, depth: usize) -> Option<&str> {
+    let mut rest = line;
+    for _ in 0..depth {
+        rest = rest.strip_prefix(INDENT)?;
+    }
+    if rest.is_empty() || rest.starts_with(' ') {
+        return None;
+    }
+    Some(rest)
+}
+
+/// Returns `node` formatted on a single line the way compact Debug formatting (`{:?}`) would
+/// have formatted it.
+fn compact(node: &Node) -> Option<String> {
+    let (open, children, close) = match node {
+        Node::Line(line) => return Some((*line).to_owned()),
+        Node::Group {
+            open,
+            children,
+            close,
+        } => (*open, children, *close),
+    };
+    let items = children
+        .iter()
+        .map(|child| Some(compact(child)?.strip_suffix(',')?.to_owned()))
+        .collect::<Option<Vec<_>>>()?
+        .join(", ");
+    let prefix = open[..open.len() - 1].trim_end();
+    // Distinguishes a named struct or tuple struct, e.g. `Foo {` or `a: Some(`, from a map, set
+    // or tuple, e.g. `{` or `"key": (`.
+    let named = prefix
+        .chars()
+        .last()
+        .map(|c| c.is_alphanumeric() || c == '_' || c == '>')
+        .unwrap_or(false);
+    let body = match open.chars().last() {
+        Some('{') if named => format!("{} {} ", open, items),
+        Some('(') if !named && children.len() == 1 => format!("{}{},", open, items),
+        _ => format!("{}{}", open, items),
+    };
+    Some(format!("{}{}", body, close))
+}
+
+fn render(node: &Node, depth: usize, width: usize, out: &mut Vec<String>) {
+    let indent = INDENT.repeat(depth);
+    if let Some(line) = compact(node) {
+        if indent.len() + line.chars().count() <= width {
+            out.push(format!("{}{}", indent, line));
+            return;
+        }
+    }
+    match node {
+        Node::Line(line) => out.push(format!("{}{}", indent, line)),
+        Node::Group {
+            open,
+            children,
+            close,
+        } => {
+            out.push(format!("{}{}", indent, open));
+            for child in children {
+                render(child, depth + 1, width, out);
+            }
+            out.push(format!("{}{}", indent, close));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fit_pretty_debug;
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Shape {
+        name: String,
+        points: Vec<Point>,
+        tag: Option<(u8,)>,
+        unit: (),
+    }
+
+    fn shape() -> Shape {
+        Shape {
+            name: "triangle".to_owned(),
+            points: vec![
+                Point { x: 0, y: 0 },
+                Point { x: 10, y: 0 },
+                Point { x: 5, y: 8 },
+            ],
+            tag: Some((1,)),
+            unit: (),
+        }
+    }
+
+    #[test]
+    fn wide_enough_gives_compact_output() {
+        let shape = shape();
+        let mut map = std::collections::BTreeMap::new();
+        map.insert("a", vec![1, 2]);
+        assert_eq!(
+            fit_pretty_debug(&format!("{:#?}", shape), 200),
+            format!("{:?}", shape)
+        );
+        assert_eq!(
+            fit_pretty_debug(&format!("{:#?}", map), 200),
+            format!("{:?}", map)
+        );
+    }
+
+    #[test]
+    fn narrow_gives_pretty_output() {
+        let shape = shape();
+        assert_eq!(
+            fit_pretty_debug(&format!("{:#?}", shape), 1),
+            format!("{:#?}", shape)
+        );
+    }
+
+    #[test]
+    fn groups_that_fit_are_joined() {
+        assert_eq!(
+            fit_pretty_debug(&format!("{:#?}", shape()), 40),
+            r#"Shape {
+    name: "triangle",
+    points: [
+        Point { x: 0, y: 0 },
+        Point { x: 10, y: 0 },
+        Point { x: 5, y: 8 },
+    ],
+    tag: Some((1,)),
+    unit: (),
+}"#
+        );
+    }
+
+    #[test]
+    fn unrecognized_structure_is_unchanged() {
+        let text = "Foo {\n  bar: 1,\n}";
+        assert_eq!(fit_pretty_debug(text, 80), text);
+        let text = "Foo {\n    bar: 1,\n";
+        assert_eq!(fit_pretty_debug(text, 80), text);
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+
+/// The MIME type of the structured results of `:doctest`. The content is a JSON array with an
+/// object for each example, which has the fields `name`, `status` (`ok`, `failed` or `ignored`),
+/// `duration_secs`, `output` (an array of the lines written to stdout) and, if it failed,
+/// `failure`.
+pub const TEST_RESULTS_MIME_TYPE: &str = "application/x-evcxr-test-results+json";
+
+/// How an example should be tested. When a code block has several attributes, the one that comes
+/// first here takes precedence.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum DoctestMode {
+    /// Don't even compile the example.
+    Ignore,
+    /// Check that the example fails to compile.
+    CompileFail,
+    /// Only check that the example compiles.
+    NoRun,
+    /// Check that the example panics.
+    ShouldPanic,
+    /// Compile and run the example.
+    Run,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Doctest {
+    pub(crate) code: String,
+    pub(crate) mode: DoctestMode,
+}
+
+/// Returns the Rust code blocks found in doc comments within `code`. Lines hidden from the
+/// rendered docs with a leading `#` are included, as they would be by rustdoc.
+pub(crate) fn extract_doctests(code: &str) -> Vec<Doctest> {
+    let file = ast::SourceFile::parse(code);
+    let mut doctests = Vec::new();
+    for node in file.tree().syntax().descendants() {
+        let doc_lines: Vec<String> = ast::DocCommentIter::from_syntax_node(&node)
+            .filter_map(|comment| comment.doc_comment().map(str::to_owned))
+            .flat_map(|text| {
+                text.lines()
+                    .map(|line| line.strip_prefix(' ').unwrap_or(line).to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        extract_from_doc_lines(&doc_lines, &mut doctests);
+    }
+    doctests
+}
+
+fn extract_from_doc_lines(lines: &[String], doctests: &mut Vec<Doctest>) {
+    let mut current: Option<(Option<DoctestMode>, String)> = None;
+    for line in lines {
+        let trimmed = line.trim();
+        if let Some(info) = trimmed.strip_prefix("```") {
+            if let Some((mode, code)) = current.take() {
+                if let Some(mode) = mode {
+                    doctests.push(Doctest { code, mode });
+                }
+            } else {
+                current = Some((mode_from_info_string(info), String::new()));
+            }
+        } else if let Some((_, code)) = &mut current {
+            let line = if trimmed == "#" {
+                ""
+            } else {
+                trimmed.strip_prefix("# ").unwrap_or(line)
+            };
+            code.push_str(line);
+            code.push('\n');
+        }
+    }
+}
+
+/// Returns how a code block with the supplied info string (the text after the opening fence)
+/// should be treated, or `None` if it isn't Rust code.
+fn mode_from_info_string(info: &str) -> Option<DoctestMode> {
+    let mut mode = DoctestMode::Run;
+    for attribute in info
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|attribute| !attribute.is_empty())
+    {
+        let attribute_mode = match attribute {
+            "ignore" => DoctestMode::Ignore,
+            "compile_fail" => DoctestMode::CompileFail,
+            "no_run" => DoctestMode::NoRun,
+            "should_panic" => DoctestMode::ShouldPanic,
+            "rust" | "edition2015" | "edition2018" | "edition2021" => DoctestMode::Run,
+            _ => return None,
+        };
+        mode = mode.min(attribute_mode);
+    }
+    Some(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_doctests;
+    use super::Doctest;
+    use super::DoctestMode;
+
+    #[test]
+    fn test_extract_doctests() {
+        let code = r#"
+/// Adds one.
+///
+/// ```
+/// # let x = 1;
+/// assert_eq!(add_one(x), 2);
+/// ```
+///
+/// ```text
+/// Not code
+/// ```
+///
+/// ```ignore
+/// add_one(());
+/// ```
+fn add_one(x: i32) -> i32 {
+    x + 1
+}
+
+struct Foo;
+impl Foo {
+    /// ```rust,no_run
+    /// Foo::run();
+    /// ```
+    fn run() {}
+}
+"#;
+        assert_eq!(
+            extract_doctests(code),
+            vec![
+                Doctest {
+                    code: "let x = 1;\nassert_eq!(add_one(x), 2);\n".to_owned(),
+                    mode: DoctestMode::Run,
+                },
+                Doctest {
+                    code: "add_one(());\n".to_owned(),
+                    mode: DoctestMode::Ignore,
+                },
+                Doctest {
+                    code: "Foo::run();\n".to_owned(),
+                    mode: DoctestMode::NoRun,
+                },
+            ]
+        );
+    }
+}
@@ -18,6 +18,7 @@ use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
+use crate::code_block::UserCodeMetadata;
 use ariadne::Color;
 use ariadne::{ColorGenerator, Label, Report, ReportKind};
 use json::JsonValue;
@@ -40,6 +41,40 @@ pub struct CompilationError {
     level: String,
 }
 
+/// Merges errors that have the same level, code, message and primary span, noting how many times
+/// each was repeated. A single mistake can otherwise produce many identical errors, e.g. one for
+/// each bit of code that we generate to store a variable.
+pub(crate) fn collapse_duplicate_errors(errors: Vec<CompilationError>) -> Vec<CompilationError> {
+    let mut collapsed: Vec<(CompilationError, usize)> = Vec::new();
+    for error in errors {
+        let span = error
+            .primary_spanned_message()
+            .and_then(|message| message.span);
+        let existing = collapsed.iter_mut().find(|(other, _)| {
+            other.level == error.level
+                && other.code() == error.code()
+                && other.message == error.message
+                && other
+                    .primary_spanned_message()
+                    .and_then(|message| message.span)
+                    == span
+        });
+        match existing {
+            Some((_, count)) => *count += 1,
+            None => collapsed.push((error, 1)),
+        }
+    }
+    collapsed
+        .into_iter()
+        .map(|(mut error, count)| {
+            if count > 1 {
+                error.message = format!("{} (repeated {} times)", error.message, count);
+            }
+            error
+        })
+        .collect()
+}
+
 pub enum Theme {
     Light,
     Dark,
@@ -95,7 +130,7 @@ impl CompilationError {
             .chain(error.help_spanned().iter())
         {
             if let Some(span) = &spanned_message.span {
-                if spanned_message.label == "" {
+                if spanned_message.label.is_empty() {
                     continue;
                 }
                 builder = builder.with_label(
@@ -257,6 +292,10 @@ impl CompilationError {
         self.message.clone()
     }
 
+    pub(crate) fn set_message(&mut self, message: String) {
+        self.message = message;
+    }
+
     pub fn code(&self) -> Option<&str> {
         if let JsonValue::Object(code) = &self.json["code"] {
             return code["code"].as_str();
@@ -421,7 +460,7 @@ fn build_spanned_messages(json: &JsonValue, code_block: &CodeBlock) -> Vec<Spann
 }
 
 #[non_exhaustive]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Span {
     /// 1-based line number in the original user code on which the span starts (inclusive).
     pub start_line: usize,
@@ -451,18 +490,8 @@ impl Span {
 
     pub(crate) fn from_segment(segment: &Segment, range: TextRange) -> Option<Span> {
         if let CodeKind::OriginalUserCode(meta) = &segment.kind {
-            let (start_line, start_column) = line_and_column(
-                &segment.code,
-                range.start(),
-                meta.column_offset,
-                meta.start_line,
-            );
-            let (end_line, end_column) = line_and_column(
-                &segment.code,
-                range.end(),
-                meta.column_offset,
-                meta.start_line,
-            );
+            let (start_line, start_column) = line_and_column(&segment.code, range.start(), meta);
+            let (end_line, end_column) = line_and_column(&segment.code, range.end(), meta);
             Some(Span {
                 start_line,
                 start_column,
@@ -477,19 +506,14 @@ impl Span {
 
 /// Returns the line and column number of `position` within `text`. Line and column numbers are
 /// 1-based.
-fn line_and_column(
-    text: &str,
-    position: TextSize,
-    first_line_column_offset: usize,
-    start_line: usize,
-) -> (usize, usize) {
-    let text = &text[..usize::from(position)];
+fn line_and_column(text: &str, position: TextSize, meta: &UserCodeMetadata) -> (usize, usize) {
+    let text = &text[..meta.rewritten_position(usize::from(position))];
     let line = text.lines().count();
-    let mut column = text.lines().last().map(count_columns).unwrap_or(0) + 1;
-    if line == 1 {
-        column += first_line_column_offset;
-    }
-    (start_line + line - 1, column)
+    let column = text.lines().last().map(count_columns).unwrap_or(0) + 1;
+    (
+        meta.start_line + line - 1,
+        meta.user_column(line.saturating_sub(1), column),
+    )
 }
 
 #[derive(Debug, Clone)]
@@ -521,19 +545,9 @@ impl SpannedMessage {
                 {
                     Some(Span {
                         start_line: start.start_line + start_line_offset,
-                        start_column: start_column
-                            + (if *start_line_offset == 0 {
-                                start.column_offset
-                            } else {
-                                0
-                            }),
+                        start_column: start.user_column(*start_line_offset, start_column),
                         end_line: end.start_line + end_line_offset,
-                        end_column: end_column
-                            + (if *end_line_offset == 0 {
-                                end.column_offset
-                            } else {
-                                0
-                            }),
+                        end_column: end.user_column(*end_line_offset, end_column),
                     })
                 } else {
                     // Spans within generated code won't mean anything to the user, suppress
@@ -563,7 +577,7 @@ impl SpannedMessage {
             .as_str()
             .map(|s| s.to_owned())
             .or(fallback_label)
-            .unwrap_or_else(String::new);
+            .unwrap_or_default();
         if let Some(replace) = span_json["suggested_replacement"].as_str() {
             label += &format!(": `{}`", replace);
         }
@@ -591,6 +605,16 @@ pub enum Error {
     TypeRedefinedVariablesLost(Vec<String>),
     Message(String),
     SubprocessTerminated(String),
+    /// User code panicked, which terminated the subprocess. `message` is the panic's message,
+    /// while `details` describes how the subprocess terminated, as for `SubprocessTerminated`. The
+    /// panic's location and any backtrace are written to stderr as usual.
+    Panic {
+        message: String,
+        details: String,
+    },
+    /// Execution was stopped via an `InterruptHandle`. See its documentation for which variables
+    /// are kept.
+    Interrupted,
 }
 
 impl std::error::Error for Error {}
@@ -610,9 +634,12 @@ impl fmt::Display for Error {
                     variables.join(", ")
                 )?;
             }
-            Error::Message(message) | Error::SubprocessTerminated(message) => {
-                write!(f, "{}", message)?
-            }
+            Error::Message(message)
+            | Error::SubprocessTerminated(message)
+            | Error::Panic {
+                details: message, ..
+            } => write!(f, "{}", message)?,
+            Error::Interrupted => write!(f, "Execution interrupted")?,
         }
         Ok(())
     }
@@ -682,3 +709,40 @@ macro_rules! _bail {
     ($($arg:tt)+) => {return Err($crate::errors::err!($($arg)+))}
 }
 pub(crate) use _bail as bail;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collapse_duplicate_errors() {
+        let (code, _) = CodeBlock::from_original_user_code("let a = b + d;");
+        let segment = &code.segments[0];
+        let error = |message: &str, start: u32, end: u32| {
+            let span =
+                Span::from_segment(segment, TextRange::new(start.into(), end.into())).unwrap();
+            CompilationError::from_segment_span(
+                segment,
+                SpannedMessage::from_segment_span(segment, span),
+                message.to_owned(),
+            )
+        };
+        let collapsed = collapse_duplicate_errors(vec![
+            error("cannot find value `b`", 8, 9),
+            error("cannot find value `d`", 12, 13),
+            error("cannot find value `b`", 8, 9),
+            error("cannot find value `b`", 8, 9),
+            // Same message, but a different span.
+            error("cannot find value `b`", 12, 13),
+        ]);
+        let messages: Vec<String> = collapsed.iter().map(CompilationError::message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "cannot find value `b` (repeated 3 times)",
+                "cannot find value `d`",
+                "cannot find value `b`",
+            ]
+        );
+    }
+}
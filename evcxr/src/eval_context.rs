@@ -12,21 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::backtrace::CellLineMap;
+use crate::child_process;
 use crate::child_process::ChildProcess;
+use crate::child_process::InterruptHandle;
+use crate::child_process::StderrMode;
+use crate::code_block::count_columns;
+use crate::code_block::CellLocation;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
+use crate::code_block::PathRewrite;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
 use crate::crate_config::ExternalCrate;
 use crate::errors::bail;
+use crate::errors::err;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::evcxr_internal_runtime;
 use crate::item;
+use crate::item::ImplKey;
 use crate::module::Module;
 use crate::module::SoFile;
+use crate::paths;
 use crate::runtime;
 use crate::rust_analyzer::Completions;
 use crate::rust_analyzer::RustAnalyzer;
@@ -37,14 +47,19 @@ use once_cell::sync::OnceCell;
 use ra_ap_ide::TextRange;
 use ra_ap_syntax::ast;
 use ra_ap_syntax::AstNode;
+use ra_ap_syntax::AstToken;
 use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
 
@@ -55,8 +70,54 @@ pub struct EvalContext {
     committed_state: ContextState,
     child_process: ChildProcess,
     stdout_sender: crossbeam_channel::Sender<String>,
+    stderr_sender: crossbeam_channel::Sender<String>,
+    status_sender: crossbeam_channel::Sender<EvalStatus>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    /// The number of cells that have been evaluated, including the current one while evaluating.
+    cell_count: usize,
+    /// Shared with the subprocess' stderr handler, which uses it to annotate backtraces.
+    cell_line_map: Arc<Mutex<CellLineMap>>,
+    /// The working directory that we last told our subprocess to use, if any. Otherwise it has
+    /// the same working directory as our process.
+    child_working_dir: Option<PathBuf>,
+    /// Whether we last told our subprocess to set `DETERMINISTIC_ENV_VARS`.
+    child_deterministic: bool,
+    /// Renderers for the final expression of a cell, keyed by type name.
+    type_renderers: HashMap<String, Arc<TypeRenderer>>,
+    /// Lines written to stdout by user code while capturing, which then aren't sent to
+    /// `stdout_sender`. `None` when not capturing.
+    captured_stdout: Option<Vec<String>>,
+}
+
+/// Converts the output of formatting a value (usually its Debug representation) into the outputs
+/// to display for that value.
+pub type TypeRenderer = dyn Fn(&str) -> EvalOutputs + Send + Sync;
+
+/// How to classify the code in a cell, overriding how it would normally be classified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ForcedCodeKind {
+    /// Evaluate items as statements, so that they only exist while the cell runs.
+    Statement,
+    /// Keep macro invocations as items, so that items they define persist.
+    Item,
+}
+
+/// Where `:trace_codegen` writes the code that we generate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CodegenTrace {
+    Stderr,
+    /// Entries are appended to this file.
+    File(PathBuf),
+}
+
+/// An item defined by the user, as listed by `:items`.
+pub(crate) struct DefinedItem {
+    pub(crate) kind: &'static str,
+    pub(crate) name: String,
+    /// Where the item was defined. Not known for items that didn't come directly from a cell, such
+    /// as modules loaded from files.
+    pub(crate) location: Option<CellLocation>,
 }
 
 #[derive(Clone, Debug)]
@@ -85,7 +146,118 @@ pub(crate) struct Config {
     pub(crate) sccache: Option<PathBuf>,
     /// Whether to attempt to avoid network access.
     pub(crate) offline_mode: bool,
+    /// Whether to set `DETERMINISTIC_ENV_VARS` when running code.
+    deterministic: bool,
+    /// The rustup toolchain to use for compilation, e.g. `nightly`. Empty to use the default.
     pub(crate) toolchain: String,
+    /// The package name of the crate that we generate for each cell.
+    pub(crate) crate_name: String,
+    /// Exact versions that dependencies should be pinned to, keyed by package name. Populated from
+    /// a Cargo.lock by `:lock_from`.
+    pub(crate) locked_versions: HashMap<String, String>,
+    /// The contents of a Cargo.lock that is written before each build, so that dependencies keep
+    /// the versions that they were resolved to. Set by `:freeze` and updated after each build to
+    /// include any newly added dependencies.
+    pub(crate) frozen_lock: Option<String>,
+    /// Whether re-adding a dependency that's already present should keep the features that it
+    /// previously had, in addition to any newly specified features.
+    merge_dep_features: bool,
+    /// The maximum number of variables to keep, if any, and what to do when a new variable would
+    /// exceed it.
+    max_vars: Option<(usize, VariableLimitAction)>,
+    /// The number of parallel jobs cargo should use when building, if not cargo's default.
+    pub(crate) jobs: Option<usize>,
+    /// The number of builds whose shared objects we keep on disk.
+    pub(crate) keep_generations: usize,
+    /// Whether to report how much memory each evaluation allocated.
+    profile_memory: bool,
+    /// The number of threads for rustc's parallel frontend to use, if enabled. Requires nightly.
+    pub(crate) parallel_frontend: Option<usize>,
+    /// Where to log the code passed to the compiler on each build attempt, if anywhere.
+    pub(crate) trace_codegen: Option<CodegenTrace>,
+    /// Whether to compile user code as `#![no_std]`.
+    no_std: bool,
+    /// Whether to install a logger for the `log` crate that writes to stderr.
+    logging: bool,
+    /// The maximum level of log records to output when `logging` is enabled.
+    log_level: String,
+    /// Use statements that are applied to every fresh state, e.g. after `:clear`.
+    prelude: Vec<String>,
+    /// Lint levels set via `:lint`, in the order they were set, as (level, lint name) pairs.
+    /// Each lint appears at most once.
+    lint_levels: Vec<(String, String)>,
+    /// The maximum number of characters in a line of output before it gets truncated, if any.
+    max_line_length: Option<usize>,
+    /// The number of decimal places with which to display floating point numbers in the final
+    /// expression of a cell, if set.
+    float_precision: Option<usize>,
+    /// The number of columns available for displaying output, if set with `:width`.
+    display_width: Option<usize>,
+    /// Features to enable when building the generated crate. Plain names are declared as features
+    /// of the generated crate, while names like `dep/feature` enable features of dependencies.
+    features: Vec<String>,
+    /// The directory that relative paths are resolved against, if it's been changed with `:cd`.
+    /// Otherwise we use the current directory of our process.
+    working_dir: Option<PathBuf>,
+    /// Whether a future returned by the final expression in async mode should be awaited and its
+    /// output displayed.
+    auto_await: bool,
+    /// Whether cells may contain `unsafe` code.
+    allow_unsafe: bool,
+    /// Whether to suppress warnings about unused imports. In a REPL, imports are often added
+    /// before they're needed, so these warnings are mostly noise.
+    allow_unused_imports: bool,
+    /// Whether to bind the value of the final expression of each cell to a variable named after
+    /// the cell, e.g. `_3`.
+    autobind: bool,
+    /// Whether output is passed on a line at a time. If false, `print!` flushes stdout and partial
+    /// lines are passed on as soon as they're written.
+    line_buffered: bool,
+    /// Whether output after a carriage return replaces the line that it was written on, rather
+    /// than being appended to it, so that progress bars update in place.
+    progress_bars: bool,
+    /// Whether persisted variables that a cell moves out of are cloned first, so that they can
+    /// still be used by later cells.
+    auto_clone: bool,
+    /// Prebuilt dynamic libraries that the generated crate links against, added with `:dylib`.
+    dylibs: Vec<PathBuf>,
+    /// Where stderr from the execution process gets sent.
+    stderr_mode: StderrMode,
+    /// Whether to report when a cell replaces a previously defined item.
+    replaced_item_notices: ReplacedItemNotices,
+    /// Whether to warn when an import shadows a name from the standard prelude.
+    prelude_shadowing_warnings: bool,
+    /// Whether the final expression of a cell should be sent together with its type name, so that
+    /// it can be displayed by a registered `TypeRenderer`.
+    send_type_names: bool,
+    /// The oldest version of rustc that may be used, as given to `:min_rust`, if any.
+    min_rust_version: Option<String>,
+    /// Whether statements that use `?` are each wrapped so that an error returned by one skips
+    /// the rest of the cell, keeping variables defined before it.
+    try_mode: bool,
+    /// Whether variables from earlier cells that a cell redefines keep their previous values if
+    /// the cell returns an error via `?`.
+    retain_on_error: bool,
+    /// Whether user code runs on a worker thread of the execution process rather than on its
+    /// main thread.
+    run_on_worker: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReplacedItemNotices {
+    Off,
+    /// Report the names of replaced items.
+    On,
+    /// Also report how the signatures of replaced items changed.
+    Verbose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VariableLimitAction {
+    /// Drop the least recently defined variables.
+    Evict,
+    /// Refuse to evaluate code that defines new variables.
+    Reject,
 }
 
 fn create_initial_config(crate_dir: PathBuf) -> Config {
@@ -116,7 +288,44 @@ impl Config {
             linker: "system".to_owned(),
             sccache: None,
             offline_mode: false,
+            deterministic: false,
             toolchain: String::new(),
+            crate_name: crate::module::CRATE_NAME.to_owned(),
+            locked_versions: HashMap::new(),
+            frozen_lock: None,
+            merge_dep_features: true,
+            max_vars: None,
+            jobs: None,
+            keep_generations: DEFAULT_KEEP_GENERATIONS,
+            profile_memory: false,
+            parallel_frontend: None,
+            trace_codegen: None,
+            no_std: false,
+            logging: false,
+            log_level: "info".to_owned(),
+            prelude: Vec::new(),
+            lint_levels: Vec::new(),
+            max_line_length: None,
+            float_precision: None,
+            display_width: None,
+            features: Vec::new(),
+            working_dir: None,
+            auto_await: false,
+            allow_unsafe: true,
+            allow_unused_imports: true,
+            autobind: false,
+            auto_clone: false,
+            line_buffered: true,
+            progress_bars: false,
+            dylibs: Vec::new(),
+            stderr_mode: StderrMode::Separate,
+            replaced_item_notices: ReplacedItemNotices::Off,
+            prelude_shadowing_warnings: true,
+            send_type_names: false,
+            min_rust_version: None,
+            try_mode: false,
+            retain_on_error: false,
+            run_on_worker: false,
         }
     }
 
@@ -137,6 +346,50 @@ impl Config {
         self.sccache.is_some()
     }
 
+    /// Returns the version of the compiler that we use, as reported by `rustc --version`.
+    pub(crate) fn rustc_version(&self) -> Result<String, Error> {
+        let mut command = Command::new("rustc");
+        if !self.toolchain.is_empty() {
+            command.arg(format!("+{}", self.toolchain));
+        }
+        let output = command
+            .arg("--version")
+            .output()
+            .map_err(|error| err!("Error running rustc: {}", error))?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Returns an error if the compiler that we use is older than `min_rust_version`.
+    fn check_min_rust_version(&self) -> Result<(), Error> {
+        let min_version = match &self.min_rust_version {
+            Some(min_version) => min_version,
+            None => return Ok(()),
+        };
+        let rustc_version = self.rustc_version()?;
+        let actual = rustc_version
+            .split_whitespace()
+            .nth(1)
+            .and_then(parse_rust_version)
+            .ok_or_else(|| {
+                err!(
+                    "Couldn't determine the rustc version from `{}`",
+                    rustc_version
+                )
+            })?;
+        if parse_rust_version(min_version).map_or(false, |min| actual < min) {
+            bail!(
+                "rustc {} or newer is required, but the toolchain in use is {}. Try `rustup \
+                 update`, or selecting a newer toolchain with `:toolchain`.",
+                min_version,
+                rustc_version
+            );
+        }
+        Ok(())
+    }
+
     pub(crate) fn cargo_command(&self, command_name: &str) -> Command {
         let mut command = if self.linker == "mold" {
             Command::new("mold")
@@ -156,6 +409,56 @@ impl Config {
         command.current_dir(&self.crate_dir);
         command
     }
+
+    /// Like `cargo_command`, but for commands that build code, so also applies build settings.
+    pub(crate) fn cargo_build_command(&self, command_name: &str) -> Command {
+        let mut command = self.cargo_command(command_name);
+        if let Some(jobs) = self.jobs {
+            command.arg("--jobs").arg(jobs.to_string());
+        }
+        if !self.features.is_empty() {
+            command.arg("--features").arg(self.features.join(","));
+        }
+        if let Some(threads) = self.parallel_frontend {
+            // Passing the flag via RUSTFLAGS means that it also applies when building dependencies.
+            if self.uses_nightly() {
+                let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
+                if !rustflags.is_empty() {
+                    rustflags.push(' ');
+                }
+                rustflags.push_str(&format!("-Z threads={}", threads));
+                command.env("RUSTFLAGS", rustflags);
+            }
+        }
+        command
+    }
+
+    /// Returns the arguments to pass to rustc so that we link against the libraries added with
+    /// `:dylib`. On platforms other than Windows, we also set an rpath so that the libraries can be
+    /// found when our code is loaded. On Windows, the directory containing the DLL needs to be on
+    /// the PATH or be the working directory.
+    pub(crate) fn dylib_link_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for path in &self.dylibs {
+            let (dir, name) = match (path.parent(), dylib_name(path)) {
+                (Some(dir), Some(name)) => (dir, name),
+                _ => continue,
+            };
+            args.push("-L".to_owned());
+            args.push(format!("native={}", dir.display()));
+            args.push("-l".to_owned());
+            args.push(format!("dylib={}", name));
+            if !cfg!(windows) {
+                args.push("-C".to_owned());
+                args.push(format!("link-arg=-Wl,-rpath,{}", dir.display()));
+            }
+        }
+        args
+    }
+
+    pub(crate) fn uses_nightly(&self) -> bool {
+        self.toolchain.starts_with("nightly")
+    }
 }
 
 #[derive(Debug)]
@@ -198,13 +501,145 @@ const SEND_TEXT_PLAIN_DEF: &str = stringify!(
     }
 );
 
+/// Like `SEND_TEXT_PLAIN_DEF`, but sends the type name of the value on the first line of the
+/// content, so that the parent can pick a `TypeRenderer`.
+const SEND_TYPED_VALUE_DEF: &str = stringify!(
+    fn evcxr_send_typed_value(type_name: &str, text: &str) {
+        use std::io::Write;
+        use std::io::{self};
+        fn try_send_text(type_name: &str, text: &str) -> io::Result<()> {
+            let stdout = io::stdout();
+            let mut output = stdout.lock();
+            output.write_all(b"EVCXR_BEGIN_CONTENT application/x-evcxr-typed-value\n")?;
+            output.write_all(type_name.as_bytes())?;
+            output.write_all(b"\n")?;
+            output.write_all(text.as_bytes())?;
+            output.write_all(b"\nEVCXR_END_CONTENT\n")?;
+            Ok(())
+        }
+        if let Err(error) = try_send_text(type_name, text) {
+            eprintln!("Failed to send content to parent: {:?}", error);
+            std::process::exit(1);
+        }
+    }
+);
+
 const PANIC_NOTIFICATION: &str = "EVCXR_PANIC_NOTIFICATION";
 
+/// Names from the standard prelude that imports are checked against, together with the paths of
+/// the items that they refer to.
+const PRELUDE_NAMES: &[(&str, &str)] = &[
+    ("Option", "std::option::Option"),
+    ("Some", "std::option::Option::Some"),
+    ("None", "std::option::Option::None"),
+    ("Result", "std::result::Result"),
+    ("Ok", "std::result::Result::Ok"),
+    ("Err", "std::result::Result::Err"),
+    ("Vec", "std::vec::Vec"),
+    ("String", "std::string::String"),
+    ("Box", "std::boxed::Box"),
+    ("ToString", "std::string::ToString"),
+    ("ToOwned", "std::borrow::ToOwned"),
+    ("Clone", "std::clone::Clone"),
+    ("Copy", "std::marker::Copy"),
+    ("Send", "std::marker::Send"),
+    ("Sync", "std::marker::Sync"),
+    ("Sized", "std::marker::Sized"),
+    ("Drop", "std::ops::Drop"),
+    ("Fn", "std::ops::Fn"),
+    ("FnMut", "std::ops::FnMut"),
+    ("FnOnce", "std::ops::FnOnce"),
+    ("Iterator", "std::iter::Iterator"),
+    ("IntoIterator", "std::iter::IntoIterator"),
+    ("Extend", "std::iter::Extend"),
+    ("Default", "std::default::Default"),
+    ("PartialEq", "std::cmp::PartialEq"),
+    ("Eq", "std::cmp::Eq"),
+    ("PartialOrd", "std::cmp::PartialOrd"),
+    ("Ord", "std::cmp::Ord"),
+    ("AsRef", "std::convert::AsRef"),
+    ("AsMut", "std::convert::AsMut"),
+    ("From", "std::convert::From"),
+    ("Into", "std::convert::Into"),
+];
+
+/// Levels accepted by `:log_level`, from least to most verbose.
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// Environment variables that `:deterministic` sets when running code, so that crates that read
+/// them use a fixed seed.
+pub(crate) const DETERMINISTIC_ENV_VARS: &[(&str, &str)] =
+    &[("EVCXR_SEED", "0"), ("PROPTEST_RNG_SEED", "0")];
+
+/// How many builds' shared objects we keep on disk by default. See `:keep_generations`.
+const DEFAULT_KEEP_GENERATIONS: usize = 5;
+
+/// Parses a Rust version such as `1.70`, `1.70.1` or `1.76.0-nightly` into its major, minor and
+/// patch numbers. Pre-release suffixes are ignored and a missing patch number is treated as 0.
+fn parse_rust_version(version: &str) -> Option<[u32; 3]> {
+    let version = version.split('-').next()?;
+    let mut parts = version.split('.');
+    let mut numbers = [0; 3];
+    for (index, number) in numbers.iter_mut().enumerate() {
+        match parts.next() {
+            Some(part) => *number = part.parse().ok()?,
+            None if index == 2 => {}
+            None => return None,
+        }
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(numbers)
+}
+
+/// Levels accepted by `:lint`.
+const LINT_LEVELS: &[&str] = &["allow", "warn", "deny", "forbid"];
+
+/// Returns the name of the `log::LevelFilter` variant corresponding to `level`, which should be
+/// one of `LOG_LEVELS`.
+fn log_level_filter_name(level: &str) -> String {
+    let mut chars = level.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+        .unwrap_or_default()
+}
+
+/// Crates that come with the compiler, so shouldn't be fetched from crates.io when the user writes
+/// `extern crate`.
+const SYSROOT_CRATES: &[&str] = &["alloc", "core", "proc_macro", "std", "test"];
+
+/// Prefix of output that's only part of a line, which frontends should display without a
+/// following newline. Such output is only sent while `:line_buffered` is off or `:progress_bars`
+/// is on.
+pub const PARTIAL_LINE_MARKER: char = '\u{1}';
+
 // Outputs from an EvalContext. This is a separate struct since users may want
 // destructure this and pass its components to separate threads.
 pub struct EvalContextOutputs {
+    /// Lines of output. Output that starts with `PARTIAL_LINE_MARKER` is only part of a line, with
+    /// the rest of the line to follow. While `:progress_bars` is on, lines that start with `\r`
+    /// replace the previous line that started with `\r`, provided no other output came in
+    /// between.
     pub stdout: crossbeam_channel::Receiver<String>,
+    /// Lines written to stderr, with the same handling of `\r` as `stdout`.
     pub stderr: crossbeam_channel::Receiver<String>,
+    /// Receives each phase that evaluation of code passes through, e.g. so that frontends can
+    /// show the status of the cell that's currently executing.
+    pub status: crossbeam_channel::Receiver<EvalStatus>,
+}
+
+/// A phase of the evaluation of some code. Each evaluation starts with `Queued` and ends with
+/// either `Done` or `Error`. `Compiling` and `Running` may be skipped, e.g. if there's a syntax
+/// error, or may occur more than once if we need to retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvalStatus {
+    Queued,
+    Compiling,
+    Running,
+    Done,
+    Error,
 }
 
 //#[non_exhaustive]
@@ -274,11 +709,45 @@ impl EvalContext {
     }
 
     pub fn with_subprocess_command(
+        subprocess_command: std::process::Command,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_subprocess_command_internal(subprocess_command, true)
+    }
+
+    /// Creates a new context with default configuration that runs code in a separate subprocess
+    /// to ours, started in the same way.
+    pub(crate) fn new_sibling(&self) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        // We always use a fresh temporary directory, since if EVCXR_TMPDIR is set, we'd otherwise
+        // be sharing it with ourselves.
+        let (mut context, outputs) =
+            Self::with_subprocess_command_internal(self.child_process.command_copy(), false)?;
+        let mut state = context.state();
+        state.set_offline_mode(self.committed_state.config.offline_mode);
+        context.commit_state(state);
+        Ok((context, outputs))
+    }
+
+    /// Returns a new context with the same items, dependencies and settings as this one, but with
+    /// no variables and its own subprocess. Code run in it can't affect our variables, even if it
+    /// panics.
+    pub(crate) fn new_detached_copy(&self) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        let (mut context, outputs) = self.new_sibling()?;
+        let mut state = self.committed_state.clone();
+        state.config.crate_dir = context.committed_state.config.crate_dir.clone();
+        state.variable_states.clear();
+        state.stored_variable_states.clear();
+        state.variables_to_drop.clear();
+        context.committed_state = state;
+        Ok((context, outputs))
+    }
+
+    fn with_subprocess_command_internal(
         mut subprocess_command: std::process::Command,
+        allow_tmpdir_from_env: bool,
     ) -> Result<(EvalContext, EvalContextOutputs), Error> {
         let mut opt_tmpdir = None;
         let tmpdir_path;
-        if let Ok(from_env) = std::env::var("EVCXR_TMPDIR") {
+        if let Some(from_env) = std::env::var_os("EVCXR_TMPDIR").filter(|_| allow_tmpdir_from_env) {
             tmpdir_path = PathBuf::from(from_env);
         } else {
             let tmpdir = tempfile::tempdir()?;
@@ -293,7 +762,14 @@ impl EvalContext {
 
         let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
         let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
-        let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
+        let (status_sender, status_receiver) = crossbeam_channel::unbounded();
+        let cell_line_map = Arc::new(Mutex::new(CellLineMap::default()));
+        let child_process = ChildProcess::new(
+            subprocess_command,
+            stdout_sender.clone(),
+            stderr_sender.clone(),
+            Arc::clone(&cell_line_map),
+        )?;
         let initial_config = create_initial_config(module.crate_dir().to_owned());
         let initial_state = ContextState::new(initial_config.clone());
         let mut context = EvalContext {
@@ -302,12 +778,21 @@ impl EvalContext {
             module,
             child_process,
             stdout_sender,
+            stderr_sender,
+            status_sender,
             analyzer,
             initial_config,
+            cell_count: 0,
+            cell_line_map,
+            child_working_dir: None,
+            child_deterministic: false,
+            type_renderers: HashMap::new(),
+            captured_stdout: None,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
             stderr: stderr_receiver,
+            status: status_receiver,
         };
         if context.committed_state.linker() == "lld" && context.eval("42").is_err() {
             context.committed_state.set_linker("system".to_owned());
@@ -317,6 +802,8 @@ impl EvalContext {
             context.eval("42")?;
         }
         context.initial_config = context.committed_state.config.clone();
+        // Our warm-up evaluation shouldn't be counted as a cell.
+        context.cell_count = 0;
         Ok((context, outputs))
     }
 
@@ -346,9 +833,25 @@ impl EvalContext {
         mut state: ContextState,
         code_info: &UserCodeInfo,
     ) -> Result<Vec<CompilationError>, Error> {
+        if !code_info.syntax_errors.is_empty() {
+            return Ok(code_info.syntax_errors.clone());
+        }
+        // As when evaluating, there's nothing to check if the code has no statements, items or
+        // expressions (e.g. it's just comments).
+        if user_code.is_empty()
+            && !self
+                .committed_state
+                .state_change_can_fail_compilation(&state)
+        {
+            return Ok(Vec::new());
+        }
         state.config.display_final_expression = false;
         state.config.expand_use_statements = false;
-        let user_code = state.apply(user_code, &code_info.nodes)?;
+        let user_code = match state.apply(user_code, &code_info.nodes) {
+            Ok(user_code) => user_code,
+            Err(Error::CompilationErrors(errors)) => return Ok(errors),
+            Err(error) => return Err(error),
+        };
         let code = state.analysis_code(user_code.clone());
         let errors = self.module.check(&code, &state.config)?;
         Ok(state.apply_custom_errors(errors, &user_code, code_info))
@@ -356,12 +859,35 @@ impl EvalContext {
 
     /// Evaluates the supplied Rust code.
     pub(crate) fn eval_with_callbacks(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        self.send_status(EvalStatus::Queued);
+        let result = self.eval_with_callbacks_internal(user_code, state, code_info, callbacks);
+        self.send_status(if result.is_ok() {
+            EvalStatus::Done
+        } else {
+            EvalStatus::Error
+        });
+        result
+    }
+
+    fn eval_with_callbacks_internal(
         &mut self,
         user_code: CodeBlock,
         mut state: ContextState,
         code_info: &UserCodeInfo,
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
+        self.cell_count += 1;
+        // Report syntax errors ourselves, since we can report them relative to the user's code,
+        // whereas the compiler typically reports additional errors caused by our wrapping code.
+        if !code_info.syntax_errors.is_empty() {
+            return Err(Error::CompilationErrors(code_info.syntax_errors.clone()));
+        }
         if user_code.is_empty()
             && !self
                 .committed_state
@@ -370,14 +896,78 @@ impl EvalContext {
             self.commit_state(state);
             return Ok(EvalOutputs::default());
         }
+        if state.config.toolchain != self.committed_state.config.toolchain {
+            self.switch_toolchain(&mut state)?;
+        }
+        if state.rebuild_requested {
+            self.module.clean_build_artifacts();
+        }
+        if !state.deps_to_reload.is_empty() {
+            self.reload_deps(&mut state)?;
+        }
         let mut phases = PhaseDetailsBuilder::new();
+        if state.config.autobind {
+            state.autobind_name = Some(format!("_{}", self.cell_count));
+        }
         let code_out = state.apply(user_code.clone(), &code_info.nodes)?;
+        state.check_variable_limit()?;
 
         let mut outputs = match self.run_statements(code_out, &mut state, &mut phases, callbacks) {
-            error @ Err(Error::SubprocessTerminated(_)) => {
+            error @ Err(Error::SubprocessTerminated(_) | Error::Panic { .. }) => {
+                self.restart_child_process()?;
+                return error;
+            }
+            error @ Err(Error::Interrupted) if self.child_process.has_terminated() => {
+                // Restarting the subprocess loses all variables, not just those used by the cell.
+                let mut lost: Vec<&str> = self
+                    .committed_state
+                    .variable_states
+                    .keys()
+                    .map(String::as_str)
+                    .collect();
+                if !lost.is_empty() {
+                    lost.sort_unstable();
+                    let _ = self.stderr_sender.send(format!(
+                        "The subprocess was restarted, the following variables have been lost: {}",
+                        lost.join(", ")
+                    ));
+                }
                 self.restart_child_process()?;
                 return error;
             }
+            error @ Err(Error::Interrupted) => {
+                // The thread that was running the cell was abandoned, taking with it any variables
+                // that the cell loaded from the variable store.
+                let mut lost: Vec<String> = self
+                    .committed_state
+                    .variable_states
+                    .keys()
+                    .filter(|var_name| !state.untouched_variables.contains(*var_name))
+                    .cloned()
+                    .collect();
+                for var_name in &lost {
+                    self.committed_state.variable_states.remove(var_name);
+                    self.committed_state.stored_variable_states.remove(var_name);
+                    // The cell might not have got as far as loading it.
+                    self.committed_state
+                        .variables_to_drop
+                        .push(var_name.clone());
+                }
+                if state.async_mode {
+                    // The abandoned thread may still hold the lock on our tokio runtime.
+                    self.committed_state
+                        .variables_to_drop
+                        .push("evcxr_tokio_runtime".to_owned());
+                }
+                if !lost.is_empty() {
+                    lost.sort_unstable();
+                    let _ = self.stderr_sender.send(format!(
+                        "The cell was interrupted, the following variables have been lost: {}",
+                        lost.join(", ")
+                    ));
+                }
+                return error;
+            }
             Err(Error::CompilationErrors(errors)) => {
                 let mut errors = state.apply_custom_errors(errors, &user_code, code_info);
                 // If we have any errors in user code then remove all errors that aren't from user
@@ -390,9 +980,47 @@ impl EvalContext {
             error @ Err(_) => return error,
             Ok(x) => x,
         };
+        self.render_typed_value(&mut outputs);
+        if let (Some(width), "{:#?}") = (state.display_width(), state.output_format()) {
+            if let Some(text) = outputs.content_by_mime_type.get_mut("text/plain") {
+                *text = crate::display_width::fit_pretty_debug(text, width);
+            }
+        }
 
         // Once, we reach here, our code has successfully executed, so we
         // conclude that variable changes are now applied.
+        state.variables_to_drop.clear();
+        let mut newly_moved: Vec<&String> = state
+            .moved_variables
+            .iter()
+            .filter(|name| {
+                !self.committed_state.moved_variables.contains(*name)
+                    && self.committed_state.variable_states.contains_key(*name)
+            })
+            .collect();
+        newly_moved.sort();
+        for variable_name in newly_moved {
+            let _ = self.stderr_sender.send(format!(
+                "Variable `{}` was moved by this cell, so is no longer available",
+                variable_name
+            ));
+        }
+        for notice in self.committed_state.notices_for_replaced_items(&state) {
+            let _ = self.stderr_sender.send(notice);
+        }
+        for warning in self.committed_state.new_prelude_shadowing_warnings(&state) {
+            let _ = self.stderr_sender.send(warning);
+        }
+        let variable_states = &state.variable_states;
+        state
+            .moved_variables
+            .retain(|name| !variable_states.contains_key(name));
+        for variable_name in state.evict_excess_variables() {
+            let _ = self.stderr_sender.send(format!(
+                "Variable `{}` was dropped to stay within the limit set by :max_vars",
+                variable_name
+            ));
+        }
         self.commit_state(state);
 
         phases.phase_complete("Execution");
@@ -401,6 +1029,12 @@ impl EvalContext {
         Ok(outputs)
     }
 
+    fn send_status(&self, status: EvalStatus) {
+        // Errors sending are ignored, since it just means the user of the library has dropped the
+        // Receiver.
+        let _ = self.status_sender.send(status);
+    }
+
     pub(crate) fn completions(
         &mut self,
         user_code: CodeBlock,
@@ -440,6 +1074,78 @@ impl EvalContext {
         Ok(completions)
     }
 
+    /// Runs `command` using the system shell, sending its stdout and stderr to the same outputs
+    /// as are used for output from user code. Returns an error if the command exits with a
+    /// non-zero status.
+    pub(crate) fn run_shell_command(&self, command: &str, working_dir: &Path) -> Result<(), Error> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        shell.arg(command).current_dir(working_dir);
+        self.stream_command_output(shell, "shell command")
+    }
+
+    /// Runs cargo with `args` in the directory of the crate that we generate, so that e.g. `tree`
+    /// shows the dependencies that have been added. Output is streamed as it's produced.
+    pub(crate) fn run_cargo_command(&self, state: &ContextState, args: &str) -> Result<(), Error> {
+        let args = split_command_args(args)?;
+        if args.is_empty() {
+            bail!(":cargo requires a subcommand to run, e.g. :cargo tree");
+        }
+        if let Some(subcommand) = cargo_subcommand(&args)? {
+            if DISALLOWED_CARGO_SUBCOMMANDS.contains(&subcommand) {
+                bail!(
+                    "`cargo {}` isn't allowed, since it could interfere with the session or \
+                     affect things outside of it",
+                    subcommand
+                );
+            }
+        }
+        // The manifest might have been left in some other state, e.g. by `:dep` validation.
+        self.write_cargo_toml(state)?;
+        let mut command = state.config.cargo_command(&args[0]);
+        command.args(&args[1..]);
+        self.stream_command_output(command, "cargo command")
+    }
+
+    /// Runs `command`, sending each line that it writes to stdout or stderr to our corresponding
+    /// channel as soon as it's written.
+    fn stream_command_output(&self, mut command: Command, description: &str) -> Result<(), Error> {
+        let mut process = command
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| err!("Failed to run {}: {}", description, e))?;
+        let stderr =
+            std::io::BufRead::lines(std::io::BufReader::new(process.stderr.take().unwrap()));
+        let stderr_thread = std::thread::spawn({
+            let stderr_sender = self.stderr_sender.clone();
+            move || {
+                for line in stderr.map_while(Result::ok) {
+                    let _ = stderr_sender.send(line);
+                }
+            }
+        });
+        let stdout =
+            std::io::BufRead::lines(std::io::BufReader::new(process.stdout.take().unwrap()));
+        for line in stdout.map_while(Result::ok) {
+            let _ = self.stdout_sender.send(line);
+        }
+        let _ = stderr_thread.join();
+        let status = process.wait()?;
+        if !status.success() {
+            bail!("The {} failed with {}", description, status);
+        }
+        Ok(())
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
         self.module.last_source()
     }
@@ -467,6 +1173,49 @@ impl EvalContext {
             .map(|(v, t)| (v.as_str(), t.type_name.as_str()))
     }
 
+    /// Returns each persisted variable together with a JSON representation of its value. Values
+    /// are only available for variables whose types have a natural JSON representation (numbers,
+    /// strings, bools and options / vectors of those). Other variables are still returned, but with
+    /// `json_value` set to `None`. Obtaining the values requires a compilation.
+    pub fn variable_values(&mut self) -> Result<Vec<VariableValue>, Error> {
+        let mut variables: Vec<VariableValue> = self
+            .variables_and_types()
+            .map(|(name, type_name)| VariableValue {
+                name: name.to_owned(),
+                type_name: type_name.to_owned(),
+                json_value: None,
+            })
+            .collect();
+        if variables.is_empty() {
+            return Ok(variables);
+        }
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut code = "let _ = { use evcxr_internal_runtime::{JsonFallback as _, JsonViaTrait as _}; evcxr_internal_runtime::send_variable_values(&[".to_owned();
+        for variable in &variables {
+            code.push_str(&format!(
+                "(\"{0}\", (&&evcxr_internal_runtime::JsonProbe(&{0})).evcxr_json()),",
+                variable.name
+            ));
+        }
+        code.push_str("]); };");
+        let cell_count = self.cell_count;
+        let outputs = self.eval(&code);
+        // This evaluation wasn't a cell from the user.
+        self.cell_count = cell_count;
+        let outputs = outputs?;
+        let values = outputs
+            .get(evcxr_internal_runtime::VARIABLE_VALUES_MIME_TYPE)
+            .and_then(|json| json::parse(json).ok())
+            .ok_or_else(|| err!("Failed to get variable values"))?;
+        for variable in &mut variables {
+            let entry = &values[variable.name.as_str()];
+            if entry.has_key("value") {
+                variable.json_value = Some(entry["value"].dump());
+            }
+        }
+        Ok(variables)
+    }
+
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
         self.committed_state
             .items_by_name
@@ -479,26 +1228,253 @@ impl EvalContext {
     // compiled. Config is preserved.
     pub fn clear(&mut self) -> Result<(), Error> {
         self.committed_state = self.cleared_state();
-        self.restart_child_process()
+        if self.committed_state.variable_states.is_empty() {
+            self.restart_child_process()
+        } else {
+            // We have pinned variables, so we need to keep the subprocess, since that's where
+            // their values live. Everything else gets dropped the next time we run code.
+            Ok(())
+        }
     }
 
-    /// Returns the state that would result from clearing. Config is preserved. Nothing is done to
-    /// the subprocess.
+    /// Returns the state that would result from clearing. Config and pinned variables are
+    /// preserved. Nothing is done to the subprocess.
     pub(crate) fn cleared_state(&self) -> ContextState {
-        ContextState::new(self.committed_state.config.clone())
+        let mut state = ContextState::new(self.committed_state.config.clone());
+        state.apply_prelude();
+        let old_state = &self.committed_state;
+        state.variable_states = old_state
+            .variable_states
+            .iter()
+            .filter(|(_, variable_state)| variable_state.pinned)
+            .map(|(name, variable_state)| (name.clone(), variable_state.clone()))
+            .collect();
+        if !state.variable_states.is_empty() {
+            state.stored_variable_states = state.variable_states.clone();
+            state.variables_to_drop = old_state.variables_to_drop.clone();
+            state.variables_to_drop.extend(
+                old_state
+                    .variable_states
+                    .keys()
+                    .filter(|name| !state.variable_states.contains_key(*name))
+                    .cloned(),
+            );
+            // Since we're keeping the same subprocess, we need to make sure we don't reuse the
+            // names of functions that have already been loaded.
+            state.build_num = old_state.build_num;
+        }
+        state
     }
 
     pub fn reset_config(&mut self) {
-        self.committed_state.config = self.initial_config.clone();
+        let mut state = self.committed_state.clone();
+        self.reset_config_of(&mut state);
+        self.committed_state = state;
+    }
+
+    /// Restores all settings in `state` to their initial values. Dependency versions pinned by
+    /// `:lock_from` or `:freeze` are kept, since they're part of how dependencies are specified.
+    pub(crate) fn reset_config_of(&self, state: &mut ContextState) {
+        let locked_versions = std::mem::take(&mut state.config.locked_versions);
+        let frozen_lock = state.config.frozen_lock.take();
+        state.config = self.initial_config.clone();
+        state.config.locked_versions = locked_versions;
+        state.config.frozen_lock = frozen_lock;
+    }
+
+    /// Returns the number of the most recently evaluated cell. Cells are numbered from 1.
+    pub(crate) fn cell_count(&self) -> usize {
+        self.cell_count
+    }
+
+    /// Discards changes made to our state since `snapshot` was taken. Variables are an exception,
+    /// since their values live in the subprocess. Variables that have been defined since the
+    /// snapshot are dropped, but existing variables that have been modified keep their new values
+    /// and existing variables that have since been lost or moved can't be brought back.
+    pub(crate) fn roll_back_to(&mut self, mut snapshot: ContextState) {
+        let current = &self.committed_state;
+        snapshot.variables_to_drop = current.variables_to_drop.clone();
+        for name in current.stored_variable_states.keys() {
+            if !snapshot.stored_variable_states.contains_key(name) {
+                snapshot.variables_to_drop.push(name.clone());
+            }
+        }
+        snapshot.stored_variable_states = current
+            .stored_variable_states
+            .iter()
+            .filter(|(name, _)| snapshot.stored_variable_states.contains_key(*name))
+            .map(|(name, state)| (name.clone(), state.clone()))
+            .collect();
+        snapshot.variable_states = snapshot.stored_variable_states.clone();
+        // The build number is used to name the function that we call, which needs to be unique.
+        snapshot.build_num = current.build_num;
+        self.committed_state = snapshot;
+    }
+
+    /// Logs the complete source of the crate that we're about to compile, including the code that
+    /// restores and stores variables.
+    fn trace_codegen(&self, trace: &CodegenTrace, code: &CodeBlock) -> Result<(), Error> {
+        let entry = format!(
+            "// ===== Generated code for cell {} =====\n{}",
+            self.cell_count,
+            code.code_string()
+        );
+        match trace {
+            CodegenTrace::Stderr => {
+                let _ = self.stderr_sender.send(entry);
+            }
+            CodegenTrace::File(path) => {
+                let write_entry = || {
+                    let mut file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?;
+                    writeln!(file, "{}", entry)
+                };
+                write_entry().map_err(|error| {
+                    err!(
+                        "Failed to write codegen trace to {}: {}",
+                        path.display(),
+                        error
+                    )
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Values created by code from one compiler can't be used by code from a different compiler,
+    /// since type IDs and layouts may differ. So when the toolchain changes, we start a new
+    /// subprocess, which means that variables are lost. Our build artifacts are fingerprinted by
+    /// cargo with the compiler version, so they get rebuilt.
+    fn switch_toolchain(&mut self, state: &mut ContextState) -> Result<(), Error> {
+        if !self.committed_state.variable_states.is_empty() {
+            let _ = self.stderr_sender.send(
+                "Variables were cleared, since they can't be used with a different toolchain"
+                    .to_owned(),
+            );
+        }
+        self.restart_child_process()?;
+        state.variable_states.clear();
+        state.stored_variable_states.clear();
+        state.variables_to_drop.clear();
+        Ok(())
+    }
+
+    /// Discards what's been built for each dependency in `state.deps_to_reload`, so that it gets
+    /// rebuilt from its current source. Git dependencies are first updated to the latest commit on
+    /// their branch. Other dependencies are left alone.
+    fn reload_deps(&mut self, state: &mut ContextState) -> Result<(), Error> {
+        self.write_cargo_toml(state)?;
+        static GIT_RE: OnceCell<Regex> = OnceCell::new();
+        let git_re = GIT_RE.get_or_init(|| Regex::new("(^|[{,]) *git *=").unwrap());
+        for dep in &state.deps_to_reload {
+            let krate = &state.external_deps[dep];
+            let package = crate::cargo_lock::package_name(&krate.name, &krate.config);
+            if git_re.is_match(&krate.config) {
+                self.module.update_dep(&package, &state.config)?;
+            }
+            self.module.clean_dep(&package, &state.config)?;
+        }
+        if state.config.frozen_lock.is_some() {
+            // Keep any newer commit that a git dependency was updated to, rather than going back
+            // to the frozen one.
+            if let Ok(lock_contents) =
+                std::fs::read_to_string(self.module.crate_dir().join("Cargo.lock"))
+            {
+                state.config.frozen_lock = Some(lock_contents);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a handle that can be used to stop execution from another thread. See
+    /// `InterruptHandle`.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.child_process.interrupt_handle()
     }
 
     fn restart_child_process(&mut self) -> Result<(), Error> {
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
+        self.committed_state.variables_to_drop.clear();
         self.child_process = self.child_process.restart()?;
+        self.child_working_dir = None;
+        self.child_deterministic = false;
         Ok(())
     }
 
+    /// Registers a renderer for values of the type `type_name`, which is used when such a value is
+    /// the final expression of a cell, instead of displaying it with the output format (Debug by
+    /// default). The renderer is passed the value formatted with the output format. Type names are
+    /// as returned by `std::any::type_name`, e.g. `alloc::string::String`. Types defined by the
+    /// user can be given without a crate prefix. Types with an `evcxr_display` method are still
+    /// displayed using that.
+    pub fn register_type_renderer(
+        &mut self,
+        type_name: &str,
+        renderer: impl Fn(&str) -> EvalOutputs + Send + Sync + 'static,
+    ) {
+        self.type_renderers
+            .insert(type_name.to_owned(), Arc::new(renderer));
+        self.committed_state.config.send_type_names = true;
+        self.initial_config.send_type_names = true;
+    }
+
+    /// If the final expression was sent together with its type, either passes it to the renderer
+    /// registered for that type or displays it as plain text.
+    fn render_typed_value(&self, outputs: &mut EvalOutputs) {
+        let typed_value = match outputs
+            .content_by_mime_type
+            .remove(evcxr_internal_runtime::TYPED_VALUE_MIME_TYPE)
+        {
+            Some(typed_value) => typed_value,
+            None => return,
+        };
+        let (type_name, text) = typed_value
+            .split_once('\n')
+            .unwrap_or((typed_value.as_str(), ""));
+        let renderer = self.type_renderers.get(type_name).or_else(|| {
+            type_name
+                .strip_prefix(self.committed_state.config.crate_name.as_str())
+                .and_then(|name| name.strip_prefix("::"))
+                .and_then(|name| self.type_renderers.get(name))
+        });
+        match renderer {
+            Some(renderer) => outputs.merge(renderer(text)),
+            None => {
+                outputs
+                    .content_by_mime_type
+                    .insert("text/plain".to_owned(), text.to_owned());
+            }
+        }
+    }
+
+    /// Starts collecting the lines that user code writes to stdout, rather than passing them on,
+    /// until `end_stdout_capture` is called.
+    pub(crate) fn begin_stdout_capture(&mut self) {
+        self.captured_stdout = Some(Vec::new());
+    }
+
+    /// Stops collecting stdout and returns the lines that were collected, with any partial lines
+    /// joined up with the rest of their line.
+    pub(crate) fn end_stdout_capture(&mut self) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut continues_line = false;
+        for line in self.captured_stdout.take().unwrap_or_default() {
+            let (text, partial) = match line.strip_prefix(PARTIAL_LINE_MARKER) {
+                Some(text) => (text, true),
+                None => (line.as_str(), false),
+            };
+            match lines.last_mut() {
+                Some(last) if continues_line => last.push_str(text),
+                _ => lines.push(text.to_owned()),
+            }
+            continues_line = partial;
+        }
+        lines
+    }
+
     pub(crate) fn last_compile_dir(&self) -> &Path {
         self.module.crate_dir()
     }
@@ -507,9 +1483,17 @@ impl EvalContext {
         for variable_state in state.variable_states.values_mut() {
             // This span only makes sense when the variable is first defined.
             variable_state.definition_span = None;
+            // Later cells might not move out of the variable.
+            variable_state.clone_before_move = false;
         }
         state.stored_variable_states = state.variable_states.clone();
-        state.commit_old_user_code();
+        state.commit_old_user_code(self.cell_count);
+        state.forced_code_kind = None;
+        state.autobind_name = None;
+        state.rebuild_requested = false;
+        state.deps_to_reload.clear();
+        state.unretained_variables.clear();
+        state.untouched_variables.clear();
         self.committed_state = state;
     }
 
@@ -521,6 +1505,7 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
         self.write_cargo_toml(state)?;
+        self.send_status(EvalStatus::Compiling);
         self.fix_variable_types(state, state.analysis_code(user_code.clone()))?;
         // In some circumstances we may need a few tries before we get the code right. Note that
         // we'll generally give up sooner than this if there's nothing left that we think we can
@@ -585,6 +1570,7 @@ impl EvalContext {
                         self.committed_state.stored_variable_states.remove(variable);
                     }
                     remaining_retries -= 1;
+                    self.send_status(EvalStatus::Compiling);
                 }
                 Err(error) => return Err(error),
             }
@@ -600,7 +1586,19 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<ExecutionArtifacts, Error> {
         let code = state.code_to_compile(user_code, compilation_mode);
+        if let Some(trace) = &state.config.trace_codegen {
+            self.trace_codegen(trace, &code)?;
+        }
         let so_file = self.module.compile(&code, &state.config)?;
+        if state.config.frozen_lock.is_some() {
+            // Cargo will have kept the versions from the frozen lock file and added entries for
+            // any new dependencies, which we freeze too.
+            if let Ok(lock_contents) =
+                std::fs::read_to_string(self.module.crate_dir().join("Cargo.lock"))
+            {
+                state.config.frozen_lock = Some(lock_contents);
+            }
+        }
 
         if compilation_mode == CompilationMode::NoCatchExpectError {
             // Uh-oh, caller was expecting an error, return OK and the caller can return the
@@ -611,6 +1609,9 @@ impl EvalContext {
         }
         phases.phase_complete("Final compile");
 
+        *self.cell_line_map.lock().unwrap() =
+            CellLineMap::new(&code, self.cell_count, self.module.crate_dir());
+        self.send_status(EvalStatus::Running);
         let output = self.run_and_capture_output(state, &so_file, callbacks)?;
         Ok(ExecutionArtifacts { output })
     }
@@ -620,6 +1621,44 @@ impl EvalContext {
         Ok(())
     }
 
+    /// Returns the dependencies of the generated crate, including transitive dependencies, as an
+    /// indented tree.
+    pub(crate) fn dependency_tree(&self, state: &ContextState) -> Result<String, Error> {
+        // The manifest might have been left in some other state, e.g. by `:dep` validation.
+        self.write_cargo_toml(state)?;
+        Ok(crate::cargo_metadata::get_dependency_tree(&state.config)?)
+    }
+
+    /// Returns the types of `expressions`, as inferred by rust-analyzer in the same way as we
+    /// determine the types of variables. Nothing is compiled or run.
+    pub(crate) fn expression_types(
+        &mut self,
+        state: &ContextState,
+        expressions: &[&str],
+    ) -> Result<Vec<String>, Error> {
+        let mut user_code = CodeBlock::new();
+        for (index, expression) in expressions.iter().enumerate() {
+            user_code = user_code.generated(format!(
+                "let evcxr_expression_{} = ({});",
+                index, expression
+            ));
+        }
+        self.analyzer
+            .set_source(state.analysis_code(user_code).code_string())?;
+        let mut variables = self.analyzer.top_level_variables("evcxr_analysis_wrapper");
+        expressions
+            .iter()
+            .enumerate()
+            .map(|(index, expression)| {
+                variables
+                    .remove(&format!("evcxr_expression_{}", index))
+                    .map(|info| info.type_name)
+                    .filter(|type_name| crate::rust_analyzer::is_type_valid(type_name))
+                    .ok_or_else(|| err!("Failed to determine the type of `{}`", expression))
+            })
+            .collect()
+    }
+
     fn fix_variable_types(
         &mut self,
         state: &mut ContextState,
@@ -647,7 +1686,23 @@ impl EvalContext {
             {
                 continue;
             }
+            // There's no point keeping final expressions that don't produce a value.
+            if state.autobind_name.as_ref() == Some(&variable_name) && type_name == "()" {
+                continue;
+            }
+            // Rust analyzer names the traits of trait objects without a path, which won't compile
+            // unless the trait happens to be in scope. For these, we keep the type that the
+            // variable was declared with, or failing that, we'll get the type from rustc's errors.
+            static TRAIT_OBJECT: OnceCell<Regex> = OnceCell::new();
+            let trait_object =
+                TRAIT_OBJECT.get_or_init(|| Regex::new(r"(?-u:\b)dyn(?-u:\b)").unwrap());
+            if trait_object.is_match(&type_name)
+                && state.variable_states.contains_key(&variable_name)
+            {
+                continue;
+            }
             let preserve_vars_on_panic = state.config.preserve_vars_on_panic;
+            let build_num = state.build_num;
             state
                 .variable_states
                 .entry(variable_name)
@@ -661,6 +1716,10 @@ impl EvalContext {
                     // is copy until we find out it's not.
                     is_copy_type: preserve_vars_on_panic,
                     definition_span: None,
+                    defined_at: build_num,
+                    pinned: false,
+                    referenced: false,
+                    clone_before_move: false,
                 })
                 .type_name = type_name;
         }
@@ -678,16 +1737,75 @@ impl EvalContext {
         // things won't work if the path isn't UTF-8 - apparently that's a thing
         // on some platforms.
         let fn_name = state.current_user_fn_name();
-        self.child_process.send(&format!(
+        let mut run_command = format!(
             "LOAD_AND_RUN {} {}",
             so_file.path.to_string_lossy(),
             fn_name,
-        ))?;
+        );
+        if state.config.profile_memory {
+            run_command.push(' ');
+            run_command.push_str(runtime::PROFILE_MEMORY_FLAG);
+        }
+        if state.config.run_on_worker {
+            run_command.push(' ');
+            run_command.push_str(runtime::RUN_ON_WORKER_FLAG);
+        }
+        self.child_process
+            .set_max_line_length(state.config.max_line_length);
+        self.child_process.set_stderr_mode(state.config.stderr_mode);
+        self.child_process
+            .set_line_buffered(state.config.line_buffered);
+        self.child_process
+            .set_progress_bars(state.config.progress_bars);
+        if state.config.working_dir != self.child_working_dir {
+            self.child_process.send(&format!(
+                "{}{}",
+                runtime::SET_WORKING_DIR,
+                state.working_dir()?.to_string_lossy()
+            ))?;
+            self.child_working_dir = state.config.working_dir.clone();
+        }
+        if state.config.deterministic != self.child_deterministic {
+            for (name, value) in DETERMINISTIC_ENV_VARS {
+                // When turning deterministic mode off, we go back to whatever our subprocess
+                // inherited from us.
+                let value = if state.config.deterministic {
+                    Some((*value).to_owned())
+                } else {
+                    std::env::var(name).ok()
+                };
+                match value {
+                    Some(value) => self.child_process.send(&format!(
+                        "{}{}={}",
+                        runtime::SET_ENV_VAR,
+                        name,
+                        value
+                    ))?,
+                    None => {
+                        self.child_process
+                            .send(&format!("{}{}", runtime::REMOVE_ENV_VAR, name))?
+                    }
+                }
+            }
+            self.child_deterministic = state.config.deterministic;
+        }
+        self.child_process
+            .begin_execution(state.config.run_on_worker);
+        self.child_process.send(&run_command)?;
 
+        // Work out which variables get put back on error before `build_num` moves on.
+        let variables_retained_on_error: Vec<String> = state
+            .variables_retained_on_error()
+            .into_iter()
+            .cloned()
+            .collect();
         state.build_num += 1;
 
         let mut got_panic = false;
         let mut lost_variables = Vec::new();
+        let mut lost_due_to_error = Vec::new();
+        let mut got_user_error = false;
+        let mut kept_on_error = None;
         static MIME_OUTPUT: OnceCell<Regex> = OnceCell::new();
         let mime_output =
             MIME_OUTPUT.get_or_init(|| Regex::new("EVCXR_BEGIN_CONTENT ([^ ]+)").unwrap());
@@ -698,21 +1816,30 @@ impl EvalContext {
             }
             if line == PANIC_NOTIFICATION {
                 got_panic = true;
+            } else if let Some(delta) = line
+                .strip_prefix(runtime::EVCXR_ALLOCATED_BYTES_DELTA)
+                .and_then(|delta| delta.parse::<i64>().ok())
+            {
+                let _ = self
+                    .stderr_sender
+                    .send(format!("Memory: {:+} bytes allocated", delta));
             } else if line.starts_with(evcxr_input::GET_CMD) {
                 let is_password = line.starts_with(evcxr_input::GET_CMD_PASSWORD);
                 let prompt = line.split(':').nth(1).unwrap_or_default();
                 self.child_process
                     .send(&(callbacks.input_reader)(prompt, is_password))?;
             } else if line == evcxr_internal_runtime::USER_ERROR_OCCURRED {
-                // A question mark operator in user code triggered an early
-                // return. Any variables moved into the block in which the code
-                // was running, including any newly defined variables will have
-                // been lost (or possibly never even defined).
-                state
-                    .variable_states
-                    .retain(|_variable_name, variable_state| {
-                        variable_state.move_state != VariableMoveState::MovedIntoCatchUnwind
-                    });
+                got_user_error = true;
+            } else if let Some(names) =
+                line.strip_prefix(evcxr_internal_runtime::VARIABLES_KEPT_ON_ERROR)
+            {
+                kept_on_error = Some(
+                    names
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_owned)
+                        .collect::<HashSet<_>>(),
+                );
             } else if let Some(variable_name) =
                 line.strip_prefix(evcxr_internal_runtime::VARIABLE_CHANGED_TYPE)
             {
@@ -738,10 +1865,54 @@ impl EvalContext {
             } else {
                 // Note, errors sending are ignored, since it just means the
                 // user of the library has dropped the Receiver.
-                // TODO: Clone might not be necessary with NLL.
-                let _ = self.stdout_sender.send(line.clone());
-            }
-        }
+                let line = match state.config.max_line_length {
+                    Some(max) => child_process::truncate_long_line(&line, max).into_owned(),
+                    None => line,
+                };
+                if let Some(captured_stdout) = &mut self.captured_stdout {
+                    captured_stdout.push(line);
+                } else {
+                    let _ = self.stdout_sender.send(line);
+                }
+            }
+        }
+        let mut retained_on_error = Vec::new();
+        if got_user_error || kept_on_error.is_some() {
+            // A question mark operator in user code triggered an early return. Any variables moved
+            // into the block in which the code was running, including any newly defined variables
+            // will have been lost (or possibly never even defined), unless the early return was
+            // from a statement wrapped for `:try` or a let-else that returned, which report which
+            // variables they stored.
+            // Variables from earlier cells that the cell redefined have their previous values put
+            // back.
+            if kept_on_error.is_none() {
+                retained_on_error = variables_retained_on_error;
+            }
+            for variable_name in &retained_on_error {
+                let previous = state.stored_variable_states[variable_name].clone();
+                state
+                    .variable_states
+                    .insert(variable_name.clone(), previous);
+            }
+            state
+                .variable_states
+                .retain(|variable_name, variable_state| {
+                    let kept = match &kept_on_error {
+                        Some(kept_on_error) => {
+                            kept_on_error.contains(variable_name)
+                                || variable_state.move_state == VariableMoveState::Available
+                        }
+                        None => {
+                            variable_state.move_state != VariableMoveState::MovedIntoCatchUnwind
+                                || retained_on_error.contains(variable_name)
+                        }
+                    };
+                    if !kept {
+                        lost_due_to_error.push(variable_name.clone());
+                    }
+                    kept
+                });
+        }
         if got_panic {
             let mut lost = Vec::new();
             state
@@ -765,6 +1936,31 @@ impl EvalContext {
             }
         } else if !lost_variables.is_empty() {
             return Err(Error::TypeRedefinedVariablesLost(lost_variables));
+        } else if !lost_due_to_error.is_empty() {
+            // Only mention variables that existed before this evaluation. Newly defined variables
+            // that never got stored aren't surprising.
+            let mut lost: Vec<String> = lost_due_to_error
+                .into_iter()
+                .filter(|variable_name| state.stored_variable_states.contains_key(variable_name))
+                .collect();
+            if !lost.is_empty() {
+                lost.sort();
+                let _ = self.stderr_sender.send(format!(
+                    "{}, the following variables have been lost: {}",
+                    if got_user_error {
+                        "An error was returned"
+                    } else {
+                        "The cell returned early"
+                    },
+                    lost.join(", ")
+                ));
+            }
+        }
+        if !retained_on_error.is_empty() {
+            let _ = self.stderr_sender.send(format!(
+                "An error was returned, the following variables keep their previous values: {}",
+                retained_on_error.join(", ")
+            ));
         }
         Ok(output)
     }
@@ -781,6 +1977,16 @@ impl EvalContext {
             DISALLOWED_TYPES.get_or_init(|| Regex::new("(impl .*|[.*@])").unwrap());
         for code_origin in &error.code_origins {
             match code_origin {
+                CodeKind::PackVariable { variable_name }
+                    if state.autobind_name.as_ref() == Some(variable_name)
+                        && error.code() != Some("E0308") =>
+                {
+                    // The value of the final expression can't be stored, so we just don't keep
+                    // it. It's still displayed.
+                    state.variable_states.remove(variable_name);
+                    state.autobind_name = None;
+                    fixed_errors.insert("Autobind skipped");
+                }
                 CodeKind::PackVariable { variable_name } => {
                     if error.code() == Some("E0308") {
                         // Handle mismatched types. We might eventually remove this code entirely
@@ -798,6 +2004,14 @@ impl EvalContext {
                             } else if actual_type == "float" {
                                 actual_type = "f64".to_string();
                             }
+                            if disallowed_types.is_match(&actual_type)
+                                && state.autobind_name.as_ref() == Some(variable_name)
+                            {
+                                state.variable_states.remove(variable_name);
+                                state.autobind_name = None;
+                                fixed_errors.insert("Autobind skipped");
+                                continue;
+                            }
                             if disallowed_types.is_match(&actual_type) {
                                 bail!(
                                     "Sorry, the type {} cannot currently be persisted",
@@ -825,8 +2039,18 @@ impl EvalContext {
                             VariableMoveState::MovedIntoCatchUnwind,
                         );
                         if old_move_state == VariableMoveState::MovedIntoCatchUnwind {
-                            // Variable is truly moved, forget about it.
-                            state.variable_states.remove(variable_name);
+                            let variable_state =
+                                state.variable_states.get_mut(variable_name).unwrap();
+                            if state.config.auto_clone && !variable_state.clone_before_move {
+                                // Store a clone of the value from before the move instead. If the
+                                // type isn't Clone, we'll find out when we try.
+                                variable_state.clone_before_move = true;
+                                fixed_errors.insert("Auto clone");
+                            } else {
+                                // Variable is truly moved, forget about it.
+                                state.variable_states.remove(variable_name);
+                                state.moved_variables.insert(variable_name.clone());
+                            }
                         }
                         fixed_errors.insert("Captured value");
                     } else if error.code() == Some("E0425") {
@@ -856,6 +2080,19 @@ impl EvalContext {
                         );
                     }
                 }
+                CodeKind::RetainVariable { variable_name } => {
+                    // The cell moved out of the previous value, so it's lost if there's an error.
+                    if state.unretained_variables.insert(variable_name.clone()) {
+                        fixed_errors.insert("Variable not retained");
+                    }
+                }
+                CodeKind::CloneVariable { variable_name } => {
+                    // The type doesn't implement Clone, so the variable really is moved.
+                    if state.variable_states.remove(variable_name).is_some() {
+                        state.moved_variables.insert(variable_name.clone());
+                        fixed_errors.insert("Variable moved");
+                    }
+                }
                 CodeKind::AssertCopyType { variable_name } => {
                     if error.code() == Some("E0277") {
                         if let Some(variable_state) = state.variable_states.get_mut(variable_name) {
@@ -879,7 +2116,10 @@ impl EvalContext {
                             self.write_cargo_toml(state)?;
                         }
                         fixed_errors.insert("Enabled async mode");
-                    } else if error.code() == Some("E0277") && !state.allow_question_mark {
+                    } else if error.code() == Some("E0277")
+                        && !state.allow_question_mark
+                        && !user_code.has_early_exit()
+                    {
                         state.allow_question_mark = true;
                         fixed_errors.insert("Allow question mark");
                     } else if error.code() == Some("E0658")
@@ -962,6 +2202,16 @@ impl PhaseDetailsBuilder {
     }
 }
 
+/// A persisted variable, as returned by `EvalContext::variable_values`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariableValue {
+    pub name: String,
+    pub type_name: String,
+    /// The JSON representation of the variable's value, or `None` if its type has no JSON
+    /// representation.
+    pub json_value: Option<String>,
+}
+
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
     pub content_by_mime_type: HashMap<String, String>,
@@ -1016,6 +2266,16 @@ struct VariableState {
     // the block will be lost.
     is_copy_type: bool,
     definition_span: Option<UserCodeSpan>,
+    /// The build number at which this variable was defined. Used to determine which variables are
+    /// the oldest.
+    defined_at: i32,
+    /// Whether this variable should be kept by `:clear` and by `:max_vars` eviction.
+    pinned: bool,
+    /// Whether a cell after the one that defined this variable appears to have referenced it.
+    referenced: bool,
+    /// Whether the current cell moves out of this variable, so needs to store a clone of the value
+    /// that it had beforehand. Only set when `:auto_clone` is on.
+    clone_before_move: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -1046,12 +2306,96 @@ enum CompilationMode {
     NoCatchExpectError,
 }
 
+/// Cargo subcommands that `:cargo` refuses to run. These either modify things outside of the
+/// crate that we generate, publish things, or would interfere with the session (e.g. `clean` would
+/// delete all our build artifacts, `add` would edit a manifest that we overwrite and `update`
+/// would change the Cargo.lock that `:freeze` and `:lock_from` rely on).
+const DISALLOWED_CARGO_SUBCOMMANDS: &[&str] = &[
+    "add",
+    "clean",
+    "fix",
+    "generate-lockfile",
+    "init",
+    "install",
+    "login",
+    "logout",
+    "new",
+    "owner",
+    "publish",
+    "remove",
+    "rm",
+    "uninstall",
+    "update",
+    "vendor",
+    "yank",
+];
+
+/// Options that cargo accepts before the subcommand that take a separate value, e.g. the `never` in
+/// `--color never`.
+const CARGO_OPTIONS_WITH_VALUES: &[&str] = &["--color", "--config", "--explain", "-C", "-Z"];
+
+/// Returns the subcommand in arguments to cargo, skipping any options that come before it. Returns
+/// None if the arguments are only options, as for `--version`.
+fn cargo_subcommand(args: &[String]) -> Result<Option<&str>, Error> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg.starts_with('+') {
+            bail!("`:cargo` can't override the toolchain. Use `:toolchain` instead");
+        } else if CARGO_OPTIONS_WITH_VALUES.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with('-') {
+            return Ok(Some(arg));
+        }
+    }
+    Ok(None)
+}
+
+/// Splits the arguments to a command at whitespace, as a shell would. Arguments containing
+/// whitespace can be quoted with single or double quotes, and a backslash outside of single quotes
+/// escapes the character that follows it.
+fn split_command_args(args: &str) -> Result<Vec<String>, Error> {
+    let mut result = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| err!("Trailing backslash in `{}`", args))?;
+                current.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                current.get_or_insert_with(String::new);
+                quote = Some(c);
+            }
+            (None, c) if c.is_whitespace() => result.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unterminated quote in `{}`", args);
+    }
+    result.extend(current);
+    Ok(result)
+}
+
 /// State that is cloned then modified every time we try to compile some code. If compilation
 /// succeeds, we keep the modified state, if it fails, we revert to the old state.
 #[derive(Clone, Debug)]
 pub struct ContextState {
+    /// Items keyed by name. Items defined while a `:module` was open are keyed by
+    /// `module::name`.
     items_by_name: HashMap<String, CodeBlock>,
     unnamed_items: Vec<CodeBlock>,
+    /// Unnamed items that were defined while a `:module` was open, keyed by module name.
+    module_unnamed_items: HashMap<String, Vec<CodeBlock>>,
+    /// Impl blocks that weren't defined together with a named item, in the order they were
+    /// defined.
+    impls: Vec<(ImplKey, CodeBlock)>,
     pub(crate) external_deps: HashMap<String, ExternalCrate>,
     // Keyed by crate name. Could use a set, except that the statement might be
     // formatted slightly differently.
@@ -1063,6 +2407,38 @@ pub struct ContextState {
     /// code was executed. Doesn't include newly defined variables until after
     /// execution completes.
     stored_variable_states: HashMap<String, VariableState>,
+    /// Variables that have been evicted, but which are still present in the variable store. They'll
+    /// be removed from the store the next time we run code.
+    variables_to_drop: Vec<String>,
+    /// Variables that a cell moved out of, so which are no longer stored. Used to explain errors
+    /// when later cells refer to them.
+    moved_variables: HashSet<String>,
+    /// Variables from earlier cells that the current cell redefines after moving out of their
+    /// previous values, so which can't keep their previous values if the cell returns an error.
+    /// Cleared once the cell has been evaluated.
+    unretained_variables: HashSet<String>,
+    /// Variables from earlier cells that the current cell doesn't appear to reference. They're left
+    /// in the variable store while the cell runs, rather than being loaded and stored again, so
+    /// they survive if the cell is interrupted. Cleared once the cell has been evaluated.
+    untouched_variables: HashSet<String>,
+    /// Set while a `:deps begin` transaction is open, in which case it holds the dependencies as
+    /// they were when the transaction began. Changes to dependencies don't trigger a build until
+    /// the transaction ends.
+    deps_before_transaction: Option<HashMap<String, ExternalCrate>>,
+    /// Overrides how the code in the current cell is classified, as set by `:stmt` or `:item`.
+    /// Cleared once the cell has been evaluated.
+    forced_code_kind: Option<ForcedCodeKind>,
+    /// The name of the variable to which the final expression of the current cell gets bound. Set
+    /// when `:autobind` is on, then cleared if the value turns out not to be something we can
+    /// store.
+    autobind_name: Option<String>,
+    /// The module opened by `:module`, if any. Items defined while it's set are placed in that
+    /// module.
+    current_module: Option<String>,
+    /// Set by `:rebuild`. Cleared once the cell has been evaluated.
+    rebuild_requested: bool,
+    /// Names of dependencies given to `:reload_dep`. Cleared once the cell has been evaluated.
+    deps_to_reload: Vec<String>,
     attributes: HashMap<String, CodeBlock>,
     async_mode: bool,
     allow_question_mark: bool,
@@ -1075,10 +2451,22 @@ impl ContextState {
         ContextState {
             items_by_name: HashMap::new(),
             unnamed_items: vec![],
+            module_unnamed_items: HashMap::new(),
+            impls: vec![],
             external_deps: HashMap::new(),
             extern_crate_stmts: HashMap::new(),
             variable_states: HashMap::new(),
             stored_variable_states: HashMap::new(),
+            variables_to_drop: Vec::new(),
+            moved_variables: HashSet::new(),
+            unretained_variables: HashSet::new(),
+            untouched_variables: HashSet::new(),
+            deps_before_transaction: None,
+            forced_code_kind: None,
+            autobind_name: None,
+            current_module: None,
+            rebuild_requested: false,
+            deps_to_reload: Vec::new(),
             attributes: HashMap::new(),
             async_mode: false,
             allow_question_mark: false,
@@ -1099,6 +2487,15 @@ impl ContextState {
         self.config.offline_mode = value;
     }
 
+    pub fn deterministic(&self) -> bool {
+        self.config.deterministic
+    }
+
+    /// Sets whether code is run with `DETERMINISTIC_ENV_VARS` set.
+    pub fn set_deterministic(&mut self, value: bool) {
+        self.config.deterministic = value;
+    }
+
     pub fn set_sccache(&mut self, enabled: bool) -> Result<(), Error> {
         self.config.set_sccache(enabled)
     }
@@ -1179,226 +2576,1481 @@ impl ContextState {
         self.config.output_format = output_format;
     }
 
-    pub fn set_toolchain(&mut self, value: &str) {
-        self.config.toolchain = value.to_owned();
+    /// Sets the toolchain to use for compilation, e.g. `nightly`, checking that it's installed.
+    /// An empty string selects the default toolchain.
+    pub fn set_toolchain(&mut self, value: &str) -> Result<(), Error> {
+        let previous = std::mem::replace(&mut self.config.toolchain, value.trim().to_owned());
+        if let Err(error) = self.config.rustc_version() {
+            let toolchain = std::mem::replace(&mut self.config.toolchain, previous);
+            bail!(
+                "Couldn't use toolchain `{}`. Try `rustup toolchain install {}`.\n{}",
+                toolchain,
+                toolchain,
+                error
+            );
+        }
+        if let Err(error) = self.config.check_min_rust_version() {
+            self.config.toolchain = previous;
+            return Err(error);
+        }
+        Ok(())
     }
 
-    pub fn toolchain(&mut self) -> &str {
-        &self.config.toolchain
+    pub fn min_rust_version(&self) -> Option<&str> {
+        self.config.min_rust_version.as_deref()
     }
 
-    /// Adds a crate dependency with the specified name and configuration.
-    pub fn add_dep(&mut self, dep: &str, dep_config: &str) -> Result<(), Error> {
-        // Avoid repeating dep validation once we're already added it.
-        if let Some(existing) = self.external_deps.get(dep) {
-            if existing.config == dep_config {
-                return Ok(());
+    /// Sets the oldest version of rustc that may be used, e.g. `1.70`, failing if the compiler
+    /// currently in use is older. While set, switching to an older toolchain also fails. `None`
+    /// removes the requirement.
+    pub fn set_min_rust_version(&mut self, version: Option<&str>) -> Result<(), Error> {
+        if let Some(version) = version {
+            if parse_rust_version(version).is_none() {
+                bail!(
+                    "Invalid Rust version `{}`. Expected something like 1.70 or 1.70.0",
+                    version
+                );
             }
         }
-        let external = ExternalCrate::new(dep.to_owned(), dep_config.to_owned())?;
-        crate::cargo_metadata::validate_dep(&external.name, &external.config, &self.config)?;
-        self.external_deps.insert(dep.to_owned(), external);
+        let previous = std::mem::replace(
+            &mut self.config.min_rust_version,
+            version.map(str::to_owned),
+        );
+        if let Err(error) = self.config.check_min_rust_version() {
+            self.config.min_rust_version = previous;
+            return Err(error);
+        }
         Ok(())
     }
 
-    /// Clears fields that aren't useful for inclusion in bug reports and which might give away
-    /// things like usernames.
-    pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
-        self.config.crate_dir = PathBuf::from("redacted");
-        if self.config.sccache.is_some() {
-            self.config.sccache = Some(PathBuf::from("redacted"));
+    pub fn rustc_version(&self) -> Result<String, Error> {
+        self.config.rustc_version()
+    }
+
+    pub fn toolchain(&mut self) -> &str {
+        &self.config.toolchain
+    }
+
+    pub fn crate_name(&self) -> &str {
+        &self.config.crate_name
+    }
+
+    /// Sets the package name of the generated crate, which may need changing if it clashes with
+    /// the name of a module or dependency.
+    pub fn set_crate_name(&mut self, name: &str) -> Result<(), Error> {
+        static IDENT_RE: OnceCell<Regex> = OnceCell::new();
+        let ident_re = IDENT_RE.get_or_init(|| Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap());
+        if !ident_re.is_match(name) || name == "_" {
+            bail!(
+                "Crate names must be made up of letters, numbers and underscores, got `{}`",
+                name
+            );
         }
+        if SYSROOT_CRATES.contains(&name) || self.external_deps.contains_key(name) {
+            bail!("The crate name `{}` is already used by a dependency", name);
+        }
+        self.config.crate_name = name.to_owned();
+        Ok(())
     }
 
-    fn apply_custom_errors(
-        &self,
-        errors: Vec<CompilationError>,
-        user_code: &CodeBlock,
-        code_info: &UserCodeInfo,
-    ) -> Vec<CompilationError> {
-        errors
-            .into_iter()
-            .filter_map(|error| self.customize_error(error, user_code))
-            .map(|mut error| {
-                error.fill_lines(code_info);
-                error
+    /// Marks a variable as pinned, so that it's kept by `:clear`.
+    pub fn pin_variable(&mut self, name: &str) -> Result<(), Error> {
+        let variable_state = self
+            .variable_states
+            .get(name)
+            .ok_or_else(|| err!("No variable named `{}`", name))?;
+        // Clearing removes all items and dependencies, so if the variable's type refers to any of
+        // those, we wouldn't be able to reference it after clearing.
+        static IDENT_RE: OnceCell<Regex> = OnceCell::new();
+        let ident_re = IDENT_RE.get_or_init(|| Regex::new("[A-Za-z_][A-Za-z0-9_]*").unwrap());
+        if let Some(ident) = ident_re
+            .find_iter(&variable_state.type_name)
+            .map(|m| m.as_str())
+            .find(|ident| {
+                self.items_by_name.contains_key(*ident)
+                    || self.module_names().any(|module| module == *ident)
+                    || self.external_deps.contains_key(*ident)
+                    || self.extern_crate_stmts.contains_key(*ident)
             })
-            .collect()
+        {
+            bail!(
+                "Can't pin `{}`, since its type `{}` refers to `{}`, which would be removed by :clear",
+                name,
+                variable_state.type_name,
+                ident
+            );
+        }
+        if let Some(variable_state) = self.variable_states.get_mut(name) {
+            variable_state.pinned = true;
+        }
+        Ok(())
     }
 
-    /// Customizes errors based on their origins.
-    fn customize_error(
-        &self,
-        error: CompilationError,
-        user_code: &CodeBlock,
-    ) -> Option<CompilationError> {
-        for origin in &error.code_origins {
-            if let CodeKind::PackVariable { variable_name } = origin {
-                if let Some(definition_span) = &self.variable_states[variable_name].definition_span
-                {
-                    if let Some(segment) =
-                        user_code.segment_with_index(definition_span.segment_index)
-                    {
-                        if let Some(span) = Span::from_segment(segment, definition_span.range) {
-                            return self.replacement_for_pack_variable_error(
-                                variable_name,
-                                span,
-                                segment,
-                                &error,
-                            );
-                        }
-                    }
-                }
-            }
+    /// Removes all variables whose type contains `type_name`. Returns the names of the removed
+    /// variables. Their values are dropped the next time we run code.
+    pub fn remove_variables_of_type(&mut self, type_name: &str) -> Vec<String> {
+        let mut removed: Vec<String> = self
+            .variable_states
+            .iter()
+            .filter(|(_, state)| state.type_name.contains(type_name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        removed.sort();
+        for name in &removed {
+            self.variable_states.remove(name);
+            self.variables_to_drop.push(name.clone());
         }
-        Some(error)
+        removed
     }
 
-    fn replacement_for_pack_variable_error(
-        &self,
-        variable_name: &str,
-        variable_span: Span,
-        segment: &Segment,
-        error: &CompilationError,
-    ) -> Option<CompilationError> {
-        let message = match error.code().unwrap_or("") {
-            "E0382" | "E0505" => {
-                // Value used after move. When we go to execute the code, we'll detect this error and
-                // remove the variable so it doesn't get stored.
-                return None;
-            }
-            "E0597" => {
-                format!(
-                    "The variable `{}` contains a reference with a non-static lifetime so can't be persisted",
-                    variable_name
-                )
-            }
-            _ => {
-                return Some(error.clone());
-            }
-        };
-        Some(CompilationError::from_segment_span(
-            segment,
-            SpannedMessage::from_segment_span(segment, variable_span),
-            message,
-        ))
+    pub fn unpin_variable(&mut self, name: &str) -> Result<(), Error> {
+        self.variable_states
+            .get_mut(name)
+            .ok_or_else(|| err!("No variable named `{}`", name))?
+            .pinned = false;
+        Ok(())
     }
 
-    /// Returns whether transitioning to `new_state` might cause compilation
-    /// failures. e.g. if `new_state` has extra dependencies, then we must
-    /// return true. If we return false, we're saying that the proposed state
-    /// change cannot cause compilation failures, so compilation can be skipped
-    /// if there is otherwise no code to execute.
-    fn state_change_can_fail_compilation(&self, new_state: &ContextState) -> bool {
-        (self.extern_crate_stmts != new_state.extern_crate_stmts
-            && !new_state.extern_crate_stmts.is_empty())
-            || (self.external_deps != new_state.external_deps
-                && !new_state.external_deps.is_empty())
-            || (self.items_by_name != new_state.items_by_name
-                && !new_state.items_by_name.is_empty())
-            || (self.config.sccache != new_state.config.sccache)
+    pub fn jobs(&self) -> Option<usize> {
+        self.config.jobs
     }
 
-    pub(crate) fn format_cargo_deps(&self) -> String {
-        self.external_deps
-            .values()
-            .map(|krate| format!("{} = {}\n", krate.name, krate.config))
-            .collect::<Vec<_>>()
-            .join("")
+    /// Sets the number of parallel jobs for cargo to use. `None` means cargo's default.
+    pub fn set_jobs(&mut self, jobs: Option<usize>) -> Result<(), Error> {
+        if jobs == Some(0) {
+            bail!("The number of jobs must be positive");
+        }
+        self.config.jobs = jobs;
+        Ok(())
     }
 
-    fn compilation_mode(&self) -> CompilationMode {
-        if self.config.preserve_vars_on_panic {
-            CompilationMode::RunAndCatchPanics
-        } else {
-            CompilationMode::NoCatch
-        }
+    pub fn keep_generations(&self) -> usize {
+        self.config.keep_generations
     }
 
-    /// Returns code suitable for analysis purposes. Doesn't attempt to preserve runtime behavior.
-    fn analysis_code(&self, user_code: CodeBlock) -> CodeBlock {
-        let mut code = CodeBlock::new()
-            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
-            .add_all(self.attributes_code())
-            .add_all(self.items_code())
-            .add_all(self.error_trait_code(true))
-            .generated("fn evcxr_variable_store<T: 'static>(_: T) {}")
-            .generated("#[allow(unused_variables)]")
-            .generated("async fn evcxr_analysis_wrapper(");
-        for (var_name, state) in &self.stored_variable_states {
-            code = code.generated(format!(
-                "{}{}: {},",
-                if state.is_mut { "mut " } else { "" },
-                var_name,
-                state.type_name
-            ));
+    /// Sets how many of the most recently built shared objects to keep on disk. Older ones are
+    /// deleted after each build.
+    pub fn set_keep_generations(&mut self, keep: usize) -> Result<(), Error> {
+        if keep == 0 {
+            bail!("The number of generations to keep must be positive");
         }
-        code = code
-            .generated(") -> Result<(), EvcxrUserCodeError> {")
-            .add_all(user_code);
+        self.config.keep_generations = keep;
+        Ok(())
+    }
 
-        // Pack variable statements in analysis mode are a lot simpler than in compiled mode. We
-        // just call a function that enforces that the variable doesn't contain any non-static
-        // lifetimes.
-        for var_name in self.variable_states.keys() {
-            code.pack_variable(
-                var_name.clone(),
-                format!("evcxr_variable_store({});", var_name),
-            );
+    pub fn parallel_frontend(&self) -> Option<usize> {
+        self.config.parallel_frontend
+    }
+
+    /// Sets the number of threads for rustc's parallel frontend. `None` disables it.
+    pub fn set_parallel_frontend(&mut self, threads: Option<usize>) -> Result<(), Error> {
+        if threads.is_some() && !self.config.uses_nightly() {
+            bail!("parallel_frontend requires a nightly toolchain. Try `:toolchain nightly` first");
         }
+        if threads == Some(0) {
+            bail!("The number of threads must be positive");
+        }
+        self.config.parallel_frontend = threads;
+        Ok(())
+    }
 
-        code = code.generated("Ok(())").generated("}");
-        code
+    pub(crate) fn trace_codegen(&self) -> Option<&CodegenTrace> {
+        self.config.trace_codegen.as_ref()
     }
 
-    fn code_to_compile(
-        &self,
-        user_code: CodeBlock,
-        compilation_mode: CompilationMode,
-    ) -> CodeBlock {
-        let mut code = CodeBlock::new()
-            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
-            .add_all(self.attributes_code())
-            .add_all(self.items_code());
-        let has_user_code = !user_code.is_empty();
-        if has_user_code {
-            code = code.add_all(self.wrap_user_code(user_code, compilation_mode));
-        } else {
-            // TODO: Add a mechanism to load a crate without any function to call then remove this.
-            code = code
-                .generated("#[no_mangle]")
-                .generated(format!(
-                    "pub extern \"C\" fn {}(",
-                    self.current_user_fn_name()
-                ))
-                .generated("mut x: *mut std::os::raw::c_void) -> *mut std::os::raw::c_void {x}");
-        }
-        code
+    pub(crate) fn set_trace_codegen(&mut self, trace: Option<CodegenTrace>) {
+        self.config.trace_codegen = trace;
     }
 
-    fn items_code(&self) -> CodeBlock {
-        let mut code = CodeBlock::new().add_all(self.get_imports());
-        for item in self.items_by_name.values().chain(self.unnamed_items.iter()) {
-            code = code.add_all(item.clone());
+    /// Returns the directory that relative paths are resolved against. This applies to path
+    /// dependencies, `mod name;`, shell commands and the code that we run.
+    pub fn working_dir(&self) -> Result<PathBuf, Error> {
+        match &self.config.working_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(std::env::current_dir()?),
         }
-        code
     }
 
-    fn attributes_code(&self) -> CodeBlock {
-        let mut code = CodeBlock::new();
-        for attrib in self.attributes.values() {
-            code = code.add_all(attrib.clone());
+    /// Returns the path supplied as an argument to a command, resolved against the working
+    /// directory if it's relative.
+    pub fn resolve_path(&self, path: &str) -> Result<PathBuf, Error> {
+        Ok(self.working_dir()?.join(paths::path_from_arg(path)))
+    }
+
+    /// Makes relative paths passed to `include_str!` and similar macros in `segment` relative to
+    /// the working directory. Otherwise they'd be relative to the source file of the crate that we
+    /// generate.
+    fn resolve_include_paths(
+        &self,
+        mut segment: Segment,
+        node: &SyntaxNode,
+    ) -> Result<Segment, Error> {
+        let include_paths = relative_include_paths(node);
+        if include_paths.is_empty() {
+            return Ok(segment);
         }
-        code
+        let working_dir = self.working_dir()?;
+        let original_code = segment.code.clone();
+        let mut rewrites = Vec::new();
+        // Replace from the end, so that earlier ranges remain valid.
+        for (token, path) in include_paths.into_iter().rev() {
+            let range =
+                std::ops::Range::<usize>::from(token.text_range() - node.text_range().start());
+            let absolute = format!("{:?}", working_dir.join(path).to_string_lossy());
+            let before = &original_code[..range.end];
+            let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+            rewrites.push(PathRewrite {
+                line_offset: before.matches('\n').count(),
+                original_end_column: count_columns(&before[line_start..]),
+                original_end_byte: range.end,
+                extra_columns: count_columns(&absolute) as isize
+                    - count_columns(&original_code[range.clone()]) as isize,
+                extra_bytes: absolute.len() as isize - range.len() as isize,
+            });
+            segment.code.replace_range(range, &absolute);
+        }
+        rewrites.reverse();
+        if let CodeKind::OriginalUserCode(meta) = &mut segment.kind {
+            meta.path_rewrites = rewrites;
+        }
+        Ok(segment)
     }
 
-    fn error_trait_code(&self, for_analysis: bool) -> CodeBlock {
+    /// Returns the range of the first `unsafe` code in `node`, together with a message to report.
+    /// Files that `node` loads via `mod name;` or `include!` are checked too, in which case the
+    /// range is that of the `mod` item or macro call.
+    fn find_unsafe_code(&self, node: &SyntaxNode) -> Option<(TextRange, String)> {
+        if let Some(unsafe_token) = find_unsafe_keyword(node) {
+            return Some((
+                unsafe_token.text_range(),
+                "`unsafe` code is disabled. Use `:allow_unsafe on` to enable it.".to_owned(),
+            ));
+        }
+        let working_dir = self.working_dir().ok()?;
+        for loader in node.descendants() {
+            let contents = if let Some(module) = ast::Module::cast(loader.clone()) {
+                if module.item_list().is_some() {
+                    continue;
+                }
+                ast::HasName::name(&module)
+                    .and_then(|name| read_module_file(&name.text(), &working_dir).ok())
+            } else if let Some(macro_call) = ast::MacroCall::cast(loader.clone()) {
+                match include_path(&macro_call) {
+                    Some((name, _, path)) if name == "include" => {
+                        std::fs::read_to_string(working_dir.join(path)).ok()
+                    }
+                    _ => continue,
+                }
+            } else {
+                continue;
+            };
+            if let Some(contents) = contents {
+                if find_unsafe_keyword(ast::SourceFile::parse(&contents).tree().syntax()).is_some()
+                {
+                    return Some((
+                        loader.text_range(),
+                        "`unsafe` code is disabled, but the file loaded here contains some. Use \
+                         `:allow_unsafe on` to enable it."
+                            .to_owned(),
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Changes the working directory. `dir` may be relative to the current working directory.
+    pub fn set_working_dir(&mut self, dir: &str) -> Result<(), Error> {
+        let dir = self.resolve_path(dir)?;
+        let dir = paths::canonicalize(&dir)
+            .map_err(|error| err!("Can't change directory to {}: {}", dir.display(), error))?;
+        if !dir.is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+        self.config.working_dir = Some(dir);
+        Ok(())
+    }
+
+    pub fn stderr_mode(&self) -> &'static str {
+        self.config.stderr_mode.name()
+    }
+
+    /// Sets where stderr from the execution process goes: `separate`, `merge` or `discard`.
+    pub fn set_stderr_mode(&mut self, mode: &str) -> Result<(), Error> {
+        self.config.stderr_mode = StderrMode::parse(mode)?;
+        Ok(())
+    }
+
+    pub fn prelude_shadowing_warnings(&self) -> bool {
+        self.config.prelude_shadowing_warnings
+    }
+
+    pub fn set_prelude_shadowing_warnings(&mut self, enabled: bool) {
+        self.config.prelude_shadowing_warnings = enabled;
+    }
+
+    pub fn replaced_item_notices(&self) -> &'static str {
+        match self.config.replaced_item_notices {
+            ReplacedItemNotices::Off => "off",
+            ReplacedItemNotices::On => "on",
+            ReplacedItemNotices::Verbose => "verbose",
+        }
+    }
+
+    pub fn set_replaced_item_notices(&mut self, value: &str) -> Result<(), Error> {
+        self.config.replaced_item_notices = match value {
+            "off" => ReplacedItemNotices::Off,
+            "on" => ReplacedItemNotices::On,
+            "verbose" => ReplacedItemNotices::Verbose,
+            other => bail!("Expected `on`, `off` or `verbose`, got `{}`", other),
+        };
+        Ok(())
+    }
+
+    pub fn dylibs(&self) -> &[PathBuf] {
+        &self.config.dylibs
+    }
+
+    /// Links subsequent cells against the dynamic library at `path`, which may be relative to the
+    /// working directory. Returns the name of the library.
+    pub fn add_dylib(&mut self, path: &str) -> Result<String, Error> {
+        let path = self.resolve_path(path)?;
+        let path = paths::canonicalize(&path)
+            .map_err(|error| err!("Can't load {}: {}", path.display(), error))?;
+        let name = dylib_name(&path).ok_or_else(|| {
+            err!(
+                "Expected a file named like {}name{}, got {}",
+                std::env::consts::DLL_PREFIX,
+                std::env::consts::DLL_SUFFIX,
+                path.display()
+            )
+        })?;
+        if !self.config.dylibs.contains(&path) {
+            self.config.dylibs.push(path);
+        }
+        Ok(name)
+    }
+
+    /// Declares functions provided by a library added with `:dylib`, so that they can be called.
+    /// `signatures` is one or more function signatures separated by semicolons, e.g.
+    /// `fn add(a: i32, b: i32) -> i32`. Each is declared in its own `extern "C"` block, so that
+    /// declaring a function again replaces the earlier declaration. Returns the function names.
+    pub fn declare_extern_functions(&mut self, signatures: &str) -> Result<Vec<String>, Error> {
+        let declarations = parse_extern_functions(signatures)?;
+        let mut names = Vec::new();
+        for (name, declaration) in declarations {
+            self.items_by_name.insert(
+                self.item_key(&name),
+                CodeBlock::new().other_user_code(format!("extern \"C\" {{\n{};\n}}", declaration)),
+            );
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    pub fn auto_await(&self) -> bool {
+        self.config.auto_await
+    }
+
+    pub fn set_auto_await(&mut self, auto_await: bool) {
+        self.config.auto_await = auto_await;
+    }
+
+    pub fn allow_unsafe(&self) -> bool {
+        self.config.allow_unsafe
+    }
+
+    pub fn set_allow_unsafe(&mut self, allow_unsafe: bool) {
+        self.config.allow_unsafe = allow_unsafe;
+    }
+
+    /// Returns the items that have been defined, excluding imports, in the order in which they were
+    /// defined.
+    pub(crate) fn defined_items(&self) -> Vec<DefinedItem> {
+        use ra_ap_syntax::ast::HasModuleItem;
+        let mut items = Vec::new();
+        let blocks = self
+            .items_by_name
+            .values()
+            .chain(self.impls.iter().map(|(_, block)| block))
+            .chain(self.unnamed_items.iter())
+            .chain(self.module_unnamed_items.values().flatten());
+        for segment in blocks.flat_map(|block| block.segments.iter()) {
+            for item in ast::SourceFile::parse(&segment.code).tree().items() {
+                let name = match &item {
+                    ast::Item::Use(_) => continue,
+                    ast::Item::Impl(_) => item_signature(&item.syntax().text().to_string())
+                        .trim_start_matches("impl")
+                        .trim_start()
+                        .to_owned(),
+                    _ => item::item_name(&item).unwrap_or_default(),
+                };
+                items.push(DefinedItem {
+                    kind: item::item_kind(&item),
+                    name,
+                    location: segment.earlier_cell(),
+                });
+            }
+        }
+        items.sort_by_key(|item| {
+            (
+                item.location.is_none(),
+                item.location.map(|location| (location.cell, location.line)),
+                item.name.clone(),
+            )
+        });
+        items
+    }
+
+    pub fn autobind(&self) -> bool {
+        self.config.autobind
+    }
+
+    pub fn set_autobind(&mut self, autobind: bool) {
+        self.config.autobind = autobind;
+    }
+
+    pub fn auto_clone(&self) -> bool {
+        self.config.auto_clone
+    }
+
+    pub fn set_auto_clone(&mut self, auto_clone: bool) {
+        self.config.auto_clone = auto_clone;
+    }
+
+    pub fn allow_unused_imports(&self) -> bool {
+        self.config.allow_unused_imports
+    }
+
+    pub fn set_allow_unused_imports(&mut self, allow_unused_imports: bool) {
+        self.config.allow_unused_imports = allow_unused_imports;
+    }
+
+    pub fn features(&self) -> &[String] {
+        &self.config.features
+    }
+
+    /// Sets the features to enable when building, from a comma or space separated list. An empty
+    /// list disables all features.
+    pub fn set_features(&mut self, features: &str) -> Result<(), Error> {
+        let features: Vec<String> = features
+            .split(|c: char| c == ',' || c == ' ')
+            .filter(|feature| !feature.is_empty())
+            .map(str::to_owned)
+            .collect();
+        for feature in &features {
+            if !feature
+                .chars()
+                .all(|c| c.is_alphanumeric() || "_-+./".contains(c))
+            {
+                bail!("Invalid feature name `{}`", feature);
+            }
+        }
+        self.config.features = features;
+        Ok(())
+    }
+
+    pub fn max_line_length(&self) -> Option<usize> {
+        self.config.max_line_length
+    }
+
+    pub fn set_max_line_length(&mut self, max_line_length: Option<usize>) -> Result<(), Error> {
+        if max_line_length == Some(0) {
+            bail!("The maximum line length must be positive");
+        }
+        self.config.max_line_length = max_line_length;
+        Ok(())
+    }
+
+    pub fn line_buffered(&self) -> bool {
+        self.config.line_buffered
+    }
+
+    pub fn set_line_buffered(&mut self, line_buffered: bool) {
+        self.config.line_buffered = line_buffered;
+    }
+
+    pub fn progress_bars(&self) -> bool {
+        self.config.progress_bars
+    }
+
+    /// Sets whether output is passed on as soon as it's written, with output that follows a
+    /// carriage return prefixed by `\r` to indicate that it replaces the previous such output.
+    pub fn set_progress_bars(&mut self, progress_bars: bool) {
+        self.config.progress_bars = progress_bars;
+    }
+
+    pub fn run_on_worker(&self) -> bool {
+        self.config.run_on_worker
+    }
+
+    /// Sets whether user code runs on a worker thread, rather than on the main thread of the
+    /// execution process. The same worker thread is used for every evaluation.
+    pub fn set_run_on_worker(&mut self, run_on_worker: bool) {
+        self.config.run_on_worker = run_on_worker;
+    }
+
+    pub fn try_mode(&self) -> bool {
+        self.config.try_mode
+    }
+
+    pub fn set_try_mode(&mut self, try_mode: bool) {
+        self.config.try_mode = try_mode;
+    }
+
+    pub fn retain_on_error(&self) -> bool {
+        self.config.retain_on_error
+    }
+
+    /// Sets whether variables from earlier cells that a cell redefines keep their previous values
+    /// if the cell returns an error via `?`. Costs an extra compilation when a cell moves out of
+    /// a variable before redefining it.
+    pub fn set_retain_on_error(&mut self, retain_on_error: bool) {
+        self.config.retain_on_error = retain_on_error;
+    }
+
+    pub fn float_precision(&self) -> Option<usize> {
+        self.config.float_precision
+    }
+
+    pub fn set_float_precision(&mut self, float_precision: Option<usize>) {
+        self.config.float_precision = float_precision;
+    }
+
+    /// Returns the number of columns that width-aware formatting of output should fit within, if
+    /// one has been set. Otherwise output is left as it was formatted.
+    pub fn display_width(&self) -> Option<usize> {
+        self.config.display_width
+    }
+
+    /// Sets the display width. `None` restores the default of not reflowing output.
+    pub fn set_display_width(&mut self, display_width: Option<usize>) -> Result<(), Error> {
+        if display_width == Some(0) {
+            bail!("Display width must be at least 1");
+        }
+        self.config.display_width = display_width;
+        Ok(())
+    }
+
+    /// Returns the use statements that make up the prelude.
+    pub fn prelude(&self) -> &[String] {
+        &self.config.prelude
+    }
+
+    /// Adds an import (e.g. `std::collections::HashMap` or `use std::fmt::Write as _;`) to the
+    /// prelude and applies it.
+    pub fn add_prelude_import(&mut self, import: &str) -> Result<(), Error> {
+        let statement = normalize_use_statement(import);
+        let imports = parse_use_statement(&statement)?;
+        if !self.config.prelude.contains(&statement) {
+            self.config.prelude.push(statement);
+        }
+        for import in imports {
+            self.register_import(import);
+        }
+        Ok(())
+    }
+
+    /// Removes an import from the prelude. Names that it imported are removed, unless they've
+    /// since been replaced by something else.
+    pub fn remove_prelude_import(&mut self, import: &str) -> Result<(), Error> {
+        let statement = normalize_use_statement(import);
+        let index = self
+            .config
+            .prelude
+            .iter()
+            .position(|existing| *existing == statement)
+            .ok_or_else(|| err!("`{}` isn't in the prelude", statement))?;
+        self.config.prelude.remove(index);
+        for import in parse_use_statement(&statement)? {
+            match import {
+                Import::Unnamed(code) => {
+                    let block = CodeBlock::new().other_user_code(code);
+                    self.unnamed_items.retain(|item| *item != block);
+                }
+                Import::Named { name, code } => {
+                    if self.items_by_name.get(&name)
+                        == Some(&CodeBlock::new().other_user_code(code))
+                    {
+                        self.items_by_name.remove(&name);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn logging(&self) -> bool {
+        self.config.logging
+    }
+
+    pub fn set_logging(&mut self, enabled: bool) {
+        self.config.logging = enabled;
+    }
+
+    pub fn log_level(&self) -> &str {
+        &self.config.log_level
+    }
+
+    pub fn set_log_level(&mut self, level: &str) -> Result<(), Error> {
+        let level = level.to_lowercase();
+        if !LOG_LEVELS.contains(&level.as_str()) {
+            bail!(
+                "Unknown log level `{}`. Expected one of: {}",
+                level,
+                LOG_LEVELS.join(", ")
+            );
+        }
+        self.config.log_level = level;
+        Ok(())
+    }
+
+    pub fn no_std(&self) -> bool {
+        self.config.no_std
+    }
+
+    pub fn lint_levels(&self) -> &[(String, String)] {
+        &self.config.lint_levels
+    }
+
+    /// Sets the level of `lint` to one of `allow`, `warn`, `deny` or `forbid`. The lint name isn't
+    /// checked beyond being a plausible path, rustc will report unknown lints.
+    pub fn set_lint_level(&mut self, level: &str, lint: &str) -> Result<(), Error> {
+        if !LINT_LEVELS.contains(&level) {
+            bail!(
+                "Unknown lint level `{}`. Expected one of: {}",
+                level,
+                LINT_LEVELS.join(", ")
+            );
+        }
+        if lint.is_empty()
+            || !lint
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        {
+            bail!("Invalid lint name `{}`", lint);
+        }
+        self.config.lint_levels.retain(|(_, name)| name != lint);
+        self.config
+            .lint_levels
+            .push((level.to_owned(), lint.to_owned()));
+        Ok(())
+    }
+
+    pub fn set_no_std(&mut self, no_std: bool) {
+        self.config.no_std = no_std;
+    }
+
+    pub fn profile_memory(&self) -> bool {
+        self.config.profile_memory
+    }
+
+    pub fn set_profile_memory(&mut self, enabled: bool) -> Result<(), Error> {
+        if enabled && !runtime::memory_stats_available() {
+            bail!(
+                "Memory profiling is unavailable. It requires evcxr to be built with the \
+                 `memory_stats` feature on Linux with glibc"
+            );
+        }
+        self.config.profile_memory = enabled;
+        Ok(())
+    }
+
+    pub fn max_vars(&self) -> String {
+        match self.config.max_vars {
+            Some((max, VariableLimitAction::Evict)) => format!("{} (evict)", max),
+            Some((max, VariableLimitAction::Reject)) => format!("{} (reject)", max),
+            None => "unlimited".to_owned(),
+        }
+    }
+
+    /// Sets the maximum number of variables. If `reject` is true, code that would define variables
+    /// beyond the limit will fail, otherwise the oldest variables will be dropped.
+    pub fn set_max_vars(&mut self, max_vars: Option<usize>, reject: bool) {
+        let action = if reject {
+            VariableLimitAction::Reject
+        } else {
+            VariableLimitAction::Evict
+        };
+        self.config.max_vars = max_vars.map(|max| (max, action));
+    }
+
+    fn check_variable_limit(&self) -> Result<(), Error> {
+        if let Some((max, VariableLimitAction::Reject)) = self.config.max_vars {
+            if self.variable_states.len() > max {
+                let mut new_variables: Vec<&str> = self
+                    .variable_states
+                    .keys()
+                    .filter(|name| !self.stored_variable_states.contains_key(*name))
+                    .map(String::as_str)
+                    .collect();
+                if !new_variables.is_empty() {
+                    new_variables.sort_unstable();
+                    bail!(
+                        "Defining {} would exceed the limit of {} variables set by :max_vars",
+                        new_variables.join(", "),
+                        max
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// If we're configured to evict variables, removes the oldest variables until we're within
+    /// the configured limit. Returns the names of the removed variables.
+    fn evict_excess_variables(&mut self) -> Vec<String> {
+        let max = match self.config.max_vars {
+            Some((max, VariableLimitAction::Evict)) => max,
+            _ => return Vec::new(),
+        };
+        let mut by_age: Vec<(i32, String)> = self
+            .variable_states
+            .iter()
+            .filter(|(_, state)| !state.pinned)
+            .map(|(name, state)| (state.defined_at, name.clone()))
+            .collect();
+        by_age.sort();
+        let num_to_evict = self.variable_states.len().saturating_sub(max);
+        let evicted: Vec<String> = by_age
+            .into_iter()
+            .take(num_to_evict)
+            .map(|(_, name)| name)
+            .collect();
+        for name in &evicted {
+            self.variable_states.remove(name);
+            self.variables_to_drop.push(name.clone());
+        }
+        evicted
+    }
+
+    /// Pins dependencies to the versions recorded in the supplied Cargo.lock contents. Returns the
+    /// number of packages for which a version was found.
+    pub fn set_locked_versions_from_lock_file(&mut self, lock_contents: &str) -> usize {
+        self.config.locked_versions = crate::cargo_lock::registry_versions(lock_contents);
+        self.config.locked_versions.len()
+    }
+
+    /// Freezes dependencies at the versions recorded in the supplied contents of the Cargo.lock
+    /// from the last build. Returns the number of packages frozen, not counting our own crate.
+    /// `None` unfreezes them.
+    pub fn set_frozen_lock(&mut self, lock_contents: Option<String>) -> usize {
+        self.config.frozen_lock = lock_contents;
+        self.config
+            .frozen_lock
+            .as_deref()
+            .map_or(0, |lock_contents| {
+                crate::cargo_lock::package_count(lock_contents).saturating_sub(1)
+            })
+    }
+
+    pub fn deps_frozen(&self) -> bool {
+        self.config.frozen_lock.is_some()
+    }
+
+    /// Returns the contents of the Cargo.lock that dependencies are frozen to, if any.
+    pub(crate) fn frozen_lock(&self) -> Option<&str> {
+        self.config.frozen_lock.as_deref()
+    }
+
+    pub fn merge_dep_features(&self) -> bool {
+        self.config.merge_dep_features
+    }
+
+    pub fn set_merge_dep_features(&mut self, value: bool) {
+        self.config.merge_dep_features = value;
+    }
+
+    /// Returns the configuration of the dependency with the specified name, if any.
+    pub(crate) fn dep_config(&self, dep: &str) -> Option<&str> {
+        self.external_deps
+            .get(dep)
+            .map(|external| external.config.as_str())
+    }
+
+    /// Adds a crate dependency with the specified name and configuration.
+    pub fn add_dep(&mut self, dep: &str, dep_config: &str) -> Result<(), Error> {
+        let mut dep_config = dep_config.to_owned();
+        // Avoid repeating dep validation once we're already added it.
+        if let Some(existing) = self.external_deps.get(dep) {
+            dep_config = crate::crate_config::update_config(&existing.config, &dep_config);
+            if self.config.merge_dep_features {
+                dep_config = crate::crate_config::merge_features(&existing.config, &dep_config);
+            }
+            if existing.config == dep_config {
+                return Ok(());
+            }
+        }
+        let external =
+            ExternalCrate::new_relative_to(dep.to_owned(), dep_config, &self.working_dir()?)?;
+        crate::cargo_metadata::validate_dep(&external.name, &external.config, &self.config)?;
+        self.external_deps.insert(dep.to_owned(), external);
+        Ok(())
+    }
+
+    /// Removes the dependency `dep`. Variables whose types come from it couldn't be referred to
+    /// by the code we'd generate afterwards, so they're removed too. Returns the names of the
+    /// removed variables. Their values are dropped the next time we run code.
+    pub fn remove_dep(&mut self, dep: &str) -> Result<Vec<String>, Error> {
+        if self.external_deps.remove(dep).is_none() {
+            bail!("No dependency named `{}`", dep);
+        }
+        Ok(self.remove_variables_with_types_from(dep))
+    }
+
+    /// Removes variables whose types come from the dependency `dep` and returns their names,
+    /// sorted. Their values are dropped the next time we run code.
+    fn remove_variables_with_types_from(&mut self, dep: &str) -> Vec<String> {
+        let crate_path = Regex::new(&format!(
+            "(^|[^A-Za-z0-9_:]){}::",
+            regex::escape(&dep.replace('-', "_"))
+        ))
+        .unwrap();
+        let mut removed: Vec<String> = self
+            .variable_states
+            .iter()
+            .filter(|(_, state)| crate_path.is_match(&state.type_name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        removed.sort();
+        for name in &removed {
+            self.variable_states.remove(name);
+            self.variables_to_drop.push(name.clone());
+        }
+        removed
+    }
+
+    /// Starts deferring the build that would normally follow changes to dependencies, so that
+    /// several dependencies can be added with a single build.
+    pub fn begin_deps_transaction(&mut self) -> Result<(), Error> {
+        if self.deps_before_transaction.is_some() {
+            bail!("Already inside a `:deps begin` transaction");
+        }
+        self.deps_before_transaction = Some(self.external_deps.clone());
+        Ok(())
+    }
+
+    /// Ends a transaction started by `begin_deps_transaction`. Any dependency changes made during
+    /// the transaction are then built together.
+    pub fn end_deps_transaction(&mut self) -> Result<(), Error> {
+        if self.deps_before_transaction.take().is_none() {
+            bail!("`:deps end` requires a preceding `:deps begin`");
+        }
+        Ok(())
+    }
+
+    /// Returns the names of modules that contain items defined via `:module`.
+    fn module_names(&self) -> impl Iterator<Item = &str> {
+        self.items_by_name
+            .keys()
+            .filter_map(|name| Some(name.split_once("::")?.0))
+            .chain(self.module_unnamed_items.keys().map(String::as_str))
+            .chain(self.impls.iter().filter_map(|(key, _)| key.module()))
+    }
+
+    /// Returns the names and types of variables that no cell has referenced since the cell that
+    /// defined them, sorted by name.
+    pub fn unused_variables(&self) -> Vec<(&str, &str)> {
+        let mut unused: Vec<(&str, &str)> = self
+            .variable_states
+            .iter()
+            .filter(|(_, variable_state)| !variable_state.referenced)
+            .map(|(name, variable_state)| (name.as_str(), variable_state.type_name.as_str()))
+            .collect();
+        unused.sort();
+        unused
+    }
+
+    /// Returns the module opened by `:module`, if any.
+    pub fn current_module(&self) -> Option<&str> {
+        self.current_module.as_deref()
+    }
+
+    /// Causes items defined from now on to be placed in the module `name` until
+    /// `close_module` is called. Items previously defined in a module of the same name are kept.
+    pub fn open_module(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(current) = &self.current_module {
+            bail!(
+                "Module `{}` is already open. Use `:module end` to close it first",
+                current
+            );
+        }
+        static NAME_RE: OnceCell<Regex> = OnceCell::new();
+        let name_re = NAME_RE.get_or_init(|| Regex::new("^[A-Za-z_][A-Za-z0-9_]*$").unwrap());
+        if !name_re.is_match(name) || name == "_" {
+            bail!("Invalid module name `{}`", name);
+        }
+        self.current_module = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Stops placing newly defined items in the module opened by `open_module`.
+    pub fn close_module(&mut self) -> Result<(), Error> {
+        if self.current_module.take().is_none() {
+            bail!("`:module end` requires a preceding `:module <name>`");
+        }
+        Ok(())
+    }
+
+    /// Sets how the code in the current cell should be classified.
+    pub(crate) fn force_code_kind(&mut self, kind: ForcedCodeKind) {
+        self.forced_code_kind = Some(kind);
+    }
+
+    /// Requests that the build artifacts of the generated crate be discarded and the crate
+    /// rebuilt from scratch when the current cell is evaluated. Variables are kept.
+    pub(crate) fn request_rebuild(&mut self) {
+        self.rebuild_requested = true;
+    }
+
+    /// Requests that the dependency `dep` be rebuilt, and for git dependencies, updated to the
+    /// latest commit, when the current cell is evaluated. The rebuilt crate may lay out its types
+    /// differently, so variables whose types come from it are removed. Returns their names.
+    pub(crate) fn request_dep_reload(&mut self, dep: &str) -> Result<Vec<String>, Error> {
+        if !self.external_deps.contains_key(dep) {
+            bail!("No dependency named `{}`", dep);
+        }
+        self.deps_to_reload.push(dep.to_owned());
+        Ok(self.remove_variables_with_types_from(dep))
+    }
+
+    /// Clears fields that aren't useful for inclusion in bug reports and which might give away
+    /// things like usernames.
+    pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
+        self.config.crate_dir = PathBuf::from("redacted");
+        if self.config.sccache.is_some() {
+            self.config.sccache = Some(PathBuf::from("redacted"));
+        }
+    }
+
+    fn apply_custom_errors(
+        &self,
+        errors: Vec<CompilationError>,
+        user_code: &CodeBlock,
+        code_info: &UserCodeInfo,
+    ) -> Vec<CompilationError> {
+        let errors = errors
+            .into_iter()
+            .filter_map(|error| self.customize_error(error, user_code))
+            .map(|mut error| {
+                error.fill_lines(code_info);
+                error
+            })
+            .collect();
+        crate::errors::collapse_duplicate_errors(errors)
+    }
+
+    /// Customizes errors based on their origins.
+    fn customize_error(
+        &self,
+        mut error: CompilationError,
+        user_code: &CodeBlock,
+    ) -> Option<CompilationError> {
+        if error.code() == Some("E0425") {
+            let message = error.message();
+            if let Some(name) = message
+                .strip_prefix("cannot find value `")
+                .and_then(|rest| rest.split('`').next())
+            {
+                if self.moved_variables.contains(name) {
+                    error.set_message(format!(
+                        "`{}` was moved by an earlier cell, so is no longer available",
+                        name
+                    ));
+                }
+            }
+        }
+        if error.code() == Some("E0659") {
+            let message = error.message();
+            if let Some(name) = message
+                .strip_prefix('`')
+                .and_then(|rest| rest.split('`').next())
+            {
+                let paths = ambiguous_glob_import_paths(&error);
+                if paths.len() >= 2 {
+                    let qualified: Vec<String> = paths
+                        .iter()
+                        .map(|path| format!("`{}::{}`", path, name))
+                        .collect();
+                    error.set_message(format!(
+                        "{}. It's imported by more than one glob import. Use {} to disambiguate, \
+                         or import it by name, e.g. `use {}::{};`",
+                        message,
+                        qualified.join(" or "),
+                        paths[0],
+                        name
+                    ));
+                }
+            }
+        }
+        for origin in &error.code_origins {
+            if let CodeKind::PackVariable { variable_name } = origin {
+                if let Some(definition_span) = &self.variable_states[variable_name].definition_span
+                {
+                    if let Some(segment) =
+                        user_code.segment_with_index(definition_span.segment_index)
+                    {
+                        if let Some(span) = Span::from_segment(segment, definition_span.range) {
+                            return self.replacement_for_pack_variable_error(
+                                variable_name,
+                                span,
+                                segment,
+                                &error,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Some(error)
+    }
+
+    fn replacement_for_pack_variable_error(
+        &self,
+        variable_name: &str,
+        variable_span: Span,
+        segment: &Segment,
+        error: &CompilationError,
+    ) -> Option<CompilationError> {
+        let message = match error.code().unwrap_or("") {
+            "E0382" | "E0505" => {
+                // Value used after move. When we go to execute the code, we'll detect this error and
+                // remove the variable so it doesn't get stored.
+                return None;
+            }
+            "E0597" => {
+                format!(
+                    "The variable `{}` contains a reference with a non-static lifetime so can't be persisted",
+                    variable_name
+                )
+            }
+            _ => {
+                return Some(error.clone());
+            }
+        };
+        Some(CompilationError::from_segment_span(
+            segment,
+            SpannedMessage::from_segment_span(segment, variable_span),
+            message,
+        ))
+    }
+
+    /// Returns a notice for each item in `new_state` that replaced a different definition of the
+    /// same name in `self`, if such notices are enabled.
+    fn notices_for_replaced_items(&self, new_state: &ContextState) -> Vec<String> {
+        let verbose = match new_state.config.replaced_item_notices {
+            ReplacedItemNotices::Off => return Vec::new(),
+            ReplacedItemNotices::On => false,
+            ReplacedItemNotices::Verbose => true,
+        };
+        let mut notices: Vec<(&String, String)> = new_state
+            .items_by_name
+            .iter()
+            .filter_map(|(name, new_block)| {
+                let old_code = self.items_by_name.get(name)?.code_string();
+                let new_code = new_block.code_string();
+                if old_code == new_code {
+                    return None;
+                }
+                let mut notice = format!("Replaced `{}`", name);
+                if verbose {
+                    let (old_signature, new_signature) =
+                        (item_signature(&old_code), item_signature(&new_code));
+                    if old_signature == new_signature {
+                        notice.push_str(" (signature unchanged)");
+                    } else {
+                        notice.push_str(&format!("\n  - {}\n  + {}", old_signature, new_signature));
+                    }
+                }
+                Some((name, notice))
+            })
+            .collect();
+        notices.sort();
+        notices.into_iter().map(|(_, notice)| notice).collect()
+    }
+
+    /// Returns a warning for each import in `new_state` that wasn't in `self` and that shadows a
+    /// name from the standard prelude, e.g. `use std::io::Result;`, if such warnings are enabled.
+    /// Importing the prelude's own item, e.g. `use std::result::Result;`, is fine.
+    fn new_prelude_shadowing_warnings(&self, new_state: &ContextState) -> Vec<String> {
+        if !new_state.config.prelude_shadowing_warnings {
+            return Vec::new();
+        }
+        let mut warnings: Vec<(&String, String)> = new_state
+            .items_by_name
+            .iter()
+            .filter_map(|(name, block)| {
+                let (_, prelude_path) = PRELUDE_NAMES
+                    .iter()
+                    .find(|(prelude_name, _)| prelude_name == name)?;
+                let code = block.code_string();
+                let path = code
+                    .trim()
+                    .strip_prefix("use ")?
+                    .trim_end_matches(';')
+                    .trim();
+                let path = path.trim_start_matches("::");
+                let path = ["core::", "alloc::"]
+                    .iter()
+                    .find_map(|krate| path.strip_prefix(krate))
+                    .map_or_else(|| path.to_owned(), |rest| format!("std::{}", rest));
+                if path == *prelude_path || self.items_by_name.get(name) == Some(block) {
+                    return None;
+                }
+                Some((
+                    name,
+                    format!(
+                        "`{}` shadows `{}` from the standard prelude. \
+                         Use `:prelude_shadowing off` to hide this warning.",
+                        code.trim(),
+                        name
+                    ),
+                ))
+            })
+            .collect();
+        warnings.sort();
+        warnings.into_iter().map(|(_, warning)| warning).collect()
+    }
+
+    /// Returns whether transitioning to `new_state` might cause compilation
+    /// failures. e.g. if `new_state` has extra dependencies, then we must
+    /// return true. If we return false, we're saying that the proposed state
+    /// change cannot cause compilation failures, so compilation can be skipped
+    /// if there is otherwise no code to execute.
+    fn state_change_can_fail_compilation(&self, new_state: &ContextState) -> bool {
+        new_state.rebuild_requested
+            || !new_state.deps_to_reload.is_empty()
+            || (self.extern_crate_stmts != new_state.extern_crate_stmts
+                && !new_state.extern_crate_stmts.is_empty())
+            || (new_state.deps_before_transaction.is_none()
+                && self
+                    .deps_before_transaction
+                    .as_ref()
+                    .unwrap_or(&self.external_deps)
+                    != &new_state.external_deps
+                && !new_state.external_deps.is_empty())
+            || (self.items_by_name != new_state.items_by_name
+                && !new_state.items_by_name.is_empty())
+            || (self.impls != new_state.impls && !new_state.impls.is_empty())
+            || (self.config.sccache != new_state.config.sccache)
+            || (self.config.locked_versions != new_state.config.locked_versions)
+            || (self.config.no_std != new_state.config.no_std)
+            || (self.config.features != new_state.config.features)
+            || (self.config.toolchain != new_state.config.toolchain)
+    }
+
+    /// Returns a description of each dependency, sorted by name. If `lock_contents` is supplied,
+    /// resolved versions are read from it.
+    pub(crate) fn describe_deps(&self, lock_contents: Option<&str>) -> Vec<String> {
+        let resolved_versions = lock_contents
+            .map(crate::cargo_lock::package_versions)
+            .unwrap_or_default();
+        let mut deps: Vec<&ExternalCrate> = self.external_deps.values().collect();
+        deps.sort_by(|a, b| a.name.cmp(&b.name));
+        deps.into_iter()
+            .map(|krate| {
+                let config = crate::cargo_lock::pin_version(
+                    &krate.name,
+                    &krate.config,
+                    &self.config.locked_versions,
+                );
+                let package_name = crate::cargo_lock::package_name(&krate.name, &krate.config);
+                match resolved_versions.get(&package_name) {
+                    Some(version) => format!(
+                        "{} = {} ({}, {})",
+                        krate.name,
+                        config,
+                        krate.source(),
+                        version
+                    ),
+                    None => format!("{} = {} ({})", krate.name, config, krate.source()),
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn format_cargo_deps(&self) -> String {
+        self.external_deps
+            .values()
+            .map(|krate| {
+                format!(
+                    "{} = {}\n",
+                    krate.name,
+                    crate::cargo_lock::pin_version(
+                        &krate.name,
+                        &krate.config,
+                        &self.config.locked_versions
+                    )
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("")
+    }
+
+    /// Returns a `[dependencies]` table containing each dependency added with `:dep`, sorted by
+    /// name, for use in a standalone Cargo.toml. Path dependencies have already been made absolute,
+    /// so still work from wherever the table is used.
+    pub(crate) fn dependencies_table(&self) -> String {
+        let mut deps: Vec<&ExternalCrate> = self.external_deps.values().collect();
+        deps.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut table = "[dependencies]\n".to_owned();
+        for krate in deps {
+            table.push_str(&format!("{} = {}\n", krate.name, krate.config));
+        }
+        table
+    }
+
+    /// Returns the contents of the `[features]` table of our Cargo.toml. Features of dependencies
+    /// don't need declaring.
+    pub(crate) fn format_cargo_features(&self) -> String {
+        self.config
+            .features
+            .iter()
+            .filter(|feature| !feature.contains('/'))
+            // Quoted, since names may contain `.`, which would otherwise make a dotted key.
+            .map(|feature| format!("\"{}\" = []\n", feature))
+            .collect()
+    }
+
+    fn compilation_mode(&self) -> CompilationMode {
+        if self.config.preserve_vars_on_panic {
+            CompilationMode::RunAndCatchPanics
+        } else {
+            CompilationMode::NoCatch
+        }
+    }
+
+    /// Returns code that allows lints that would otherwise produce warnings for typical REPL
+    /// usage.
+    fn allowed_lints_code(&self) -> CodeBlock {
+        CodeBlock::new().generated(if self.config.allow_unused_imports {
+            "#![allow(unused_imports, unused_mut, dead_code)]"
+        } else {
+            "#![allow(unused_mut, dead_code)]"
+        })
+    }
+
+    fn lint_levels_code(&self) -> CodeBlock {
+        let mut code = CodeBlock::new();
+        for (level, lint) in &self.config.lint_levels {
+            code = code.generated(format!("#![{}({})]", level, lint));
+        }
+        code
+    }
+
+    /// Returns the format string with which to display final expressions. If a float precision has
+    /// been set, it's added to the Debug formats, which pass it down to any floats within
+    /// containers (and derived Debug impls) while ignoring it for other types, like integers.
+    fn display_format(&self) -> String {
+        match (
+            self.config.float_precision,
+            self.config.output_format.as_str(),
+        ) {
+            (Some(precision), "{:?}") => format!("{{:.{}?}}", precision),
+            (Some(precision), "{:#?}") => format!("{{:#.{}?}}", precision),
+            _ => self.config.output_format.clone(),
+        }
+    }
+
+    /// Returns code that displays the final expression in `segment` using the configured output
+    /// format.
+    fn debug_display_code(&self, segment: Segment) -> CodeBlock {
+        let format = self.display_format();
+        let (debug_display, debug_display_end) = if self.config.send_type_names {
+            (
+                CodeBlock::new()
+                    .generated("{")
+                    .generated(SEND_TYPED_VALUE_DEF)
+                    .generated(
+                        "fn evcxr_type_name<T: ?Sized>(_: &T) -> &'static str { \
+                     std::any::type_name::<T>() }\
+                     let evcxr_value = &(\n",
+                    ),
+                format!(
+                    ");evcxr_send_typed_value(evcxr_type_name(evcxr_value), \
+                     &format!(\"{}\", evcxr_value));}}",
+                    format
+                ),
+            )
+        } else {
+            (
+                CodeBlock::new()
+                    .generated(SEND_TEXT_PLAIN_DEF)
+                    .generated(&format!(
+                        "evcxr_send_text_plain(&format!(\"{}\",&(\n",
+                        format
+                    )),
+                ")));".to_owned(),
+            )
+        };
+        if !self.async_mode {
+            return debug_display
+                .with_segment(segment)
+                .generated(&debug_display_end);
+        }
+        // We check whether we've got a future before trying debug format, since some futures, such
+        // as `std::future::Ready`, implement Debug, but printing them isn't useful. If the value
+        // isn't a future, we fall back to debug format, retaining the user's code so that errors
+        // are reported against it.
+        let expression = CodeBlock::new().with_segment(segment.clone()).code_string();
+        let future_display = if self.config.auto_await {
+            format!(
+                "{{{}async fn evcxr_await<F: std::future::Future>(f: F) -> F::Output {{ f.await }}\
+                 let evcxr_output = evcxr_await(\n{}\n).await;\
+                 evcxr_send_text_plain(&format!(\"{}\", &evcxr_output));}}",
+                SEND_TEXT_PLAIN_DEF, expression, format
+            )
+        } else {
+            format!(
+                "{{{}fn evcxr_assert_future<F: std::future::Future>(_: &F) {{}}\
+                 evcxr_assert_future(&(\n{}\n));\
+                 evcxr_send_text_plain(\"Future not awaited. Add .await or use :auto_await on\");}}",
+                SEND_TEXT_PLAIN_DEF, expression
+            )
+        };
+        CodeBlock::new().code_with_fallback(
+            future_display,
+            debug_display
+                .with_segment(segment)
+                .generated(&debug_display_end),
+        )
+    }
+
+    /// Returns code suitable for analysis purposes. Doesn't attempt to preserve runtime behavior.
+    fn analysis_code(&self, user_code: CodeBlock) -> CodeBlock {
+        let mut code = CodeBlock::new()
+            .add_all(self.allowed_lints_code())
+            .add_all(self.lint_levels_code())
+            .add_all(self.attributes_code())
+            .add_all(self.items_code())
+            .add_all(self.error_trait_code(true))
+            .generated("fn evcxr_variable_store<T: 'static>(_: T) {}")
+            .generated("#[allow(unused_variables)]")
+            .generated("async fn evcxr_analysis_wrapper(");
+        for (var_name, state) in &self.stored_variable_states {
+            code = code.generated(format!(
+                "{}{}: {},",
+                if state.is_mut { "mut " } else { "" },
+                var_name,
+                state.type_name
+            ));
+        }
+        code = code
+            .generated(") -> std::result::Result<(), EvcxrUserCodeError> {")
+            .add_all(
+                user_code.expand_early_exits(|_| CodeBlock::new().generated("return Ok(());")),
+            );
+
+        // Pack variable statements in analysis mode are a lot simpler than in compiled mode. We
+        // just call a function that enforces that the variable doesn't contain any non-static
+        // lifetimes.
+        for var_name in self.variable_states.keys() {
+            code.pack_variable(
+                var_name.clone(),
+                format!("evcxr_variable_store({});", var_name),
+            );
+        }
+
+        code = code.generated("Ok(())").generated("}");
+        code
+    }
+
+    fn code_to_compile(
+        &self,
+        user_code: CodeBlock,
+        compilation_mode: CompilationMode,
+    ) -> CodeBlock {
+        let mut code = self.allowed_lints_code();
+        if self.config.no_std {
+            code = code.generated("#![no_std]");
+        }
+        code = code
+            .add_all(self.lint_levels_code())
+            .add_all(self.attributes_code());
+        if self.config.no_std {
+            // Our generated code still needs std, so we link it, but don't include its prelude.
+            for krate in ["std", "alloc"] {
+                if !self.extern_crate_stmts.contains_key(krate) {
+                    code = code.generated(format!("extern crate {};", krate));
+                }
+            }
+        }
+        code = code
+            .add_all(self.flushing_print_code())
+            .add_all(self.items_code())
+            .add_all(self.panic_hook_code());
+        let has_user_code = !user_code.is_empty();
+        if has_user_code {
+            code = code.add_all(self.wrap_user_code(user_code, compilation_mode));
+        } else {
+            // TODO: Add a mechanism to load a crate without any function to call then remove this.
+            code = code
+                .generated("#[no_mangle]")
+                .generated(format!(
+                    "pub extern \"C\" fn {}(",
+                    self.current_user_fn_name()
+                ))
+                .generated("mut x: *mut std::os::raw::c_void) -> *mut std::os::raw::c_void {x}");
+        }
+        code
+    }
+
+    fn items_code(&self) -> CodeBlock {
+        let mut code = CodeBlock::new().add_all(self.get_imports());
+        let mut modules: BTreeMap<&str, CodeBlock> = BTreeMap::new();
+        for (name, item) in &self.items_by_name {
+            match name.split_once("::") {
+                Some((module, _)) => modules
+                    .entry(module)
+                    .or_default()
+                    .modify(|block| block.add_all(item.clone())),
+                None => code = code.add_all(item.clone()),
+            }
+        }
+        for item in &self.unnamed_items {
+            code = code.add_all(item.clone());
+        }
+        for (module, items) in &self.module_unnamed_items {
+            let block = modules.entry(module.as_str()).or_default();
+            for item in items {
+                block.modify(|block| block.add_all(item.clone()));
+            }
+        }
+        for (key, item) in &self.impls {
+            match key.module() {
+                Some(module) => modules
+                    .entry(module)
+                    .or_default()
+                    .modify(|block| block.add_all(item.clone())),
+                None => code = code.add_all(item.clone()),
+            }
+        }
+        // Items defined while a `:module` was open. They can refer to everything at the top level
+        // of the crate, including items in other modules.
+        for (module, items) in modules {
+            code = code
+                .generated(format!("pub mod {} {{ use super::*;", module))
+                .add_all(items)
+                .generated("}");
+        }
+        code
+    }
+
+    fn attributes_code(&self) -> CodeBlock {
+        let mut code = CodeBlock::new();
+        for attrib in self.attributes.values() {
+            code = code.add_all(attrib.clone());
+        }
+        code
+    }
+
+    fn error_trait_code(&self, for_analysis: bool) -> CodeBlock {
         CodeBlock::new().generated(format!(
             r#"
             struct EvcxrUserCodeError {{}}
             impl<T: {}> From<T> for EvcxrUserCodeError {{
                 fn from(error: T) -> Self {{
-                    eprintln!("{}", error);
+                    std::eprintln!("{}", error);
                     {}
                     EvcxrUserCodeError {{}}
                 }}
@@ -1409,7 +4061,7 @@ impl ContextState {
             if for_analysis {
                 ""
             } else {
-                "println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
+                "std::println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
             }
         ))
     }
@@ -1419,18 +4071,31 @@ impl ContextState {
         mut user_code: CodeBlock,
         compilation_mode: CompilationMode,
     ) -> CodeBlock {
-        let needs_variable_store = !self.variable_states.is_empty()
-            || !self.stored_variable_states.is_empty()
-            || self.async_mode
-            || self.allow_question_mark;
+        let needs_variable_store = self.needs_variable_store(&user_code);
+        let has_early_exit = user_code.has_early_exit();
         let mut code = CodeBlock::new();
-        if self.allow_question_mark {
+        if self.allow_question_mark || has_early_exit {
             code = code.add_all(self.error_trait_code(false));
+            user_code = user_code.expand_early_exits(|defined_variables| {
+                self.early_exit_code(defined_variables, compilation_mode)
+            });
         }
         if needs_variable_store {
+            code = code.generated("mod evcxr_internal_runtime {");
+            if self.config.no_std {
+                code = code
+                    .generated("use std::prelude::rust_2021::*;")
+                    .generated("use std::{eprintln, format, println};");
+            }
             code = code
-                .generated("mod evcxr_internal_runtime {")
                 .generated(include_str!("evcxr_internal_runtime.rs"))
+                .generated("}")
+                .generated("#[no_mangle]")
+                .generated(format!(
+                    "pub extern \"C\" fn {}() {{",
+                    runtime::ABANDON_VARIABLE_STORE_FN
+                ))
+                .generated("evcxr_internal_runtime::abandon_variable_store();")
                 .generated("}");
         }
         code = code.generated("#[no_mangle]").generated(format!(
@@ -1447,14 +4112,19 @@ impl ContextState {
                 )
                 .generated("}")
                 .generated("let evcxr_variable_store = unsafe {&mut *evcxr_variable_store};")
+                .add_all(self.drop_variable_statements())
                 .add_all(self.check_variable_statements())
                 .add_all(self.load_variable_statements());
-            user_code = user_code
+            user_code = self
+                .clone_variable_statements()
+                .add_all(user_code)
+                .add_all(self.restore_cloned_variable_statements())
                 .add_all(self.store_variable_statements(&VariableMoveState::MovedIntoCatchUnwind))
                 .add_all(self.store_variable_statements(&VariableMoveState::CopiedIntoCatchUnwind));
         } else {
             code = code.generated("evcxr_variable_store: *mut u8) -> *mut u8 {");
         }
+        code = code.add_all(self.logger_init_code());
         if self.async_mode {
             user_code = CodeBlock::new()
                 .generated(stringify!(evcxr_variable_store
@@ -1472,11 +4142,21 @@ impl ContextState {
                     .generated("Ok::<(), EvcxrUserCodeError>(())");
             }
             user_code = user_code.generated("});")
-        } else if self.allow_question_mark {
-            user_code = CodeBlock::new()
-                .generated("let _ = (|| -> std::result::Result<(), EvcxrUserCodeError> {")
-                .add_all(user_code)
-                .generated("Ok(())})();");
+        } else if self.allow_question_mark && !has_early_exit {
+            let retain_variable_statements = self.retain_variable_statements();
+            if retain_variable_statements.is_empty() {
+                user_code = CodeBlock::new()
+                    .generated("let _ = (|| -> std::result::Result<(), EvcxrUserCodeError> {")
+                    .add_all(user_code)
+                    .generated("Ok(())})();");
+            } else {
+                user_code = CodeBlock::new()
+                    .generated("if (|| -> std::result::Result<(), EvcxrUserCodeError> {")
+                    .add_all(user_code)
+                    .generated("Ok(())})().is_err() {")
+                    .add_all(retain_variable_statements)
+                    .generated("}");
+            }
         }
         if compilation_mode == CompilationMode::RunAndCatchPanics {
             if needs_variable_store {
@@ -1498,30 +4178,222 @@ impl ContextState {
                     .add_all(
                         self.store_variable_statements(&VariableMoveState::CopiedIntoCatchUnwind),
                     )
-                    .generated(format!("    println!(\"{}\");", PANIC_NOTIFICATION))
+                    .generated(format!("    std::println!(\"{}\");", PANIC_NOTIFICATION))
                     .generated("}}");
             } else {
                 code = code
                     .generated("if std::panic::catch_unwind(||{")
                     .add_all(user_code)
                     .generated("}).is_err() {")
-                    .generated(format!("    println!(\"{}\");", PANIC_NOTIFICATION))
+                    .generated(format!("    std::println!(\"{}\");", PANIC_NOTIFICATION))
                     .generated("}");
             }
-        } else {
-            code = code.add_all(user_code);
-        }
-        if needs_variable_store {
-            code = code.add_all(self.store_variable_statements(&VariableMoveState::Available));
+        } else {
+            code = code.add_all(user_code);
+        }
+        if needs_variable_store {
+            code = code.add_all(self.store_variable_statements(&VariableMoveState::Available));
+        }
+        code = code.generated("evcxr_variable_store");
+        code.generated("}")
+    }
+
+    /// Returns whether `user_code` needs access to the variable store. If the code doesn't define,
+    /// drop or reference any variables, then we can skip generating the code that restores and
+    /// stores variables and just leave the store untouched.
+    fn needs_variable_store(&self, user_code: &CodeBlock) -> bool {
+        if !self.variables_to_drop.is_empty()
+            || self.async_mode
+            || self.allow_question_mark
+            || user_code.has_early_exit()
+        {
+            return true;
+        }
+        if self.variable_states.len() != self.stored_variable_states.len()
+            || self
+                .variable_states
+                .keys()
+                .any(|name| !self.stored_variable_states.contains_key(name))
+        {
+            return true;
+        }
+        if self.stored_variable_states.is_empty() {
+            return false;
+        }
+        identifiers(&user_code.code_string())
+            .any(|ident| self.stored_variable_states.contains_key(ident))
+    }
+
+    /// Returns the stored variables that `user_code` doesn't reference.
+    fn find_untouched_variables(&self, user_code: &CodeBlock) -> HashSet<String> {
+        let code = user_code.code_string();
+        let referenced: HashSet<&str> = identifiers(&code).collect();
+        self.stored_variable_states
+            .keys()
+            .filter(|var_name| !referenced.contains(var_name.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the function that the runtime calls, once per process, to install a panic hook that
+    /// sends us the messages of panics. If a panic terminates the subprocess, this lets us report
+    /// the message separately from everything else that was written to stderr.
+    fn panic_hook_code(&self) -> CodeBlock {
+        CodeBlock::new().generated(format!(
+            r#"#[no_mangle]
+            pub extern "C" fn {fn_name}() {{
+                let default_hook = std::panic::take_hook();
+                std::panic::set_hook(std::boxed::Box::new(move |info| {{
+                    let payload = info.payload();
+                    let message = payload
+                        .downcast_ref::<&str>()
+                        .map(|message| std::string::ToString::to_string(message))
+                        .or_else(|| payload.downcast_ref::<std::string::String>().cloned());
+                    if let std::option::Option::Some(message) = message {{
+                        std::println!(
+                            "{prefix}{{}}",
+                            message.replace('\\', "\\\\").replace('\n', "\\n")
+                        );
+                    }}
+                    default_hook(info);
+                }}));
+            }}"#,
+            fn_name = runtime::INSTALL_PANIC_HOOK_FN,
+            prefix = runtime::EVCXR_PANIC_MESSAGE,
+        ))
+    }
+
+    /// Returns a definition of `print!` that flushes stdout after printing, if line buffering is
+    /// off or progress bars are on. Since it's defined before all other code in the crate, it
+    /// shadows the standard macro. Code in dependencies isn't affected.
+    fn flushing_print_code(&self) -> CodeBlock {
+        if self.config.line_buffered && !self.config.progress_bars {
+            return CodeBlock::new();
+        }
+        CodeBlock::new().generated(
+            r#"macro_rules! print {
+                ($($arg:tt)*) => {{
+                    std::print!($($arg)*);
+                    let _ = std::io::Write::flush(&mut std::io::stdout());
+                }};
+            }"#,
+        )
+    }
+
+    /// Returns code to install a logger for the `log` crate, if logging is enabled and the crate is
+    /// a dependency. Each compilation gets its own copy of the `log` crate, so this needs to be run
+    /// every time.
+    fn logger_init_code(&self) -> CodeBlock {
+        if !self.config.logging || !self.external_deps.contains_key("log") {
+            return CodeBlock::new();
+        }
+        CodeBlock::new().generated(format!(
+            r#"{{
+                struct EvcxrLogger;
+                impl log::Log for EvcxrLogger {{
+                    fn enabled(&self, metadata: &log::Metadata) -> bool {{
+                        metadata.level() <= log::max_level()
+                    }}
+                    fn log(&self, record: &log::Record) {{
+                        if self.enabled(record.metadata()) {{
+                            std::eprintln!("[{{}} {{}}] {{}}", record.level(), record.target(), record.args());
+                        }}
+                    }}
+                    fn flush(&self) {{}}
+                }}
+                static EVCXR_LOGGER: EvcxrLogger = EvcxrLogger;
+                let _ = log::set_logger(&EVCXR_LOGGER);
+                log::set_max_level(log::LevelFilter::{});
+            }}"#,
+            log_level_filter_name(&self.config.log_level)
+        ))
+    }
+
+    /// Returns code to clone variables that the user's code moves out of. This runs before the
+    /// user's code, so the clones hold the values that the variables had beforehand.
+    fn clone_variable_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        for (var_name, var_state) in &self.variable_states {
+            if var_state.clone_before_move {
+                statements.clone_variable(
+                    var_name.clone(),
+                    format!(
+                        "let evcxr_clone_of_{var_name} = std::clone::Clone::clone(&{var_name});",
+                        var_name = var_name
+                    ),
+                );
+            }
+        }
+        statements
+    }
+
+    /// Returns code to put the clones made by `clone_variable_statements` in place of the
+    /// variables that they were cloned from, so that they get stored.
+    fn restore_cloned_variable_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        for (var_name, var_state) in &self.variable_states {
+            if var_state.clone_before_move {
+                statements = statements.generated(format!(
+                    "let {var_name} = evcxr_clone_of_{var_name};",
+                    var_name = var_name
+                ));
+            }
+        }
+        statements
+    }
+
+    /// Returns the code run when a `?` in a statement wrapped for `:try` returns an error. It
+    /// stores the variables that are in scope, reports which ones it stored, then returns from the
+    /// generated function, or from the catch_unwind closure, in which case the variables that
+    /// weren't moved into the closure get stored as usual.
+    fn early_exit_code(
+        &self,
+        defined_variables: &[String],
+        compilation_mode: CompilationMode,
+    ) -> CodeBlock {
+        let mut code = CodeBlock::new();
+        let mut stored = Vec::new();
+        for (var_name, var_state) in &self.variable_states {
+            // Variables from earlier cells that this cell redefines after this point still hold
+            // their old values here, which would need storing with their old types. For
+            // simplicity, we treat them as lost.
+            let in_scope = defined_variables.contains(var_name)
+                || (self.stored_variable_states.contains_key(var_name)
+                    && var_state.defined_at < self.build_num);
+            if !in_scope
+                || self.untouched_variables.contains(var_name)
+                || (compilation_mode == CompilationMode::RunAndCatchPanics
+                    && var_state.move_state == VariableMoveState::Available)
+            {
+                continue;
+            }
+            // The statement that returned the error may have moved out of a variable that we
+            // cloned, in which case we store the clone.
+            let value = if var_state.clone_before_move {
+                format!("evcxr_clone_of_{}", var_name)
+            } else {
+                var_name.clone()
+            };
+            code.pack_variable(
+                var_name.clone(),
+                format!(
+                    "evcxr_variable_store.put_variable::<{}>(stringify!({}), {});",
+                    var_state.type_name, var_name, value
+                ),
+            );
+            stored.push(var_name.as_str());
         }
-        code = code.generated("evcxr_variable_store");
-        code.generated("}")
+        code.generated(format!(
+            "std::println!(\"{{}}{{}}\", evcxr_internal_runtime::VARIABLES_KEPT_ON_ERROR, {:?});",
+            stored.join(",")
+        ))
+        .generated("return evcxr_variable_store;")
     }
 
     fn store_variable_statements(&self, move_state: &VariableMoveState) -> CodeBlock {
         let mut statements = CodeBlock::new();
         for (var_name, var_state) in &self.variable_states {
-            if var_state.move_state == *move_state {
+            if var_state.move_state == *move_state && !self.untouched_variables.contains(var_name) {
                 statements.pack_variable(
                     var_name.clone(),
                     format!(
@@ -1542,6 +4414,55 @@ impl ContextState {
         statements
     }
 
+    /// Returns the variables from earlier cells that the current cell redefines, but which keep
+    /// their previous values if the cell returns an error via `?`. Empty unless `:retain_on_error`
+    /// is on.
+    fn variables_retained_on_error(&self) -> Vec<&String> {
+        if !self.config.retain_on_error || !self.allow_question_mark || self.async_mode {
+            return Vec::new();
+        }
+        let mut retained: Vec<&String> = self
+            .stored_variable_states
+            .keys()
+            .filter(|var_name| {
+                self.variable_states
+                    .get(*var_name)
+                    .map_or(false, |var_state| var_state.defined_at == self.build_num)
+                    && !self.unretained_variables.contains(*var_name)
+            })
+            .collect();
+        retained.sort();
+        retained
+    }
+
+    // Returns code to store the values that variables had before they were shadowed by the
+    // current cell. This runs outside the closure in which the cell's code runs, so the previous
+    // values are still in scope.
+    fn retain_variable_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        for var_name in self.variables_retained_on_error() {
+            statements.retain_variable(
+                var_name.clone(),
+                format!(
+                    "evcxr_variable_store.put_variable::<{}>(stringify!({}), {});",
+                    self.stored_variable_states[var_name].type_name, var_name, var_name
+                ),
+            );
+        }
+        statements
+    }
+
+    fn drop_variable_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        for var_name in &self.variables_to_drop {
+            statements = statements.generated(format!(
+                "evcxr_variable_store.drop_variable(stringify!({}));",
+                var_name
+            ));
+        }
+        statements
+    }
+
     fn check_variable_statements(&self) -> CodeBlock {
         let mut statements = CodeBlock::new().generated("{let mut vars_ok = true;");
         for (var_name, var_state) in &self.stored_variable_states {
@@ -1557,6 +4478,9 @@ impl ContextState {
     fn load_variable_statements(&self) -> CodeBlock {
         let mut statements = CodeBlock::new();
         for (var_name, var_state) in &self.stored_variable_states {
+            if self.untouched_variables.contains(var_name) {
+                continue;
+            }
             let mutability = if var_state.is_mut { "mut " } else { "" };
             statements.load_variable(format!(
                 "let {}{} = evcxr_variable_store.take_variable::<{}>(stringify!({}));",
@@ -1582,12 +4506,18 @@ impl ContextState {
     /// used for the current code that's being evaluated, otherwise things like
     /// tab completion will be confused, since there will be multiple bits of
     /// code at a particular offset.
-    fn commit_old_user_code(&mut self) {
+    fn commit_old_user_code(&mut self, cell: usize) {
         for block in self.items_by_name.values_mut() {
-            block.commit_old_user_code();
+            block.commit_old_user_code(cell);
         }
         for block in self.unnamed_items.iter_mut() {
-            block.commit_old_user_code();
+            block.commit_old_user_code(cell);
+        }
+        for block in self.module_unnamed_items.values_mut().flatten() {
+            block.commit_old_user_code(cell);
+        }
+        for (_, block) in self.impls.iter_mut() {
+            block.commit_old_user_code(cell);
         }
     }
 
@@ -1611,7 +4541,22 @@ impl ContextState {
                 variable_state.move_state = VariableMoveState::Available;
             }
         }
+        self.untouched_variables = self.find_untouched_variables(&user_code);
+        for var_name in &self.untouched_variables {
+            if let Some(variable_state) = self.variable_states.get_mut(var_name) {
+                variable_state.move_state = VariableMoveState::Available;
+            }
+        }
 
+        // With `:try`, each statement that uses `?` gets wrapped in its own closure, so that an
+        // error only skips the rest of the cell and variables defined before it are kept. `.await`
+        // can't be used within those closures, so in async mode, cells are instead wrapped as a
+        // whole, as when `:try` is off.
+        let wrap_try_statements = self.config.try_mode
+            && !self.async_mode
+            && !nodes
+                .iter()
+                .any(|node| contains_token(node, SyntaxKind::AWAIT_KW));
         let mut code_out = CodeBlock::new();
         let mut previous_item_name = None;
         let num_statements = user_code.segments.len();
@@ -1622,35 +4567,163 @@ impl ContextState {
                 code_out = code_out.with_segment(segment);
                 continue;
             };
+            let segment = self.resolve_include_paths(segment, node)?;
+            self.record_variable_references(node);
+            if !self.config.allow_unsafe {
+                if let Some((range, message)) = self.find_unsafe_code(node) {
+                    let range = range - node.text_range().start();
+                    let span = Span::from_segment(&segment, range)
+                        .ok_or_else(|| err!("Internal error: unsafe code not from user code"))?;
+                    return Err(Error::CompilationErrors(vec![
+                        CompilationError::from_segment_span(
+                            &segment,
+                            SpannedMessage::from_segment_span(&segment, span),
+                            message,
+                        ),
+                    ]));
+                }
+            }
+            if self.forced_code_kind == Some(ForcedCodeKind::Item) {
+                if let Some(macro_call) = macro_call_statement(node) {
+                    // Macros invoked in statement position are normally expanded within our
+                    // generated function, so any items that they define only last for the cell.
+                    let mut item_block = CodeBlock::new().with_segment(segment);
+                    let needs_semicolon = !ast::ExprStmt::can_cast(node.kind())
+                        && !macro_call
+                            .syntax()
+                            .text()
+                            .to_string()
+                            .trim_end()
+                            .ends_with('}');
+                    if needs_semicolon {
+                        item_block = item_block.generated(";");
+                    }
+                    self.add_unnamed_item(item_block);
+                    continue;
+                }
+                if !ast::Item::can_cast(node.kind()) && !ast::Attr::can_cast(node.kind()) {
+                    let span =
+                        Span::from_segment(&segment, node.text_range() - node.text_range().start())
+                            .ok_or_else(|| err!("Internal error: statement not from user code"))?;
+                    return Err(Error::CompilationErrors(vec![
+                        CompilationError::from_segment_span(
+                            &segment,
+                            SpannedMessage::from_segment_span(&segment, span),
+                            "`:item` only applies to items and macro invocations".to_owned(),
+                        ),
+                    ]));
+                }
+            }
+            if self.forced_code_kind == Some(ForcedCodeKind::Statement)
+                && ast::Item::can_cast(node.kind())
+            {
+                // Items within our generated function are only visible for the rest of the cell.
+                code_out = code_out.with_segment(segment);
+                continue;
+            }
             if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
+                let returns_from_else = let_stmt
+                    .let_else()
+                    .and_then(|let_else| find_top_level_return(let_else.syntax()))
+                    .is_some();
                 if let Some(pat) = let_stmt.pat() {
-                    self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
-                    code_out = code_out.with_segment(segment);
+                    if returns_from_else {
+                        // A `return` in the `else` branch would return from our generated
+                        // function, skipping the code that stores variables. Instead it returns
+                        // from a closure, after which we leave the cell via an early exit.
+                        let defined_variables = self.variables_defined_in_cell();
+                        let names = pattern_variable_names(&pat);
+                        self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
+                        let bindings: String =
+                            names.iter().map(|name| format!("mut {},", name)).collect();
+                        let values: String =
+                            names.iter().map(|name| format!("{},", name)).collect();
+                        code_out = code_out.add_all(let_else_wrapped_statement(
+                            &format!("({})", bindings),
+                            with_semicolon(segment)
+                                .generated(format!("evcxr_let_else_value = Some(({}));", values)),
+                            defined_variables,
+                        ));
+                    } else if wrap_try_statements && contains_token(node, SyntaxKind::QUESTION) {
+                        let defined_variables = self.variables_defined_in_cell();
+                        let names = pattern_variable_names(&pat);
+                        self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
+                        // The closure returns the values of the variables that the statement
+                        // defines, which we then bind outside of it.
+                        let bindings: String =
+                            names.iter().map(|name| format!("mut {},", name)).collect();
+                        let values: String =
+                            names.iter().map(|name| format!("{},", name)).collect();
+                        code_out = code_out.add_all(try_wrapped_statement(
+                            &format!("({})", bindings),
+                            with_semicolon(segment).generated(format!("Ok(({}))", values)),
+                            defined_variables,
+                        ));
+                    } else {
+                        self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
+                        code_out = code_out.with_segment(segment);
+                    }
                 }
             } else if ast::Attr::can_cast(node.kind()) {
                 self.attributes.insert(
                     node.text().to_string(),
                     CodeBlock::new().with_segment(segment),
                 );
+            } else if wrap_try_statements
+                && contains_token(node, SyntaxKind::QUESTION)
+                && (ast::ExprStmt::can_cast(node.kind())
+                    || (ast::Expr::can_cast(node.kind()) && statement_index != num_statements - 1))
+            {
+                code_out = code_out.add_all(try_wrapped_statement(
+                    "()",
+                    with_semicolon(segment).generated("Ok(())"),
+                    self.variables_defined_in_cell(),
+                ));
             } else if ast::Expr::can_cast(node.kind()) {
                 if statement_index == num_statements - 1 {
-                    if self.config.display_final_expression {
+                    let segment =
+                        if wrap_try_statements && contains_token(node, SyntaxKind::QUESTION) {
+                            code_out = code_out.add_all(try_wrapped_statement(
+                                "evcxr_try_value",
+                                CodeBlock::new()
+                                    .generated("Ok(")
+                                    .with_segment(segment)
+                                    .generated(")"),
+                                self.variables_defined_in_cell(),
+                            ));
+                            Segment::generated("evcxr_try_value")
+                        } else {
+                            segment
+                        };
+                    // Our fallback for displaying values relies on std's prelude, which isn't
+                    // available in no_std mode.
+                    if self.config.display_final_expression && !self.config.no_std {
+                        let segment = match self.autobind_name.clone() {
+                            Some(name) if can_autobind(node) => {
+                                // We bind the value to a variable, which then gets stored like
+                                // any other, then display that variable.
+                                code_out = code_out
+                                    .generated(format!("let {} = (", name))
+                                    .with_segment(segment)
+                                    .generated(");");
+                                Segment::generated(name)
+                            }
+                            _ => {
+                                self.autobind_name = None;
+                                segment
+                            }
+                        };
                         code_out = code_out.code_with_fallback(
-                            // First we try calling .evcxr_display().
+                            // First we try calling .evcxr_display(). Since this is a method
+                            // call, autoderef means that this also works for references and smart
+                            // pointers like Box, Rc and Arc to types with an evcxr_display method.
                             CodeBlock::new()
                                 .generated("(")
                                 .with_segment(segment.clone())
                                 .generated(").evcxr_display();")
                                 .code_string(),
                             // If that fails, we try debug format.
-                            CodeBlock::new()
-                                .generated(SEND_TEXT_PLAIN_DEF)
-                                .generated(&format!(
-                                    "evcxr_send_text_plain(&format!(\"{}\",&(\n",
-                                    self.config.output_format
-                                ))
-                                .with_segment(segment)
-                                .generated(")));"),
+                            self.debug_display_code(segment),
                         );
                     } else {
                         code_out = code_out
@@ -1670,7 +4743,9 @@ impl ContextState {
                     ast::Item::ExternCrate(extern_crate) => {
                         if let Some(crate_name) = extern_crate.name_ref() {
                             let crate_name = crate_name.text().to_string();
-                            if !self.dependency_lib_names()?.contains(&crate_name) {
+                            if !SYSROOT_CRATES.contains(&crate_name.as_str())
+                                && !self.dependency_lib_names()?.contains(&crate_name)
+                            {
                                 self.external_deps
                                     .entry(crate_name.clone())
                                     .or_insert_with(|| {
@@ -1686,11 +4761,64 @@ impl ContextState {
                         if let Some(name) = ast::HasName::name(&macro_rules) {
                             let item_block = CodeBlock::new().with_segment(segment);
                             self.items_by_name
-                                .insert(name.text().to_string(), item_block);
+                                .insert(self.item_key(&name.text()), item_block);
+                        } else {
+                            code_out = code_out.with_segment(segment);
+                        }
+                    }
+                    ast::Item::Module(module) if module.item_list().is_none() => {
+                        // `mod name;` refers to a file, which won't be found relative to our
+                        // generated crate, so we inline its contents.
+                        if let Some(name) = ast::HasName::name(&module) {
+                            let name = name.text().to_string();
+                            let contents = self
+                                .working_dir()
+                                .map_err(|error| error.to_string())
+                                .and_then(|dir| read_module_file(&name, &dir))
+                                .map_err(|message| {
+                                    let range =
+                                        module.syntax().text_range() - node.text_range().start();
+                                    match Span::from_segment(&segment, range) {
+                                        Some(span) => Error::CompilationErrors(vec![
+                                            CompilationError::from_segment_span(
+                                                &segment,
+                                                SpannedMessage::from_segment_span(&segment, span),
+                                                message,
+                                            ),
+                                        ]),
+                                        None => Error::Message(message),
+                                    }
+                                })?;
+                            let declaration = module.syntax().text().to_string();
+                            let declaration = declaration.trim_end().trim_end_matches(';');
+                            self.items_by_name.insert(
+                                self.item_key(&name),
+                                CodeBlock::new().other_user_code(format!(
+                                    "{} {{\n{}\n}}",
+                                    declaration, contents
+                                )),
+                            );
                         } else {
                             code_out = code_out.with_segment(segment);
                         }
                     }
+                    ast::Item::Static(static_item) if static_item.mut_token().is_some() => {
+                        // Every evaluation loads a freshly compiled crate, so any changes made to
+                        // a `static mut` would be silently lost at the end of the evaluation.
+                        let range = static_item.syntax().text_range() - node.text_range().start();
+                        let span = Span::from_segment(&segment, range).ok_or_else(|| {
+                            err!("Internal error: static item not from user code")
+                        })?;
+                        return Err(Error::CompilationErrors(vec![
+                            CompilationError::from_segment_span(
+                                &segment,
+                                SpannedMessage::from_segment_span(&segment, span),
+                                "`static mut` isn't supported, since changes to it wouldn't persist \
+                                 between evaluations. Consider using a variable instead."
+                                    .to_owned(),
+                            ),
+                        ]));
+                    }
                     ast::Item::Use(use_stmt) => {
                         if let Some(use_tree) = use_stmt.use_tree() {
                             if self.config.expand_use_statements {
@@ -1700,18 +4828,7 @@ impl ContextState {
                                 // traceability back to the original user's code, so isn't so useful
                                 // for analysis purposes.
                                 crate::use_trees::use_tree_names_do(&use_tree, &mut |import| {
-                                    match import {
-                                        Import::Unnamed(code) => {
-                                            self.unnamed_items
-                                                .push(CodeBlock::new().other_user_code(code));
-                                        }
-                                        Import::Named { name, code } => {
-                                            self.items_by_name.insert(
-                                                name,
-                                                CodeBlock::new().other_user_code(code),
-                                            );
-                                        }
-                                    }
+                                    self.register_module_import(import);
                                 });
                             } else {
                                 // This mode finds all names that the use statement expands to, then
@@ -1723,11 +4840,11 @@ impl ContextState {
                                 // through away the state after we're done with analysis.
                                 crate::use_trees::use_tree_names_do(&use_tree, &mut |import| {
                                     if let Import::Named { name, .. } = import {
-                                        self.items_by_name.remove(&name);
+                                        let key = self.item_key(&name);
+                                        self.items_by_name.remove(&key);
                                     }
                                 });
-                                self.unnamed_items
-                                    .push(CodeBlock::new().with_segment(segment));
+                                self.add_unnamed_item(CodeBlock::new().with_segment(segment));
                             }
                         } else {
                             // No use-tree probably means something is malformed, just put it into
@@ -1738,8 +4855,8 @@ impl ContextState {
                     item => {
                         let item_block = CodeBlock::new().with_segment(segment);
                         if let Some(item_name) = item::item_name(&item) {
-                            *self.items_by_name.entry(item_name.to_owned()).or_default() =
-                                item_block;
+                            let item_name = self.item_key(&item_name);
+                            *self.items_by_name.entry(item_name.clone()).or_default() = item_block;
                             previous_item_name = Some(item_name);
                         } else if let Some(item_name) = &previous_item_name {
                             // unwrap below should never fail because we put
@@ -1750,8 +4867,15 @@ impl ContextState {
                                 .get_mut(item_name)
                                 .unwrap()
                                 .modify(move |block_for_name| block_for_name.add_all(item_block));
+                        } else if let Some(key) = match &item {
+                            ast::Item::Impl(impl_) => ImplKey::from_impl(impl_)
+                                .map(|key| key.in_module(self.current_module.clone())),
+                            _ => None,
+                        } {
+                            self.impls.retain(|(existing, _)| !key.replaces(existing));
+                            self.impls.push((key, item_block));
                         } else {
-                            self.unnamed_items.push(item_block);
+                            self.add_unnamed_item(item_block);
                         }
                     }
                 }
@@ -1762,11 +4886,78 @@ impl ContextState {
         Ok(code_out)
     }
 
+    fn register_import(&mut self, import: Import) {
+        match import {
+            Import::Unnamed(code) => {
+                self.unnamed_items
+                    .push(CodeBlock::new().other_user_code(code));
+            }
+            Import::Named { name, code } => {
+                self.items_by_name
+                    .insert(name, CodeBlock::new().other_user_code(code));
+            }
+        }
+    }
+
+    /// Like `register_import`, but places the import in any module opened by `:module`.
+    fn register_module_import(&mut self, import: Import) {
+        match import {
+            Import::Unnamed(code) => {
+                self.add_unnamed_item(CodeBlock::new().other_user_code(code));
+            }
+            Import::Named { name, code } => {
+                self.items_by_name
+                    .insert(self.item_key(&name), CodeBlock::new().other_user_code(code));
+            }
+        }
+    }
+
+    /// Returns the key under which an item named `name` should be stored, taking into account any
+    /// module opened by `:module`.
+    fn item_key(&self, name: &str) -> String {
+        match &self.current_module {
+            Some(module) => format!("{}::{}", module, name),
+            None => name.to_owned(),
+        }
+    }
+
+    fn add_unnamed_item(&mut self, item: CodeBlock) {
+        match &self.current_module {
+            Some(module) => self
+                .module_unnamed_items
+                .entry(module.clone())
+                .or_default()
+                .push(item),
+            None => self.unnamed_items.push(item),
+        }
+    }
+
+    fn apply_prelude(&mut self) {
+        for statement in self.config.prelude.clone() {
+            // Entries were validated when they were added.
+            if let Ok(imports) = parse_use_statement(&statement) {
+                for import in imports {
+                    self.register_import(import);
+                }
+            }
+        }
+    }
+
     fn dependency_lib_names(&self) -> Result<Vec<String>> {
         use crate::cargo_metadata;
         cargo_metadata::get_library_names(&self.config)
     }
 
+    /// Returns the names of the variables that earlier statements in the cell being applied have
+    /// defined.
+    fn variables_defined_in_cell(&self) -> Vec<String> {
+        self.variable_states
+            .iter()
+            .filter(|(_, variable_state)| variable_state.defined_at == self.build_num)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     fn record_new_locals(
         &mut self,
         pat: ast::Pat,
@@ -1799,6 +4990,30 @@ impl ContextState {
         }
     }
 
+    /// Marks variables from earlier cells that `node` refers to as referenced. This is best-effort,
+    /// since we can't tell for example whether an identifier within a macro invocation or a
+    /// closure refers to a variable or to something else with the same name.
+    fn record_variable_references(&mut self, node: &SyntaxNode) {
+        for token in node
+            .descendants_with_tokens()
+            .filter_map(|element| element.into_token())
+        {
+            if token.kind() != SyntaxKind::IDENT
+                || !token.parent().map_or(false, |parent| {
+                    ast::NameRef::can_cast(parent.kind()) || ast::TokenTree::can_cast(parent.kind())
+                })
+            {
+                continue;
+            }
+            if let Some(variable_state) = self.variable_states.get_mut(token.text()) {
+                // Variables defined by the current cell have the current build number.
+                if variable_state.defined_at < self.build_num {
+                    variable_state.referenced = true;
+                }
+            }
+        }
+    }
+
     fn record_local(
         &mut self,
         pat_ident: ast::IdentPat,
@@ -1818,6 +5033,11 @@ impl ContextState {
             _ => "String".to_owned(),
         };
         if let Some(name) = ast::HasName::name(&pat_ident) {
+            // Pins are by name, so redefining a pinned variable keeps it pinned.
+            let pinned = self
+                .variable_states
+                .get(name.text().as_str())
+                .map_or(false, |state| state.pinned);
             self.variable_states.insert(
                 name.text().to_string(),
                 VariableState {
@@ -1836,12 +5056,350 @@ impl ContextState {
                             range,
                         }
                     }),
+                    defined_at: self.build_num,
+                    pinned,
+                    referenced: false,
+                    clone_before_move: false,
                 },
             );
         }
     }
 }
 
+/// Returns the first `return` expression within `node` that would return from the enclosing
+/// function, ignoring any within closures or nested items.
+fn find_top_level_return(node: &SyntaxNode) -> Option<ast::ReturnExpr> {
+    let mut preorder = node.preorder();
+    while let Some(event) = preorder.next() {
+        if let ra_ap_syntax::WalkEvent::Enter(child) = event {
+            if let Some(return_expr) = ast::ReturnExpr::cast(child.clone()) {
+                return Some(return_expr);
+            }
+            if ast::ClosureExpr::can_cast(child.kind()) || ast::Item::can_cast(child.kind()) {
+                preorder.skip_subtree();
+            }
+        }
+    }
+    None
+}
+
+/// Returns whether `node` contains a token of the specified kind. Tokens within macro invocations
+/// are included, but not those within strings or comments.
+fn contains_token(node: &SyntaxNode, kind: SyntaxKind) -> bool {
+    node.descendants_with_tokens()
+        .any(|element| element.kind() == kind)
+}
+
+/// Returns everything in `code` that looks like an identifier. When looking for references to
+/// variables, we err on the side of treating anything that looks like a variable's name as a
+/// reference to it. This includes names within string literals, since format strings can capture
+/// variables.
+fn identifiers(code: &str) -> impl Iterator<Item = &str> {
+    static IDENT_RE: OnceCell<Regex> = OnceCell::new();
+    let ident_re = IDENT_RE.get_or_init(|| Regex::new("[A-Za-z_][A-Za-z0-9_]*").unwrap());
+    ident_re.find_iter(code).map(|ident| ident.as_str())
+}
+
+/// Returns the names of the variables that `pat` binds, without duplicates.
+fn pattern_variable_names(pat: &ast::Pat) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for ident in pat.syntax().descendants().filter_map(ast::IdentPat::cast) {
+        if let Some(name) = ast::HasName::name(&ident) {
+            let name = name.text().to_string();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Returns a block containing `segment`, followed by a semicolon if it doesn't already end with
+/// one.
+fn with_semicolon(segment: Segment) -> CodeBlock {
+    let needs_semicolon = !segment.code.trim_end().ends_with(';');
+    let code = CodeBlock::new().with_segment(segment);
+    if needs_semicolon {
+        code.generated(";")
+    } else {
+        code
+    }
+}
+
+/// Wraps a statement that uses `?` for `:try`. `body` runs in a closure from which `?` can return
+/// errors. If it returns a value, that's bound to `binding`, otherwise we leave the cell via an
+/// early exit.
+fn try_wrapped_statement(
+    binding: &str,
+    body: CodeBlock,
+    defined_variables: Vec<String>,
+) -> CodeBlock {
+    CodeBlock::new()
+        .generated(format!(
+            "let {} = match (|| -> std::result::Result<_, EvcxrUserCodeError> {{",
+            binding
+        ))
+        .add_all(body)
+        .generated("})() {")
+        .generated("Ok(evcxr_value) => evcxr_value,")
+        .generated("Err(_) => {")
+        .early_exit(defined_variables)
+        .generated("}};")
+}
+
+/// Wraps a let-else statement whose `else` branch uses `return` to leave the cell. `body` runs in a
+/// closure, so that the `return` leaves that. If the pattern matched, `body` sets
+/// `evcxr_let_else_value` to the bound values, which are then bound to `binding`, otherwise we
+/// leave the cell via an early exit.
+fn let_else_wrapped_statement(
+    binding: &str,
+    body: CodeBlock,
+    defined_variables: Vec<String>,
+) -> CodeBlock {
+    CodeBlock::new()
+        .generated(format!("let {} = {{", binding))
+        .generated("let mut evcxr_let_else_value = None;")
+        .generated("(|| {")
+        .add_all(body)
+        .generated("})();")
+        .generated("match evcxr_let_else_value {")
+        .generated("Some(evcxr_value) => evcxr_value,")
+        .generated("None => {")
+        .early_exit(defined_variables)
+        .generated("}}};")
+}
+
+/// Returns the name that the linker knows the dynamic library at `path` by. e.g. `foo` for
+/// `libfoo.so` on Linux or `foo.dll` on Windows.
+fn dylib_name(path: &Path) -> Option<String> {
+    let file_name = path.file_name()?.to_str()?;
+    let name = file_name.strip_suffix(std::env::consts::DLL_SUFFIX)?;
+    let name = name
+        .strip_prefix(std::env::consts::DLL_PREFIX)
+        .unwrap_or(name);
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// Returns the part of an item's code that comes before its body or value, with attributes and
+/// comments removed and whitespace collapsed. e.g. `fn foo(x: i32) -> i32`.
+fn item_signature(code: &str) -> String {
+    use ra_ap_syntax::ast::HasModuleItem;
+    let item = match ast::SourceFile::parse(code).tree().items().next() {
+        Some(item) => item,
+        None => return code.split_whitespace().collect::<Vec<_>>().join(" "),
+    };
+    let mut signature = String::new();
+    for child in item.syntax().children_with_tokens() {
+        match child.kind() {
+            SyntaxKind::ATTR | SyntaxKind::COMMENT => continue,
+            SyntaxKind::BLOCK_EXPR
+            | SyntaxKind::RECORD_FIELD_LIST
+            | SyntaxKind::VARIANT_LIST
+            | SyntaxKind::ASSOC_ITEM_LIST
+            | SyntaxKind::ITEM_LIST
+            | SyntaxKind::TOKEN_TREE
+            | SyntaxKind::EQ
+            | SyntaxKind::SEMICOLON => break,
+            _ => signature.push_str(&child.to_string()),
+        }
+    }
+    signature.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the paths of the glob imports (`use path::*;`) that an ambiguity error (E0659) says
+/// the ambiguous name could refer to.
+fn ambiguous_glob_import_paths(error: &CompilationError) -> Vec<String> {
+    let mut paths = Vec::new();
+    for child in error.json["children"].members() {
+        if !child["message"]
+            .as_str()
+            .map_or(false, |message| message.contains("refer to"))
+        {
+            continue;
+        }
+        for span in child["spans"].members() {
+            for line in span["text"].members() {
+                let path = line["text"]
+                    .as_str()
+                    .map(str::trim)
+                    .and_then(|text| text.strip_prefix("use "))
+                    .and_then(|text| text.strip_suffix("::*;"))
+                    .map(str::trim);
+                if let Some(path) = path {
+                    if !paths.iter().any(|existing| existing == path) {
+                        paths.push(path.to_owned());
+                    }
+                }
+            }
+        }
+    }
+    paths
+}
+
+/// Returns whether the value of the final expression `node` can be bound to a variable by
+/// `:autobind`. Expressions that refer to existing values, like `a` or `a.b`, would move them into
+/// the new variable, while references can't be stored.
+fn can_autobind(node: &SyntaxNode) -> bool {
+    let mut expr = ast::Expr::cast(node.clone());
+    while let Some(ast::Expr::ParenExpr(paren)) = &expr {
+        expr = paren.expr();
+    }
+    match expr {
+        Some(ast::Expr::PrefixExpr(prefix)) => prefix.op_kind() != Some(ast::UnaryOp::Deref),
+        Some(
+            ast::Expr::PathExpr(_)
+            | ast::Expr::FieldExpr(_)
+            | ast::Expr::IndexExpr(_)
+            | ast::Expr::RefExpr(_),
+        )
+        | None => false,
+        Some(_) => true,
+    }
+}
+
+/// Returns the macro call if `node` is a statement or expression that consists of just a macro
+/// invocation.
+fn macro_call_statement(node: &SyntaxNode) -> Option<ast::MacroCall> {
+    let expr = match ast::ExprStmt::cast(node.clone()) {
+        Some(expr_stmt) => expr_stmt.expr()?,
+        None => ast::Expr::cast(node.clone())?,
+    };
+    match expr {
+        ast::Expr::MacroExpr(macro_expr) => macro_expr.macro_call(),
+        _ => None,
+    }
+}
+
+/// Returns the string literals that give relative paths to `include_str!`, `include_bytes!` or
+/// `include!`, together with the paths.
+fn relative_include_paths(node: &SyntaxNode) -> Vec<(ra_ap_syntax::SyntaxToken, String)> {
+    node.descendants()
+        .filter_map(ast::MacroCall::cast)
+        .filter_map(|macro_call| include_path(&macro_call))
+        .filter(|(_, _, path)| Path::new(path).is_relative())
+        .map(|(_, token, path)| (token, path))
+        .collect()
+}
+
+/// If `macro_call` is `include_str!`, `include_bytes!` or `include!` with a literal path, returns
+/// the name of the macro, the string literal and the path.
+fn include_path(
+    macro_call: &ast::MacroCall,
+) -> Option<(String, ra_ap_syntax::SyntaxToken, String)> {
+    let name = macro_call.path()?.segment()?.name_ref()?.text().to_string();
+    if !matches!(name.as_str(), "include_str" | "include_bytes" | "include") {
+        return None;
+    }
+    let token = macro_call
+        .token_tree()?
+        .syntax()
+        .children_with_tokens()
+        .filter_map(|element| element.into_token())
+        .find(|token| token.kind() == SyntaxKind::STRING)?;
+    let path = ast::String::cast(token.clone())?.value()?.into_owned();
+    Some((name, token, path))
+}
+
+/// Returns the first `unsafe` keyword within `node`. Since we look at tokens, occurrences within
+/// strings and comments aren't matched.
+fn find_unsafe_keyword(node: &SyntaxNode) -> Option<ra_ap_syntax::SyntaxToken> {
+    node.descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .find(|token| token.kind() == SyntaxKind::UNSAFE_KW)
+}
+
+/// Converts `std::fmt::Write as _` or `use std::fmt::Write as _` into `use std::fmt::Write as _;`.
+fn normalize_use_statement(import: &str) -> String {
+    let import = import.trim().trim_end_matches(';').trim_end();
+    let import = import.strip_prefix("use ").unwrap_or(import).trim_start();
+    format!("use {};", import)
+}
+
+/// Returns the names imported by `statement`, which should be a single use statement.
+fn parse_use_statement(statement: &str) -> Result<Vec<Import>, Error> {
+    use ra_ap_syntax::ast::HasModuleItem;
+    let parsed = ast::SourceFile::parse(statement);
+    let mut imports = Vec::new();
+    if parsed.errors().is_empty() {
+        for item in parsed.tree().items() {
+            if let Some(use_tree) = ast::Use::cast(item.syntax().clone()).and_then(|u| u.use_tree())
+            {
+                crate::use_trees::use_tree_names_do(&use_tree, &mut |import| imports.push(import));
+            }
+        }
+    }
+    if imports.is_empty() {
+        bail!("Invalid import `{}`", statement);
+    }
+    Ok(imports)
+}
+
+/// Parses function signatures separated by semicolons, as accepted by `:extern`, returning the name
+/// and declaration of each function.
+fn parse_extern_functions(signatures: &str) -> Result<Vec<(String, String)>, Error> {
+    use ra_ap_syntax::ast::HasModuleItem;
+    let declarations: Vec<&str> = signatures
+        .split(';')
+        .map(str::trim)
+        .filter(|declaration| !declaration.is_empty())
+        .collect();
+    let block: String = declarations
+        .iter()
+        .map(|declaration| format!("{};\n", declaration))
+        .collect();
+    let parsed = ast::SourceFile::parse(&format!("extern \"C\" {{\n{}}}", block));
+    if !parsed.errors().is_empty() {
+        bail!("Invalid function signatures `{}`", signatures.trim());
+    }
+    let items: Vec<ast::ExternItem> = parsed
+        .tree()
+        .items()
+        .filter_map(|item| ast::ExternBlock::cast(item.syntax().clone()))
+        .filter_map(|extern_block| extern_block.extern_item_list())
+        .flat_map(|item_list| item_list.extern_items())
+        .collect();
+    if items.is_empty() || items.len() != declarations.len() {
+        bail!("Invalid function signatures `{}`", signatures.trim());
+    }
+    items
+        .iter()
+        .zip(declarations)
+        .map(|(item, declaration)| match item {
+            ast::ExternItem::Fn(function) => match ast::HasName::name(function) {
+                Some(name) => Ok((name.text().to_string(), declaration.to_owned())),
+                None => bail!("Missing function name in `{}`", declaration),
+            },
+            _ => bail!("Only functions can be declared, got `{}`", declaration),
+        })
+        .collect()
+}
+
+/// Reads the source of a module declared as `mod name;`, looking for `name.rs` or `name/mod.rs` in
+/// `dir`.
+fn read_module_file(name: &str, dir: &Path) -> Result<String, String> {
+    let candidates = [
+        dir.join(format!("{}.rs", name)),
+        dir.join(name).join("mod.rs"),
+    ];
+    for candidate in &candidates {
+        match std::fs::read_to_string(candidate) {
+            Ok(contents) => return Ok(contents),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(format!("Failed to read {}: {}", candidate.display(), error)),
+        }
+    }
+    Err(format!(
+        "Couldn't find a file for module `{}`. Looked for {} and {}",
+        name,
+        candidates[0].display(),
+        candidates[1].display()
+    ))
+}
+
 fn replace_reserved_words_in_type(ty: &str) -> String {
     static RESERVED_WORDS: OnceCell<Regex> = OnceCell::new();
     RESERVED_WORDS
@@ -1857,6 +5415,113 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_split_command_args() {
+        assert_eq!(
+            split_command_args(r#"tree -e 'normal, build' --format "{p} \"x\"" a\ b"#).unwrap(),
+            vec![
+                "tree",
+                "-e",
+                "normal, build",
+                "--format",
+                "{p} \"x\"",
+                "a b"
+            ]
+        );
+        assert_eq!(split_command_args("  ").unwrap(), Vec::<String>::new());
+        assert_eq!(split_command_args("''").unwrap(), vec![""]);
+        assert!(split_command_args("tree 'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_cargo_subcommand() {
+        let subcommand = |args: &str| {
+            cargo_subcommand(&split_command_args(args).unwrap())
+                .map(|subcommand| subcommand.map(str::to_owned))
+        };
+        assert_eq!(
+            subcommand("tree --depth 0").unwrap().as_deref(),
+            Some("tree")
+        );
+        assert_eq!(
+            subcommand("--color never clean").unwrap().as_deref(),
+            Some("clean")
+        );
+        assert_eq!(
+            subcommand("--color=never -v clean").unwrap().as_deref(),
+            Some("clean")
+        );
+        assert_eq!(subcommand("--version").unwrap(), None);
+        assert!(subcommand("+nightly clean").is_err());
+    }
+
+    #[test]
+    fn test_cargo_build_command_jobs() {
+        let mut config = Config::new(PathBuf::from("/tmp"));
+        let args = |command: Command| {
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert!(!args(config.cargo_build_command("rustc")).contains(&"--jobs".to_owned()));
+        config.jobs = Some(3);
+        let build_args = args(config.cargo_build_command("rustc"));
+        let jobs_index = build_args.iter().position(|arg| arg == "--jobs").unwrap();
+        assert_eq!(build_args[jobs_index + 1], "3");
+        // Metadata doesn't build anything, so doesn't accept --jobs.
+        assert!(!args(config.cargo_command("metadata")).contains(&"--jobs".to_owned()));
+    }
+
+    #[test]
+    fn test_cargo_build_command_features() {
+        let mut config = Config::new(PathBuf::from("/tmp"));
+        let args = |command: Command| {
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert!(!args(config.cargo_build_command("check")).contains(&"--features".to_owned()));
+        config.features = vec!["a".to_owned(), "serde/derive".to_owned()];
+        let build_args = args(config.cargo_build_command("check"));
+        let features_index = build_args
+            .iter()
+            .position(|arg| arg == "--features")
+            .unwrap();
+        assert_eq!(build_args[features_index + 1], "a,serde/derive");
+    }
+
+    #[test]
+    fn test_cargo_build_command_parallel_frontend() {
+        let mut config = Config::new(PathBuf::from("/tmp"));
+        let rustflags = |command: Command| {
+            command
+                .get_envs()
+                .find(|(key, _)| *key == "RUSTFLAGS")
+                .and_then(|(_, value)| value)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        config.parallel_frontend = Some(4);
+        // Stable compilers don't accept -Z flags, so we leave them out.
+        assert_eq!(rustflags(config.cargo_build_command("rustc")), None);
+        config.toolchain = "nightly".to_owned();
+        assert!(rustflags(config.cargo_build_command("rustc"))
+            .unwrap()
+            .ends_with("-Z threads=4"));
+    }
+
+    #[test]
+    fn test_parse_rust_version() {
+        assert_eq!(parse_rust_version("1.70"), Some([1, 70, 0]));
+        assert_eq!(parse_rust_version("1.70.1"), Some([1, 70, 1]));
+        assert_eq!(parse_rust_version("1.76.0-nightly"), Some([1, 76, 0]));
+        assert_eq!(parse_rust_version("1"), None);
+        assert_eq!(parse_rust_version("1.70.0.1"), None);
+        assert_eq!(parse_rust_version("1.x"), None);
+        assert!(parse_rust_version("1.9").unwrap() < parse_rust_version("1.10").unwrap());
+    }
+
     #[test]
     fn test_replace_reserved_words_in_type() {
         use super::replace_reserved_words_in_type as repl;
@@ -1868,6 +5533,32 @@ mod tests {
         assert_eq!(repl("Bar<async::foo::Baz>"), "Bar<r#async::foo::Baz>");
     }
 
+    #[test]
+    fn test_item_signature() {
+        use super::item_signature;
+        assert_eq!(
+            item_signature("/// Docs\n#[inline]\nfn foo(x: i32) -> i32 {\n    x\n}"),
+            "fn foo(x: i32) -> i32"
+        );
+        assert_eq!(
+            item_signature("fn f<I: Iterator<Item = u8>>(i: I) { }"),
+            "fn f<I: Iterator<Item = u8>>(i: I)"
+        );
+        assert_eq!(
+            item_signature("fn f(x: [u8; 4]) -> [u8; 4] { x }"),
+            "fn f(x: [u8; 4]) -> [u8; 4]"
+        );
+        assert_eq!(
+            item_signature("const N: [u8; 2] = [1, 2];"),
+            "const N: [u8; 2]"
+        );
+        assert_eq!(
+            item_signature("struct Foo<T>\nwhere\n    T: Clone,\n{\n    t: T,\n}"),
+            "struct Foo<T> where T: Clone,"
+        );
+        assert_eq!(item_signature("struct Bar(u32);"), "struct Bar(u32)");
+    }
+
     fn create_state() -> ContextState {
         let config = Config::new(PathBuf::from("/dummy_path"));
         ContextState::new(config)
@@ -1887,7 +5578,7 @@ mod tests {
         let source_file = SourceFile::parse(&final_code.code_string()).ok().unwrap();
         let mut attrs: Vec<String> = source_file
             .attrs()
-            .map(|attr| attr.syntax().text().to_string().replace(" ", ""))
+            .map(|attr| attr.syntax().text().to_string().replace(' ', ""))
             .collect();
         attrs.sort();
         assert_eq!(
@@ -1899,4 +5590,92 @@ mod tests {
             ]
         );
     }
+
+    /// Returns the code that we'd try in turn to display the final expression of `code`, applying
+    /// one fallback each time.
+    fn final_expression_attempts(state: &mut ContextState, code: &str) -> Vec<String> {
+        let (user_code, code_info) = CodeBlock::from_original_user_code(code);
+        let mut user_code = state.apply(user_code, &code_info.nodes).unwrap();
+        let mut attempts = vec![user_code.code_string()];
+        loop {
+            let fallback = user_code.segments.iter().find_map(|segment| {
+                if let CodeKind::WithFallback(fallback) = &segment.kind {
+                    Some(fallback.clone())
+                } else {
+                    None
+                }
+            });
+            match fallback {
+                Some(fallback) => {
+                    user_code.apply_fallback(&fallback);
+                    attempts.push(user_code.code_string());
+                }
+                None => return attempts,
+            }
+        }
+    }
+
+    #[test]
+    fn test_final_future_display() {
+        let mut state = create_state();
+        // Outside of async mode, we just try evcxr_display then debug format.
+        assert_eq!(final_expression_attempts(&mut state, "f()").len(), 2);
+
+        state.async_mode = true;
+        // In async mode, we check for a future before falling back to debug format.
+        let attempts = final_expression_attempts(&mut state, "f()");
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[1].contains("evcxr_assert_future(&("));
+        assert!(attempts[1].contains("Future not awaited"));
+        assert!(!attempts[2].contains("evcxr_assert_future"));
+        assert!(attempts[2].contains("evcxr_send_text_plain(&format!"));
+
+        state.set_auto_await(true);
+        let attempts = final_expression_attempts(&mut state, "f()");
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[1].contains("evcxr_await("));
+        assert!(!attempts[1].contains("Future not awaited"));
+    }
+
+    #[test]
+    fn test_locked_versions() {
+        let mut state = create_state();
+        for (name, config) in [("regex", "\"1.0\""), ("rand", "\"0.8\"")] {
+            state.external_deps.insert(
+                name.to_owned(),
+                ExternalCrate::new(name.to_owned(), config.to_owned()).unwrap(),
+            );
+        }
+        state.set_locked_versions_from_lock_file(
+            "[[package]]\nname = \"regex\"\nversion = \"1.5.4\"\nsource = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+        );
+        let deps = state.format_cargo_deps();
+        assert!(deps.contains("regex = \"=1.5.4\"\n"));
+        assert!(deps.contains("rand = \"0.8\"\n"));
+    }
+
+    #[test]
+    fn test_dependencies_table() {
+        let mut state = create_state();
+        for (name, config) in [
+            ("regex", "\"1.0\""),
+            (
+                "foo",
+                "{ git = \"https://github.com/example/foo\", branch = \"main\" }",
+            ),
+            ("bar", "{ path = \"/work/bar\", features = [\"x\"] }"),
+        ] {
+            state.external_deps.insert(
+                name.to_owned(),
+                ExternalCrate::new(name.to_owned(), config.to_owned()).unwrap(),
+            );
+        }
+        assert_eq!(
+            state.dependencies_table(),
+            "[dependencies]\n\
+             bar = { path = \"/work/bar\", features = [\"x\"] }\n\
+             foo = { git = \"https://github.com/example/foo\", branch = \"main\" }\n\
+             regex = \"1.0\"\n"
+        );
+    }
 }
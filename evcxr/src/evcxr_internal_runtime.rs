@@ -18,6 +18,65 @@
 
 pub const VARIABLE_CHANGED_TYPE: &str = "EVCXR_VARIABLE_CHANGED_TYPE:";
 pub const USER_ERROR_OCCURRED: &str = "EVCXR_ERROR_OCCURRED";
+pub const VARIABLES_KEPT_ON_ERROR: &str = "EVCXR_VARIABLES_KEPT_ON_ERROR:";
+pub const VARIABLE_VALUES_MIME_TYPE: &str = "application/x-evcxr-variable-values+json";
+pub const TYPED_VALUE_MIME_TYPE: &str = "application/x-evcxr-typed-value";
+
+/// Whether the variable store is in use by this cell. Each cell is compiled separately, so has its
+/// own copy of this.
+static STORE_ACCESS: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(STORE_IDLE);
+const STORE_IDLE: u8 = 0;
+const STORE_IN_USE: u8 = 1;
+/// Set once this cell has been interrupted, after which the variable store belongs to later cells.
+const STORE_ABANDONED: u8 = 2;
+
+/// Held while a method of `VariableStore` runs.
+struct StoreAccess;
+
+impl StoreAccess {
+    fn acquire() -> StoreAccess {
+        use std::sync::atomic::Ordering;
+        loop {
+            match STORE_ACCESS.compare_exchange(
+                STORE_IDLE,
+                STORE_IN_USE,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return StoreAccess,
+                // The thread running this cell was abandoned when the cell was interrupted. It
+                // can't be stopped, but it can be kept away from the variable store.
+                Err(STORE_ABANDONED) => loop {
+                    std::thread::park();
+                },
+                Err(_) => std::thread::yield_now(),
+            }
+        }
+    }
+}
+
+impl Drop for StoreAccess {
+    fn drop(&mut self) {
+        STORE_ACCESS.store(STORE_IDLE, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Called by the runtime when this cell is interrupted. Waits for any use of the variable store
+/// that's in progress to finish, then blocks any further use of it by this cell.
+pub fn abandon_variable_store() {
+    use std::sync::atomic::Ordering;
+    while let Err(state) = STORE_ACCESS.compare_exchange(
+        STORE_IDLE,
+        STORE_ABANDONED,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    ) {
+        if state == STORE_ABANDONED {
+            return;
+        }
+        std::thread::yield_now();
+    }
+}
 
 pub struct VariableStore {
     variables: std::collections::HashMap<String, Box<dyn std::any::Any + 'static>>,
@@ -33,10 +92,12 @@ impl VariableStore {
     pub fn assert_copy_type<T: Copy>(&self, _: T) {}
 
     pub fn put_variable<T: 'static>(&mut self, name: &str, value: T) {
+        let _access = StoreAccess::acquire();
         self.variables.insert(name.to_owned(), Box::new(value));
     }
 
     pub fn check_variable<T: 'static>(&mut self, name: &str) -> bool {
+        let _access = StoreAccess::acquire();
         if let Some(v) = self.variables.get(name) {
             if v.downcast_ref::<T>().is_none() {
                 eprintln!(
@@ -51,6 +112,7 @@ impl VariableStore {
     }
 
     pub fn take_variable<T: 'static>(&mut self, name: &str) -> T {
+        let _access = StoreAccess::acquire();
         match self.variables.remove(name) {
             Some(v) => {
                 if let Ok(value) = v.downcast() {
@@ -69,6 +131,7 @@ impl VariableStore {
         name: &str,
         create: F,
     ) -> std::sync::Arc<T> {
+        let _access = StoreAccess::acquire();
         if let Some(value) = self
             .variables
             .entry(name.to_owned())
@@ -81,7 +144,13 @@ impl VariableStore {
         }
     }
 
+    pub fn drop_variable(&mut self, name: &str) {
+        let _access = StoreAccess::acquire();
+        self.variables.remove(name);
+    }
+
     pub fn merge(&mut self, mut other: VariableStore) {
+        let _access = StoreAccess::acquire();
         self.variables.extend(other.variables.drain());
     }
 }
@@ -89,3 +158,147 @@ impl VariableStore {
 pub fn create_variable_store() -> *mut VariableStore {
     Box::into_raw(Box::new(VariableStore::new()))
 }
+
+/// Implemented for types that have a natural JSON representation. Used when reporting variable
+/// values via `EvalContext::variable_values`.
+pub trait EvcxrToJson {
+    fn evcxr_to_json(&self) -> String;
+}
+
+macro_rules! evcxr_to_json_via_to_string {
+    ($($t:ty),*) => {
+        $(
+            impl EvcxrToJson for $t {
+                fn evcxr_to_json(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+evcxr_to_json_via_to_string!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, bool);
+
+macro_rules! evcxr_to_json_float {
+    ($($t:ty),*) => {
+        $(
+            impl EvcxrToJson for $t {
+                fn evcxr_to_json(&self) -> String {
+                    // JSON has no representation for NaN or infinity.
+                    if self.is_finite() {
+                        self.to_string()
+                    } else {
+                        "null".to_owned()
+                    }
+                }
+            }
+        )*
+    };
+}
+
+evcxr_to_json_float!(f32, f64);
+
+impl EvcxrToJson for str {
+    fn evcxr_to_json(&self) -> String {
+        json_string(self)
+    }
+}
+
+impl EvcxrToJson for String {
+    fn evcxr_to_json(&self) -> String {
+        json_string(self)
+    }
+}
+
+impl EvcxrToJson for char {
+    fn evcxr_to_json(&self) -> String {
+        json_string(&self.to_string())
+    }
+}
+
+impl<T: EvcxrToJson + ?Sized> EvcxrToJson for &T {
+    fn evcxr_to_json(&self) -> String {
+        (**self).evcxr_to_json()
+    }
+}
+
+impl<T: EvcxrToJson> EvcxrToJson for Option<T> {
+    fn evcxr_to_json(&self) -> String {
+        match self {
+            Some(value) => value.evcxr_to_json(),
+            None => "null".to_owned(),
+        }
+    }
+}
+
+impl<T: EvcxrToJson> EvcxrToJson for [T] {
+    fn evcxr_to_json(&self) -> String {
+        let values: Vec<String> = self.iter().map(EvcxrToJson::evcxr_to_json).collect();
+        format!("[{}]", values.join(","))
+    }
+}
+
+impl<T: EvcxrToJson> EvcxrToJson for Vec<T> {
+    fn evcxr_to_json(&self) -> String {
+        self.as_slice().evcxr_to_json()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Wraps a reference to a variable so that `(&&JsonProbe(&v)).evcxr_json()` picks `JsonViaTrait`
+/// when the variable's type implements `EvcxrToJson` and `JsonFallback` otherwise.
+pub struct JsonProbe<'a, T>(pub &'a T);
+
+pub trait JsonViaTrait {
+    fn evcxr_json(&self) -> Option<String>;
+}
+
+impl<'a, T: EvcxrToJson> JsonViaTrait for &JsonProbe<'a, T> {
+    fn evcxr_json(&self) -> Option<String> {
+        Some(self.0.evcxr_to_json())
+    }
+}
+
+pub trait JsonFallback {
+    fn evcxr_json(&self) -> Option<String>;
+}
+
+impl<'a, T> JsonFallback for JsonProbe<'a, T> {
+    fn evcxr_json(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Sends a JSON object mapping each variable name to either `{"value": ...}` or, if the variable
+/// has no JSON representation, `null`.
+pub fn send_variable_values(values: &[(&str, Option<String>)]) {
+    let entries: Vec<String> = values
+        .iter()
+        .map(|(name, value)| match value {
+            Some(value) => format!("{}:{{\"value\":{}}}", json_string(name), value),
+            None => format!("{}:null", json_string(name)),
+        })
+        .collect();
+    println!(
+        "EVCXR_BEGIN_CONTENT {}\n{{{}}}\nEVCXR_END_CONTENT",
+        VARIABLE_VALUES_MIME_TYPE,
+        entries.join(",")
+    );
+}
@@ -13,12 +13,35 @@
 // limitations under the License.
 
 use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
 
 /// Returns the name of an item if it has one.
 pub(crate) fn item_name(item: &ast::Item) -> Option<String> {
     item_ident(item).map(|ident| format!("{}", ident))
 }
 
+/// Returns the keyword that introduces `item`, which we use to describe what kind of item it is.
+pub(crate) fn item_kind(item: &ast::Item) -> &'static str {
+    match item {
+        ast::Item::Const(_) => "const",
+        ast::Item::Enum(_) => "enum",
+        ast::Item::ExternBlock(_) => "extern",
+        ast::Item::ExternCrate(_) => "extern crate",
+        ast::Item::Fn(_) => "fn",
+        ast::Item::Impl(_) => "impl",
+        ast::Item::MacroCall(_) => "macro call",
+        ast::Item::MacroRules(_) => "macro_rules!",
+        ast::Item::MacroDef(_) => "macro",
+        ast::Item::Module(_) => "mod",
+        ast::Item::Static(_) => "static",
+        ast::Item::Struct(_) => "struct",
+        ast::Item::Trait(_) => "trait",
+        ast::Item::TypeAlias(_) => "type",
+        ast::Item::Union(_) => "union",
+        ast::Item::Use(_) => "use",
+    }
+}
+
 /// Returns the ident of an item if it has one.
 fn item_ident(item: &ast::Item) -> Option<ast::Name> {
     match item {
@@ -36,3 +59,72 @@ fn item_ident(item: &ast::Item) -> Option<ast::Name> {
         _ => None,
     }
 }
+
+/// Identifies an impl block, so that re-evaluating an impl block can replace the version that was
+/// previously evaluated rather than adding a duplicate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ImplKey {
+    self_ty: String,
+    trait_: Option<String>,
+    /// Names of the associated items (functions, constants and types) in the impl block.
+    item_names: Vec<String>,
+    /// The module opened by `:module` in which the impl block was defined, if any.
+    module: Option<String>,
+}
+
+impl ImplKey {
+    pub(crate) fn from_impl(impl_: &ast::Impl) -> Option<ImplKey> {
+        fn without_whitespace(node: &impl AstNode) -> String {
+            node.syntax()
+                .text()
+                .to_string()
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect()
+        }
+        let self_ty = without_whitespace(&impl_.self_ty()?);
+        let trait_ = impl_.trait_().map(|t| without_whitespace(&t));
+        let item_names = impl_
+            .assoc_item_list()
+            .map(|list| {
+                list.assoc_items()
+                    .filter_map(|item| match item {
+                        ast::AssocItem::Fn(f) => ast::HasName::name(&f),
+                        ast::AssocItem::Const(c) => ast::HasName::name(&c),
+                        ast::AssocItem::TypeAlias(t) => ast::HasName::name(&t),
+                        ast::AssocItem::MacroCall(_) => None,
+                    })
+                    .map(|name| name.text().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(ImplKey {
+            self_ty,
+            trait_,
+            item_names,
+            module: None,
+        })
+    }
+
+    pub(crate) fn in_module(self, module: Option<String>) -> ImplKey {
+        ImplKey { module, ..self }
+    }
+
+    pub(crate) fn module(&self) -> Option<&str> {
+        self.module.as_deref()
+    }
+
+    /// Returns whether an impl block with this key should replace an existing block with key
+    /// `other`. A trait can only be implemented once for a type, whereas a type can have multiple
+    /// inherent impl blocks, provided they define different items.
+    pub(crate) fn replaces(&self, other: &ImplKey) -> bool {
+        self.self_ty == other.self_ty
+            && self.trait_ == other.trait_
+            && self.module == other.module
+            && (self.trait_.is_some()
+                || self
+                    .item_names
+                    .iter()
+                    .any(|name| other.item_names.contains(name)))
+    }
+}
@@ -18,30 +18,43 @@ extern crate sig;
 
 #[macro_use]
 mod errors;
+mod backtrace;
+mod cargo_lock;
 mod cargo_metadata;
 mod child_process;
 mod code_block;
 mod command_context;
 mod crash_guard;
 mod crate_config;
+mod display_width;
+mod doctest;
 mod eval_context;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
 mod item;
 mod module;
+mod paths;
 mod runtime;
 mod rust_analyzer;
 mod statement_splitter;
 mod use_trees;
 
+pub use crate::child_process::InterruptHandle;
 pub use crate::command_context::CommandContext;
+pub use crate::command_context::CustomCommandHandler;
+pub use crate::doctest::TEST_RESULTS_MIME_TYPE;
 pub use crate::errors::Error;
 pub use crate::errors::{CompilationError, Theme};
 pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::eval_context::EvalStatus;
+pub use crate::eval_context::TypeRenderer;
+pub use crate::eval_context::VariableValue;
+pub use crate::eval_context::PARTIAL_LINE_MARKER;
 pub use crate::runtime::runtime_hook;
+pub use crate::statement_splitter::InputStatus;
 pub use rust_analyzer::Completions;
 
 /// Return the directory that evcxr tools should use for their configuration.
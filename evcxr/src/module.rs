@@ -20,6 +20,7 @@ use crate::eval_context::Config;
 use crate::eval_context::ContextState;
 use once_cell::sync::OnceCell;
 use regex::Regex;
+use std::collections::VecDeque;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -51,6 +52,27 @@ fn write_file(dir: &Path, basename: &str, contents: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Deletes whatever it can from within `dir`, ignoring failures.
+fn remove_dir_contents(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Uses the entry's own file type so that we don't follow symlinks.
+        if entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_dir())
+        {
+            remove_dir_contents(&path);
+            let _ = fs::remove_dir(&path);
+        } else {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
 /// On Mac, if we copy the dylib, we get intermittent failures where we end up
 /// with the previous version of the file when we shouldn't. On windows, if
 /// rename the file, we get errors subsequently when something (perhaps the
@@ -85,9 +107,12 @@ pub(crate) struct Module {
     pub(crate) tmpdir: PathBuf,
     build_num: i32,
     target: String,
+    /// The shared objects produced by our most recent builds, oldest first.
+    so_files: VecDeque<PathBuf>,
 }
 
-const CRATE_NAME: &str = "ctx";
+/// The default package name of the crate that we generate.
+pub(crate) const CRATE_NAME: &str = "ctx";
 
 impl Module {
     pub(crate) fn new(tmpdir: PathBuf) -> Result<Module, Error> {
@@ -95,6 +120,7 @@ impl Module {
             tmpdir,
             build_num: 0,
             target: get_host_target()?,
+            so_files: VecDeque::new(),
         };
         Ok(module)
     }
@@ -107,9 +133,9 @@ impl Module {
         self.tmpdir.join("target").join(&self.target)
     }
 
-    fn so_path(&self) -> PathBuf {
+    fn so_path(&self, crate_name: &str) -> PathBuf {
         self.deps_dir()
-            .join(shared_object_name_from_crate_name(CRATE_NAME))
+            .join(shared_object_name_from_crate_name(crate_name))
     }
 
     fn src_dir(&self) -> PathBuf {
@@ -124,13 +150,18 @@ impl Module {
         std::fs::read_to_string(self.src_dir().join("lib.rs"))
     }
 
-    // Writes Cargo.toml. Should be called before compile.
+    // Writes Cargo.toml, and Cargo.lock if dependencies are frozen. Should be called before
+    // compile.
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<(), Error> {
         write_file(
             self.crate_dir(),
             "Cargo.toml",
             &self.get_cargo_toml_contents(state),
-        )
+        )?;
+        if let Some(lock_contents) = state.frozen_lock() {
+            write_file(self.crate_dir(), "Cargo.lock", lock_contents)?;
+        }
+        Ok(())
     }
 
     pub(crate) fn check(
@@ -140,7 +171,7 @@ impl Module {
     ) -> Result<Vec<CompilationError>, Error> {
         self.write_code(code_block)?;
         let output = config
-            .cargo_command("check")
+            .cargo_build_command("check")
             .arg("--message-format=json")
             .output();
 
@@ -157,10 +188,13 @@ impl Module {
         code_block: &CodeBlock,
         config: &Config,
     ) -> Result<SoFile, Error> {
-        let mut command = config.cargo_command("rustc");
+        let mut command = config.cargo_build_command("rustc");
         if config.time_passes && config.toolchain != "nightly" {
             bail!("time_passes option requires nightly compiler");
         }
+        if config.parallel_frontend.is_some() && !config.uses_nightly() {
+            bail!("parallel_frontend option requires nightly compiler");
+        }
 
         command
             .arg("--target")
@@ -175,6 +209,7 @@ impl Module {
                 .arg("-C")
                 .arg(format!("link-arg=-fuse-ld={}", config.linker));
         }
+        command.args(config.dylib_link_args());
         if let Some(sccache) = &config.sccache {
             command.env("RUSTC_WRAPPER", sccache);
         }
@@ -199,12 +234,55 @@ impl Module {
         // be able to load the result of the next compilation. Also, on Windows,
         // a loaded dll gets locked, so we couldn't even compile a second time
         // if we didn't load a different file.
-        rename_or_copy_so_file(&self.so_path(), &copied_so_file)?;
+        rename_or_copy_so_file(&self.so_path(&config.crate_name), &copied_so_file)?;
+        self.so_files.push_back(copied_so_file.clone());
+        self.prune_so_files(config.keep_generations);
         Ok(SoFile {
             path: copied_so_file,
         })
     }
 
+    /// Deletes the shared objects from all but the last `keep` builds, so that long sessions don't
+    /// use ever more disk space. Shared objects are only read when they're first loaded, so this
+    /// doesn't affect code that's already been loaded. On Windows, loaded DLLs are locked, so
+    /// deleting them fails, in which case we leave them in place.
+    fn prune_so_files(&mut self, keep: usize) {
+        while self.so_files.len() > keep {
+            if let Some(so_file) = self.so_files.pop_front() {
+                let _ = fs::remove_file(so_file);
+            }
+        }
+    }
+
+    /// Deletes everything that's been built for the generated crate, including compiled
+    /// dependencies, so that the next compilation starts from scratch. Shared objects that are
+    /// already loaded aren't affected. As with pruning, files that can't be deleted (e.g. loaded
+    /// DLLs on Windows) are left in place.
+    pub(crate) fn clean_build_artifacts(&mut self) {
+        remove_dir_contents(&self.tmpdir.join("target"));
+        self.so_files.retain(|so_file| so_file.exists());
+    }
+
+    /// Deletes what's been built for `package`, so that it's rebuilt the next time we compile.
+    pub(crate) fn clean_dep(&self, package: &str, config: &Config) -> Result<(), Error> {
+        let mut command = config.cargo_command("clean");
+        command
+            .arg("-p")
+            .arg(package)
+            .arg("--target")
+            .arg(&self.target)
+            .env("CARGO_TARGET_DIR", "target");
+        run_cargo_maintenance(command, "clean", package)
+    }
+
+    /// Updates `package` in Cargo.lock to the latest version allowed, e.g. the latest commit of a
+    /// git dependency.
+    pub(crate) fn update_dep(&self, package: &str, config: &Config) -> Result<(), Error> {
+        let mut command = config.cargo_command("update");
+        command.arg("-p").arg(package);
+        run_cargo_maintenance(command, "update", package)
+    }
+
     fn write_code(&self, code_block: &CodeBlock) -> Result<(), Error> {
         write_file(&self.src_dir(), "lib.rs", &code_block.code_string())?;
         self.maybe_bump_lib_mtime();
@@ -253,16 +331,44 @@ panic = 'unwind'
 incremental = true
 overflow-checks = true
 
+# Line tables for our own crate let backtraces refer to the user's code.
+[profile.dev.package.{}]
+debug = 1
+
 [dependencies]
 {}
-"#,
-            CRATE_NAME,
+[features]
+{}"#,
+            state.crate_name(),
             state.opt_level(),
-            crate_imports
+            state.crate_name(),
+            crate_imports,
+            state.format_cargo_features()
         )
     }
 }
 
+/// Runs a cargo command that doesn't build anything, such as `cargo clean`, on `package`.
+fn run_cargo_maintenance(
+    mut command: std::process::Command,
+    command_name: &str,
+    package: &str,
+) -> Result<(), Error> {
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(err) => bail!("Error running 'cargo {}': {}", command_name, err),
+    };
+    if !output.status.success() {
+        bail!(
+            "'cargo {} -p {}' failed:\n{}",
+            command_name,
+            package,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
 fn run_cargo(
     mut command: std::process::Command,
     code_block: &CodeBlock,
@@ -276,8 +382,14 @@ fn run_cargo(
     } else {
         let (errors, non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
         if errors.is_empty() {
+            let stderr = String::from_utf8_lossy(&cargo_output.stderr);
             if let Some(error) = non_json_error {
                 bail!(Error::Message(error));
+            } else if let Some(hint) = build_script_failure_hint(&stderr) {
+                bail!(Error::Message(format!(
+                    "{}\n\nFull build output:\n{}",
+                    hint, stderr
+                )));
             } else {
                 bail!(Error::Message(format!(
                     "Compilation failed, but no parsable errors were found. STDERR:\n\
@@ -292,6 +404,59 @@ fn run_cargo(
     }
 }
 
+/// If `stderr` shows that a build script failed in a way that typically means that something needs
+/// to be installed on the system, returns a short description of what's likely missing.
+fn build_script_failure_hint(stderr: &str) -> Option<String> {
+    static BUILD_SCRIPT_FAILED: OnceCell<Regex> = OnceCell::new();
+    let build_script_failed = BUILD_SCRIPT_FAILED
+        .get_or_init(|| Regex::new("failed to run custom build command for `([^`]+)`").unwrap());
+    let krate = build_script_failed.captures(stderr)?[1].to_owned();
+    static PATTERNS: OnceCell<Vec<(Regex, &str)>> = OnceCell::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        [
+            (
+                "The pkg-config command could not be found|Could not run `\"?pkg-config",
+                "pkg-config doesn't appear to be installed. Try installing it with your system's \
+                 package manager (e.g. `apt install pkg-config`).",
+            ),
+            (
+                "The system library `([^`]+)` required by crate `[^`]+` was not found",
+                "The system library `$1` wasn't found. Try installing its development package \
+                 (e.g. `apt install lib$1-dev`).",
+            ),
+            (
+                "Package ([^ ]+) was not found in the pkg-config search path",
+                "The system library `$1` wasn't found. Try installing its development package \
+                 (e.g. `apt install lib$1-dev`).",
+            ),
+            (
+                "Could not find directory of OpenSSL installation",
+                "OpenSSL development files weren't found. Try installing them (e.g. \
+                 `apt install libssl-dev`).",
+            ),
+            (
+                "fatal error: '?([^ ':]+\\.h)'?:? (No such file or directory|file not found)",
+                "The C header `$1` wasn't found. Try installing the development package for the \
+                 library that provides it.",
+            ),
+            (
+                "Failed to find tool\\. Is `[^`]+` installed\\?|is `c[c+]+` not installed\\?",
+                "A C compiler couldn't be found. Try installing one (e.g. \
+                 `apt install build-essential`).",
+            ),
+        ]
+        .iter()
+        .map(|(pattern, hint)| (Regex::new(pattern).unwrap(), *hint))
+        .collect()
+    });
+    patterns.iter().find_map(|(pattern, hint)| {
+        let captures = pattern.captures(stderr)?;
+        let mut expanded = String::new();
+        captures.expand(hint, &mut expanded);
+        Some(format!("Failed to build `{}`. {}", krate, expanded))
+    })
+}
+
 fn errors_from_cargo_output(
     cargo_output: &std::process::Output,
     code_block: &CodeBlock,
@@ -346,3 +511,52 @@ fn get_host_target() -> Result<String, Error> {
         stderr
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::build_script_failure_hint;
+
+    #[test]
+    fn test_build_script_failure_hint() {
+        let stderr = r#"
+   Compiling foo-sys v0.1.0
+error: failed to run custom build command for `foo-sys v0.1.0`
+
+Caused by:
+  process didn't exit successfully: `/tmp/target/debug/build/foo-sys-1234/build-script-build` (exit status: 101)
+  --- stderr
+  thread 'main' panicked at 'called `Result::unwrap()` on an `Err` value: "`\"pkg-config\" \"--libs\" \"--cflags\" \"foo\"` did not exit successfully: exit status: 1\nerror: could not find system library 'foo' required by the 'foo-sys' crate\n\n--- stderr\nPackage foo was not found in the pkg-config search path.\nPerhaps you should add the directory containing `foo.pc'\nto the PKG_CONFIG_PATH environment variable\n"', build.rs:2:33
+"#;
+        assert_eq!(
+            build_script_failure_hint(stderr).as_deref(),
+            Some(
+                "Failed to build `foo-sys v0.1.0`. The system library `foo` wasn't found. Try \
+                 installing its development package (e.g. `apt install libfoo-dev`)."
+            )
+        );
+
+        let stderr = "error: failed to run custom build command for `bar-sys v0.2.0`\n\
+                      \n\
+                      --- stderr\n\
+                      src/wrapper.c:1:10: fatal error: bar.h: No such file or directory\n";
+        assert_eq!(
+            build_script_failure_hint(stderr).as_deref(),
+            Some(
+                "Failed to build `bar-sys v0.2.0`. The C header `bar.h` wasn't found. Try \
+                 installing the development package for the library that provides it."
+            )
+        );
+
+        // Unrecognized failures and non-build-script errors don't get a hint.
+        assert_eq!(
+            build_script_failure_hint(
+                "error: failed to run custom build command for `baz v0.1.0`\n--- stderr\nOops\n"
+            ),
+            None
+        );
+        assert_eq!(
+            build_script_failure_hint("fatal error: bar.h: No such file or directory"),
+            None
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2020 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Converts the argument to a command into a path. Surrounding quotes, which are needed by some
+/// shells for paths containing spaces, are removed.
+pub(crate) fn path_from_arg(arg: &str) -> PathBuf {
+    let arg = arg.trim();
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|quote| arg.strip_prefix(*quote)?.strip_suffix(*quote));
+    PathBuf::from(unquoted.unwrap_or(arg))
+}
+
+/// Like `Path::canonicalize`, but on Windows, avoids `\\?\` prefixes where they're not needed,
+/// since they confuse cargo and look odd when displayed.
+pub(crate) fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    path.canonicalize().map(strip_verbatim_prefix)
+}
+
+fn strip_verbatim_prefix(path: PathBuf) -> PathBuf {
+    let path_str = path.to_string_lossy();
+    if let Some(rest) = path_str.strip_prefix(r"\\?\") {
+        let bytes = rest.as_bytes();
+        // Only strip the prefix from paths like `\\?\C:\`. UNC paths and the like need it.
+        if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && &bytes[1..3] == b":\\" {
+            return PathBuf::from(rest);
+        }
+    }
+    path
+}
+
+/// Returns the value of a TOML string, which may be either a basic string (double quotes) or a
+/// literal string (single quotes). In basic strings, only `\\` and `\"` are treated as escape
+/// sequences. Other backslashes are kept, so that unescaped Windows paths like `"C:\new\foo"`
+/// work.
+pub(crate) fn toml_string_value(toml: &str) -> Option<String> {
+    if let Some(literal) = toml.strip_prefix('\'') {
+        return literal.strip_suffix('\'').map(str::to_owned);
+    }
+    let basic = toml.strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::new();
+    let mut chars = basic.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.peek() {
+                Some('\\') | Some('"') => value.extend(chars.next()),
+                _ => value.push(c),
+            }
+        } else {
+            value.push(c);
+        }
+    }
+    Some(value)
+}
+
+/// Returns `path` as a TOML basic string.
+pub(crate) fn toml_string(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_from_arg() {
+        assert_eq!(path_from_arg(" data/x.csv "), PathBuf::from("data/x.csv"));
+        assert_eq!(
+            path_from_arg(r#""C:\Program Files\foo""#),
+            PathBuf::from(r"C:\Program Files\foo")
+        );
+        assert_eq!(path_from_arg(r"'C:\work'"), PathBuf::from(r"C:\work"));
+        assert_eq!(path_from_arg("\"unbalanced"), PathBuf::from("\"unbalanced"));
+    }
+
+    #[test]
+    fn test_strip_verbatim_prefix() {
+        assert_eq!(
+            strip_verbatim_prefix(PathBuf::from(r"\\?\C:\work\foo")),
+            PathBuf::from(r"C:\work\foo")
+        );
+        assert_eq!(
+            strip_verbatim_prefix(PathBuf::from(r"\\?\UNC\server\share")),
+            PathBuf::from(r"\\?\UNC\server\share")
+        );
+        assert_eq!(
+            strip_verbatim_prefix(PathBuf::from("/tmp/foo")),
+            PathBuf::from("/tmp/foo")
+        );
+    }
+
+    #[test]
+    fn test_toml_string_value() {
+        assert_eq!(
+            toml_string_value(r#""C:\\work\\foo""#).as_deref(),
+            Some(r"C:\work\foo")
+        );
+        assert_eq!(
+            toml_string_value(r#""C:\new\foo""#).as_deref(),
+            Some(r"C:\new\foo")
+        );
+        assert_eq!(
+            toml_string_value(r"'C:\work\foo'").as_deref(),
+            Some(r"C:\work\foo")
+        );
+        assert_eq!(toml_string_value("\"a\\\"b\"").as_deref(), Some("a\"b"));
+        assert_eq!(toml_string_value("unquoted"), None);
+    }
+
+    #[test]
+    fn test_toml_string() {
+        assert_eq!(toml_string(Path::new(r"C:\work\foo")), r#""C:\\work\\foo""#);
+        assert_eq!(
+            toml_string_value(&toml_string(Path::new(r"C:\work\foo"))).as_deref(),
+            Some(r"C:\work\foo")
+        );
+    }
+}
@@ -13,16 +13,109 @@
 // limitations under the License.
 
 use crate::errors::bail;
+use crate::errors::err;
 use crate::errors::Error;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::io;
 use std::marker::PhantomData;
+use std::os::raw::c_void;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
 use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
 pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+/// Printed instead of `EVCXR_EXECUTION_COMPLETE` when a cell running on our worker thread was
+/// interrupted.
+pub(crate) const EVCXR_EXECUTION_INTERRUPTED: &str = "EVCXR_EXECUTION_INTERRUPTED";
+/// Printed by `evcxr_runtime::spawn` when it starts and finishes a background thread.
+pub(crate) const EVCXR_BACKGROUND_THREAD_STARTED: &str = "EVCXR_BACKGROUND_THREAD_STARTED";
+pub(crate) const EVCXR_BACKGROUND_THREAD_FINISHED: &str = "EVCXR_BACKGROUND_THREAD_FINISHED";
+pub(crate) const EVCXR_ALLOCATED_BYTES_DELTA: &str = "EVCXR_ALLOCATED_BYTES_DELTA ";
+pub(crate) const PROFILE_MEMORY_FLAG: &str = "PROFILE_MEMORY";
+/// Passed with LOAD_AND_RUN to run the user's code on our worker thread rather than on the main
+/// thread.
+pub(crate) const RUN_ON_WORKER_FLAG: &str = "RUN_ON_WORKER";
+/// The name of the thread on which user code runs when `:run_on worker` is set.
+pub(crate) const WORKER_THREAD_NAME: &str = "evcxr_worker";
+pub(crate) const SET_WORKING_DIR: &str = "SET_WORKING_DIR ";
+/// Followed by `name=value`.
+pub(crate) const SET_ENV_VAR: &str = "SET_ENV_VAR ";
+pub(crate) const REMOVE_ENV_VAR: &str = "REMOVE_ENV_VAR ";
+/// Prefix for the line that our panic hook prints with the panic's message. Backslashes and
+/// newlines in the message are escaped.
+pub(crate) const EVCXR_PANIC_MESSAGE: &str = "EVCXR_PANIC_MESSAGE ";
+/// The function that each compiled cell exports to install our panic hook.
+pub(crate) const INSTALL_PANIC_HOOK_FN: &str = "evcxr_install_panic_hook";
+/// The function that compiled cells that use the variable store export to stop an interrupted
+/// cell from using it any further.
+pub(crate) const ABANDON_VARIABLE_STORE_FN: &str = "evcxr_abandon_variable_store";
+/// How often we check for an interrupt while waiting for our worker thread.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Set by our signal handler when we're asked to interrupt the cell that's running on our worker
+/// thread.
+static INTERRUPT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether a cell running on our worker thread can be interrupted without killing the
+/// process.
+pub(crate) fn worker_interrupt_supported() -> bool {
+    cfg!(all(unix, not(target_os = "freebsd")))
+}
+
+/// Asks the subprocess with the specified ID to interrupt the cell that's running on its worker
+/// thread. Only used when `worker_interrupt_supported` returns true.
+#[cfg(all(unix, not(target_os = "freebsd")))]
+pub(crate) fn request_worker_interrupt(pid: u32) {
+    use sig::ffi::Sig;
+    kill!(pid, Sig::USR1);
+}
+
+#[cfg(not(all(unix, not(target_os = "freebsd"))))]
+pub(crate) fn request_worker_interrupt(_pid: u32) {}
+
+/// Reverses the escaping of a panic message that was sent after `EVCXR_PANIC_MESSAGE`.
+pub(crate) fn unescape_panic_message(escaped: &str) -> String {
+    let mut message = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => message.push('\n'),
+                Some(other) => message.push(other),
+                None => message.push(c),
+            }
+        } else {
+            message.push(c);
+        }
+    }
+    message
+}
+
+/// Returns whether we can report how much memory user code allocates. This requires the
+/// `memory_stats` feature and an allocator that we know how to query.
+pub(crate) fn memory_stats_available() -> bool {
+    allocated_bytes().is_some()
+}
+
+/// Returns the number of bytes currently allocated via malloc. User code is compiled as a cdylib,
+/// which uses the system allocator regardless of what allocator our own binary uses.
+#[cfg(all(feature = "memory_stats", target_os = "linux", target_env = "gnu"))]
+fn allocated_bytes() -> Option<usize> {
+    let info = unsafe { libc::mallinfo2() };
+    // uordblks covers allocations from the heap, while hblkhd covers large allocations that were
+    // satisfied by mmap.
+    Some(info.uordblks + info.hblkhd)
+}
+
+#[cfg(not(all(feature = "memory_stats", target_os = "linux", target_env = "gnu")))]
+fn allocated_bytes() -> Option<usize> {
+    None
+}
 
 /// Binaries can call this just after staring. If we detect that we're actually
 /// running as a subprocess, control will not return.
@@ -32,9 +125,73 @@ pub fn runtime_hook() {
     }
 }
 
+type UserFn = extern "C" fn(*mut c_void) -> *mut c_void;
+
+/// A pointer to the variable store, which we pass to and from our worker thread. Only one thread
+/// runs user code at a time, so the store is never accessed concurrently. When a cell is
+/// interrupted, the thread running it is stopped from accessing the store any further before the
+/// store is passed on.
+struct VariableStorePtr(*mut c_void);
+
+unsafe impl Send for VariableStorePtr {}
+
+/// A thread that runs user code for `:run_on worker`. The same thread is used for every
+/// evaluation, so thread-local state persists between evaluations, as it does on the main thread.
+struct WorkerThread {
+    jobs: mpsc::Sender<(UserFn, VariableStorePtr)>,
+    results: mpsc::Receiver<VariableStorePtr>,
+}
+
+impl WorkerThread {
+    fn new() -> Result<WorkerThread, Error> {
+        let (jobs, job_receiver) = mpsc::channel::<(UserFn, VariableStorePtr)>();
+        let (result_sender, results) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(WORKER_THREAD_NAME.to_owned())
+            .spawn(move || {
+                for (user_fn, variable_store) in job_receiver {
+                    let variable_store = VariableStorePtr(user_fn(variable_store.0));
+                    if result_sender.send(variable_store).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(WorkerThread { jobs, results })
+    }
+
+    /// Runs `user_fn`, returning the variable store, or None if we were interrupted first.
+    fn run(
+        &self,
+        user_fn: UserFn,
+        variable_store: *mut c_void,
+    ) -> Result<Option<*mut c_void>, Error> {
+        self.jobs
+            .send((user_fn, VariableStorePtr(variable_store)))
+            .map_err(|_| err!("Worker thread has terminated"))?;
+        // If the worker thread dies, we fail, which ends the process, as if the main thread had
+        // died.
+        loop {
+            match self.results.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+                Ok(variable_store) => return Ok(Some(variable_store.0)),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if INTERRUPT_REQUESTED.swap(false, Ordering::SeqCst) {
+                        return Ok(None);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    bail!("Worker thread terminated while running user code")
+                }
+            }
+        }
+    }
+}
+
 struct Runtime {
     shared_objects: Vec<libloading::Library>,
-    variable_store_ptr: *mut std::os::raw::c_void,
+    variable_store_ptr: *mut c_void,
+    /// Started the first time that code is run with `:run_on worker`.
+    worker_thread: Option<WorkerThread>,
+    panic_hook_installed: bool,
     // Our variable store is permitted to contain non-Send types (e.g. Rc), therefore we need to be
     // non-Send as well.
     _phantom_rc: PhantomData<Rc<()>>,
@@ -45,6 +202,8 @@ impl Runtime {
         Runtime {
             shared_objects: Vec::new(),
             variable_store_ptr: std::ptr::null_mut(),
+            worker_thread: None,
+            panic_hook_installed: false,
             _phantom_rc: PhantomData,
         }
     }
@@ -53,6 +212,7 @@ impl Runtime {
         use std::io::BufRead;
 
         self.install_crash_handlers();
+        self.install_interrupt_handler();
 
         let stdin = std::io::stdin();
         #[allow(unknown_lints, clippy::significant_drop_in_scrutinee)]
@@ -71,22 +231,96 @@ impl Runtime {
     fn handle_line(&mut self, line: &io::Result<String>) -> Result<(), Error> {
         let line = line.as_ref()?;
         static LOAD_AND_RUN: OnceCell<Regex> = OnceCell::new();
-        let load_and_run =
-            LOAD_AND_RUN.get_or_init(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+)").unwrap());
+        let load_and_run = LOAD_AND_RUN.get_or_init(|| {
+            Regex::new(&format!(
+                "LOAD_AND_RUN ([^ ]+) ([^ ]+)( {})?( {})?",
+                PROFILE_MEMORY_FLAG, RUN_ON_WORKER_FLAG
+            ))
+            .unwrap()
+        });
         if let Some(captures) = load_and_run.captures(line) {
-            self.load_and_run(&captures[1], &captures[2])
+            self.load_and_run(
+                &captures[1],
+                &captures[2],
+                captures.get(3).is_some(),
+                captures.get(4).is_some(),
+            )
+        } else if let Some(dir) = line.strip_prefix(SET_WORKING_DIR) {
+            std::env::set_current_dir(dir)?;
+            Ok(())
+        } else if let Some(name_and_value) = line.strip_prefix(SET_ENV_VAR) {
+            let (name, value) = name_and_value
+                .split_once('=')
+                .ok_or_else(|| err!("Invalid environment variable: {}", name_and_value))?;
+            std::env::set_var(name, value);
+            Ok(())
+        } else if let Some(name) = line.strip_prefix(REMOVE_ENV_VAR) {
+            std::env::remove_var(name);
+            Ok(())
         } else {
             bail!("Unrecognised line: {}", line);
         }
     }
 
-    fn load_and_run(&mut self, so_path: &str, fn_name: &str) -> Result<(), Error> {
-        use std::os::raw::c_void;
+    fn load_and_run(
+        &mut self,
+        so_path: &str,
+        fn_name: &str,
+        profile_memory: bool,
+        run_on_worker: bool,
+    ) -> Result<(), Error> {
         let shared_object = unsafe { libloading::Library::new(so_path) }?;
-        unsafe {
-            let user_fn = shared_object
-                .get::<extern "C" fn(*mut c_void) -> *mut c_void>(fn_name.as_bytes())?;
-            self.variable_store_ptr = user_fn(self.variable_store_ptr);
+        let allocated_before = if profile_memory {
+            allocated_bytes()
+        } else {
+            None
+        };
+        let user_fn = unsafe { *shared_object.get::<UserFn>(fn_name.as_bytes())? };
+        if !self.panic_hook_installed {
+            // User code is linked against its own copy of the standard library, which is where
+            // panics in user code get reported, so a hook set by us wouldn't see them. Instead,
+            // we have the first library that we load install the hook. Later libraries share the
+            // same copy of the standard library.
+            let install_panic_hook =
+                unsafe { *shared_object.get::<extern "C" fn()>(INSTALL_PANIC_HOOK_FN.as_bytes())? };
+            install_panic_hook();
+            self.panic_hook_installed = true;
+        }
+        self.variable_store_ptr = if run_on_worker {
+            let worker_thread = match self.worker_thread.take() {
+                Some(worker_thread) => worker_thread,
+                None => WorkerThread::new()?,
+            };
+            // Any interrupt that arrived since the last cell was meant for that cell.
+            INTERRUPT_REQUESTED.store(false, Ordering::SeqCst);
+            match worker_thread.run(user_fn, self.variable_store_ptr)? {
+                Some(variable_store_ptr) => {
+                    self.worker_thread = Some(worker_thread);
+                    variable_store_ptr
+                }
+                None => {
+                    // We can't stop the worker thread, so we leave it to finish in its own time and
+                    // start a new one for the next cell. Variables that the cell took from the
+                    // store went with it, but the rest are still there, so we keep the store.
+                    if let Ok(abandon_variable_store) = unsafe {
+                        shared_object.get::<extern "C" fn()>(ABANDON_VARIABLE_STORE_FN.as_bytes())
+                    } {
+                        abandon_variable_store();
+                    }
+                    println!("{}", EVCXR_EXECUTION_INTERRUPTED);
+                    self.shared_objects.push(shared_object);
+                    return Ok(());
+                }
+            }
+        } else {
+            user_fn(self.variable_store_ptr)
+        };
+        if let (Some(before), Some(after)) = (allocated_before, allocated_bytes()) {
+            println!(
+                "{}{}",
+                EVCXR_ALLOCATED_BYTES_DELTA,
+                after as i64 - before as i64
+            );
         }
         println!("{}", EVCXR_EXECUTION_COMPLETE);
         self.shared_objects.push(shared_object);
@@ -118,6 +352,19 @@ impl Runtime {
 
     #[cfg(not(all(unix, not(target_os = "freebsd"))))]
     pub fn install_crash_handlers(&self) {}
+
+    #[cfg(all(unix, not(target_os = "freebsd")))]
+    fn install_interrupt_handler(&self) {
+        use sig::ffi::Sig;
+        extern "C" fn interrupt_handler(_signal: i32) {
+            INTERRUPT_REQUESTED.store(true, Ordering::SeqCst);
+        }
+
+        signal!(Sig::USR1, interrupt_handler);
+    }
+
+    #[cfg(not(all(unix, not(target_os = "freebsd"))))]
+    fn install_interrupt_handler(&self) {}
 }
 
 impl Drop for Runtime {
@@ -411,7 +411,6 @@ mod test {
     use super::is_type_valid;
     use super::RustAnalyzer;
     use anyhow::Result;
-    use tempfile;
 
     #[test]
     fn get_variable_types() -> Result<()> {
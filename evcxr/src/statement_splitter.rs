@@ -12,10 +12,120 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use once_cell::sync::OnceCell;
 use ra_ap_syntax::ast;
 use ra_ap_syntax::AstNode;
+use ra_ap_syntax::Parse;
 use ra_ap_syntax::SourceFile;
+use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
+use regex::Regex;
+
+const PRELUDE: &str = "fn f(){";
+
+fn parse(code: &str) -> Parse<SourceFile> {
+    SourceFile::parse(&(PRELUDE.to_owned() + code + "}"))
+}
+
+/// A syntax error found while parsing user code.
+pub(crate) struct SyntaxError {
+    pub(crate) message: String,
+    /// The byte offset within the code at which the error was detected.
+    pub(crate) offset: usize,
+}
+
+/// Returns any syntax errors found when parsing `code` as a sequence of statements.
+pub(crate) fn syntax_errors(code: &str) -> Vec<SyntaxError> {
+    parse(code)
+        .errors()
+        .iter()
+        .map(|error| SyntaxError {
+            message: describe_tokens(&error.to_string()),
+            offset: (usize::from(error.range().start()).saturating_sub(PRELUDE.len()))
+                .min(code.len()),
+        })
+        .collect()
+}
+
+/// Whether some code is ready to be evaluated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputStatus {
+    /// The code parses without errors.
+    Complete,
+    /// The code has unclosed delimiters or an unterminated string or comment, so more input might
+    /// make it valid.
+    Incomplete,
+    /// The code has syntax errors that more input won't fix.
+    Invalid,
+}
+
+/// Determines whether `code` is complete, or whether we should wait for more input before trying
+/// to evaluate it.
+pub(crate) fn input_status(code: &str) -> InputStatus {
+    let errors = parse(code).errors().to_owned();
+    if errors.is_empty() {
+        return InputStatus::Complete;
+    }
+    if errors.iter().any(|error| {
+        let message = error.to_string();
+        message.starts_with("Missing trailing")
+            && (message.contains("string literal") || message.contains("block comment"))
+    }) {
+        return InputStatus::Incomplete;
+    }
+    let mut open_delimiters = Vec::new();
+    for token in SourceFile::parse(code)
+        .syntax_node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+    {
+        let expected_opener = match token.kind() {
+            SyntaxKind::L_PAREN | SyntaxKind::L_BRACK | SyntaxKind::L_CURLY => {
+                open_delimiters.push(token.kind());
+                continue;
+            }
+            SyntaxKind::R_PAREN => SyntaxKind::L_PAREN,
+            SyntaxKind::R_BRACK => SyntaxKind::L_BRACK,
+            SyntaxKind::R_CURLY => SyntaxKind::L_CURLY,
+            _ => continue,
+        };
+        if open_delimiters.pop() != Some(expected_opener) {
+            return InputStatus::Invalid;
+        }
+    }
+    if open_delimiters.is_empty() {
+        InputStatus::Invalid
+    } else {
+        InputStatus::Incomplete
+    }
+}
+
+/// Replaces token kinds like SEMICOLON in a parser error message with the token itself.
+fn describe_tokens(message: &str) -> String {
+    static TOKEN_RE: OnceCell<Regex> = OnceCell::new();
+    let token_re = TOKEN_RE.get_or_init(|| Regex::new("[A-Z][A-Z_]+").unwrap());
+    token_re
+        .replace_all(message, |captures: &regex::Captures| {
+            let token = match &captures[0] {
+                "SEMICOLON" => ";",
+                "COMMA" => ",",
+                "COLON" => ":",
+                "EQ" => "=",
+                "FAT_ARROW" => "=>",
+                "L_PAREN" => "(",
+                "R_PAREN" => ")",
+                "L_CURLY" => "{",
+                "R_CURLY" => "}",
+                "L_BRACK" => "[",
+                "R_BRACK" => "]",
+                "L_ANGLE" => "<",
+                "R_ANGLE" => ">",
+                other => return other.to_owned(),
+            };
+            format!("`{}`", token)
+        })
+        .into_owned()
+}
 
 pub(crate) struct OriginalUserCode<'a> {
     pub(crate) code: &'a str,
@@ -28,8 +138,8 @@ pub(crate) struct OriginalUserCode<'a> {
 /// point where we can't parse onwards will be returned as a single statement.
 pub(crate) fn split_into_statements(code: &str) -> Vec<OriginalUserCode> {
     let mut output = Vec::new();
-    let prelude = "fn f(){";
-    let parsed_file = SourceFile::parse(&(prelude.to_owned() + code + "}"));
+    let prelude = PRELUDE;
+    let parsed_file = parse(code);
     let mut start_byte = 0;
     if let Some(stmt_list) = parsed_file
         .syntax_node()
@@ -66,7 +176,10 @@ mod test {
     use ra_ap_syntax::AstNode;
     use ra_ap_syntax::SyntaxKind;
 
+    use super::input_status;
     use super::split_into_statements;
+    use super::syntax_errors;
+    use super::InputStatus;
 
     fn split_and_get_text(code: &str) -> Vec<&str> {
         split_into_statements(code)
@@ -75,6 +188,35 @@ mod test {
             .collect()
     }
 
+    #[test]
+    fn missing_semicolon() {
+        let errors = syntax_errors("let a = 1;\nlet b = 2\nlet c = 3;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expected `;`");
+        assert_eq!(errors[0].offset, "let a = 1;\nlet b = 2".len());
+    }
+
+    #[test]
+    fn incomplete_input() {
+        assert_eq!(
+            input_status("fn foo() {\n    bar(1);"),
+            InputStatus::Incomplete
+        );
+        assert_eq!(input_status("let v = vec![(1, 2"), InputStatus::Incomplete);
+        assert_eq!(input_status("let s = \"abc"), InputStatus::Incomplete);
+        assert_eq!(input_status("/* comment"), InputStatus::Incomplete);
+        // Delimiters inside strings and comments don't count.
+        assert_eq!(input_status("let s = \"{\"; s"), InputStatus::Complete);
+        assert_eq!(input_status("// {\nlet a = 1;"), InputStatus::Complete);
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(input_status("let = 5;"), InputStatus::Invalid);
+        assert_eq!(input_status("foo(1));"), InputStatus::Invalid);
+        assert_eq!(input_status("let a = [1, 2};"), InputStatus::Invalid);
+    }
+
     #[test]
     fn single_line() {
         assert_eq!(
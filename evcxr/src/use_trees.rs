@@ -158,6 +158,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_grouped_underscores() {
+        assert_eq!(
+            use_tree_names("use std::io::{Read as _, Write as _};"),
+            vec![
+                unnamed("use std::io::Read as _;"),
+                unnamed("use std::io::Write as _;"),
+            ]
+        );
+    }
+
     #[test]
     fn test_glob() {
         assert_eq!(
@@ -165,4 +176,18 @@ mod tests {
             vec![unnamed("use foo::bar::*;"),]
         );
     }
+
+    #[test]
+    fn test_relative_to_crate_root() {
+        // User-defined modules live in the root of the generated crate, so paths starting with
+        // `self` or `crate` need to be preserved as written.
+        assert_eq!(
+            use_tree_names("use self::utils::*; use crate::utils::{nested::f, g as h};"),
+            vec![
+                unnamed("use self::utils::*;"),
+                named("f", "use crate::utils::nested::f;"),
+                named("h", "use crate::utils::g as h;"),
+            ]
+        );
+    }
 }
@@ -16,14 +16,16 @@ use evcxr::CommandContext;
 use evcxr::Error;
 use evcxr::EvalContext;
 use evcxr::EvalContextOutputs;
+use evcxr::EvalOutputs;
+use evcxr::EvalStatus;
 use once_cell::sync::OnceCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::Mutex;
-use tempfile;
 
 #[track_caller]
 fn eval_and_unwrap(ctxt: &mut CommandContext, code: &str) -> HashMap<String, String> {
@@ -143,6 +145,13 @@ fn variable_names_and_types(ctx: &CommandContext) -> Vec<(&str, &str)> {
     var_names
 }
 
+/// Returns the next line from `receiver`, failing if none arrives within a reasonable time.
+fn next_line(receiver: &crossbeam_channel::Receiver<String>) -> String {
+    receiver
+        .recv_timeout(std::time::Duration::from_secs(10))
+        .unwrap()
+}
+
 fn variable_names(ctx: &CommandContext) -> Vec<&str> {
     let mut var_names = ctx
         .variables_and_types()
@@ -152,6 +161,19 @@ fn variable_names(ctx: &CommandContext) -> Vec<&str> {
     var_names
 }
 
+/// Returns whether rustup has a nightly toolchain installed. Tests that need one skip the parts that
+/// do when there isn't.
+fn nightly_toolchain_installed() -> bool {
+    std::process::Command::new("rustup")
+        .args(["toolchain", "list"])
+        .output()
+        .map_or(false, |output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.starts_with("nightly"))
+        })
+}
+
 #[test]
 fn single_statement() {
     let mut e = new_context();
@@ -205,13 +227,125 @@ fn save_and_restore_variables() {
     );
 }
 
+#[test]
+fn persist_trait_objects() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        trait Shape {
+            fn area(&self) -> f64;
+        }
+        struct Square(f64);
+        impl Shape for Square {
+            fn area(&self) -> f64 { self.0 * self.0 }
+        }
+        fn make_display() -> Box<dyn std::fmt::Display> {
+            Box::new("inferred")
+        }
+    "#,
+    );
+    eval!(e, let boxed: Box<dyn std::fmt::Display> = Box::new(5););
+    eval!(e, let shape: std::rc::Rc<dyn Shape> = std::rc::Rc::new(Square(2.0)););
+    eval!(e, let double: std::sync::Arc<dyn Fn(i32) -> i32 + Send + Sync> = std::sync::Arc::new(|x| x * 2););
+    eval!(e, let inferred = make_display(););
+    assert_eq!(eval!(e, boxed.to_string()), text_plain("\"5\""));
+    assert_eq!(eval!(e, shape.area()), text_plain("4.0"));
+    assert_eq!(eval!(e, double(21)), text_plain("42"));
+    assert_eq!(eval!(e, inferred.to_string()), text_plain("\"inferred\""));
+}
+
+#[test]
+fn variable_values() {
+    let mut e = new_context();
+    eval!(e,
+        struct NotJson;
+        let a = 42;
+        let s = "say \"hi\"".to_owned();
+        let v = vec![Some(1.5), None];
+        let n = NotJson;
+    );
+    let values = e.variable_values().unwrap();
+    let values: Vec<(&str, Option<&str>)> = values
+        .iter()
+        .map(|v| (v.name.as_str(), v.json_value.as_deref()))
+        .collect();
+    assert_eq!(
+        values,
+        vec![
+            ("a", Some("42")),
+            ("n", None),
+            ("s", Some(r#""say \"hi\"""#)),
+            ("v", Some("[1.5,null]")),
+        ]
+    );
+    // Variables should still be usable afterwards.
+    assert_eq!(eval!(e, a + s.len() as i32), text_plain("50"));
+}
+
+#[test]
+fn grouped_underscore_imports() {
+    let mut e = new_context();
+    eval!(e, use std::io::{Read as _, Write as _};);
+    eval!(e, let mut buf: Vec<u8> = Vec::new(););
+    assert_eq!(
+        eval!(e,
+            std::io::Cursor::new(b"ab".to_vec()).read_to_end(&mut buf).unwrap();
+            buf.write_all(b"c").unwrap();
+            buf.len()
+        ),
+        text_plain("3")
+    );
+}
+
+#[test]
+fn const_and_static_items() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        const MAX: usize = 100;
+        static GREETING: &str = "hi";
+    "#,
+    );
+    eval!(e, let x: usize = 1;);
+    assert_eq!(eval!(e, MAX + GREETING.len() + x), text_plain("103"));
+    match e.execute("static mut COUNTER: i32 = 0;") {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].message().contains("static mut"));
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert_eq!(e.check("static mut COUNTER: i32 = 0;").unwrap().len(), 1);
+}
+
+#[test]
+fn impl_in_later_cell() {
+    let mut e = new_context();
+    eval!(e, struct Foo;);
+    eval!(e, let foo = Foo;);
+    eval!(e, impl Foo { fn bar(&self) -> i32 { 1 } });
+    // Re-evaluating an impl should replace it, not produce duplicate definitions.
+    eval!(e, impl Foo { fn bar(&self) -> i32 { 40 } });
+    eval!(e, impl Foo { fn baz(&self) -> i32 { 2 } });
+    eval!(e, impl std::fmt::Debug for Foo {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, "Foo1") }
+    });
+    eval!(e, impl std::fmt::Debug for Foo {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, "Foo2") }
+    });
+    assert_eq!(eval!(e, foo.bar() + foo.baz()), text_plain("42"));
+    assert_eq!(eval!(e, foo), text_plain("Foo2"));
+}
+
 #[test]
 fn missing_semicolon_on_let_stmt() {
     let mut e = new_context();
     eval_and_unwrap(&mut e, "mod foo {pub mod bar { pub struct Baz {} }}");
     match e.execute("let v1 = foo::bar::Baz {}") {
         Err(Error::CompilationErrors(e)) => {
-            assert!(e.first().unwrap().message().contains(";"));
+            assert!(e.first().unwrap().message().contains(';'));
         }
         x => {
             panic!("Unexpected result: {:?}", x);
@@ -220,208 +354,2482 @@ fn missing_semicolon_on_let_stmt() {
 }
 
 #[test]
-fn printing() {
+fn syntax_error_location() {
+    let mut e = new_context();
+    match e.execute("let a = 1;\nlet b = 2\nlet c = 3;") {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].message().contains(';'));
+            let spanned_message = errors[0].primary_spanned_message().unwrap();
+            let span = spanned_message.span.unwrap();
+            assert_eq!((span.start_line, span.start_column), (2, 9));
+            assert_eq!(spanned_message.lines, vec!["let b = 2"]);
+        }
+        x => {
+            panic!("Unexpected result: {:?}", x);
+        }
+    }
+}
+
+#[test]
+fn shell_command() {
     let (mut e, outputs) = new_command_context_and_outputs();
+    assert!(e.execute(":sh echo hello").is_err());
+    eval_and_unwrap(&mut e, ":allow_shell 1");
+    eval_and_unwrap(&mut e, ":sh echo hello");
+    assert_eq!(outputs.stdout.recv(), Ok("hello".to_owned()));
+    assert!(e.execute(":sh exit 3").is_err());
+}
 
-    eval!(e,
-        println!("This is stdout");
-        eprintln!("This is stderr");
-        println!("Another stdout line");
-        eprintln!("Another stderr line");
+#[test]
+fn cargo_command() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":cargo --version");
+    assert!(outputs.stdout.recv().unwrap().starts_with("cargo "));
+    eval_and_unwrap(&mut e, "let x = 1;");
+    eval_and_unwrap(&mut e, ":cargo tree --depth 0");
+    assert!(outputs.stdout.recv().unwrap().starts_with("ctx v1.0.0"));
+    eval_and_unwrap(&mut e, ":cargo tree --depth 0 --format \"{p} quoted\"");
+    assert!(outputs.stdout.recv().unwrap().ends_with(") quoted"));
+    assert!(e.execute(":cargo clean").is_err());
+    // These would rewrite the Cargo.lock that `:freeze` and `:lock_from` rely on, or our code.
+    for subcommand in ["update", "generate-lockfile", "fix", "vendor"] {
+        let error = e
+            .execute(&format!(":cargo {}", subcommand))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("isn't allowed"), "{}", error);
+    }
+    // Options before the subcommand don't hide it.
+    assert!(e.execute(":cargo --color never clean").is_err());
+    assert!(e.execute(":cargo +nightly clean").is_err());
+    assert!(e.execute(":cargo").is_err());
+}
+
+#[test]
+fn min_rust_version() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":min_rust 1.0"),
+        text_plain("Minimum Rust version: 1.0\n")
+    );
+    let error = e.execute(":min_rust 999.0").unwrap_err().to_string();
+    assert!(
+        error.contains("rustc 999.0 or newer is required"),
+        "{}",
+        error
+    );
+    assert!(e.execute(":min_rust latest").is_err());
+    // A failed attempt leaves the previous minimum in place.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":min_rust"),
+        text_plain("Minimum Rust version: 1.0\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":min_rust off"),
+        text_plain("Minimum Rust version: none\n")
     );
-    assert_eq!(outputs.stdout.recv(), Ok("This is stdout".to_owned()));
-    assert_eq!(outputs.stderr.recv(), Ok("This is stderr".to_owned()));
-    assert_eq!(outputs.stdout.recv(), Ok("Another stdout line".to_owned()));
-    assert_eq!(outputs.stderr.recv(), Ok("Another stderr line".to_owned()));
 }
 
 #[test]
-fn rc_refcell_etc() {
-    let mut e = new_context();
-    eval!(e,
-        use std::cell::RefCell; use std::rc::Rc;
-        let r: Rc<RefCell<String>> = Rc::new(RefCell::new(String::new()));
-        let r2: Rc<RefCell<String>> = Rc::clone(&r);
+fn run_on_thread() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let main_id = std::thread::current().id();");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":run_on worker"),
+        text_plain("Run on: worker\n")
     );
-    eval!(e,
-        r.borrow_mut().push_str("f");
-        let s = "oo";
+    assert_eq!(
+        eval!(e, main_id == std::thread::current().id()),
+        text_plain("false")
     );
-    eval!(e,
-        r.borrow_mut().push_str(s);
-        assert!(*r.borrow() == "foo");
+    // The same worker thread is used each time.
+    eval_and_unwrap(&mut e, "let worker_id = std::thread::current().id();");
+    assert_eq!(
+        eval!(e, worker_id == std::thread::current().id()),
+        text_plain("true")
+    );
+    eval_and_unwrap(&mut e, ":run_on main");
+    assert_eq!(
+        eval!(e, worker_id == std::thread::current().id()),
+        text_plain("false")
     );
+    assert_eq!(
+        eval!(e, main_id == std::thread::current().id()),
+        text_plain("true")
+    );
+    assert!(e.execute(":run_on other").is_err());
 }
 
 #[test]
-fn define_then_call_function() {
+fn line_buffering() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    let code = r#"
+        print!("Working...");
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        println!(" done");
+    "#;
+    eval_and_unwrap(&mut e, code);
+    assert_eq!(outputs.stdout.recv(), Ok("Working... done".to_owned()));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":line_buffered off"),
+        text_plain("Line buffered: off\n")
+    );
+    eval_and_unwrap(&mut e, code);
+    // The first part of the line arrives on its own, marked as partial, so that frontends join it
+    // up with the rest of the line.
+    let first = outputs.stdout.recv().unwrap();
+    assert_eq!(first, format!("{}Working...", evcxr::PARTIAL_LINE_MARKER));
+    let mut line = first[evcxr::PARTIAL_LINE_MARKER.len_utf8()..].to_owned();
+    loop {
+        let chunk = outputs.stdout.recv().unwrap();
+        match chunk.strip_prefix(evcxr::PARTIAL_LINE_MARKER) {
+            Some(text) => line.push_str(text),
+            None => {
+                line.push_str(&chunk);
+                break;
+            }
+        }
+    }
+    assert_eq!(line, "Working... done");
+}
+
+#[test]
+fn progress_bars() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":progress_bars on"),
+        text_plain("Progress bars: on\n")
+    );
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        for i in 1..=5 {
+            eprint!("\rProgress {}/5", i);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        eprintln!();
+        eprintln!("Finished");
+    "#,
+    );
+    let mut lines = Vec::new();
+    loop {
+        let line = next_line(&outputs.stderr);
+        if line == "Finished" {
+            break;
+        }
+        lines.push(line);
+    }
+    // Each write replaces the last, rather than adding a line. How many of them we see depends on
+    // how they get batched up, but we always see the final state.
+    assert!(
+        lines.iter().all(|line| line.starts_with("\rProgress ")),
+        "{:?}",
+        lines
+    );
+    assert_eq!(lines.last().map(String::as_str), Some("\rProgress 5/5"));
+    assert!(outputs.stderr.try_recv().is_err());
+}
+
+#[test]
+fn max_vars() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":max_vars 2");
+    eval_and_unwrap(&mut e, "let a = String::from(\"a\");");
+    eval_and_unwrap(&mut e, "let b = 2;");
+    eval_and_unwrap(&mut e, "let c = 3;");
+    assert_eq!(
+        outputs.stderr.recv(),
+        Ok("Variable `a` was dropped to stay within the limit set by :max_vars".to_owned())
+    );
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("b", "i32"), ("c", "i32")]
+    );
+    // Reassigning an existing variable doesn't count as a new variable.
+    eval_and_unwrap(&mut e, ":max_vars 2 reject");
+    eval_and_unwrap(&mut e, "let b = 20;");
+    assert!(e.execute("let d = 4;").is_err());
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("b", "i32"), ("c", "i32")]
+    );
+    eval_and_unwrap(&mut e, ":max_vars off");
+    eval_and_unwrap(&mut e, "let d = 4;");
+    assert_eq!(eval!(e, b + c + d), text_plain("27"));
+}
+
+#[test]
+fn profile_memory() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    if !cfg!(all(
+        feature = "memory_stats",
+        target_os = "linux",
+        target_env = "gnu"
+    )) {
+        assert!(e
+            .execute(":profile_memory on")
+            .unwrap_err()
+            .to_string()
+            .contains("Memory profiling is unavailable"));
+        return;
+    }
+    eval_and_unwrap(&mut e, ":profile_memory on");
+    eval_and_unwrap(&mut e, "let data = vec![0u8; 1_000_000];");
+    let report = next_line(&outputs.stderr);
+    let bytes: i64 = report
+        .strip_prefix("Memory: ")
+        .and_then(|rest| rest.strip_suffix(" bytes allocated"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(bytes >= 1_000_000, "{}", report);
+    eval_and_unwrap(&mut e, ":profile_memory off");
+    eval_and_unwrap(&mut e, "let more = data.len();");
+    assert!(outputs
+        .stderr
+        .recv_timeout(std::time::Duration::from_millis(100))
+        .is_err());
+}
+
+#[test]
+fn unused_variables() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let used = 40; let unused = 1; let in_macro = 2;");
+    eval_and_unwrap(&mut e, "let total = used + 2; println!(\"{}\", in_macro);");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":vars_unused"),
+        text_plain("total: i32\nunused: i32\n")
+    );
+    eval_and_unwrap(&mut e, "let total = total + unused;");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":vars_unused"),
+        text_plain("total: i32\n")
+    );
+}
+
+#[test]
+fn pinned_variables_survive_clear() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let keep = vec![1, 2, 3]; let discard = 42;");
+    eval_and_unwrap(&mut e, "struct Foo; let foo = Foo;");
+    eval_and_unwrap(&mut e, ":pin keep");
+    // Foo will be removed by :clear, so we can't keep a variable of that type.
+    assert!(e.execute(":pin foo").is_err());
+    eval_and_unwrap(&mut e, ":clear");
+    assert_eq!(variable_names_and_types(&e), vec![("keep", "Vec<i32>")]);
+    assert_eq!(eval!(e, keep.len()), text_plain("3"));
+    eval_and_unwrap(&mut e, ":unpin keep");
+    eval_and_unwrap(&mut e, ":clear");
+    assert!(variable_names_and_types(&e).is_empty());
+}
+
+#[test]
+fn custom_display_through_references_and_smart_pointers() {
     let mut e = new_context();
-    eval!(
-        e,
-        pub fn bar() -> i32 {
-            42
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        use std::rc::Rc;
+        use std::sync::Arc;
+        struct Displayable(i32);
+        impl Displayable {
+            pub fn evcxr_display(&self) {
+                println!("EVCXR_BEGIN_CONTENT text/html\n<b>{}</b>\nEVCXR_END_CONTENT", self.0);
+            }
         }
+        let d = Displayable(1);
+        "#,
     );
-    eval!(
-        e,
-        pub fn foo() -> i32 {
-            bar()
+    let html = |html: &str| {
+        vec![("text/html".to_owned(), html.to_owned())]
+            .into_iter()
+            .collect()
+    };
+    assert_eq!(eval!(e, &d), html("<b>1</b>"));
+    assert_eq!(eval!(e, &&d), html("<b>1</b>"));
+    assert_eq!(eval!(e, Box::new(Displayable(2))), html("<b>2</b>"));
+    assert_eq!(eval!(e, Rc::new(Displayable(3))), html("<b>3</b>"));
+    assert_eq!(eval!(e, Arc::new(Displayable(4))), html("<b>4</b>"));
+}
+
+/// Returns the minor version of the rustc that will be used to compile user code.
+fn rustc_minor_version() -> u32 {
+    let output = std::process::Command::new("rustc")
+        .arg("--version")
+        .output()
+        .unwrap();
+    let version = String::from_utf8_lossy(&output.stdout).into_owned();
+    version
+        .split(|c: char| c == ' ' || c == '.')
+        .nth(2)
+        .and_then(|minor| minor.parse().ok())
+        .unwrap()
+}
+
+#[test]
+fn let_else_bindings_persist() {
+    // let-else was stabilized in Rust 1.65.
+    if rustc_minor_version() < 65 {
+        return;
+    }
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":preserve_vars_on_panic 1");
+    eval_and_unwrap(&mut e, "let opt = Some(41);");
+    eval_and_unwrap(&mut e, "let Some(v) = opt else { panic!(\"no value\") };");
+    assert_eq!(eval!(e, v + 1), text_plain("42"));
+    // A failed match panics. Since we're preserving variables on panic, our existing variables
+    // survive.
+    let _ = e.execute("let Some(w) = None::<i32> else { panic!(\"no value\") };");
+    assert_eq!(eval!(e, v), text_plain("41"));
+    // Returning from a closure within the else branch is fine.
+    eval_and_unwrap(
+        &mut e,
+        "let Some(w) = opt else { let f = || { return; }; f(); panic!() };",
+    );
+    assert_eq!(eval!(e, w), text_plain("41"));
+}
+
+#[test]
+fn let_else_return_leaves_cell() {
+    // let-else was stabilized in Rust 1.65.
+    if rustc_minor_version() < 65 {
+        return;
+    }
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let opt = Some(41);");
+    eval_and_unwrap(&mut e, "let Some(v) = opt else { return; };");
+    assert_eq!(eval!(e, v + 1), text_plain("42"));
+    // When the pattern doesn't match, the rest of the cell is skipped, but variables defined
+    // before the let-else are kept.
+    eval_and_unwrap(
+        &mut e,
+        "let before = 1; let Some(w) = None::<i32> else { return; }; let after = 2;",
+    );
+    assert_eq!(variable_names(&e), vec!["before", "opt", "v"]);
+    assert_eq!(eval!(e, before + v), text_plain("42"));
+    // A variable from an earlier cell that's redefined after the let-else is lost.
+    eval_and_unwrap(
+        &mut e,
+        "let Some(w) = None::<i32> else { return; }; let before = 3;",
+    );
+    assert_eq!(
+        outputs.stderr.recv(),
+        Ok("The cell returned early, the following variables have been lost: before".to_owned())
+    );
+    assert_eq!(variable_names(&e), vec!["opt", "v"]);
+}
+
+#[test]
+fn change_working_dir() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().canonicalize().unwrap();
+    std::fs::create_dir(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("sub").join("data.txt"), "from sub").unwrap();
+    eval_and_unwrap(&mut e, "let a = 1;");
+    eval_and_unwrap(&mut e, &format!(":cd {}", dir.display()));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":cd sub"),
+        text_plain(&format!("{}\n", dir.join("sub").display()))
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":pwd"),
+        text_plain(&format!("{}\n", dir.join("sub").display()))
+    );
+    assert_eq!(
+        eval!(e, std::fs::read_to_string("data.txt").unwrap()),
+        text_plain("\"from sub\"")
+    );
+    assert_eq!(
+        eval!(e, include_str!("data.txt")),
+        text_plain("\"from sub\"")
+    );
+    assert!(e.execute(":cd does_not_exist").is_err());
+    assert!(e.execute(":cd data.txt").is_err());
+    assert_eq!(variable_names(&e), vec!["a"]);
+}
+
+#[test]
+fn error_location_after_include_path() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().canonicalize().unwrap();
+    std::fs::write(dir.join("data.txt"), "data").unwrap();
+    eval_and_unwrap(&mut e, &format!(":cd {}", dir.display()));
+    // The path gets replaced with a longer, absolute one, which mustn't shift the error.
+    match e.execute("let s = (include_str!(\"data.txt\"), 1 + \"x\");") {
+        Err(Error::CompilationErrors(errors)) => {
+            let spanned_message = errors[0].primary_spanned_message().unwrap();
+            let span = spanned_message.span.unwrap();
+            assert_eq!((span.start_line, span.start_column), (1, 38));
         }
-        assert_eq!(foo(), 42);
+        x => {
+            panic!("Unexpected result: {:?}", x);
+        }
+    }
+}
+
+#[test]
+fn clear_variables_by_type() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(
+        &mut e,
+        "let a = vec![1]; let b: Vec<String> = vec![]; let c = 1u8; let d = String::new();",
     );
-    assert_eq!(defined_item_names(&e), vec!["bar", "foo"]);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":vars_clear_type Vec"),
+        text_plain("Removed 2 variables of type `Vec`\n")
+    );
+    assert_eq!(variable_names(&e), vec!["c", "d"]);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":vars_clear_type HashMap"),
+        text_plain("Removed 0 variables of type `HashMap`\n")
+    );
+    assert_eq!(eval!(e, c), text_plain("1"));
+    assert!(e.execute(":vars_clear_type").is_err());
 }
 
-// This test has recently started failing on windows. It fails when deleting the .pdb file with an
-// "access denied" error. No idea why. Perhaps in this scenario the file is still locked for some
-// reason. This is a somewhat obscure test and Windows is a somewhat obscure platform, so I'll just
-// disable this for now.
+// Building the library with `cc` isn't something we can rely on for Windows.
 #[cfg(not(windows))]
 #[test]
-fn function_panics_with_variable_preserving() {
-    // Don't allow stderr to be printed here. We don't really want to see the
-    // panic stack trace when running tests.
+fn link_dylib() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path();
+    std::fs::write(
+        dir.join("add.c"),
+        "int evcxr_add(int a, int b) { return a + b; }",
+    )
+    .unwrap();
+    let lib_path = dir.join(format!(
+        "{}add{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    ));
+    let status = std::process::Command::new("cc")
+        .arg("-shared")
+        .arg("-fPIC")
+        .arg("-o")
+        .arg(&lib_path)
+        .arg(dir.join("add.c"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(e.execute(":dylib does_not_exist.so").is_err());
+    assert!(e
+        .execute(&format!(":dylib {}", dir.join("add.c").display()))
+        .is_err());
+    eval_and_unwrap(&mut e, &format!(":dylib {}", lib_path.display()));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":extern fn evcxr_add(a: i32, b: i32) -> i32"),
+        text_plain("Declared evcxr_add\n")
+    );
+    assert_eq!(eval!(e, unsafe { evcxr_add(40, 2) }), text_plain("42"));
+    // Declaring a function again replaces the earlier declaration.
+    eval_and_unwrap(
+        &mut e,
+        ":extern fn evcxr_add(a: i32, b: i32) -> i32; fn evcxr_unused()",
+    );
+    assert_eq!(eval!(e, unsafe { evcxr_add(1, 2) }), text_plain("3"));
+    assert!(e.execute(":extern struct Foo").is_err());
+}
+
+#[test]
+fn stderr_modes() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":stderr discard"),
+        text_plain("Stderr: discard\n")
+    );
+    eval_and_unwrap(&mut e, "eprintln!(\"hidden\"); println!(\"shown\");");
+    assert_eq!(next_line(&outputs.stdout), "shown");
+    eval_and_unwrap(&mut e, ":stderr merge");
+    eval_and_unwrap(&mut e, "eprintln!(\"merged\");");
+    assert_eq!(next_line(&outputs.stdout), "merged");
+    eval_and_unwrap(&mut e, ":stderr separate");
+    eval_and_unwrap(&mut e, "eprintln!(\"separate\");");
+    assert_eq!(next_line(&outputs.stderr), "separate");
+    assert!(outputs.stderr.try_recv().is_err());
+    assert!(e.execute(":stderr elsewhere").is_err());
+}
+
+#[test]
+fn replaced_item_notices() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "fn foo(x: i32) -> i32 { x }");
+    // Without notices enabled, nothing is reported.
+    eval_and_unwrap(&mut e, "fn foo(x: i32) -> i32 { x + 1 }");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":replaced_items on"),
+        text_plain("Replaced item notices: on\n")
+    );
+    eval_and_unwrap(&mut e, "fn foo(x: i32) -> i32 { x + 2 }");
+    assert_eq!(next_line(&outputs.stderr), "Replaced `foo`");
+    // Re-evaluating an identical definition doesn't count as replacing it.
+    eval_and_unwrap(&mut e, "fn foo(x: i32) -> i32 { x + 2 }");
+    eval_and_unwrap(&mut e, ":replaced_items verbose");
+    eval_and_unwrap(&mut e, "fn foo(x: i64) -> i64 { x }");
+    assert_eq!(
+        next_line(&outputs.stderr),
+        "Replaced `foo`\n  - fn foo(x: i32) -> i32\n  + fn foo(x: i64) -> i64"
+    );
+    assert!(outputs.stderr.try_recv().is_err());
+    assert_eq!(eval!(e, foo(42)), text_plain("42"));
+}
+
+#[test]
+fn prelude_shadowing_warnings() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "use std::io::Result;");
+    assert_eq!(
+        next_line(&outputs.stderr),
+        "`use std::io::Result;` shadows `Result` from the standard prelude. Use \
+         `:prelude_shadowing off` to hide this warning."
+    );
+    // Only newly added imports are reported.
+    eval_and_unwrap(&mut e, "let a = 1;");
+    eval_and_unwrap(&mut e, "use std::result::Result;");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":prelude_shadowing off"),
+        text_plain("Prelude shadowing warnings: off\n")
+    );
+    eval_and_unwrap(&mut e, "use std::fmt::Result;");
+    assert!(outputs.stderr.try_recv().is_err());
+}
+
+#[test]
+fn doctests() {
+    let mut e = new_command_context_and_outputs().0;
+    let outputs = eval_and_unwrap(
+        &mut e,
+        r#":doctest
+/// Adds one to `x`.
+///
+/// ```
+/// let y = add_one(41);
+/// assert_eq!(y, 42);
+/// ```
+///
+/// ```ignore
+/// add_one("not a number");
+/// ```
+///
+/// ```
+/// assert_eq!(add_one(1), 3);
+/// ```
+fn add_one(x: i32) -> i32 {
+    x + 1
+}"#,
+    );
+    let report = &outputs["text/plain"];
+    assert!(report.starts_with("Example 1 ... ok\nExample 2 ... ignored\nExample 3 ... FAILED"));
+    assert!(report.ends_with("Doctests: 1 passed, 1 failed, 1 ignored\n"));
+    // Examples don't leave variables behind, but the function from the cell is kept.
+    assert!(variable_names(&e).is_empty());
+    assert_eq!(eval!(e, add_one(1)), text_plain("2"));
+}
+
+#[test]
+fn doctest_failure_keeps_variables() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let kept = String::from(\"kept\");");
+    let outputs = eval_and_unwrap(
+        &mut e,
+        ":doctest\n/// ```\n/// panic!(\"example failed\");\n/// ```\nfn documented() {}",
+    );
+    assert!(outputs["text/plain"].starts_with("Example 1 ... FAILED"));
+    assert_eq!(variable_names(&e), vec!["kept"]);
+    assert_eq!(eval!(e, kept), text_plain("\"kept\""));
+}
+
+#[test]
+fn doctest_structured_results() {
+    let (mut e, session_outputs) = new_command_context_and_outputs();
+    let outputs = eval_and_unwrap(
+        &mut e,
+        r#":doctest
+/// ```
+/// println!("checking {}", double(2));
+/// assert_eq!(double(2), 4);
+/// ```
+///
+/// ```ignore
+/// double(());
+/// ```
+///
+/// ```
+/// assert_eq!(double(1), 3);
+/// ```
+fn double(x: i32) -> i32 {
+    x * 2
+}"#,
+    );
+    let results = json::parse(&outputs[evcxr::TEST_RESULTS_MIME_TYPE]).unwrap();
+    assert_eq!(results.len(), 3);
+    let statuses: Vec<&str> = results
+        .members()
+        .map(|result| result["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, vec!["ok", "ignored", "failed"]);
+    assert_eq!(results[0]["name"], "Example 1");
+    assert_eq!(results[0]["output"][0], "checking 4");
+    assert!(results[0]["duration_secs"].as_f64().unwrap() > 0.0);
+    assert!(results[0]["failure"].is_null());
+    assert!(!results[2]["failure"].as_str().unwrap().is_empty());
+    // Output of examples is captured rather than being passed on.
+    assert_eq!(
+        session_outputs.stdout.try_iter().collect::<Vec<_>>(),
+        Vec::<String>::new()
+    );
+    assert_eq!(eval!(e, double(2)), text_plain("4"));
+}
+
+#[test]
+fn prewarm() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":prewarm"),
+        text_plain("Prewarming the compiler in the background\n")
+    );
+    e.wait_for_prewarm().unwrap();
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[test]
+fn allow_unsafe() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":allow_unsafe off"),
+        text_plain("Allow unsafe: off\n")
+    );
+    match e.execute("let x = 1 + unsafe { std::mem::transmute::<u32, i32>(1) };") {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].message().contains(":allow_unsafe on"));
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert!(e.execute("unsafe fn f() {}").is_err());
+    // Mentions in strings and comments are fine.
+    assert_eq!(
+        eval_and_unwrap(&mut e, "// unsafe\n\"unsafe\""),
+        text_plain("\"unsafe\"")
+    );
+    // Files loaded by `mod name;` and `include!` are checked too.
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path().canonicalize().unwrap();
+    std::fs::write(dir.join("helpers.rs"), "pub unsafe fn f() {}").unwrap();
+    std::fs::write(
+        dir.join("code.rs"),
+        "unsafe { std::mem::transmute::<u32, i32>(1) }",
+    )
+    .unwrap();
+    eval_and_unwrap(&mut e, &format!(":cd {}", dir.display()));
+    for code in ["mod helpers;", "let x = include!(\"code.rs\");"] {
+        match e.execute(code) {
+            Err(Error::CompilationErrors(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert!(errors[0].message().contains(":allow_unsafe on"));
+            }
+            x => panic!("Unexpected result for {}: {:?}", code, x),
+        }
+    }
+    eval_and_unwrap(&mut e, ":allow_unsafe on");
+    assert_eq!(eval!(e, include!("code.rs")), text_plain("1"));
+    assert_eq!(
+        eval!(e, unsafe { std::mem::transmute::<u32, i32>(1) }),
+        text_plain("1")
+    );
+}
+
+#[test]
+fn overlapping_evaluation_rejected() {
+    let mut e = new_command_context_and_outputs().0;
+    e.register_command(
+        ":nested",
+        "Evaluate a cell from within a cell",
+        |ctx, args| ctx.execute(args.unwrap_or_default()),
+    )
+    .unwrap();
+    eval_and_unwrap(&mut e, "let a = 1;");
+    let error = e.execute(":nested let b = 2;").unwrap_err().to_string();
+    assert!(error.starts_with("Busy:"), "{}", error);
+    assert_eq!(variable_names(&e), vec!["a"]);
+    // Once the outer cell has finished, we can evaluate again.
+    assert_eq!(eval!(e, a + 1), text_plain("2"));
+}
+
+#[test]
+fn custom_command() {
+    let mut e = new_command_context_and_outputs().0;
+    e.register_command(
+        ":shout",
+        "Print the arguments in upper case",
+        |_ctx, args| {
+            let mut outputs = EvalOutputs::new();
+            outputs.content_by_mime_type.insert(
+                "text/plain".to_owned(),
+                args.unwrap_or_default().to_uppercase(),
+            );
+            Ok(outputs)
+        },
+    )
+    .unwrap();
+    assert!(e
+        .register_command(":vars", "", |_ctx, _args| Ok(EvalOutputs::new()))
+        .is_err());
+    assert!(e
+        .register_command(":shout", "", |_ctx, _args| Ok(EvalOutputs::new()))
+        .is_err());
+    assert_eq!(eval_and_unwrap(&mut e, ":shout hello"), text_plain("HELLO"));
+    assert!(eval_and_unwrap(&mut e, ":help")["text/plain"].contains(":shout"));
+    // Custom commands can use the context.
+    e.register_command(
+        ":num_vars",
+        "Print the number of variables",
+        |ctx, _args| {
+            let mut outputs = EvalOutputs::new();
+            outputs.content_by_mime_type.insert(
+                "text/plain".to_owned(),
+                ctx.variables_and_types().count().to_string(),
+            );
+            Ok(outputs)
+        },
+    )
+    .unwrap();
+    eval_and_unwrap(&mut e, "let a = 1; let b = 2;");
+    assert_eq!(eval_and_unwrap(&mut e, ":num_vars"), text_plain("2"));
+}
+
+#[test]
+fn float_precision() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(eval!(e, 1.0f64 / 3.0), text_plain("0.3333333333333333"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":float_precision 3"),
+        text_plain("Float precision: 3\n")
+    );
+    assert_eq!(eval!(e, 1.0f64 / 3.0), text_plain("0.333"));
+    assert_eq!(
+        eval!(e, (vec![0.5f32, 2.0 / 3.0], 7)),
+        text_plain("([0.500, 0.667], 7)")
+    );
+    // Values aren't affected.
+    eval_and_unwrap(&mut e, "let third = 1.0f64 / 3.0;");
+    assert_eq!(eval!(e, third * 3000.0), text_plain("1000.000"));
+    eval_and_unwrap(&mut e, ":float_precision off");
+    assert_eq!(eval!(e, third), text_plain("0.3333333333333333"));
+    assert!(e.execute(":float_precision lots").is_err());
+}
+
+#[test]
+fn display_width() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, ":fmt {:#?}");
+    eval_and_unwrap(
+        &mut e,
+        "#[derive(Debug)] struct Point { x: i32, y: i32 }\n\
+         let points = vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }];",
+    );
+    // Without an explicit width, output is left as formatted.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":width"),
+        text_plain("Width: default\n")
+    );
+    assert_eq!(
+        eval!(e, &points[0]),
+        text_plain("Point {\n    x: 1,\n    y: 2,\n}")
+    );
+    eval_and_unwrap(&mut e, ":width 80");
+    assert_eq!(
+        eval!(e, &points),
+        text_plain("[Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":width 30"),
+        text_plain("Width: 30\n")
+    );
+    assert_eq!(
+        eval!(e, &points),
+        text_plain("[\n    Point { x: 1, y: 2 },\n    Point { x: 3, y: 4 },\n]")
+    );
+    eval_and_unwrap(&mut e, ":width 10");
+    assert_eq!(
+        eval!(e, &points[0]),
+        text_plain("Point {\n    x: 1,\n    y: 2,\n}")
+    );
+    eval_and_unwrap(&mut e, ":width default");
+    assert_eq!(
+        eval!(e, &points[0]),
+        text_plain("Point {\n    x: 1,\n    y: 2,\n}")
+    );
+    assert!(e.execute(":width 0").is_err());
+    assert!(e.execute(":width wide").is_err());
+}
+
+#[test]
+fn auto_clone() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(
+        &mut e,
+        "fn total(v: Vec<i32>) -> i32 { v.into_iter().sum() }\n\
+         let v = vec![1, 2, 3];\n\
+         let n = std::sync::Mutex::new(0);",
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":auto_clone on"),
+        text_plain("Auto clone: on\n")
+    );
+    assert_eq!(eval!(e, total(v)), text_plain("6"));
+    assert_eq!(eval!(e, v.len()), text_plain("3"));
+    // Types that don't implement Clone still get moved.
+    eval_and_unwrap(&mut e, "drop(n);");
+    let variables: Vec<&str> = e.variables_and_types().map(|(name, _)| name).collect();
+    assert!(variables.contains(&"v"));
+    assert!(!variables.contains(&"n"));
+    eval_and_unwrap(&mut e, ":auto_clone off");
+    assert_eq!(eval!(e, total(v)), text_plain("6"));
+    assert!(e.execute("v").is_err());
+}
+
+#[test]
+fn autobind() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":autobind on"),
+        text_plain("Autobind: on\n")
+    );
+    // Cells are numbered from 1 and the command above was cell 1.
+    assert_eq!(eval!(e, 40 + 1), text_plain("41"));
+    assert_eq!(eval!(e, _2 + 1), text_plain("42"));
+    assert_eq!(eval!(e, _2 * _3), text_plain("1722"));
+    eval_and_unwrap(&mut e, "let v = vec![1, 2, 3];");
+    // Naming an existing variable doesn't move it and references aren't kept, but both are still
+    // displayed.
+    assert_eq!(eval!(e, v), text_plain("[1, 2, 3]"));
+    assert_eq!(eval!(e, &v[1..]), text_plain("[2, 3]"));
+    assert_eq!(eval!(e, v.len()), text_plain("3"));
+    // Values that can't be stored in a variable are displayed but not kept.
+    assert_eq!(eval!(e, v.iter()), text_plain("Iter([1, 2, 3])"));
+    let variables: Vec<String> = e
+        .variables_and_types()
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    assert!(variables.contains(&"_8".to_owned()));
+    for name in ["_6", "_7", "_9"] {
+        assert!(!variables.contains(&name.to_owned()), "{}", name);
+    }
+    eval_and_unwrap(&mut e, ":autobind off");
+    assert_eq!(eval!(e, 7), text_plain("7"));
+    assert!(e.execute("_11").is_err());
+    assert_eq!(eval!(e, _8 as i32 + _2), text_plain("44"));
+}
+
+#[test]
+fn type_renderer() {
+    let mut e = new_command_context_and_outputs().0;
+    e.register_type_renderer("Point", |text| {
+        let mut outputs = EvalOutputs::new();
+        outputs
+            .content_by_mime_type
+            .insert("text/html".to_owned(), format!("<b>{}</b>", text));
+        outputs
+    });
+    eval_and_unwrap(&mut e, "#[derive(Debug)] struct Point { x: i32, y: i32 }");
+    let mut expected = HashMap::new();
+    expected.insert(
+        "text/html".to_owned(),
+        "<b>Point { x: 1, y: 2 }</b>".to_owned(),
+    );
+    assert_eq!(eval_and_unwrap(&mut e, "Point { x: 1, y: 2 }"), expected);
+    // Types without a renderer are still displayed as normal.
+    assert_eq!(eval_and_unwrap(&mut e, "40 + 2"), text_plain("42"));
+    e.register_type_renderer("alloc::string::String", |text| {
+        let mut outputs = EvalOutputs::new();
+        outputs
+            .content_by_mime_type
+            .insert("text/plain".to_owned(), text.to_uppercase());
+        outputs
+    });
+    assert_eq!(
+        eval_and_unwrap(&mut e, "String::from(\"hi\")"),
+        text_plain("\"HI\"")
+    );
+}
+
+#[test]
+fn backtrace_refers_to_cells() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "fn fail() {\n    panic!(\"boom\");\n}");
+    assert!(e.execute("let a = 1;\nfail();").is_err());
+    // Stderr is forwarded by a separate thread, so it may arrive after execute returns.
+    let mut stderr = String::new();
+    while !(stderr.contains("(cell 1, line 2)") && stderr.contains("(cell 2, line 2)")) {
+        match outputs
+            .stderr
+            .recv_timeout(std::time::Duration::from_secs(10))
+        {
+            Ok(line) => {
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+            Err(_) => panic!("Backtrace didn't refer to both cells:\n{}", stderr),
+        }
+    }
+}
+
+#[test]
+fn jobs_command() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":jobs"),
+        text_plain("Jobs: default\n")
+    );
+    assert!(e.execute(":jobs 0").is_err());
+    assert!(e.execute(":jobs many").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, ":jobs 2"), text_plain("Jobs: 2\n"));
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":jobs default"),
+        text_plain("Jobs: default\n")
+    );
+}
+
+#[test]
+fn deterministic() {
+    let mut e = new_command_context_and_outputs().0;
+    let output = eval_and_unwrap(&mut e, ":deterministic on");
+    assert!(output["text/plain"].starts_with("Deterministic mode: on\n"));
+    assert_eq!(
+        eval!(e, std::env::var("EVCXR_SEED")),
+        text_plain("Ok(\"0\")")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":deterministic off"),
+        text_plain("Deterministic mode: off\n")
+    );
+    assert_eq!(
+        eval!(e, std::env::var("EVCXR_SEED").is_err()),
+        text_plain("true")
+    );
+}
+
+// Windows doesn't allow deleting DLLs that are loaded.
+#[cfg(not(windows))]
+#[test]
+fn keep_generations() {
+    let mut e = new_command_context_and_outputs().0;
+    assert!(e.execute(":keep_generations 0").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":keep_generations 2"),
+        text_plain("Keep generations: 2\n")
+    );
+    eval_and_unwrap(&mut e, "fn forty() -> i32 { 40 }");
+    for i in 0..5 {
+        eval_and_unwrap(&mut e, &format!("let v{} = {};", i, i));
+    }
+    assert_eq!(eval!(e, forty() + v2), text_plain("42"));
+    let compile_dir = eval_and_unwrap(&mut e, ":last_compile_dir")["text/plain"].clone();
+    let compile_dir = compile_dir.trim().trim_matches('"').to_owned();
+    fn count_generations(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| {
+                let path = entry.path();
+                if path.is_dir() {
+                    count_generations(&path)
+                } else {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    usize::from(
+                        name.starts_with("libcode_")
+                            && (name.ends_with(".so") || name.ends_with(".dylib")),
+                    )
+                }
+            })
+            .sum()
+    }
+    assert_eq!(
+        count_generations(Path::new(&compile_dir).join("target").as_path()),
+        2
+    );
+}
+
+#[test]
+fn trace_codegen() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let trace_path = tempdir.path().join("codegen.rs");
+    let output = eval_and_unwrap(&mut e, &format!(":trace_codegen {}", trace_path.display()));
+    assert_eq!(
+        output,
+        text_plain(&format!("Codegen tracing: {}\n", trace_path.display()))
+    );
+    eval_and_unwrap(&mut e, "let traced_var = 40;");
+    assert_eq!(eval!(e, traced_var + 2), text_plain("42"));
+    let trace = std::fs::read_to_string(&trace_path).unwrap();
+    let last_entry = trace
+        .split("// ===== Generated code for cell ")
+        .last()
+        .unwrap();
+    assert!(last_entry.starts_with("3 ====="));
+    assert!(last_entry.contains("traced_var + 2"));
+    // Variables are restored before the user's code and stored afterwards.
+    assert!(last_entry.contains("evcxr_variable_store.take_variable"));
+    assert!(last_entry.contains("evcxr_variable_store.put_variable"));
+    eval_and_unwrap(&mut e, ":trace_codegen off");
+    eval!(e, traced_var);
+    assert_eq!(std::fs::read_to_string(&trace_path).unwrap(), trace);
+}
+
+#[test]
+fn variable_store_skipped_when_unused() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let trace_path = tempdir.path().join("codegen.rs");
+    eval_and_unwrap(&mut e, &format!(":trace_codegen {}", trace_path.display()));
+    eval_and_unwrap(&mut e, "let unused_var = 40;");
+    eval_and_unwrap(&mut e, "fn add_two(x: i32) -> i32 { x + 2 }");
+    assert_eq!(eval!(e, add_two(40)), text_plain("42"));
+    let trace = std::fs::read_to_string(&trace_path).unwrap();
+    let last_entry = trace
+        .split("// ===== Generated code for cell ")
+        .last()
+        .unwrap();
+    assert!(last_entry.contains("add_two(40)"));
+    assert!(!last_entry.contains("evcxr_variable_store.take_variable"));
+    assert!(!last_entry.contains("evcxr_variable_store.put_variable"));
+    // Variables that weren't touched are still available afterwards.
+    assert_eq!(eval!(e, unused_var + 2), text_plain("42"));
+}
+
+#[test]
+fn status_events() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    // Discard events from evaluations done while creating the context.
+    outputs.status.try_iter().count();
+    eval!(e, 40 + 2);
+    assert_eq!(
+        outputs.status.try_iter().collect::<Vec<_>>(),
+        vec![
+            EvalStatus::Queued,
+            EvalStatus::Compiling,
+            EvalStatus::Running,
+            EvalStatus::Done
+        ]
+    );
+    assert!(e.execute("let status_var: i32 = \"42\";").is_err());
+    assert_eq!(
+        outputs.status.try_iter().collect::<Vec<_>>(),
+        vec![EvalStatus::Queued, EvalStatus::Compiling, EvalStatus::Error]
+    );
+}
+
+#[test]
+fn types_eq() {
+    let mut e = new_context();
+    eval!(e, let v = vec![1u8, 2];);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":types_eq 1u8 b'a'"),
+        text_plain("1u8: u8\nb'a': u8\nThe types are the same\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":types_eq &v[..], v.clone()"),
+        text_plain("&v[..]: &[u8]\nv.clone(): Vec<u8>\nThe types differ\n")
+    );
+    assert!(e.execute(":types_eq 1u8").is_err());
+}
+
+#[test]
+fn named_module() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        ":module math\npub fn helper(x: i32) -> i32 { x * 2 }\nconst OFFSET: i32 = 1;",
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":module"),
+        text_plain("Current module: math\n")
+    );
+    eval_and_unwrap(
+        &mut e,
+        "pub fn offset_helper(x: i32) -> i32 { helper(x) + OFFSET }",
+    );
+    eval_and_unwrap(&mut e, ":module end\nfn helper() -> i32 { 100 }");
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            "math::helper(20) + math::offset_helper(1) + helper()"
+        ),
+        text_plain("143")
+    );
+    assert!(e.execute(":module end").is_err());
+    assert!(e.execute(":module not-a-name").is_err());
+}
+
+#[test]
+fn toolchain_switching() {
+    if !nightly_toolchain_installed() {
+        eprintln!("Skipping toolchain_switching, since no nightly toolchain is installed");
+        return;
+    }
+    let mut e = new_command_context_and_outputs().0;
+    fn rustc_version(e: &mut CommandContext) -> String {
+        let version = eval_and_unwrap(e, ":version")["text/plain"].clone();
+        version.lines().nth(1).unwrap().to_owned()
+    }
+    let default_version = rustc_version(&mut e);
+    assert!(default_version.starts_with("rustc "));
+    assert!(e
+        .execute(":toolchain no-such-toolchain")
+        .unwrap_err()
+        .to_string()
+        .contains("rustup toolchain install no-such-toolchain"));
+    eval_and_unwrap(&mut e, "let x = 40;");
+    eval_and_unwrap(&mut e, ":toolchain nightly");
+    let nightly_version = rustc_version(&mut e);
+    assert!(nightly_version.contains("nightly"));
+    assert_ne!(nightly_version, default_version);
+    // Variables don't survive the switch, but code can be compiled with the new toolchain.
+    assert!(e.execute("x").is_err());
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    eval_and_unwrap(&mut e, ":toolchain default");
+    assert_eq!(rustc_version(&mut e), default_version);
+}
+
+#[test]
+fn parallel_frontend_command() {
+    let mut e = new_command_context_and_outputs().0;
+    assert!(e
+        .execute(":parallel_frontend 4")
+        .unwrap_err()
+        .to_string()
+        .contains("requires a nightly toolchain"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":parallel_frontend"),
+        text_plain("Parallel frontend: off\n")
+    );
+    if !nightly_toolchain_installed() {
+        eprintln!("Skipping the rest of parallel_frontend_command, since no nightly toolchain is installed");
+        return;
+    }
+    eval_and_unwrap(&mut e, ":toolchain nightly");
+    assert!(e.execute(":parallel_frontend 0").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":parallel_frontend 4"),
+        text_plain("Parallel frontend: 4\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":parallel_frontend off"),
+        text_plain("Parallel frontend: off\n")
+    );
+}
+
+#[test]
+fn lint_levels() {
+    let mut e = new_command_context_and_outputs().0;
+    let code = "fn has_unused_variable() { let unused = 1; }";
+    eval_and_unwrap(&mut e, code);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":lint deny unused_variables"),
+        text_plain("unused_variables: deny\n")
+    );
+    match e.execute(code) {
+        Err(Error::CompilationErrors(errors)) => {
+            assert!(
+                errors
+                    .iter()
+                    .any(|error| error.message().contains("unused variable")),
+                "{:?}",
+                errors
+            );
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert!(e.execute(":lint deny not a lint").is_err());
+    assert!(e.execute(":lint shout unused_variables").is_err());
+    eval_and_unwrap(&mut e, ":lint allow unused_variables");
+    eval_and_unwrap(&mut e, code);
+}
+
+#[test]
+fn no_std_mode() {
+    let mut e = new_command_context_and_outputs().0;
+    // Sysroot crates shouldn't be looked up on crates.io.
+    eval_and_unwrap(&mut e, "extern crate alloc;");
+    eval_and_unwrap(&mut e, ":no_std on");
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        use alloc::string::String;
+        use alloc::vec::Vec;
+        fn total(values: &[u32]) -> u32 {
+            values.iter().copied().sum()
+        }
+        let mut values: Vec<u32> = Vec::new();
+        values.push(1);
+        values.push(2);
+        let name = String::from("core");
+        "#,
+    );
+    eval_and_unwrap(
+        &mut e,
+        "assert_eq!(total(&values), 3); assert_eq!(core::mem::size_of::<u32>(), 4);",
+    );
+    // Without std's prelude, Box isn't in scope unless imported.
+    assert!(e
+        .execute("fn unqualified() -> Box<u8> { todo!() }")
+        .is_err());
+    eval_and_unwrap(&mut e, ":no_std off");
+    assert_eq!(eval!(e, name.len() + values.len()), text_plain("6"));
+}
+
+#[test]
+fn replay_session() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let mut values = vec![1, 2];");
+    eval_and_unwrap(&mut e, "fn double(x: i32) -> i32 { x * 2 }");
+    // Failed cells aren't part of the session, so aren't replayed.
+    assert!(e.execute("values.push(undefined);").is_err());
+    eval_and_unwrap(&mut e, "values.push(double(3));");
+    assert_eq!(eval!(e, values), text_plain("[1, 2, 6]"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":replay"),
+        text_plain("Replayed 4 cells successfully\n")
+    );
+    // Our variables are unaffected by replaying.
+    assert_eq!(eval!(e, values.len()), text_plain("3"));
+}
+
+#[test]
+fn list_items() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":items"),
+        text_plain("No items defined\n")
+    );
+    // Cells are numbered from 1, including cells that only contain commands.
+    eval_and_unwrap(&mut e, "fn double(x: i32) -> i32 { x * 2 }");
+    eval_and_unwrap(
+        &mut e,
+        r#"
+struct Point { x: i32 }
+impl Point { fn x(&self) -> i32 { self.x } }
+use std::collections::HashMap;
+const LIMIT: usize = 10;
+type Map = HashMap<String, Point>;
+mod util { pub fn one() -> i32 { 1 } }
+macro_rules! twice { ($e:expr) => { $e * 2 } }
+"#,
+    );
+    eval_and_unwrap(&mut e, "impl std::fmt::Display for Point { fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, \"{}\", self.x) } }");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":items"),
+        text_plain(
+            "fn double (cell 2)\n\
+             struct Point (cell 3)\n\
+             impl Point (cell 3)\n\
+             const LIMIT (cell 3)\n\
+             type Map (cell 3)\n\
+             mod util (cell 3)\n\
+             macro_rules! twice (cell 3)\n\
+             impl std::fmt::Display for Point (cell 4)\n"
+        )
+    );
+}
+
+#[test]
+fn history() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":history"),
+        text_plain("No cells in history\n")
+    );
+    eval_and_unwrap(&mut e, "let a = 1;");
+    eval_and_unwrap(&mut e, ":vars");
+    eval_and_unwrap(&mut e, ":timing\nfn f() -> i32 {\n    2\n}");
+    assert!(e.execute("undefined").is_err());
+    // Cells are listed by the same numbers as `:items` uses, where cells with only commands count
+    // too. Those cells aren't listed, but failed cells are.
+    assert!(eval_and_unwrap(&mut e, ":items")["text/plain"].contains("fn f (cell 4)"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":history"),
+        text_plain(
+            "[2] let a = 1;\n[4] fn f() -> i32 {\n        2\n    }\n[5] (failed) undefined\n"
+        )
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":history 4"),
+        text_plain("fn f() -> i32 {\n    2\n}\n")
+    );
+    assert!(e.execute(":history 3").is_err());
+    // Only the last 100 cells are kept. Cells with syntax errors are quick to evaluate.
+    for _ in 0..100 {
+        assert!(e.execute("let x = ;").is_err());
+    }
+    let history = eval_and_unwrap(&mut e, ":history");
+    assert_eq!(history["text/plain"].lines().count(), 100);
+    assert!(history["text/plain"].starts_with("[9] (failed) let x = ;\n"));
+    assert!(e.execute(":history 1").is_err());
+}
+
+#[test]
+fn replay_detects_failure_and_divergence() {
+    let mut e = new_command_context_and_outputs().0;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("input.txt");
+    std::fs::write(&path, "42").unwrap();
+    eval_and_unwrap(
+        &mut e,
+        &format!("let input = std::fs::read_to_string({:?}).unwrap();", path),
+    );
+    std::fs::remove_file(&path).unwrap();
+    let error = e.execute(":replay").unwrap_err().to_string();
+    assert!(error.starts_with("Replay failed at cell 1:"), "{}", error);
+
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "std::process::id()");
+    let error = e.execute(":replay").unwrap_err().to_string();
+    assert!(error.starts_with("Replay diverged at cell 1:"), "{}", error);
+}
+
+#[test]
+fn export_html() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(
+        &mut e,
+        r#"println!("EVCXR_BEGIN_CONTENT text/html\n<b>bold</b>\nEVCXR_END_CONTENT");"#,
+    );
+    eval_and_unwrap(&mut e, "\"<plain>\"");
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("session.html");
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":export_html {}", path.display())),
+        text_plain(&format!("Exported 2 outputs to {}\n", path.display()))
+    );
+    let html = std::fs::read_to_string(&path).unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"), "{}", html);
+    assert!(html.contains("<b>bold</b>"), "{}", html);
+    assert!(html.contains("<pre>\"&lt;plain&gt;\"</pre>"), "{}", html);
+}
+
+#[test]
+fn moved_variable_reported_as_gone() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let s = String::from(\"hello\"); let kept = 1;");
+    eval_and_unwrap(&mut e, "fn consume(s: String) -> usize { s.len() }");
+    assert_eq!(eval!(e, consume(s)), text_plain("5"));
+    assert_eq!(
+        next_line(&outputs.stderr),
+        "Variable `s` was moved by this cell, so is no longer available"
+    );
+    assert_eq!(variable_names(&e), vec!["kept"]);
+    match e.execute("s.len()") {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(
+                errors[0].message(),
+                "`s` was moved by an earlier cell, so is no longer available"
+            );
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    // Defining the variable again makes it available as usual.
+    eval_and_unwrap(&mut e, "let s = String::from(\"again\");");
+    assert_eq!(eval!(e, s.len()), text_plain("5"));
+}
+
+#[test]
+fn crate_features() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":features"),
+        text_plain("Features: none\n")
+    );
+    assert_eq!(eval!(e, cfg!(feature = "fast")), text_plain("false"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":features fast, extra"),
+        text_plain("Features: fast,extra\n")
+    );
+    assert_eq!(
+        eval!(e, (cfg!(feature = "fast"), cfg!(feature = "extra"))),
+        text_plain("(true, true)")
+    );
+    assert!(e.execute(":features \"quoted\"").is_err());
+    // Names with characters that aren't allowed in bare TOML keys.
+    eval_and_unwrap(&mut e, ":features v1.2 c++");
+    assert_eq!(
+        eval!(e, (cfg!(feature = "v1.2"), cfg!(feature = "c++"))),
+        text_plain("(true, true)")
+    );
+    eval_and_unwrap(&mut e, ":features none");
+    assert_eq!(eval!(e, cfg!(feature = "fast")), text_plain("false"));
+}
+
+/// A stand-in for tokio that just polls futures until they're ready, so that tests of async mode
+/// don't need to fetch tokio.
+const TOKIO_STUB: &str = r#"
+pub mod runtime {
+    use std::future::Future;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    pub struct Runtime;
+
+    impl Runtime {
+        pub fn new() -> std::io::Result<Runtime> {
+            Ok(Runtime)
+        }
+
+        pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+            fn raw_waker() -> RawWaker {
+                fn clone(_: *const ()) -> RawWaker {
+                    raw_waker()
+                }
+                fn noop(_: *const ()) {}
+                static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+                RawWaker::new(std::ptr::null(), &VTABLE)
+            }
+            let waker = unsafe { Waker::from_raw(raw_waker()) };
+            let mut context = Context::from_waker(&waker);
+            let mut future = Box::pin(future);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                    return output;
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+}
+"#;
+
+/// Returns a context that's in async mode, using `TOKIO_STUB` rather than tokio.
+fn async_context(tokio: &TmpCrate) -> CommandContext {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, &tokio.dep_command(""));
+    assert_eq!(eval!(e, std::future::ready(1).await), text_plain("1"));
+    e
+}
+
+#[test]
+fn future_not_awaited() {
+    let tokio = TmpCrate::new("tokio", TOKIO_STUB).unwrap();
+    let mut e = async_context(&tokio);
+    // `Ready` implements Debug, but is still reported as a future.
+    assert_eq!(
+        eval_and_unwrap(&mut e, "std::future::ready(42)"),
+        text_plain("Future not awaited. Add .await or use :auto_await on")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "async { 42 }"),
+        text_plain("Future not awaited. Add .await or use :auto_await on")
+    );
+    // Values that aren't futures are displayed as usual.
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[test]
+fn auto_await() {
+    let tokio = TmpCrate::new("tokio", TOKIO_STUB).unwrap();
+    let mut e = async_context(&tokio);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":auto_await on"),
+        text_plain("Auto await: on\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "std::future::ready(42)"),
+        text_plain("42")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "async { \"done\" }"),
+        text_plain("\"done\"")
+    );
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[test]
+fn long_lines_truncated() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":max_line_length 10"),
+        text_plain("Max line length: 10\n")
+    );
+    eval_and_unwrap(
+        &mut e,
+        "println!(\"{}\", \"x\".repeat(1_000_000)); println!(\"short\"); \
+         eprintln!(\"{}\", \"y\".repeat(15));",
+    );
+    assert_eq!(
+        next_line(&outputs.stdout),
+        "xxxxxxxxxx… [999990 more characters]"
+    );
+    assert_eq!(next_line(&outputs.stdout), "short");
+    assert_eq!(
+        next_line(&outputs.stderr),
+        "yyyyyyyyyy… [5 more characters]"
+    );
+    assert!(e.execute(":max_line_length 0").is_err());
+    eval_and_unwrap(&mut e, ":max_line_length off");
+    eval_and_unwrap(&mut e, "println!(\"{}\", \"z\".repeat(15));");
+    assert_eq!(next_line(&outputs.stdout), "z".repeat(15));
+}
+
+#[test]
+fn logging() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":dep log = \"0.4\"");
+    eval_and_unwrap(&mut e, ":logging on");
+    eval_and_unwrap(&mut e, "log::debug!(\"hidden\"); log::info!(\"hello\");");
+    assert_eq!(next_line(&outputs.stderr), "[INFO ctx] hello");
+    // The logger needs to be installed again each time we compile.
+    eval_and_unwrap(&mut e, ":log_level debug");
+    eval_and_unwrap(&mut e, "log::debug!(\"visible\");");
+    assert_eq!(next_line(&outputs.stderr), "[DEBUG ctx] visible");
+    assert!(e.execute(":log_level loud").is_err());
+    eval_and_unwrap(&mut e, ":logging off");
+    eval_and_unwrap(&mut e, "log::error!(\"not printed\");");
+    assert!(outputs
+        .stderr
+        .recv_timeout(std::time::Duration::from_millis(100))
+        .is_err());
+}
+
+#[test]
+fn scratch_cell() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let kept = 1;");
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            ":scratch\n\
+             :fmt {:x}\n\
+             use std::collections::HashMap;\n\
+             fn scratch_fn() -> i32 { 10 }\n\
+             let x = scratch_fn() + kept;\n\
+             x + 4"
+        ),
+        text_plain("Output format: {:x}\nf")
+    );
+    assert_eq!(variable_names(&e), vec!["kept"]);
+    assert!(e.execute("x").is_err());
+    assert!(e.execute("scratch_fn()").is_err());
+    assert!(e.execute("HashMap::<i32, i32>::new()").is_err());
+    // The output format is back to its previous value.
+    assert_eq!(eval!(e, kept + 10), text_plain("11"));
+}
+
+#[test]
+fn undo_last_cell() {
+    let mut e = new_command_context_and_outputs().0;
+    assert!(e.execute(":undo").is_err());
+    eval_and_unwrap(&mut e, "let mut kept = 1;");
+    eval_and_unwrap(
+        &mut e,
+        "let undone = 2; kept += 1; fn undone_fn() -> i32 { 3 }",
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":undo"),
+        text_plain("Reverted the last cell\n")
+    );
+    assert_eq!(variable_names(&e), vec!["kept"]);
+    assert!(e.execute("undone").is_err());
+    assert!(e.execute("undone_fn()").is_err());
+    // Modifications to existing variables aren't reverted.
+    assert_eq!(eval!(e, kept), text_plain("2"));
+    // Undo goes back more than one cell.
+    eval_and_unwrap(&mut e, ":undo");
+    eval_and_unwrap(&mut e, ":undo");
+    assert!(variable_names(&e).is_empty());
+}
+
+#[test]
+fn prelude_imports() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":prelude"),
+        text_plain("The prelude is empty\n")
+    );
+    eval_and_unwrap(&mut e, ":prelude add std::collections::HashMap");
+    eval_and_unwrap(&mut e, ":prelude add use std::fmt::Write as _;");
+    assert!(e.execute(":prelude add not valid").is_err());
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":prelude"),
+        text_plain("use std::collections::HashMap;\nuse std::fmt::Write as _;\n")
+    );
+    assert_eq!(
+        eval!(e,
+            let mut map: HashMap<i32, String> = HashMap::new();
+            map.insert(1, String::new());
+            write!(map.get_mut(&1).unwrap(), "{}", 42).unwrap();
+            map[&1].clone()
+        ),
+        text_plain("\"42\"")
+    );
+    // The prelude is applied again after clearing.
+    eval_and_unwrap(&mut e, ":clear");
+    assert_eq!(eval!(e, HashMap::<i32, i32>::new().len()), text_plain("0"));
+    eval_and_unwrap(&mut e, ":prelude remove std::collections::HashMap");
+    assert!(e.execute("HashMap::<i32, i32>::new().len()").is_err());
+    assert!(e
+        .execute(":prelude remove std::collections::HashMap")
+        .is_err());
+}
+
+#[test]
+fn reset_config_command() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let x = 255;");
+    eval_and_unwrap(&mut e, ":fmt {:x}");
+    assert_eq!(eval!(e, x), text_plain("ff"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":max_vars 10"),
+        text_plain("Max vars: 10 (evict)\n")
+    );
+    eval_and_unwrap(&mut e, ":reset_config");
+    assert_eq!(eval!(e, x), text_plain("255"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":max_vars"),
+        text_plain("Max vars: unlimited\n")
+    );
+}
+
+#[test]
+fn printing() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+
+    eval!(e,
+        println!("This is stdout");
+        eprintln!("This is stderr");
+        println!("Another stdout line");
+        eprintln!("Another stderr line");
+    );
+    assert_eq!(outputs.stdout.recv(), Ok("This is stdout".to_owned()));
+    assert_eq!(outputs.stderr.recv(), Ok("This is stderr".to_owned()));
+    assert_eq!(outputs.stdout.recv(), Ok("Another stdout line".to_owned()));
+    assert_eq!(outputs.stderr.recv(), Ok("Another stderr line".to_owned()));
+}
+
+#[test]
+fn rc_refcell_etc() {
+    let mut e = new_context();
+    eval!(e,
+        use std::cell::RefCell; use std::rc::Rc;
+        let r: Rc<RefCell<String>> = Rc::new(RefCell::new(String::new()));
+        let r2: Rc<RefCell<String>> = Rc::clone(&r);
+    );
+    eval!(e,
+        r.borrow_mut().push_str("f");
+        let s = "oo";
+    );
+    eval!(e,
+        r.borrow_mut().push_str(s);
+        assert!(*r.borrow() == "foo");
+    );
+}
+
+#[test]
+fn define_then_call_function() {
+    let mut e = new_context();
+    eval!(
+        e,
+        pub fn bar() -> i32 {
+            42
+        }
+    );
+    eval!(
+        e,
+        pub fn foo() -> i32 {
+            bar()
+        }
+        assert_eq!(foo(), 42);
+    );
+    assert_eq!(defined_item_names(&e), vec!["bar", "foo"]);
+}
+
+// This test has recently started failing on windows. It fails when deleting the .pdb file with an
+// "access denied" error. No idea why. Perhaps in this scenario the file is still locked for some
+// reason. This is a somewhat obscure test and Windows is a somewhat obscure platform, so I'll just
+// disable this for now.
+#[cfg(not(windows))]
+#[test]
+fn function_panics_with_variable_preserving() {
+    // Don't allow stderr to be printed here. We don't really want to see the
+    // panic stack trace when running tests.
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        :preserve_vars_on_panic 1
+        let a = vec![1, 2, 3];
+        let b = 42;
+    "#,
+    );
+    eval!(e, panic!("Intentional panic {}", b););
+    // The variable a isn't referenced by the code that panics, while the variable b implements
+    // Copy, so neither should be lost.
+    assert_eq!(
+        eval!(e, format!("{:?}, {}", a, b)),
+        text_plain("\"[1, 2, 3], 42\"")
+    );
+}
+
+#[test]
+fn function_panics_without_variable_preserving() {
+    // Don't allow stderr to be printed here. We don't really want to see the
+    // panic stack trace when running tests.
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        :preserve_vars_on_panic 0
+        let a = vec![1, 2, 3];
+        let b = 42;
+    "#,
+    );
+    let result = e.execute(stringify!(panic!("Intentional panic {}", b);));
+    if let Err(Error::Panic { message, details }) = result {
+        assert_eq!(message, "Intentional panic 42");
+        assert!(details.contains("Subprocess terminated"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(variable_names_and_types(&e), vec![]);
+    // Make sure that a compilation error doesn't bring the variables back from
+    // the dead.
+    assert!(e.execute("This will not compile").is_err());
+    assert_eq!(variable_names_and_types(&e), vec![]);
+}
+
+#[test]
+fn panic_message() {
+    let (mut e, _) = new_command_context_and_outputs();
+    match e.execute(r#"panic!("boom");"#) {
+        Err(Error::Panic { message, .. }) => assert_eq!(message, "boom"),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    // Messages with multiple lines are kept intact.
+    match e.execute(r#"panic!("{}\\{}", "line 1\nline 2", 3);"#) {
+        Err(Error::Panic { message, .. }) => assert_eq!(message, "line 1\nline 2\\3"),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    // The message is still found if the cell had written part of a line.
+    match e.execute(r#"print!("partial"); panic!("after partial");"#) {
+        Err(Error::Panic { message, details }) => {
+            assert_eq!(message, "after partial");
+            assert!(!details.contains("EVCXR_"), "{}", details);
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    // The first cell run by the restarted subprocess has no user code, only an item.
+    eval_and_unwrap(&mut e, "fn f() {}");
+    match e.execute(r#"panic!("after restart");"#) {
+        Err(Error::Panic { message, .. }) => assert_eq!(message, "after restart"),
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[test]
+fn interrupt_running_cell() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "fn answer() -> i32 { 42 }\nlet a = 1;");
+    let handle = e.interrupt_handle();
+    // Nothing is running, so there's nothing to interrupt.
+    assert!(!handle.interrupt());
+    let interrupter = std::thread::spawn(move || {
+        while !handle.interrupt() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+    let result = e.execute("loop { std::thread::sleep(std::time::Duration::from_millis(1)); };");
+    interrupter.join().unwrap();
+    match result {
+        Err(Error::Interrupted) => {}
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert_eq!(
+        outputs.stderr.recv(),
+        Ok("The subprocess was restarted, the following variables have been lost: a".to_owned())
+    );
+    // The main thread can't be abandoned, so interrupting it kills the subprocess, which holds the
+    // variables. Definitions survive the restart.
+    assert_eq!(variable_names(&e), Vec::<&str>::new());
+    assert_eq!(eval!(e, answer()), text_plain("42"));
+    eval_and_unwrap(&mut e, "let b = 2;");
+    assert_eq!(eval!(e, b), text_plain("2"));
+}
+
+#[test]
+#[cfg(all(unix, not(target_os = "freebsd")))]
+fn interrupt_cell_on_worker_thread() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":run_on worker");
+    eval_and_unwrap(
+        &mut e,
+        "let a = 1;\nlet mut b = vec![2];\nlet s = String::from(\"s\");",
+    );
+    let handle = e.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        while !handle.interrupt() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+    let result =
+        e.execute("b.push(3); loop { std::thread::sleep(std::time::Duration::from_millis(1)); };");
+    interrupter.join().unwrap();
+    match result {
+        Err(Error::Interrupted) => {}
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    assert_eq!(
+        outputs.stderr.recv(),
+        Ok("The cell was interrupted, the following variables have been lost: b".to_owned())
+    );
+    // Only the variable that the interrupted cell used is lost.
+    assert_eq!(variable_names(&e), vec!["a", "s"]);
+    assert_eq!(eval!(e, a + 1), text_plain("2"));
+    assert_eq!(eval!(e, s.clone()), text_plain("\"s\""));
+    eval_and_unwrap(&mut e, "let b = 4;");
+    assert_eq!(eval!(e, a + b), text_plain("5"));
+}
+
+// Also tests multiple item definitions in the one compilation unit.
+#[test]
+fn tls_implementing_drop() {
+    let mut e = new_context();
+    eval!(e,
+        pub struct Foo {}
+        impl Drop for Foo {
+            fn drop(&mut self) {
+                println!("Dropping Foo");
+            }
+        }
+        pub fn init_foo() {
+            thread_local! {
+                pub static FOO: Foo = Foo {};
+            }
+            FOO.with(|f| ())
+        }
+    );
+    eval!(e, init_foo(););
+}
+
+fn text_plain(content: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    map.insert("text/plain".to_owned(), content.to_owned());
+    map
+}
+
+#[test]
+fn moved_value() {
+    let mut e = new_context();
+    eval!(e, let a = Some("foo".to_owned()););
+    assert_eq!(variable_names_and_types(&e), vec![("a", "Option<String>")]);
+    assert_eq!(eval!(e, a.unwrap()), text_plain("\"foo\""));
+    assert_eq!(variable_names_and_types(&e), vec![]);
+}
+
+struct TmpCrate {
+    name: String,
+    tempdir: tempfile::TempDir,
+}
+
+impl TmpCrate {
+    fn new(name: &str, src: &str) -> Result<TmpCrate, io::Error> {
+        let tempdir = tempfile::tempdir()?;
+        let src_dir = tempdir.path().join("src");
+        std::fs::create_dir_all(&src_dir)?;
+        std::fs::write(
+            tempdir.path().join("Cargo.toml"),
+            format!(
+                "\
+                 [package]\n\
+                 name = \"{}\"\n\
+                 version = \"0.0.1\"\n\
+                 edition = \"2018\"\n\
+                 ",
+                name
+            ),
+        )?;
+        std::fs::write(src_dir.join("lib.rs"), src)?;
+        Ok(TmpCrate {
+            name: name.to_owned(),
+            tempdir,
+        })
+    }
+
+    fn add_features(&self, features: &[&str]) -> Result<(), io::Error> {
+        let manifest = self.tempdir.path().join("Cargo.toml");
+        let mut contents = std::fs::read_to_string(&manifest)?;
+        contents.push_str("[features]\n");
+        for feature in features {
+            contents.push_str(&format!("{} = []\n", feature));
+        }
+        std::fs::write(manifest, contents)
+    }
+
+    fn dep_command(&self, extra_options: &str) -> String {
+        format!(
+            ":dep {} = {{ path = \"{}\"{}{} }}",
+            self.name,
+            self.tempdir.path().to_string_lossy().replace('\\', "\\\\"),
+            if extra_options.is_empty() { "" } else { ", " },
+            extra_options
+        )
+    }
+}
+
+/// Counts the builds done by a context, by having it record the code of each build in a file.
+struct BuildCounter {
+    tempdir: tempfile::TempDir,
+}
+
+impl BuildCounter {
+    fn new(ctx: &mut CommandContext) -> BuildCounter {
+        let tempdir = tempfile::tempdir().unwrap();
+        eval_and_unwrap(
+            ctx,
+            &format!(
+                ":trace_codegen {}",
+                tempdir.path().join("trace.rs").display()
+            ),
+        );
+        BuildCounter { tempdir }
+    }
+
+    /// Returns the number of builds since we were created.
+    fn count(&self) -> usize {
+        std::fs::read_to_string(self.tempdir.path().join("trace.rs"))
+            .unwrap_or_default()
+            .matches("// ===== Generated code for cell")
+            .count()
+    }
+}
+
+#[test]
+fn comment_only_cells() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let a = 40;");
+    let builds = BuildCounter::new(&mut e);
+    for code in [
+        "// A comment",
+        "    // An indented comment\n/* A block comment */",
+        "/// A doc comment",
+        "//! An inner doc comment\n;",
+        "// A comment before a command\n:stmt",
+    ] {
+        let outputs = e.execute(code).unwrap();
+        assert!(outputs.content_by_mime_type.is_empty(), "{}", code);
+    }
+    assert_eq!(builds.count(), 0);
+    assert_eq!(eval!(e, a + 2), text_plain("42"));
+}
+
+#[test]
+fn rebuild_keeps_variables() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        let boxed: Box<dyn std::any::Any> = Box::new(40i32);
+        let s = String::from("kept");
+        "#,
+    );
+    let builds = BuildCounter::new(&mut e);
+    eval_and_unwrap(&mut e, ":rebuild");
+    assert_eq!(builds.count(), 1);
+    assert_eq!(
+        eval!(e, boxed.downcast_ref::<i32>().unwrap() + 2),
+        text_plain("42")
+    );
+    assert_eq!(eval!(e, s), text_plain("\"kept\""));
+}
+
+#[test]
+fn deps_transaction() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r() -> u32 { 1 }").unwrap();
+    let crate2 = TmpCrate::new("crate2", "pub fn r() -> u32 { 2 }").unwrap();
+    eval_and_unwrap(&mut e, "let a = 40u32;");
+    let builds = BuildCounter::new(&mut e);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":deps begin"),
+        text_plain("Deferring builds until :deps end\n")
+    );
+    assert!(e.execute(":deps begin").is_err());
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    eval_and_unwrap(&mut e, &crate2.dep_command(""));
+    assert_eq!(builds.count(), 0);
+    eval_and_unwrap(&mut e, ":deps end");
+    assert_eq!(builds.count(), 1);
+    assert_eq!(eval!(e, a + crate1::r() + crate2::r()), text_plain("43"));
+    assert!(e.execute(":deps end").is_err());
+}
+
+#[test]
+fn offline_mode() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":offline on"),
+        text_plain("Offline mode: true\n")
+    );
+    // Path dependencies don't need the network, so work the same as crates in the local cache.
+    let krate = TmpCrate::new("offline_dep", "pub fn r() -> u32 { 42 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    assert_eq!(eval!(e, offline_dep::r()), text_plain("42"));
+    let start = std::time::Instant::now();
+    let error = e
+        .execute(":dep evcxr_crate_that_is_not_cached = \"1.0\"")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Offline mode is on"), "{}", error);
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+    assert!(e.execute(":offline maybe").is_err());
+}
+
+#[test]
+fn freeze_deps() {
+    let mut e = new_command_context_and_outputs().0;
+    // Build with an old version of log, then freeze it.
+    eval_and_unwrap(&mut e, ":dep log = \"=0.4.14\"");
+    eval_and_unwrap(&mut e, "log::info!(\"built\");");
+    assert!(eval_and_unwrap(&mut e, ":freeze")["text/plain"].starts_with("Froze the versions of "));
+    let compile_dir = eval_and_unwrap(&mut e, ":last_compile_dir")["text/plain"].clone();
+    let lock_file = Path::new(compile_dir.trim().trim_matches('"')).join("Cargo.lock");
+    let log_version = |lock: &str| {
+        lock.split("[[package]]")
+            .find(|package| package.contains("name = \"log\""))
+            .and_then(|package| package.lines().find(|line| line.starts_with("version = ")))
+            .map(str::to_owned)
+    };
+    // Relax the version requirement and discard the lock file that cargo would otherwise reuse.
+    // Without the freeze, cargo would now pick the latest 0.4 release.
+    std::fs::remove_file(&lock_file).unwrap();
+    eval_and_unwrap(&mut e, ":dep log = \"0.4\"");
+    let krate = TmpCrate::new("frozen_extra", "pub fn r() -> u32 { 42 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    assert_eq!(eval!(e, frozen_extra::r()), text_plain("42"));
+    let built = std::fs::read_to_string(&lock_file).unwrap();
+    assert_eq!(
+        log_version(&built).as_deref(),
+        Some("version = \"0.4.14\""),
+        "{}",
+        built
+    );
+    assert!(built.contains("name = \"frozen_extra\""), "{}", built);
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":freeze off"),
+        text_plain("Dependency versions unfrozen\n")
+    );
+    std::fs::remove_file(&lock_file).unwrap();
+    eval_and_unwrap(&mut e, "log::info!(\"rebuilt\");");
+    let unfrozen = std::fs::read_to_string(&lock_file).unwrap();
+    assert_ne!(
+        log_version(&unfrozen).as_deref(),
+        Some("version = \"0.4.14\""),
+        "{}",
+        unfrozen
+    );
+}
+
+#[test]
+fn load_config() {
+    let config_dir = tempfile::tempdir().unwrap();
+    std::fs::write(config_dir.path().join("init.evcxr"), ":fmt {:x}\n").unwrap();
+    std::fs::write(
+        config_dir.path().join("prelude.rs"),
+        "use std::collections::{\n    HashMap,\n};\nlet from_prelude = 42;",
+    )
+    .unwrap();
+    // No other test loads config, so setting this doesn't affect them.
+    std::env::set_var("EVCXR_CONFIG_DIR", config_dir.path());
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, ":load_config --quiet");
+    assert_eq!(eval!(e, from_prelude), text_plain("2a"));
+    // Imports from prelude.rs become part of the :prelude, so survive clearing.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":prelude"),
+        text_plain("use std::collections::{ HashMap, };\n")
+    );
+    eval_and_unwrap(&mut e, ":clear");
+    assert_eq!(eval!(e, HashMap::<i32, i32>::new().len()), text_plain("0"));
+}
+
+#[test]
+fn toggle_commands_accept_on_off_and_1_0() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":preserve_vars_on_panic on"),
+        text_plain("Preserve vars on panic: true\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":preserve_vars_on_panic 0"),
+        text_plain("Preserve vars on panic: false\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":autobind 1"),
+        text_plain("Autobind: on\n")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":autobind off"),
+        text_plain("Autobind: off\n")
+    );
+    assert!(e.execute(":autobind yes").is_err());
+}
+
+#[test]
+fn custom_crate_name() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let a = 40;");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":crate_name notebook"),
+        text_plain("Crate name: notebook\n")
+    );
+    // With the default name, this module would be confusing, since `ctx::` would also refer to
+    // our crate in type names.
+    eval_and_unwrap(&mut e, "mod ctx { pub fn two() -> i32 { 2 } }");
+    assert_eq!(eval!(e, a + crate::ctx::two()), text_plain("42"));
+    let compile_dir = eval_and_unwrap(&mut e, ":last_compile_dir")["text/plain"].clone();
+    let compile_dir = compile_dir.trim().trim_matches('"').replace("\\\\", "\\");
+    let manifest =
+        std::fs::read_to_string(std::path::Path::new(&compile_dir).join("Cargo.toml")).unwrap();
+    assert!(manifest.contains("name = \"notebook\""), "{}", manifest);
+    eval_and_unwrap(&mut e, "struct Point;");
+    assert_eq!(
+        eval!(e, std::any::type_name::<Point>()),
+        text_plain("\"notebook::Point\"")
+    );
+    assert!(e.execute(":crate_name not-valid").is_err());
+    assert!(e.execute(":crate_name std").is_err());
+}
+
+#[test]
+fn export_deps() {
+    let mut e = new_command_context_and_outputs().0;
+    let krate = TmpCrate::new("exported_dep", "pub fn r() -> u32 { 42 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    eval_and_unwrap(&mut e, ":dep log = \"0.4\"");
+    let path = krate.tempdir.path().join("deps.toml");
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":export_deps {}", path.display())),
+        text_plain(&format!("Exported 2 dependencies to {}\n", path.display()))
+    );
+    let exported = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = exported.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", exported);
+    assert_eq!(lines[0], "[dependencies]");
+    assert!(
+        lines[1].starts_with("exported_dep = { path = "),
+        "{}",
+        exported
+    );
+    let dir_name = krate.tempdir.path().file_name().unwrap().to_string_lossy();
+    assert!(lines[1].contains(dir_name.as_ref()), "{}", exported);
+    assert_eq!(lines[2], "log = \"0.4\"");
+}
+
+#[test]
+fn native_style_paths() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let krate = TmpCrate::new("native_paths", "pub fn r() -> u32 { 42 }").unwrap();
+    // Quotes around a command's path are removed.
+    eval_and_unwrap(
+        &mut e,
+        &format!(":cd \"{}\"", krate.tempdir.path().join("src").display()),
+    );
+    // On Windows, users tend to write paths with backslashes without escaping them.
+    let relative_path = if cfg!(windows) {
+        r"..\src\.."
+    } else {
+        "../src/.."
+    };
+    eval_and_unwrap(
+        &mut e,
+        &format!(":dep native_paths = {{ path = \"{}\" }}", relative_path),
+    );
+    assert_eq!(eval!(e, native_paths::r()), text_plain("42"));
+}
+
+#[test]
+fn crate_deps() {
+    let (mut e, _) = new_command_context_and_outputs();
+    // Try loading a crate that doesn't exist. This it to make sure that we
+    // don't keep this bad crate around for subsequent execution attempts.
+    let r = e.execute(
+        r#"
+       :dep bad = { path = "this_path_does_not_exist"}
+       40"#,
+    );
+    assert!(r.is_err());
+    let crate1 = TmpCrate::new("crate1", "pub fn r20() -> i32 {20}").unwrap();
+    let error = e
+        .execute(&crate1.dep_command(r#"features = ["no_such_feature"]"#))
+        .unwrap_err();
+    assert!(error.to_string().contains("no_such_feature"));
+    let crate2 = TmpCrate::new("crate2", "pub fn r22() -> i32 {22}").unwrap();
+    let to_run =
+        crate1.dep_command("") + "\n" + &crate2.dep_command("") + "\ncrate1::r20() + crate2::r22()";
+    let outputs = e.execute(&to_run).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+}
+
+#[test]
+fn dep_features_accumulate() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new(
+        "crate1",
+        r#"
+        #[cfg(feature = "a")]
+        pub fn a() -> i32 {40}
+        #[cfg(feature = "b")]
+        pub fn b() -> i32 {2}
+    "#,
+    )
+    .unwrap();
+    crate1.add_features(&["a", "b"]).unwrap();
+    eval_and_unwrap(&mut e, &crate1.dep_command(r#"features = ["a"]"#));
+    let outputs = eval_and_unwrap(&mut e, &crate1.dep_command(r#"features = ["b"]"#));
+    assert!(outputs["text/plain"].contains(r#"features = ["a", "b"]"#));
+    assert_eq!(eval!(e, crate1::a() + crate1::b()), text_plain("42"));
+}
+
+#[test]
+fn dep_tree() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn a() -> i32 {42}").unwrap();
+    let crate2 = TmpCrate::new("crate2", "pub use crate1::a;").unwrap();
+    let manifest = crate2.tempdir.path().join("Cargo.toml");
+    let mut contents = std::fs::read_to_string(&manifest).unwrap();
+    contents.push_str(&format!(
+        "[dependencies]\ncrate1 = {{ path = \"{}\" }}\n",
+        crate1
+            .tempdir
+            .path()
+            .to_string_lossy()
+            .replace('\\', "\\\\")
+    ));
+    std::fs::write(&manifest, contents).unwrap();
+    eval_and_unwrap(&mut e, &crate2.dep_command(""));
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":dep_tree"),
+        text_plain("crate2 v0.0.1\n  crate1 v0.0.1\n")
+    );
+}
+
+#[test]
+fn reload_dep() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let krate = TmpCrate::new("reloaded_dep", "pub fn r() -> u32 { 1 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    eval_and_unwrap(&mut e, "let a = 40u32;");
+    assert_eq!(eval!(e, reloaded_dep::r()), text_plain("1"));
+    std::fs::write(
+        krate.tempdir.path().join("src").join("lib.rs"),
+        "pub fn r() -> u32 { 2 }",
+    )
+    .unwrap();
+    let builds = BuildCounter::new(&mut e);
+    eval_and_unwrap(&mut e, ":reload_dep reloaded_dep");
+    assert_eq!(builds.count(), 1);
+    assert_eq!(eval!(e, a + reloaded_dep::r()), text_plain("42"));
+    assert!(e.execute(":reload_dep not_a_dep").is_err());
+}
+
+#[test]
+fn reload_dep_removes_variables_of_its_types() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let krate =
+        TmpCrate::new("changed_dep", "#[derive(Debug)] pub struct S { pub a: u8 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    eval_and_unwrap(&mut e, "let s = changed_dep::S { a: 1 }; let n = 41;");
+    // Reading the old value as the new layout would be undefined behavior.
+    std::fs::write(
+        krate.tempdir.path().join("src").join("lib.rs"),
+        "#[derive(Debug)] pub struct S { pub a: u8, pub b: String }",
+    )
+    .unwrap();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":reload_dep changed_dep"),
+        text_plain("Removed variable `s`, since its type came from `changed_dep`\n")
+    );
+    assert_eq!(variable_names(&e), vec!["n"]);
+    assert_eq!(
+        eval!(
+            e,
+            changed_dep::S {
+                a: n as u8 + 1,
+                b: "new".to_owned()
+            }
+        ),
+        text_plain("S { a: 42, b: \"new\" }")
+    );
+}
+
+#[test]
+fn dep_remove_drops_variables_of_its_types() {
     let (mut e, _) = new_command_context_and_outputs();
+    let krate = TmpCrate::new(
+        "removed_dep",
+        "#[derive(Debug)] pub struct Bar(pub i32); pub fn r() -> i32 { 1 }",
+    )
+    .unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
     eval_and_unwrap(
         &mut e,
-        r#"
-        :preserve_vars_on_panic 1
-        let a = vec![1, 2, 3];
-        let b = 42;
-    "#,
+        "let bar = removed_dep::Bar(40); let bars = vec![removed_dep::Bar(1)]; \
+         let n = removed_dep::r();",
     );
-    eval!(e, panic!("Intentional panic {}", b););
-    // The variable a isn't referenced by the code that panics, while the variable b implements
-    // Copy, so neither should be lost.
     assert_eq!(
-        eval!(e, format!("{:?}, {}", a, b)),
-        text_plain("\"[1, 2, 3], 42\"")
+        eval_and_unwrap(&mut e, ":dep_remove removed_dep"),
+        text_plain("Removed variables `bar`, `bars`, since their types came from `removed_dep`\n")
     );
+    assert_eq!(variable_names(&e), vec!["n"]);
+    assert_eq!(eval!(e, n + 41), text_plain("42"));
+    assert!(e.execute(":dep_remove removed_dep").is_err());
 }
 
 #[test]
-fn function_panics_without_variable_preserving() {
-    // Don't allow stderr to be printed here. We don't really want to see the
-    // panic stack trace when running tests.
+fn dep_respecify_default_features() {
     let (mut e, _) = new_command_context_and_outputs();
-    eval_and_unwrap(
-        &mut e,
+    let crate1 = TmpCrate::new(
+        "crate1",
         r#"
-        :preserve_vars_on_panic 0
-        let a = vec![1, 2, 3];
-        let b = 42;
+        #[cfg(feature = "a")]
+        pub fn a() -> i32 {40}
+        pub fn b() -> i32 {2}
     "#,
+    )
+    .unwrap();
+    crate1.add_features(&["a"]).unwrap();
+    let manifest = crate1.tempdir.path().join("Cargo.toml");
+    let mut contents = std::fs::read_to_string(&manifest).unwrap();
+    contents.push_str("default = [\"a\"]\n");
+    std::fs::write(&manifest, contents).unwrap();
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    assert_eq!(eval!(e, crate1::a() + crate1::b()), text_plain("42"));
+    let outputs = eval_and_unwrap(&mut e, ":dep crate1 = { default-features = false }");
+    let output = &outputs["text/plain"];
+    assert!(
+        output.starts_with("Updated crate1 = { path = "),
+        "{}",
+        output
     );
-    let result = e.execute(stringify!(panic!("Intentional panic {}", b);));
-    if let Err(Error::SubprocessTerminated(message)) = result {
-        assert!(message.contains("Subprocess terminated"));
-    } else {
-        panic!("Unexpected result: {:?}", result);
-    }
-    assert_eq!(variable_names_and_types(&e), vec![]);
-    // Make sure that a compilation error doesn't bring the variables back from
-    // the dead.
-    assert!(e.execute("This will not compile").is_err());
-    assert_eq!(variable_names_and_types(&e), vec![]);
+    assert!(
+        output.ends_with(", default-features = false }\n"),
+        "{}",
+        output
+    );
+    assert!(e.execute("crate1::a()").is_err());
+    assert_eq!(eval!(e, crate1::b()), text_plain("2"));
 }
 
-// Also tests multiple item definitions in the one compilation unit.
 #[test]
-fn tls_implementing_drop() {
-    let mut e = new_context();
-    eval!(e,
-        pub struct Foo {}
-        impl Drop for Foo {
-            fn drop(&mut self) {
-                println!("Dropping Foo");
-            }
-        }
-        pub fn init_foo() {
-            thread_local! {
-                pub static FOO: Foo = Foo {};
-            }
-            FOO.with(|f| ())
-        }
-    );
-    eval!(e, init_foo(););
+fn list_deps() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let crate1 = TmpCrate::new("crate1", "pub fn r40() -> i32 {40}").unwrap();
+    let crate2 = TmpCrate::new("crate2", "pub fn r2() -> i32 {2}").unwrap();
+    eval_and_unwrap(&mut e, &crate2.dep_command(""));
+    eval_and_unwrap(&mut e, &crate1.dep_command(""));
+    let outputs = eval_and_unwrap(&mut e, ":deps");
+    let lines: Vec<&str> = outputs["text/plain"].lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("crate1 = { path = "));
+    assert!(lines[0].ends_with("(path, 0.0.1)"));
+    assert!(lines[1].starts_with("crate2 = { path = "));
 }
 
-fn text_plain(content: &str) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    map.insert("text/plain".to_owned(), content.to_owned());
-    map
+/// Adds the evcxr_runtime crate from this repository as a dependency, with `features` enabled.
+fn add_runtime_dep(e: &mut CommandContext, features: &[&str]) {
+    let runtime_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../evcxr_runtime")
+        .canonicalize()
+        .unwrap();
+    eval_and_unwrap(
+        e,
+        &format!(
+            ":dep evcxr_runtime = {{ path = \"{}\", features = {:?} }}",
+            runtime_dir.to_string_lossy(),
+            features
+        ),
+    );
 }
 
 #[test]
-fn moved_value() {
-    let mut e = new_context();
-    eval!(e, let a = Some("foo".to_owned()););
-    assert_eq!(variable_names_and_types(&e), vec![("a", "Option<String>")]);
-    assert_eq!(eval!(e, a.unwrap()), text_plain("\"foo\""));
-    assert_eq!(variable_names_and_types(&e), vec![]);
+fn latex_output() {
+    let (mut e, _) = new_command_context_and_outputs();
+    add_runtime_dep(&mut e, &[]);
+    let outputs = eval_and_unwrap(&mut e, r#"evcxr_runtime::latex(r"$$\frac{1}{2}$$");"#);
+    assert_eq!(
+        outputs.get("text/latex").map(String::as_str),
+        Some(r"$$\frac{1}{2}$$")
+    );
 }
 
-struct TmpCrate {
-    name: String,
-    tempdir: tempfile::TempDir,
+#[test]
+fn markdown_output() {
+    let (mut e, _) = new_command_context_and_outputs();
+    add_runtime_dep(&mut e, &[]);
+    let outputs = eval_and_unwrap(
+        &mut e,
+        r##"evcxr_runtime::markdown("# Results\n\n*All* passed");"##,
+    );
+    assert_eq!(
+        outputs.get("text/markdown").map(String::as_str),
+        Some("# Results\n\n*All* passed")
+    );
 }
 
-impl TmpCrate {
-    fn new(name: &str, src: &str) -> Result<TmpCrate, io::Error> {
-        let tempdir = tempfile::tempdir()?;
-        let src_dir = tempdir.path().join("src");
-        std::fs::create_dir_all(&src_dir)?;
-        std::fs::write(
-            tempdir.path().join("Cargo.toml"),
-            format!(
-                "\
-                 [package]\n\
-                 name = \"{}\"\n\
-                 version = \"0.0.1\"\n\
-                 edition = \"2018\"\n\
-                 ",
-                name
-            ),
-        )?;
-        std::fs::write(src_dir.join("lib.rs"), src)?;
-        Ok(TmpCrate {
-            name: name.to_owned(),
-            tempdir,
-        })
-    }
-
-    fn dep_command(&self, extra_options: &str) -> String {
-        format!(
-            ":dep {} = {{ path = \"{}\"{}{} }}",
-            self.name,
-            self.tempdir.path().to_string_lossy().replace("\\", "\\\\"),
-            if extra_options.is_empty() { "" } else { ", " },
-            extra_options
-        )
-    }
+#[test]
+fn json_output() {
+    let (mut e, _) = new_command_context_and_outputs();
+    add_runtime_dep(&mut e, &["json"]);
+    let outputs = eval_and_unwrap(&mut e, r#"evcxr_runtime::json(&(1, "two", [3.5]));"#);
+    assert_eq!(
+        outputs.get("application/json").map(String::as_str),
+        Some(r#"[1,"two",[3.5]]"#)
+    );
+    assert_eq!(
+        outputs.get("text/plain").map(String::as_str),
+        Some("[\n  1,\n  \"two\",\n  [\n    3.5\n  ]\n]")
+    );
+    // Maps with non-string keys can't be serialized as JSON, so we fall back to Debug.
+    let outputs = eval_and_unwrap(
+        &mut e,
+        "let mut map = std::collections::BTreeMap::new();\n\
+         map.insert((1, 2), 3);\n\
+         evcxr_runtime::json(&map);",
+    );
+    assert_eq!(outputs.get("application/json"), None);
+    assert_eq!(
+        outputs.get("text/plain").map(String::as_str),
+        Some("{(1, 2): 3}")
+    );
 }
 
 #[test]
-fn crate_deps() {
-    let (mut e, _) = new_command_context_and_outputs();
-    // Try loading a crate that doesn't exist. This it to make sure that we
-    // don't keep this bad crate around for subsequent execution attempts.
-    let r = e.execute(
+fn background_thread_output() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    add_runtime_dep(&mut e, &[]);
+    eval_and_unwrap(
+        &mut e,
         r#"
-       :dep bad = { path = "this_path_does_not_exist"}
-       40"#,
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        evcxr_runtime::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            println!("Delayed output");
+            let _ = sender.send(());
+        });
+        "#,
     );
-    assert!(r.is_err());
-    let crate1 = TmpCrate::new("crate1", "pub fn r20() -> i32 {20}").unwrap();
-    let error = e
-        .execute(&crate1.dep_command(r#"features = ["no_such_feature"]"#))
-        .unwrap_err();
-    assert!(error.to_string().contains("no_such_feature"));
-    let crate2 = TmpCrate::new("crate2", "pub fn r22() -> i32 {22}").unwrap();
-    let to_run =
-        crate1.dep_command("") + "\n" + &crate2.dep_command("") + "\ncrate1::r20() + crate2::r22()";
-    let outputs = e.execute(&to_run).unwrap();
-    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    // The cell has finished, but the thread's output is still forwarded.
+    assert_eq!(
+        outputs
+            .stdout
+            .recv_timeout(std::time::Duration::from_secs(30))
+            .unwrap(),
+        "Delayed output"
+    );
+    eval_and_unwrap(&mut e, "receiver.recv().unwrap();");
 }
 
 #[test]
@@ -538,6 +2946,87 @@ fn multiple_identical_use_statements() {
     eval!(e, use std::collections::HashMap;);
 }
 
+#[test]
+fn glob_import_from_user_module() {
+    let mut e = new_context();
+    eval!(
+        e,
+        mod utils {
+            pub fn double(x: i32) -> i32 {
+                x * 2
+            }
+            pub mod nested {
+                pub fn triple(x: i32) -> i32 {
+                    super::double(x) + x
+                }
+            }
+        }
+    );
+    eval!(e, use utils::*;);
+    assert_eq!(eval!(e, double(20)), text_plain("40"));
+    eval!(e, use self::utils::nested::*;);
+    assert_eq!(eval!(e, triple(3)), text_plain("9"));
+    eval!(e, use crate::utils::nested::triple as t;);
+    assert_eq!(eval!(e, t(2) + nested::triple(1)), text_plain("9"));
+    // Redefining the module should be picked up by the existing glob import.
+    eval!(
+        e,
+        mod utils {
+            pub fn double(x: i32) -> i32 {
+                x + x + 1
+            }
+            pub mod nested {
+                pub fn triple(x: i32) -> i32 {
+                    x * 3
+                }
+            }
+        }
+    );
+    assert_eq!(eval!(e, double(20)), text_plain("41"));
+}
+
+#[test]
+fn load_module_from_file() {
+    // Modules are loaded relative to the current directory. Tests run in parallel, so rather than
+    // changing directory, we use a module name that nothing else uses and clean up afterwards.
+    struct RemoveOnDrop(std::path::PathBuf);
+    impl Drop for RemoveOnDrop {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+    let path = std::env::current_dir()
+        .unwrap()
+        .join("evcxr_test_external_module.rs");
+    let _cleanup = RemoveOnDrop(path.clone());
+    std::fs::write(
+        &path,
+        "//! Helpers.\npub fn double(x: i32) -> i32 { x * 2 }\npub struct Wrapper(pub i32);\n",
+    )
+    .unwrap();
+    let mut e = new_context();
+    eval!(e, mod evcxr_test_external_module;);
+    // The module's contents were captured when it was loaded.
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        eval_and_unwrap(
+            &mut e,
+            "use evcxr_test_external_module::*; double(Wrapper(21).0)"
+        ),
+        text_plain("42")
+    );
+
+    match e.execute("mod evcxr_test_missing_module;") {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0]
+                .message()
+                .starts_with("Couldn't find a file for module `evcxr_test_missing_module`"));
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+}
+
 #[test]
 fn redefine_type_with_existing_var() {
     let mut e = new_context();
@@ -721,6 +3210,83 @@ fn question_mark_operator() {
     );
 }
 
+#[test]
+fn question_mark_operator_keeps_previous_values() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":efmt {:?}");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":retain_on_error on"),
+        text_plain("Retain on error: on\n")
+    );
+    eval_and_unwrap(
+        &mut e,
+        "let kept = 1; let replaced = 2; let moved = String::from(\"moved\");",
+    );
+    eval_and_unwrap(
+        &mut e,
+        "let replaced = 3; let fresh = 4; let moved = moved + \"!\"; \
+         std::fs::read_to_string(\"/does/not/exist\")?;",
+    );
+    // `replaced` keeps the value it had before the cell. The cell moved out of the previous value
+    // of `moved`, so that's lost. `fresh` was never defined, so isn't mentioned.
+    let mut expected = vec![
+        "An error was returned, the following variables have been lost: moved",
+        "An error was returned, the following variables keep their previous values: replaced",
+    ];
+    while !expected.is_empty() {
+        match outputs
+            .stderr
+            .recv_timeout(std::time::Duration::from_secs(10))
+        {
+            Ok(line) => expected.retain(|expected| *expected != line),
+            Err(error) => panic!("Didn't get variable warnings {:?}: {}", expected, error),
+        }
+    }
+    assert_eq!(variable_names(&e), vec!["kept", "replaced"]);
+    assert_eq!(eval!(e, kept), text_plain("1"));
+    assert_eq!(eval!(e, replaced), text_plain("2"));
+}
+
+#[test]
+fn try_mode() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":try on"),
+        text_plain("Try mode: on\n")
+    );
+    eval_and_unwrap(&mut e, ":efmt {:?}");
+    eval_and_unwrap(&mut e, "let kept = 1;");
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        let parsed: i32 = "41".parse()?;
+        let mut names = vec!["a".to_owned()];
+        names.push("b".to_owned());
+        let contents = std::fs::read_to_string("/does/not/exist")?;
+        let after = contents.len();
+        "#,
+    );
+    // The error is displayed.
+    loop {
+        match outputs
+            .stderr
+            .recv_timeout(std::time::Duration::from_secs(10))
+        {
+            Ok(line) if line.contains("NotFound") => break,
+            Ok(_) => {}
+            Err(error) => panic!("Didn't get error: {}", error),
+        }
+    }
+    // Variables defined before the failed statement are kept.
+    assert_eq!(variable_names(&e), vec!["kept", "names", "parsed"]);
+    assert_eq!(eval!(e, parsed + kept), text_plain("42"));
+    assert_eq!(eval!(e, names.len()), text_plain("2"));
+    assert_eq!(
+        eval_and_unwrap(&mut e, r#""42".parse::<i32>()?"#),
+        text_plain("42")
+    );
+}
+
 #[test]
 fn format() {
     let mut e = new_context();
@@ -767,6 +3333,40 @@ fn define_then_call_macro() {
     assert_eq!(eval!(e, foo!(2)), text_plain("42"));
 }
 
+#[test]
+fn force_statement_or_item() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(
+        &mut e,
+        r#"
+macro_rules! define_answer {
+    () => {
+        fn answer() -> i32 { 42 }
+    };
+}
+"#,
+    );
+    // By default, macro invocations are statements, so the function is only defined within the
+    // cell.
+    eval_and_unwrap(&mut e, "define_answer!();");
+    assert!(e.execute("answer()").is_err());
+    eval_and_unwrap(&mut e, ":stmt\ndefine_answer!();\nanswer()");
+    assert!(e.execute("answer()").is_err());
+    eval_and_unwrap(&mut e, ":item\ndefine_answer!()");
+    assert_eq!(eval!(e, answer()), text_plain("42"));
+    // Items can be made local to the cell.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":stmt\nfn local() -> i32 { 1 }\nlocal()"),
+        text_plain("1")
+    );
+    assert!(e.execute("local()").is_err());
+    // The override only applies to the cell that it's in.
+    eval_and_unwrap(&mut e, "fn local() -> i32 { 2 }");
+    assert_eq!(eval!(e, local()), text_plain("2"));
+    assert!(e.execute(":item\nlet x = 1;").is_err());
+    assert!(e.execute(":stmt define_answer!()").is_err());
+}
+
 fn simple_completions(ctx: &mut CommandContext, code: &str) -> HashSet<String> {
     ctx.completions(code, code.len())
         .unwrap()
@@ -824,7 +3424,13 @@ fn code_completion() {
             .iter()
             .map(|c| c.code.as_str())
             .collect::<Vec<_>>(),
-        vec![":dep"]
+        vec![
+            ":dep",
+            ":dep_remove",
+            ":dep_tree",
+            ":deps",
+            ":deterministic"
+        ]
     );
 
     // Check that we get zero completions when expected.
@@ -938,6 +3544,68 @@ fn assert_no_errors(ctx: &mut CommandContext, code: &str) {
     assert_eq!(strs(&check(ctx, code)), Vec::<&str>::new());
 }
 
+#[test]
+fn incomplete_input() {
+    use evcxr::InputStatus;
+    let e = new_context();
+    assert_eq!(e.input_status("let a = 1;"), InputStatus::Complete);
+    assert_eq!(
+        e.input_status(":dep foo = \"1.0\"\nfn f() {\n    if true {"),
+        InputStatus::Incomplete
+    );
+    assert_eq!(e.input_status("let = 5;"), InputStatus::Invalid);
+    assert_eq!(e.input_status("fn f() { 1 }}"), InputStatus::Invalid);
+    assert_eq!(e.input_status(":vars"), InputStatus::Complete);
+}
+
+#[test]
+fn check_does_not_affect_state() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(&mut e, "let a = 1;");
+    assert_eq!(
+        strs(&check(&mut e, "let b: i32 = \"not an int\";")),
+        vec!["error 1:14-1:26"]
+    );
+    assert_no_errors(&mut e, ":fmt {:x}\nlet checked = a + 1; fn checked_fn() {}");
+    assert_eq!(variable_names(&e), vec!["a"]);
+    assert!(e.execute("checked_fn()").is_err());
+    assert_eq!(eval!(e, a + 10), text_plain("11"));
+}
+
+#[test]
+fn allow_unused_imports() {
+    let mut e = new_command_context_and_outputs().0;
+    assert_no_errors(&mut e, "use std::collections::*;");
+    eval_and_unwrap(&mut e, ":allow_unused_imports off");
+    assert_eq!(
+        strs(&check(&mut e, "use std::collections::*;")),
+        vec!["warning 1:5-1:24"]
+    );
+    eval_and_unwrap(&mut e, ":allow_unused_imports on");
+    assert_no_errors(&mut e, "use std::collections::*;");
+}
+
+#[test]
+fn ambiguous_glob_imports() {
+    let mut e = new_command_context_and_outputs().0;
+    eval_and_unwrap(
+        &mut e,
+        "mod first { pub type Output = i32; }\nmod second { pub type Output = u8; }",
+    );
+    eval_and_unwrap(&mut e, "use first::*;");
+    eval_and_unwrap(&mut e, "use second::*;");
+    match e.execute("let x: Output = 1;") {
+        Err(Error::CompilationErrors(errors)) => {
+            let message = errors[0].message();
+            assert!(message.starts_with("`Output` is ambiguous"), "{}", message);
+            assert!(message.contains("`first::Output`"), "{}", message);
+            assert!(message.contains("`second::Output`"), "{}", message);
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+    eval_and_unwrap(&mut e, "let x: first::Output = 1;");
+}
+
 #[test]
 fn check_for_errors() {
     let mut ctx = new_context();
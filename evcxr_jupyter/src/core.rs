@@ -21,6 +21,7 @@ use ariadne::sources;
 use colored::*;
 use crossbeam_channel::Select;
 use evcxr::CommandContext;
+use evcxr::InputStatus;
 use evcxr::Theme;
 use json::JsonValue;
 use std::collections::HashMap;
@@ -29,6 +30,7 @@ use std::sync::Mutex;
 use std::thread;
 use std::time;
 use std::time::Duration;
+use uuid::Uuid;
 
 // Note, to avoid potential deadlocks, each thread should lock at most one mutex at a time.
 #[derive(Clone)]
@@ -36,6 +38,9 @@ pub(crate) struct Server {
     iopub: Arc<Mutex<Connection>>,
     stdin: Arc<Mutex<Connection>>,
     latest_execution_request: Arc<Mutex<Option<JupyterMessage>>>,
+    /// The ID of the display that shows the line currently being rewritten by progress output, if
+    /// any. See `pass_output_line`.
+    progress_display_id: Arc<Mutex<Option<String>>>,
     shutdown_requested_receiver: Arc<Mutex<crossbeam_channel::Receiver<()>>>,
     shutdown_requested_sender: Arc<Mutex<crossbeam_channel::Sender<()>>>,
 }
@@ -73,6 +78,7 @@ impl Server {
         let server = Server {
             iopub,
             latest_execution_request: Arc::new(Mutex::new(None)),
+            progress_display_id: Arc::new(Mutex::new(None)),
             stdin: Arc::new(Mutex::new(stdin_socket)),
             shutdown_requested_receiver: Arc::new(Mutex::new(shutdown_requested_receiver)),
             shutdown_requested_sender: Arc::new(Mutex::new(shutdown_requested_sender)),
@@ -83,8 +89,11 @@ impl Server {
             crossbeam_channel::unbounded();
 
         thread::spawn(move || Self::handle_hb(&heartbeat));
-        server.start_thread(move |server: Server| server.handle_control(control_socket));
         let (mut context, outputs) = CommandContext::new()?;
+        let interrupt_handle = context.interrupt_handle();
+        server.start_thread(move |server: Server| {
+            server.handle_control(control_socket, interrupt_handle)
+        });
         context.execute(":load_config")?;
         let context = Arc::new(Mutex::new(context));
         server.start_thread({
@@ -162,6 +171,7 @@ impl Server {
             // If we want this clone to be cheaper, we probably only need the header, not the
             // whole message.
             *self.latest_execution_request.lock().unwrap() = Some(message.clone());
+            *self.progress_display_id.lock().unwrap() = None;
             let src = message.code();
             execution_count += 1;
             message
@@ -316,10 +326,12 @@ impl Server {
                 .with_content(kernel_info())
                 .send(connection)?;
         } else if message.message_type() == "is_complete_request" {
-            message
-                .new_reply()
-                .with_content(object! {"status" => "complete"})
-                .send(connection)?;
+            let content = match context.lock().unwrap().input_status(message.code()) {
+                InputStatus::Complete => object! {"status" => "complete"},
+                InputStatus::Incomplete => object! {"status" => "incomplete", "indent" => ""},
+                InputStatus::Invalid => object! {"status" => "invalid"},
+            };
+            message.new_reply().with_content(content).send(connection)?;
         } else if message.message_type() == "execute_request" {
             execution_channel.send(message)?;
             execution_reply_receiver.recv()?.send(connection)?;
@@ -351,16 +363,18 @@ impl Server {
         Ok(())
     }
 
-    fn handle_control(self, connection: Connection) -> Result<()> {
+    fn handle_control(
+        self,
+        connection: Connection,
+        interrupt_handle: evcxr::InterruptHandle,
+    ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&connection)?;
             match message.message_type() {
                 "shutdown_request" => self.signal_shutdown(),
                 "interrupt_request" => {
+                    interrupt_handle.interrupt();
                     message.new_reply().send(&connection)?;
-                    eprintln!(
-                        "Rust doesn't support interrupting execution. Perhaps restart kernel?"
-                    );
                 }
                 _ => {
                     eprintln!(
@@ -398,17 +412,53 @@ impl Server {
         });
     }
 
+    /// Sends a line of output to the frontend. Lines that start with `\r` are progress output
+    /// (see `:progress_bars`), which replaces the preceding progress output, so we show them in a
+    /// display that we then update. Partial lines are sent without a newline, since the frontend
+    /// joins up consecutive stream output.
     fn pass_output_line(&self, output_name: &'static str, line: String) {
+        let (message_type, content) = match line.strip_prefix('\r') {
+            Some(text) => {
+                let mut progress_display_id = self.progress_display_id.lock().unwrap();
+                let message_type = if progress_display_id.is_some() {
+                    "update_display_data"
+                } else {
+                    "display_data"
+                };
+                let display_id = progress_display_id
+                    .get_or_insert_with(|| Uuid::new_v4().to_string())
+                    .clone();
+                (
+                    message_type,
+                    object! {
+                        "data" => object! {"text/plain" => text},
+                        "metadata" => object! {},
+                        "transient" => object! {"display_id" => display_id},
+                    },
+                )
+            }
+            None => {
+                *self.progress_display_id.lock().unwrap() = None;
+                let text = match line.strip_prefix(evcxr::PARTIAL_LINE_MARKER) {
+                    Some(text) => text.to_owned(),
+                    None => format!("{}\n", line),
+                };
+                (
+                    "stream",
+                    object! {
+                        "name" => output_name,
+                        "text" => text,
+                    },
+                )
+            }
+        };
         let mut message = None;
         if let Some(exec_request) = &*self.latest_execution_request.lock().unwrap() {
-            message = Some(exec_request.new_message("stream"));
+            message = Some(exec_request.new_message(message_type));
         }
         if let Some(message) = message {
             if let Err(error) = message
-                .with_content(object! {
-                    "name" => output_name,
-                    "text" => format!("{}\n", line),
-                })
+                .with_content(content)
                 .send(&self.iopub.lock().unwrap())
             {
                 eprintln!("{}", error);
@@ -488,6 +538,17 @@ impl Server {
                     }
                 }
             }
+            evcxr::Error::Interrupted => {
+                let displayed_error = format!("{}", errors);
+                parent_message
+                    .new_message("error")
+                    .with_content(object! {
+                        "ename" => "Interrupted",
+                        "evalue" => displayed_error.clone(),
+                        "traceback" => array![displayed_error],
+                    })
+                    .send(&self.iopub.lock().unwrap())?;
+            }
             error => {
                 let displayed_error = format!("{}", error);
                 parent_message
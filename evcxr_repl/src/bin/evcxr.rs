@@ -32,6 +32,8 @@ use rustyline::Movement;
 use rustyline::Word;
 use std::fs;
 use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use structopt::StructOpt;
 
@@ -40,40 +42,157 @@ const PROMPT: &str = ">> ";
 struct Repl {
     command_context: Arc<BgInitMutex<CommandContext>>,
     ide_mode: bool,
+    /// Whether `:protocol json` is active, in which case everything we write to stdout is a JSON
+    /// object on a single line.
+    json_protocol: Arc<AtomicBool>,
+    /// One per output forwarding thread. Each request carries a sender that the thread signals
+    /// once it has written all the output that was queued when it received the request.
+    flush_requests: Vec<crossbeam_channel::Sender<crossbeam_channel::Sender<()>>>,
 }
 
 fn send_output<T: io::Write + Send + 'static>(
     channel: crossbeam_channel::Receiver<String>,
     mut output: T,
     color: Option<Color>,
+    stream_name: &'static str,
+    json_protocol: Arc<AtomicBool>,
+    flush_requests: crossbeam_channel::Receiver<crossbeam_channel::Sender<()>>,
+    in_progress_line: Arc<AtomicBool>,
 ) {
     std::thread::spawn(move || {
-        while let Ok(line) = channel.recv() {
-            let status = if let Some(color) = color {
-                writeln!(output, "{}", line.color(color))
-            } else {
-                writeln!(output, "{}", line)
+        let mut write_line = |line: String| {
+            // Partial lines are written without a newline, since the rest of the line follows.
+            let (text, partial) = match line.strip_prefix(evcxr::PARTIAL_LINE_MARKER) {
+                Some(text) => (text, true),
+                None => (line.as_str(), false),
             };
-            if status.is_err() {
-                break;
+            if json_protocol.load(Ordering::Relaxed) {
+                // Output of user code is always written to stdout, so that the consumer only needs
+                // to read one stream.
+                let mut message = json::JsonValue::new_object();
+                message[stream_name] = text.into();
+                if partial {
+                    message["partial"] = true.into();
+                }
+                println!("{}", message.dump());
+                return Ok(());
+            }
+            let progress = text.strip_prefix('\r');
+            let text = progress.unwrap_or(text);
+            let text = match color {
+                Some(color) => text.color(color).to_string(),
+                None => text.to_owned(),
+            };
+            if progress.is_some() {
+                // Return to the start of the line and clear it, so that this replaces the
+                // previous progress output.
+                write!(output, "\r\x1b[K{}", text)?;
+                in_progress_line.store(true, Ordering::Relaxed);
+                return output.flush();
+            }
+            if in_progress_line.swap(false, Ordering::Relaxed) {
+                writeln!(output)?;
+            }
+            if partial {
+                write!(output, "{}", text)?;
+                output.flush()
+            } else {
+                writeln!(output, "{}", text)
+            }
+        };
+        loop {
+            crossbeam_channel::select! {
+                recv(channel) -> line => {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if write_line(line).is_err() {
+                        break;
+                    }
+                }
+                recv(flush_requests) -> done => {
+                    if let Ok(done) = done {
+                        for line in channel.try_iter() {
+                            let _ = write_line(line);
+                        }
+                        let _ = done.send(());
+                    }
+                }
             }
         }
     });
 }
 
 impl Repl {
-    fn new(ide_mode: bool, opt: String) -> Repl {
-        let initialize = move || -> Result<CommandContext, Error> {
-            let (mut command_context, outputs) = CommandContext::new()?;
+    fn new(ide_mode: bool, opt: String, prewarm: bool) -> Repl {
+        let json_protocol = Arc::new(AtomicBool::new(false));
+        let (stdout_flush_sender, stdout_flush_requests) = crossbeam_channel::unbounded();
+        let (stderr_flush_sender, stderr_flush_requests) = crossbeam_channel::unbounded();
+        let initialize = {
+            let json_protocol = Arc::clone(&json_protocol);
+            // Whether the last thing written to either stdout or stderr was progress output (see
+            // `:progress_bars`), which isn't followed by a newline, so that the next progress
+            // output can replace it.
+            let in_progress_line = Arc::new(AtomicBool::new(false));
+            move || -> Result<CommandContext, Error> {
+                let (mut command_context, outputs) = CommandContext::new()?;
 
-            send_output(outputs.stdout, io::stdout(), None);
-            send_output(outputs.stderr, io::stderr(), Some(Color::BrightRed));
-            command_context.execute(":load_config --quiet")?;
-            if !opt.is_empty() {
-                // Ignore failure
-                command_context.set_opt_level(&opt).ok();
+                send_output(
+                    outputs.stdout,
+                    io::stdout(),
+                    None,
+                    "stdout",
+                    Arc::clone(&json_protocol),
+                    stdout_flush_requests,
+                    Arc::clone(&in_progress_line),
+                );
+                send_output(
+                    outputs.stderr,
+                    io::stderr(),
+                    Some(Color::BrightRed),
+                    "stderr",
+                    Arc::clone(&json_protocol),
+                    stderr_flush_requests,
+                    Arc::clone(&in_progress_line),
+                );
+                command_context.register_command(
+                    ":protocol",
+                    "Set how results are written to stdout (human/json). In json mode, each \
+                     result is a single line of JSON",
+                    move |_ctx, args| {
+                        match args.map(str::trim) {
+                            None | Some("") => {}
+                            Some("json") => json_protocol.store(true, Ordering::Relaxed),
+                            Some("human") => json_protocol.store(false, Ordering::Relaxed),
+                            Some(other) => {
+                                return Err(
+                                    format!("Expected `human` or `json`, got `{}`", other).into()
+                                )
+                            }
+                        }
+                        let mut outputs = evcxr::EvalOutputs::new();
+                        outputs.content_by_mime_type.insert(
+                            "text/plain".to_owned(),
+                            if json_protocol.load(Ordering::Relaxed) {
+                                "Protocol: json".to_owned()
+                            } else {
+                                "Protocol: human".to_owned()
+                            },
+                        );
+                        Ok(outputs)
+                    },
+                )?;
+                command_context.execute(":load_config --quiet")?;
+                if prewarm {
+                    command_context.execute(":prewarm")?;
+                }
+                if !opt.is_empty() {
+                    // Ignore failure
+                    command_context.set_opt_level(&opt).ok();
+                }
+                Ok(command_context)
             }
-            Ok(command_context)
         };
         let command_context = Arc::new(BgInitMutex::new(move || {
             initialize().unwrap_or_else(|e| {
@@ -85,10 +204,31 @@ impl Repl {
         Repl {
             command_context,
             ide_mode,
+            json_protocol,
+            flush_requests: vec![stdout_flush_sender, stderr_flush_sender],
+        }
+    }
+
+    /// Waits until all output produced so far by user code has been written.
+    fn flush_output(&self) {
+        for flush_requests in &self.flush_requests {
+            let (done_sender, done) = crossbeam_channel::bounded(1);
+            if flush_requests.send(done_sender).is_ok() {
+                // An error means the forwarding thread has stopped, so has nothing to write.
+                let _ = done.recv();
+            }
         }
     }
+
     fn execute(&mut self, to_run: &str) {
         let execution_result = self.command_context.lock().execute(to_run);
+        if self.json_protocol.load(Ordering::Relaxed) {
+            // Consumers match output to the cell that produced it by its position before the
+            // cell's result.
+            self.flush_output();
+            println!("{}", json_result(execution_result).dump());
+            return;
+        }
         let success = match execution_result {
             Ok(output) => {
                 if let Some(text) = output.get("text/plain") {
@@ -196,6 +336,33 @@ impl Repl {
     }
 }
 
+/// Returns the result of executing a cell as a JSON object for `:protocol json`.
+fn json_result(execution_result: Result<evcxr::EvalOutputs, Error>) -> json::JsonValue {
+    let mut result = json::JsonValue::new_object();
+    match execution_result {
+        Ok(output) => {
+            result["success"] = true.into();
+            result["output"] = output.get("text/plain").into();
+            if let Some(duration) = output.timing {
+                result["duration_ms"] = (duration.as_millis() as u64).into();
+            }
+        }
+        Err(evcxr::Error::CompilationErrors(errors)) => {
+            result["success"] = false.into();
+            result["errors"] = errors
+                .iter()
+                .map(|error| error.message())
+                .collect::<Vec<_>>()
+                .into();
+        }
+        Err(err) => {
+            result["success"] = false.into();
+            result["errors"] = vec![err.to_string()].into();
+        }
+    }
+    result
+}
+
 /// Returns a 0-based grapheme index corresponding to the supplied 0-based character column.
 fn character_column_to_grapheme_number(character_column: usize, line: &str) -> usize {
     let mut characters_remaining = character_column;
@@ -247,6 +414,10 @@ struct Options {
         default_value = "emacs"
      )]
     edit_mode: rustyline::EditMode,
+    /// Compile a throwaway cell in the background at startup, so that the first real cell is
+    /// faster
+    #[structopt(long)]
+    prewarm: bool,
 }
 
 fn main() {
@@ -270,7 +441,7 @@ fn main() {
             println!("Prelude will be loaded from {}", prelude.display());
         }
     }
-    let mut repl = Repl::new(options.ide_mode, options.opt.clone());
+    let mut repl = Repl::new(options.ide_mode, options.opt.clone(), options.prewarm);
     let mut config_builder = match options.edit_mode {
         EditMode::Vi => {
             rustyline::Config::builder()
@@ -304,7 +475,12 @@ fn main() {
         opt_history_file = Some(history_file);
     }
     loop {
-        let prompt = format!("{}", PROMPT.yellow());
+        let prompt = if repl.json_protocol.load(Ordering::Relaxed) {
+            // A prompt would get in the way of parsing our output.
+            String::new()
+        } else {
+            format!("{}", PROMPT.yellow())
+        };
         let readline = if options.disable_readline {
             readline_direct(&prompt)
         } else {
@@ -12,8 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::io::Write;
 use std::process;
 
+fn evcxr_binary() -> std::path::PathBuf {
+    std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("evcxr")
+}
+
 // Checks that our binary can be executed. This used to be an important thing to
 // check due to https://github.com/rust-lang/rust/issues/45601 which meant that
 // we could easily end up with a binary that couldn't be executed (without
@@ -21,18 +32,10 @@ use std::process;
 // perhaps still has some value.
 #[test]
 fn test_binary_execution() {
-    let output = process::Command::new(
-        std::env::current_exe()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .join("evcxr"),
-    )
-    .env_remove("LD_LIBRARY_PATH")
-    .output()
-    .unwrap();
+    let output = process::Command::new(evcxr_binary())
+        .env_remove("LD_LIBRARY_PATH")
+        .output()
+        .unwrap();
     let stdout = std::str::from_utf8(&output.stdout).unwrap();
     let stderr = std::str::from_utf8(&output.stderr).unwrap();
     assert_eq!(stderr, "");
@@ -40,3 +43,56 @@ fn test_binary_execution() {
         panic!("Unexpected output:\n{:?}", stdout);
     }
 }
+
+#[test]
+fn test_json_protocol() {
+    let mut child = process::Command::new(evcxr_binary())
+        .arg("--disable-readline")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b":protocol json\nprintln!(\"hi\"); 40 + 2\nlet x: i32 = \"\";\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = std::str::from_utf8(&output.stdout).unwrap();
+    // The welcome message and the first prompt are written before we switch protocols.
+    let messages: Vec<json::JsonValue> = stdout
+        .lines()
+        .filter_map(|line| line.find('{').map(|start| &line[start..]))
+        .map(|line| json::parse(line).unwrap())
+        .collect();
+    assert_eq!(messages.len(), 4, "{}", stdout);
+    assert_eq!(messages[0]["output"], "Protocol: json");
+    assert_eq!(messages[1]["stdout"], "hi");
+    assert_eq!(messages[2]["success"], true);
+    assert_eq!(messages[2]["output"], "42");
+    assert_eq!(messages[3]["success"], false);
+    assert!(messages[3]["errors"][0].is_string());
+}
+
+#[test]
+fn test_prewarm_option() {
+    let mut child = process::Command::new(evcxr_binary())
+        .args(["--disable-readline", "--prewarm"])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"40 + 2\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = std::str::from_utf8(&output.stdout).unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    assert!(
+        stdout.lines().any(|line| line.ends_with("42")),
+        "{}",
+        stdout
+    );
+    assert_eq!(stderr, "");
+}
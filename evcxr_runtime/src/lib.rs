@@ -45,11 +45,14 @@ impl ContentMimeType {
     ///     .text("<span style=\"color: red\">>Hello world</span>");
     /// ```
     pub fn text<S: AsRef<str>>(self, text: S) {
-        println!(
+        println!("{}", self.format_content(text.as_ref()));
+    }
+
+    fn format_content(&self, text: &str) -> String {
+        format!(
             "EVCXR_BEGIN_CONTENT {}\n{}\nEVCXR_END_CONTENT",
-            self.mime_type,
-            text.as_ref()
-        );
+            self.mime_type, text
+        )
     }
 
     /// Emits the supplied content, which should be of the mime type already
@@ -65,8 +68,86 @@ impl ContentMimeType {
     }
 }
 
+/// Emits LaTeX for display by frontends that support it, such as Jupyter. The supplied LaTeX
+/// should include math delimiters.
+/// ```
+/// evcxr_runtime::latex(r"$$\frac{1}{2}$$");
+/// ```
+pub fn latex<S: AsRef<str>>(latex: S) {
+    mime_type("text/latex").text(latex)
+}
+
+/// Emits Markdown for display by frontends that support it, such as Jupyter.
+/// ```
+/// evcxr_runtime::markdown("# Results\n\n*All* tests passed");
+/// ```
+pub fn markdown<S: AsRef<str>>(markdown: S) {
+    mime_type("text/markdown").text(markdown)
+}
+
+/// Emits `value` serialized as JSON, for frontends that can render JSON specially, such as
+/// Jupyter. The JSON is also emitted pretty-printed as plain text, for frontends that can't. If
+/// `value` can't be serialized, e.g. because it's a map with non-string keys, its Debug
+/// representation is emitted as plain text instead. Requires the `json` feature.
+/// ```
+/// evcxr_runtime::json(&vec![("alice", 3), ("bob", 5)]);
+/// ```
+#[cfg(feature = "json")]
+pub fn json<T: serde::Serialize + std::fmt::Debug + ?Sized>(value: &T) {
+    for (mime, text) in json_content(value) {
+        mime_type(mime).text(text);
+    }
+}
+
+/// Returns the content emitted by `json` as (mime type, content) pairs.
+#[cfg(feature = "json")]
+fn json_content<T: serde::Serialize + std::fmt::Debug + ?Sized>(
+    value: &T,
+) -> Vec<(&'static str, String)> {
+    match (
+        serde_json::to_string(value),
+        serde_json::to_string_pretty(value),
+    ) {
+        (Ok(compact), Ok(pretty)) => vec![("application/json", compact), ("text/plain", pretty)],
+        _ => vec![("text/plain", format!("{:?}", value))],
+    }
+}
+
+/// Spawns a thread whose output continues to be shown after the cell that spawned it has finished
+/// running. Output from the thread is forwarded as it's written until either the thread finishes
+/// or the next cell starts running. After that, any further output is shown with whichever cell is
+/// running when it's written. Only plain text output is forwarded between cells, content emitted
+/// via `mime_type` is held until the next cell runs.
+/// ```
+/// let handle = evcxr_runtime::spawn(|| println!("Hello from a background thread"));
+/// handle.join().unwrap();
+/// ```
+pub fn spawn<F, T>(f: F) -> std::thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    /// Reports that the thread has finished when dropped, even if the thread panics.
+    struct FinishGuard;
+
+    impl Drop for FinishGuard {
+        fn drop(&mut self) {
+            println!("EVCXR_BACKGROUND_THREAD_FINISHED");
+        }
+    }
+
+    // Printed from the spawning thread, so that it arrives before the cell finishes running.
+    println!("EVCXR_BACKGROUND_THREAD_STARTED");
+    std::thread::spawn(move || {
+        let _guard = FinishGuard;
+        f()
+    })
+}
+
 #[cfg(test)]
 mod tests {
+    use super::latex;
+    use super::markdown;
     use super::mime_type;
 
     #[test]
@@ -74,6 +155,46 @@ mod tests {
         mime_type("text/plain").text("Hello world");
     }
 
+    #[test]
+    fn test_format_content() {
+        assert_eq!(
+            mime_type("text/latex").format_content("$$x^2$$"),
+            "EVCXR_BEGIN_CONTENT text/latex\n$$x^2$$\nEVCXR_END_CONTENT"
+        );
+    }
+
+    #[test]
+    fn test_emit_latex() {
+        latex("$$x^2$$");
+    }
+
+    #[test]
+    fn test_emit_markdown() {
+        markdown("# Heading\n\nSome *text*");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_content() {
+        use super::json_content;
+        assert_eq!(
+            json_content(&(1, "two", [3.5])),
+            vec![
+                ("application/json", r#"[1,"two",[3.5]]"#.to_owned()),
+                (
+                    "text/plain",
+                    "[\n  1,\n  \"two\",\n  [\n    3.5\n  ]\n]".to_owned()
+                ),
+            ]
+        );
+        let mut map = std::collections::BTreeMap::new();
+        map.insert((1, 2), 3);
+        assert_eq!(
+            json_content(&map),
+            vec![("text/plain", "{(1, 2): 3}".to_owned())]
+        );
+    }
+
     #[test]
     fn test_mime_type_accept_string() {
         mime_type("text/plain".to_owned()).text("Hello world");